@@ -0,0 +1,213 @@
+//! Device-authenticated requests signed with the device's Ed25519 key
+//!
+//! Every request carries an `Authorization` header:
+//!
+//! ```text
+//! Authorization: Genesis-Ed25519 device="sensor-0042", ts="1760000000", nonce="9f86d0...", sig="4e1c..."
+//! ```
+//!
+//! `device` is the percent-encoded device ID, `ts` the Unix time in seconds,
+//! `nonce` 16 bytes and `sig` the 64-byte Ed25519 signature, both hex. The
+//! signature covers these lines joined with `\n`:
+//!
+//! ```text
+//! genesis-request-v1
+//! <device, as in the header>
+//! <method>
+//! <request target: path and query>
+//! <ts>
+//! <nonce>
+//! ```
+//!
+//! A server looks up the public key registered for `device`, checks the
+//! signature, rejects timestamps outside a small window and nonces it has
+//! already seen in that window. `tools/request-verifier` is a reference
+//! implementation.
+//!
+//! Only the HTTP transports send the header; CoAP and serial have no
+//! equivalent and drop it.
+
+use crate::config::MAX_URL_LENGTH;
+use crate::error::{NetworkError, Result};
+use crate::http::Method;
+use crate::identity::DeviceKey;
+use crate::url::percent_encode;
+use core::fmt::Write as _;
+use embassy_time::Instant;
+use heapless::String;
+use sha2::{Digest, Sha256};
+
+/// Authentication scheme named in the `Authorization` header
+pub const AUTH_SCHEME: &str = "Genesis-Ed25519";
+
+/// First line of every signed message, versioning the format
+pub const SIGNATURE_CONTEXT: &str = "genesis-request-v1";
+
+/// Maximum length of an `Authorization` header value
+pub const MAX_AUTHORIZATION_LENGTH: usize = 384;
+
+/// Size of a request nonce in bytes
+const NONCE_SIZE: usize = 16;
+
+/// Maximum length of the percent-encoded device ID (32 bytes, all escaped)
+const MAX_DEVICE_LENGTH: usize = 96;
+
+/// Maximum length of a signed message
+const MAX_MESSAGE_LENGTH: usize = 64 + MAX_DEVICE_LENGTH + MAX_URL_LENGTH + 2 * NONCE_SIZE;
+
+/// Signs requests with the device key
+pub struct RequestSigner {
+    key: DeviceKey,
+    counter: u32,
+}
+
+/// Signer and parameters for the requests of one operation
+pub struct RequestAuth<'a> {
+    signer: &'a mut RequestSigner,
+    device_id: &'a str,
+    unix_time: u64,
+}
+
+impl RequestSigner {
+    /// Create a signer using `key`
+    pub fn new(key: DeviceKey) -> Self {
+        Self { key, counter: 0 }
+    }
+
+    /// Public key the server should have registered for this device
+    pub fn public_key(&self) -> [u8; 32] {
+        self.key.public_key()
+    }
+
+    /// Build the `Authorization` header value for a request
+    ///
+    /// `target` is the path and query exactly as sent in the request line.
+    pub fn authorize(
+        &mut self,
+        device_id: &str,
+        method: Method,
+        target: &str,
+        unix_time: u64,
+    ) -> Result<String<MAX_AUTHORIZATION_LENGTH>> {
+        let mut device: String<MAX_DEVICE_LENGTH> = String::new();
+        percent_encode(&mut device, device_id)?;
+        let nonce = self.next_nonce(&device, unix_time);
+        self.header(&device, method, target, unix_time, &nonce)
+    }
+
+    /// Build the `Authorization` header value for a request from the
+    /// percent-encoded `device` with `nonce`
+    fn header(
+        &self,
+        device: &str,
+        method: Method,
+        target: &str,
+        unix_time: u64,
+        nonce: &[u8; NONCE_SIZE],
+    ) -> Result<String<MAX_AUTHORIZATION_LENGTH>> {
+        let mut message: String<MAX_MESSAGE_LENGTH> = String::new();
+        write!(
+            message,
+            "{}\n{}\n{}\n{}\n{}\n",
+            SIGNATURE_CONTEXT,
+            device,
+            method.as_str(),
+            target,
+            unix_time
+        )
+        .map_err(|_| NetworkError::RequestTooLarge)?;
+        write_hex(&mut message, nonce)?;
+        let signature = self.key.sign(message.as_bytes());
+
+        let mut header = String::new();
+        write!(header, "{} device=\"{}\", ts=\"{}\", nonce=\"", AUTH_SCHEME, device, unix_time)
+            .map_err(|_| NetworkError::RequestTooLarge)?;
+        write_hex(&mut header, nonce)?;
+        header.push_str("\", sig=\"").map_err(|_| NetworkError::RequestTooLarge)?;
+        write_hex(&mut header, &signature)?;
+        header.push('"').map_err(|_| NetworkError::RequestTooLarge)?;
+        Ok(header)
+    }
+
+    /// Derive a nonce unique to this request
+    ///
+    /// Mixes a per-boot counter, the monotonic clock and the timestamp. It
+    /// need not be secret, only unlikely to repeat within the server's window.
+    fn next_nonce(&mut self, device: &str, unix_time: u64) -> [u8; NONCE_SIZE] {
+        self.counter = self.counter.wrapping_add(1);
+        let digest = Sha256::new()
+            .chain_update(device.as_bytes())
+            .chain_update(self.counter.to_le_bytes())
+            .chain_update(Instant::now().as_ticks().to_le_bytes())
+            .chain_update(unix_time.to_le_bytes())
+            .finalize();
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&digest[..NONCE_SIZE]);
+        nonce
+    }
+}
+
+impl<'a> RequestAuth<'a> {
+    /// Sign requests as `device_id` at `unix_time`
+    pub fn new(signer: &'a mut RequestSigner, device_id: &'a str, unix_time: u64) -> Self {
+        Self {
+            signer,
+            device_id,
+            unix_time,
+        }
+    }
+
+    /// Build the `Authorization` header value for a request
+    pub fn authorize(&mut self, method: Method, target: &str) -> Result<String<MAX_AUTHORIZATION_LENGTH>> {
+        self.signer
+            .authorize(self.device_id, method, target, self.unix_time)
+    }
+}
+
+/// Append `bytes` to `out` as lowercase hex
+fn write_hex<const N: usize>(out: &mut String<N>, bytes: &[u8]) -> Result<()> {
+    for byte in bytes {
+        write!(out, "{:02x}", byte).map_err(|_| NetworkError::RequestTooLarge)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    include!("../testdata/signed_requests.rs");
+
+    fn signer() -> RequestSigner {
+        RequestSigner::new(DeviceKey::new(DEVICE_SEED))
+    }
+
+    fn device() -> String<MAX_DEVICE_LENGTH> {
+        let mut device = String::new();
+        percent_encode(&mut device, DEVICE).unwrap();
+        device
+    }
+
+    #[test]
+    fn headers_match_the_verifier_vectors() {
+        let signer = signer();
+        let get = signer.header(&device(), Method::Get, MANIFEST, TIMESTAMP, &GET_NONCE).unwrap();
+        assert_eq!(get.as_str(), DEVICE_GET);
+        let post = signer.header(&device(), Method::Post, REPORT, TIMESTAMP, &POST_NONCE).unwrap();
+        assert_eq!(post.as_str(), DEVICE_POST);
+    }
+
+    #[test]
+    fn every_request_gets_a_new_nonce() {
+        let mut signer = signer();
+        let first = signer.authorize(DEVICE, Method::Get, MANIFEST, TIMESTAMP).unwrap();
+        let second = signer.authorize(DEVICE, Method::Get, MANIFEST, TIMESTAMP).unwrap();
+        assert_ne!(first, second);
+
+        // Same form as the vectors, up to the nonce and the signature
+        let prefix = "Genesis-Ed25519 device=\"sensor%2042%2F%CE%B1\", ts=\"1760000000\", nonce=\"";
+        assert!(first.starts_with(prefix));
+        assert_eq!(first.len(), DEVICE_GET.len());
+    }
+}
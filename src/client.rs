@@ -1,24 +1,58 @@
 //! Main OTA client implementation
 
-use crate::config::{OtaConfig};
-use crate::error::{Error, NetworkError, OtaError, Result};
-use crate::manifest::{UpdateManifest, UpdateFile};
+use crate::auth::{RequestAuth, RequestSigner};
+use crate::config::{OtaConfig, TlsTrust, Version, MAX_URL_LENGTH};
+use crate::consent::{self, AutoConsent, ConsentAnswer, ConsentHandler, ConsentRecord, ConsentStage, UpdateSummary};
+#[cfg(feature = "mdns")]
+use crate::discovery::{self, OTA_SERVICE};
+use crate::error::{ConfigError, Error, NetworkError, OtaError, Result};
+#[cfg(feature = "mdns")]
+use crate::error::TimeoutPhase;
+use crate::identity::{ClientIdentity, DeviceKey};
+use crate::install::{FileHandler, NoFileHandler, Route, StagingArea};
+use crate::manifest::{ManifestCache, UpdateManifest, UpdateFile, MAX_MANIFEST_SIZE};
+use crate::mirror::{self, MirrorHealth, MAX_SERVERS};
+#[cfg(feature = "mqtt")]
+use crate::mqtt::MqttSession;
+use crate::redirect;
+use crate::report::{InstallOutcome, InstallReport, ReportQueue, MAX_REPORT_SIZE, REPORT_CONTENT_TYPE};
+use crate::retry::{self, Backoff, RetryDecision};
+use crate::state::{self, NoStateStore, StateKey, StateStore};
 use crate::storage::{UpdateProgress, UpdateOperation, UpdateStorage};
+use crate::time::{TimeSource, WallClock};
 use crate::verification::{PublicKey, SignatureVerifier};
+use crate::writer::{DownloadCheckpoint, FirmwareWriter};
 
-use embassy_net::tcp::TcpSocket;
-use embedded_tls::{Aes128GcmSha256, TlsConfig};
-use heapless::{String, Vec};
+use crate::http::{Discard, Request};
+use crate::transport::OtaTransport;
+use crate::url::{Scheme, Url};
 
-/// Maximum response buffer size
-const MAX_RESPONSE_SIZE: usize = 4096;
+use core::fmt::Write as _;
+use core::ops::Range;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{String, Vec};
 
 /// OTA client for managing updates
-pub struct OtaClient<S> {
+pub struct OtaClient<S, T, P = NoStateStore, H = NoFileHandler, C = AutoConsent> {
     config: OtaConfig,
     storage: S,
+    handler: H,
+    consent: C,
+    transport: T,
     verifier: SignatureVerifier,
+    state: P,
+    mirrors: MirrorHealth,
+    signer: Option<RequestSigner>,
+    clock: WallClock,
     progress: Option<UpdateProgress>,
+    /// Download attempts of the current install, over all its files
+    attempts: u8,
+    /// Digest of the manifest whose files are staged and waiting for a window
+    staged: Option<[u8; 32]>,
+    /// Release deferred by the user and until when, in case the clock is not set
+    deferral: Option<(Version, Instant)>,
+    /// Release installed last, awaiting consent to reboot
+    installed: Option<UpdateSummary>,
 }
 
 /// Update check result
@@ -32,180 +66,785 @@ pub enum UpdateStatus {
     CheckFailed(Error),
 }
 
-impl<S> OtaClient<S>
+impl<S, T> OtaClient<S, T>
 where
     S: UpdateStorage,
+    T: OtaTransport,
 {
-    /// Create a new OTA client
-    pub fn new(config: OtaConfig, storage: S, public_key: PublicKey) -> Self {
+    /// Create a new OTA client fetching updates through `transport`
+    pub fn new(config: OtaConfig, storage: S, public_key: PublicKey, mut transport: T) -> Self {
+        transport.configure(&config);
+        let mirrors = MirrorHealth::new(
+            config.server_count(),
+            Duration::from_secs(config.mirror_cooldown_secs as u64),
+        );
+        
         Self {
             config,
             storage,
+            handler: NoFileHandler,
+            consent: AutoConsent,
+            transport,
             verifier: SignatureVerifier::new(public_key),
+            state: NoStateStore,
+            mirrors,
+            signer: None,
+            clock: WallClock::new(),
             progress: None,
+            attempts: 0,
+            staged: None,
+            deferral: None,
+            installed: None,
+        }
+    }
+}
+
+impl<S, T, P, H, C> OtaClient<S, T, P, H, C>
+where
+    S: UpdateStorage,
+    T: OtaTransport,
+    P: StateStore,
+    H: FileHandler,
+    C: ConsentHandler,
+{
+    /// Persist client state (e.g. download checkpoints) in the given store
+    pub fn with_state_store<Q: StateStore>(self, state: Q) -> OtaClient<S, T, Q, H, C> {
+        OtaClient {
+            config: self.config,
+            storage: self.storage,
+            handler: self.handler,
+            consent: self.consent,
+            transport: self.transport,
+            verifier: self.verifier,
+            state,
+            mirrors: self.mirrors,
+            signer: self.signer,
+            clock: self.clock,
+            progress: self.progress,
+            attempts: self.attempts,
+            staged: self.staged,
+            deferral: self.deferral,
+            installed: self.installed,
         }
     }
     
+    /// Install the files of a release other than the firmware image with `handler`
+    ///
+    /// Without a handler, releases carrying such files are refused (see
+    /// [`crate::install`]).
+    pub fn with_file_handler<G: FileHandler>(self, handler: G) -> OtaClient<S, T, P, G, C> {
+        OtaClient {
+            config: self.config,
+            storage: self.storage,
+            handler,
+            consent: self.consent,
+            transport: self.transport,
+            verifier: self.verifier,
+            state: self.state,
+            mirrors: self.mirrors,
+            signer: self.signer,
+            clock: self.clock,
+            progress: self.progress,
+            attempts: self.attempts,
+            staged: self.staged,
+            deferral: self.deferral,
+            installed: self.installed,
+        }
+    }
+    
+    /// Ask the user through `consent` before downloading, installing and
+    /// rebooting into a release (see [`crate::consent`])
+    pub fn with_consent_handler<D: ConsentHandler>(self, consent: D) -> OtaClient<S, T, P, H, D> {
+        OtaClient {
+            config: self.config,
+            storage: self.storage,
+            handler: self.handler,
+            consent,
+            transport: self.transport,
+            verifier: self.verifier,
+            state: self.state,
+            mirrors: self.mirrors,
+            signer: self.signer,
+            clock: self.clock,
+            progress: self.progress,
+            attempts: self.attempts,
+            staged: self.staged,
+            deferral: self.deferral,
+            installed: self.installed,
+        }
+    }
+    
+    /// Authenticate this device to the server with a client certificate
+    pub fn with_client_identity(mut self, identity: ClientIdentity) -> Self {
+        self.transport.set_client_identity(identity);
+        self
+    }
+    
+    /// Sign every request to the update server with the device's Ed25519 key
+    ///
+    /// The server can then authenticate the device without TLS client
+    /// certificates (see [`crate::auth`]). Signatures carry a timestamp, so
+    /// requests fail with `OtaError::ClockNotSet` until [`Self::set_unix_time`]
+    /// has been called.
+    pub fn with_device_key(mut self, key: DeviceKey) -> Self {
+        self.signer = Some(RequestSigner::new(key));
+        self
+    }
+    
+    /// Set the current Unix time in seconds, e.g. from SNTP
+    pub fn set_unix_time(&mut self, unix_secs: u64) {
+        self.clock.set(unix_secs);
+    }
+    
+    /// Set the clock from `source`, e.g. an SNTP server
+    ///
+    /// Until the clock is set, the `Date` header of manifest responses is
+    /// used, which is close enough for maintenance windows, but only from a
+    /// server whose certificate was checked.
+    pub async fn sync_time<Src: TimeSource>(&mut self, source: &mut Src) -> Result<()> {
+        let unix_secs = source.unix_time().await?;
+        self.clock.set(unix_secs);
+        Ok(())
+    }
+    
+    /// Find the update server on the local network with DNS-SD
+    ///
+    /// Browses for [`OTA_SERVICE`] for up to the configured DNS timeout and
+    /// uses the first server that answers. Only needed when the configured
+    /// server URL is "discover"; checks fail with
+    /// `ConfigError::ServerNotDiscovered` until then.
+    #[cfg(feature = "mdns")]
+    pub async fn discover_server(&mut self, stack: embassy_net::Stack<'_>) -> Result<()> {
+        let timeout = embassy_time::Duration::from_millis(self.config.timeouts.dns_ms as u64);
+        let service = embassy_time::with_timeout(timeout, discovery::browse(stack, OTA_SERVICE))
+            .await
+            .map_err(|_| NetworkError::Timeout(TimeoutPhase::Dns))??;
+        self.config.set_server_url(&service.url()?)
+    }
+    
     /// Check for available updates
-    pub async fn check_update<'a>(
-        &mut self,
-        socket: &'a mut TcpSocket<'a>,
-        tls_rx_buffer: &'a mut [u8],
-        tls_tx_buffer: &'a mut [u8],
-    ) -> UpdateStatus {
-        match self.fetch_manifest(socket, tls_rx_buffer, tls_tx_buffer).await {
-            Ok(manifest) => {
+    ///
+    /// The manifest is requested conditionally when a previous check found
+    /// nothing to install; a `304 Not Modified` reports [`UpdateStatus::UpToDate`]
+    /// without downloading or verifying the manifest again.
+    pub async fn check_update(&mut self) -> UpdateStatus {
+        self.progress = Some(UpdateProgress::new(0));
+        
+        match self.fetch_manifest_with_retry().await {
+            Ok(Some(manifest)) => {
+                // The server is reachable; deliver reports left from earlier
+                let _ = self.flush_reports().await;
                 if manifest.is_applicable(&self.config.current_version) {
                     UpdateStatus::Available(manifest)
                 } else {
                     UpdateStatus::UpToDate
                 }
             }
+            Ok(None) => {
+                let _ = self.flush_reports().await;
+                UpdateStatus::UpToDate
+            }
             Err(e) => UpdateStatus::CheckFailed(e),
         }
     }
     
+    /// Wait for a signed release notice over MQTT, then check for the update
+    ///
+    /// Notices for versions not newer than the running firmware are ignored.
+    /// An error means the MQTT session was lost and should be reconnected.
+    #[cfg(feature = "mqtt")]
+    pub async fn check_on_release<M>(&mut self, session: &mut MqttSession<M>) -> Result<UpdateStatus>
+    where
+        M: embedded_io_async::Read + embedded_io_async::Write,
+    {
+        loop {
+            let announcement = session.next_notice(&self.verifier, self.clock.now()).await?;
+            if announcement.version > self.config.current_version {
+                return Ok(self.check_update().await);
+            }
+        }
+    }
+    
     /// Download and apply an update
-    pub async fn download_and_apply<'a>(
-        &mut self,
-        manifest: UpdateManifest,
-        socket: &'a mut TcpSocket<'a>,
-        tls_rx_buffer: &'a mut [u8],
-        tls_tx_buffer: &'a mut [u8],
-    ) -> Result<()> {
+    ///
+    /// With a report URL configured, the outcome is reported to the server
+    /// (see [`Self::report_install`]).
+    pub async fn download_and_apply(&mut self, manifest: UpdateManifest) -> Result<()> {
+        let started = Instant::now();
+        let from_version = self.config.current_version;
+        let to_version = manifest.version;
+        let result = self.install(manifest).await;
+        if result.as_ref().is_err_and(is_held_back) {
+            // Not an outcome of the install; the user or a window holds it back
+            return result;
+        }
+        
+        let outcome = match result {
+            Ok(()) => InstallOutcome::Installed,
+            Err(_) => InstallOutcome::Failed,
+        };
+        let mut report = InstallReport::new(from_version, to_version, outcome)
+            .with_attempts(self.attempts)
+            .with_duration(started.elapsed().as_secs() as u32);
+        if let Err(e) = &result {
+            report = report.with_error(e);
+        }
+        // A failed report must not mask the outcome of the install itself
+        let _ = self.report_install(report).await;
+        result
+    }
+    
+    /// Report the outcome of an install to the server
+    ///
+    /// [`Self::download_and_apply`] reports its own outcome; use this for
+    /// what is only known after the reboot (`Booted`, `Confirmed`,
+    /// `RolledBack`). Reports that cannot be delivered now are queued in the
+    /// state store and sent after the next successful check. Does nothing
+    /// when no report URL is configured.
+    pub async fn report_install(&mut self, report: InstallReport) -> Result<()> {
+        if self.config.report_url.is_none() {
+            return Ok(());
+        }
+        let mut queue: ReportQueue = state::load_record(&mut self.state, StateKey::ReportQueue)
+            .await?
+            .unwrap_or_default();
+        queue.push(report);
+        // Delivery failures leave the report queued
+        let _ = self.send_reports(&mut queue).await;
+        self.save_reports(&queue).await
+    }
+    
+    /// Send queued reports, returning how many were delivered
+    ///
+    /// Stops at the first report that cannot be delivered; it stays queued.
+    pub async fn flush_reports(&mut self) -> Result<usize> {
+        if self.config.report_url.is_none() {
+            return Ok(0);
+        }
+        let queue: Option<ReportQueue> =
+            state::load_record(&mut self.state, StateKey::ReportQueue).await?;
+        let Some(mut queue) = queue else {
+            return Ok(0);
+        };
+        let result = self.send_reports(&mut queue).await;
+        self.save_reports(&queue).await?;
+        result
+    }
+    
+    /// Download, verify and install every file of the update
+    ///
+    /// Nothing is committed unless every file was staged and verified.
+    /// Outside the maintenance windows, or when the user defers the install,
+    /// the staged files are kept and installing the same release again later
+    /// goes straight to committing them.
+    async fn install(&mut self, manifest: UpdateManifest) -> Result<()> {
         // Initialize progress tracking
-        self.progress = Some(UpdateProgress::new(manifest.total_size()));
+        let total = manifest.total_size();
+        self.progress = Some(
+            UpdateProgress::new(total).with_file_count(manifest.files.len() as u8),
+        );
+        self.attempts = 0;
         
-        // Find firmware file
-        let firmware_file = manifest
-            .firmware_file()
-            .ok_or(Error::Ota(OtaError::InvalidState))?;
+        let digest = manifest.digest()?;
+        if self.staged != Some(digest) {
+            self.staged = None;
+            self.ask_consent(&manifest, ConsentStage::Download).await?;
+            self.stage_files(&manifest).await?;
+            self.staged = Some(digest);
+        }
         
-        // Download firmware
-        self.update_progress(0, UpdateOperation::Downloading);
-        let firmware_data = self
-            .download_file(firmware_file, socket, tls_rx_buffer, tls_tx_buffer)
-            .await?;
+        if !self.in_maintenance_window() {
+            self.update_progress(total, UpdateOperation::AwaitingWindow);
+            return Err(OtaError::OutsideMaintenanceWindow.into());
+        }
+        self.ask_consent(&manifest, ConsentStage::Install).await?;
+        self.staged = None;
         
-        // Verify integrity
-        self.update_progress(firmware_file.size, UpdateOperation::Verifying);
-        self.verifier
-            .verify_firmware(&firmware_data, &firmware_file.sha256)?;
+        // Handler files first; switching to the new firmware comes last
+        self.update_progress(total, UpdateOperation::Finalizing);
+        for (index, file) in manifest.files.iter().enumerate() {
+            if Route::of(&manifest, index) != Route::Handler {
+                continue;
+            }
+            if let Err(e) = self.handler.commit(file).await {
+                // What is committed stays committed; drop the rest
+                self.abort_files(&manifest, index..manifest.files.len()).await;
+                return Err(e);
+            }
+        }
+        self.finalize_update(&manifest).await?;
         
-        // Write to storage
-        self.update_progress(firmware_file.size, UpdateOperation::Writing);
-        self.write_firmware(&firmware_data).await?;
+        // Earlier answers about this release no longer matter
+        let _ = self.state.remove(StateKey::Consent).await;
+        self.deferral = None;
+        self.installed = Some(UpdateSummary::of(&manifest));
         
-        // Finalize update
-        self.update_progress(firmware_file.size, UpdateOperation::Finalizing);
-        self.finalize_update(&manifest).await?;
+        self.update_progress(total, UpdateOperation::Complete);
+        Ok(())
+    }
+    
+    /// Ask the consent handler about `stage` of `manifest`, unless an earlier
+    /// answer still stands
+    ///
+    /// Fails with `OtaError::ConsentDeferred` or `OtaError::UpdateRejected`
+    /// when the update may not go ahead now.
+    async fn ask_consent(&mut self, manifest: &UpdateManifest, stage: ConsentStage) -> Result<()> {
+        let stored: Option<ConsentRecord> =
+            state::load_record(&mut self.state, StateKey::Consent).await?;
+        let mut record = stored
+            .filter(|record| record.version == manifest.version)
+            .unwrap_or_else(|| ConsentRecord::new(manifest.version));
+        if record.rejected {
+            return Err(OtaError::UpdateRejected.into());
+        }
+        if self.is_deferred(&record) {
+            return Err(OtaError::ConsentDeferred.into());
+        }
         
-        self.update_progress(firmware_file.size, UpdateOperation::Complete);
+        let summary = UpdateSummary::of(manifest);
+        let answer = self.consent.consent(stage, &summary).await;
+        let cap = self.config.critical_deferral_cap_secs;
+        let result = if answer == ConsentAnswer::Reject && !summary.is_critical() {
+            record.rejected = true;
+            Err(OtaError::UpdateRejected.into())
+        } else if let Some(secs) =
+            consent::deferral_secs(answer, summary.is_critical(), record.deferred_secs, cap)
+        {
+            record.deferred_secs = record.deferred_secs.saturating_add(secs);
+            record.deferred_until = self.clock.now().map(|now| now + secs as u64);
+            self.deferral = Some((manifest.version, Instant::now() + Duration::from_secs(secs as u64)));
+            Err(OtaError::ConsentDeferred.into())
+        } else {
+            return Ok(());
+        };
+        // Without the record the user is asked again after a restart
+        let _ = state::store_record(&mut self.state, StateKey::Consent, &record).await;
+        result
+    }
+    
+    /// Check whether the user's deferral of the release in `record` still holds
+    fn is_deferred(&self, record: &ConsentRecord) -> bool {
+        match self.deferral {
+            Some((version, until)) if version == record.version => Instant::now() < until,
+            // Deferred before a restart; only the wall clock can tell
+            _ => match (record.deferred_until, self.clock.now()) {
+                (Some(until), Some(now)) => now < until,
+                _ => false,
+            },
+        }
+    }
+    
+    /// Wait for the user's consent to reboot into the release just installed
+    ///
+    /// Deferrals are waited out here, so this can take a while; for a
+    /// critical release, no longer than `critical_deferral_cap_secs` in all.
+    /// Fails with `OtaError::UpdateRejected` if the user would rather not
+    /// reboot; the release then starts with the next restart anyway. For the
+    /// same reason, deferrals here are not stored, and a restart while one is
+    /// waited out ends it. Returns at once when nothing has been installed
+    /// since the last call.
+    pub async fn await_reboot_consent(&mut self) -> Result<()> {
+        let Some(summary) = self.installed.take() else {
+            return Ok(());
+        };
+        let cap = self.config.critical_deferral_cap_secs;
+        let mut waited = 0u32;
+        loop {
+            let answer = self.consent.consent(ConsentStage::Reboot, &summary).await;
+            if answer == ConsentAnswer::Reject && !summary.is_critical() {
+                return Err(OtaError::UpdateRejected.into());
+            }
+            let Some(secs) = consent::deferral_secs(answer, summary.is_critical(), waited, cap) else {
+                return Ok(());
+            };
+            waited = waited.saturating_add(secs);
+            Timer::after(Duration::from_secs(secs as u64)).await;
+        }
+    }
+    
+    /// Download and verify every file of the update, or none of them
+    async fn stage_files(&mut self, manifest: &UpdateManifest) -> Result<()> {
+        // Every file needs somewhere to go before anything is downloaded
+        for (index, file) in manifest.files.iter().enumerate() {
+            if Route::of(manifest, index) == Route::Handler && !self.handler.accepts(file) {
+                return Err(OtaError::UnsupportedFile.into());
+            }
+        }
+        
+        let mut offset = 0;
+        for (index, file) in manifest.files.iter().enumerate() {
+            if let Err(e) = self.stage_file(manifest, index, offset).await {
+                // The release failed, so the files staged before this one are
+                // dropped. This one keeps its checkpoint, but only a first file
+                // resumes from it: the next attempt starts over with the first
+                // file, whose download replaces the checkpoint.
+                self.abort_files(manifest, 0..index).await;
+                return Err(e);
+            }
+            offset += file.size;
+        }
         Ok(())
     }
     
+    /// Download file `index` of the update into its staging area and verify it
+    ///
+    /// `offset` is the size of the files before it, for progress reporting.
+    async fn stage_file(&mut self, manifest: &UpdateManifest, index: usize, offset: u32) -> Result<()> {
+        let file = &manifest.files[index];
+        let route = Route::of(manifest, index);
+        if let Some(progress) = &mut self.progress {
+            progress.start_file(index as u8, offset, file.size);
+        }
+        
+        // Stream the file into storage; retries resume from the last checkpoint
+        self.update_progress(offset, UpdateOperation::Downloading);
+        let mut backoff = self.backoff();
+        let digest = loop {
+            self.record_attempt(backoff.retries().saturating_add(1));
+            self.attempts = self.attempts.saturating_add(1);
+            match self.download_with_failover(manifest, file, route).await {
+                Ok(digest) => break digest,
+                Err(e) => match backoff.next_delay(&e) {
+                    Some(delay) => Timer::after(delay).await,
+                    None => return Err(e),
+                },
+            }
+        };
+        
+        // Verify integrity; a corrupt file cannot be resumed, so drop the checkpoint either way
+        self.update_progress(offset + file.size, UpdateOperation::Verifying);
+        self.state.remove(StateKey::DownloadCheckpoint).await?;
+        self.verifier.verify_firmware_digest(&digest, &file.sha256)
+    }
+    
+    /// Tell the handler to drop the staged files in `indices`
+    async fn abort_files(&mut self, manifest: &UpdateManifest, indices: Range<usize>) {
+        for index in indices {
+            if Route::of(manifest, index) == Route::Handler {
+                // Best effort: the release has failed either way
+                let _ = self.handler.abort(&manifest.files[index]).await;
+            }
+        }
+    }
+    
     /// Get current update progress
     pub fn progress(&self) -> Option<&UpdateProgress> {
         self.progress.as_ref()
     }
     
-    /// Fetch update manifest from server
-    async fn fetch_manifest<'a>(
-        &self,
-        socket: &'a mut TcpSocket<'a>,
-        tls_rx_buffer: &'a mut [u8],
-        tls_tx_buffer: &'a mut [u8],
-    ) -> Result<UpdateManifest> {
-        let manifest_url = self.build_manifest_url()?;
-        let response = self
-            .http_get(&manifest_url, socket, tls_rx_buffer, tls_tx_buffer)
-            .await?;
+    /// Get the client configuration
+    pub fn config(&self) -> &OtaConfig {
+        &self.config
+    }
+    
+    /// Current Unix time in seconds, if it has been set
+    pub fn unix_time(&self) -> Option<u64> {
+        self.clock.now()
+    }
+    
+    /// Check whether updates may be installed now
+    ///
+    /// False while the time is unknown if maintenance windows are configured.
+    pub fn in_maintenance_window(&self) -> bool {
+        self.config.in_maintenance_window(self.clock.now())
+    }
+    
+    /// Get the store holding the client's persistent state
+    pub(crate) fn state_store(&mut self) -> &mut P {
+        &mut self.state
+    }
+    
+    /// Health of the primary server and its mirrors
+    pub fn mirror_health(&self) -> &MirrorHealth {
+        &self.mirrors
+    }
+    
+    /// Get the transport used by this client
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+    
+    /// Get mutable access to the transport used by this client
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+    
+    /// Fetch the manifest, retrying transient failures per the retry configuration
+    async fn fetch_manifest_with_retry(&mut self) -> Result<Option<UpdateManifest>> {
+        let mut backoff = self.backoff();
+        loop {
+            self.record_attempt(backoff.retries().saturating_add(1));
+            match self.fetch_manifest_with_failover().await {
+                Ok(manifest) => return Ok(manifest),
+                Err(e) => match backoff.next_delay(&e) {
+                    Some(delay) => Timer::after(delay).await,
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+    
+    /// Fetch the manifest from the first server able to serve it
+    async fn fetch_manifest_with_failover(&mut self) -> Result<Option<UpdateManifest>> {
+        let mut last_error = None;
+        for server in self.servers() {
+            match self.fetch_manifest(server).await {
+                Err(e) if mirror::should_fail_over(&e) => {
+                    self.mirrors.record_failure(server, Instant::now());
+                    last_error = Some(e);
+                }
+                result => {
+                    if result.is_ok() {
+                        self.mirrors.record_success(server);
+                    }
+                    return result;
+                }
+            }
+        }
+        // Nothing to try only while the primary is yet to be discovered and there are no mirrors
+        Err(last_error.unwrap_or(ConfigError::ServerNotDiscovered.into()))
+    }
+    
+    /// Fetch update manifest from server `server`
+    ///
+    /// Returns `None` if the server reports the manifest as not modified since
+    /// the last check.
+    async fn fetch_manifest(&mut self, server: usize) -> Result<Option<UpdateManifest>> {
+        let manifest_url = self.build_manifest_url(server)?;
+        
+        // Validators only apply to the version the cached manifest was checked against
+        let cache: Option<ManifestCache> =
+            state::load_record(&mut self.state, StateKey::ManifestCache).await?;
+        let cache = cache.filter(|c| c.version == self.config.current_version);
+        let mut headers: Vec<(&str, &str), 2> = Vec::new();
+        if let Some(cache) = &cache {
+            if let Some(etag) = &cache.etag {
+                let _ = headers.push(("If-None-Match", etag.as_str()));
+            }
+            if let Some(date) = &cache.last_modified {
+                let _ = headers.push(("If-Modified-Since", date.as_str()));
+            }
+        }
+        
+        let mut response: Vec<u8, MAX_MANIFEST_SIZE> = Vec::new();
+        let mut final_url = String::new();
+        let mut auth = request_auth(&mut self.signer, &self.clock, &self.config.device_id)?;
+        let result = redirect::fetch_following(
+            &mut self.transport,
+            &self.config.redirect_policy,
+            &Request::get(&manifest_url).with_headers(&headers),
+            auth.as_mut(),
+            &mut response,
+            &mut final_url,
+        )
+        .await;
+        self.record_final_url(&final_url);
+        let head = result?;
+        if let Some(date) = head.date.filter(|_| self.is_authenticated(&final_url)) {
+            self.clock.observe_server_date(date);
+        }
+        if head.is_not_modified() {
+            if headers.is_empty() {
+                // Not a conditional request; a 304 makes no sense here
+                return Err(NetworkError::HttpError(head.status).into());
+            }
+            return Ok(None);
+        }
         
         // Parse and verify manifest
         let manifest = crate::manifest::Manifest::parse(&response)?;
         
         // Verify signature
-        let manifest_bytes = postcard::to_vec::<_, 512>(&manifest)
-            .map_err(|_| Error::Manifest(crate::error::ManifestError::InvalidFormat))?;
+        let mut buffer = [0u8; MAX_MANIFEST_SIZE];
+        let manifest_bytes = manifest.signed_bytes(&mut buffer)?;
         self.verifier
-            .verify_manifest(&manifest_bytes, &manifest.signature)?;
+            .verify_manifest(manifest_bytes, &manifest.signature)?;
+        
+        // Remember the validators only while there is nothing to install, so a
+        // pending update is never hidden behind a 304
+        let has_validators = head.etag.is_some() || head.last_modified.is_some();
+        if has_validators && !manifest.is_applicable(&self.config.current_version) {
+            let fresh = ManifestCache {
+                version: self.config.current_version,
+                etag: head.etag,
+                last_modified: head.last_modified,
+            };
+            // Caching is an optimisation; a failed write only costs a full fetch
+            let _ = state::store_record(&mut self.state, StateKey::ManifestCache, &fresh).await;
+        } else if cache.is_some() {
+            let _ = self.state.remove(StateKey::ManifestCache).await;
+        }
         
-        Ok(manifest)
+        Ok(Some(manifest))
     }
     
-    /// Download a file from the update
-    async fn download_file<'a>(
-        &self,
+    /// Load the checkpoint for `file`, discarding one left by a different download
+    async fn load_checkpoint(
+        &mut self,
+        manifest: &UpdateManifest,
         file: &UpdateFile,
-        socket: &'a mut TcpSocket<'a>,
-        tls_rx_buffer: &'a mut [u8],
-        tls_tx_buffer: &'a mut [u8],
-    ) -> Result<Vec<u8, 65536>> {
-        let file_url = self.build_file_url(&file.url)?;
-        
-        // For larger files, we'd want to stream directly to storage
-        // For now, we'll buffer in RAM (limited to 64KB)
-        let response = self
-            .http_get(&file_url, socket, tls_rx_buffer, tls_tx_buffer)
-            .await?;
+    ) -> Result<DownloadCheckpoint> {
+        let current = DownloadCheckpoint::new(manifest.digest()?, file.sha256);
         
-        Vec::from_slice(&response)
-            .map_err(|_| Error::Storage(crate::error::StorageError::InsufficientSpace))
-    }
-    
-    /// Perform HTTP GET request
-    async fn http_get<'a>(
-        &self,
-        url: &str,
-        socket: &'a mut TcpSocket<'a>,
-        tls_rx_buffer: &'a mut [u8],
-        tls_tx_buffer: &'a mut [u8],
-    ) -> Result<Vec<u8, MAX_RESPONSE_SIZE>> {
-        // This is a simplified HTTP client implementation
-        // In production, you'd want proper error handling and retries
+        let stored: Option<DownloadCheckpoint> =
+            state::load_record(&mut self.state, StateKey::DownloadCheckpoint).await?;
+        match stored {
+            Some(stored) if stored.matches(&current) && stored.bytes_written <= file.size => {
+                Ok(stored)
+            }
+            Some(_) => {
+                self.state.remove(StateKey::DownloadCheckpoint).await?;
+                Ok(current)
+            }
+            None => Ok(current),
+        }
+    }
+    
+    /// Download `file` from the first server able to serve it
+    ///
+    /// A download interrupted on one server resumes from its checkpoint on
+    /// the next.
+    async fn download_with_failover(
+        &mut self,
+        manifest: &UpdateManifest,
+        file: &UpdateFile,
+        route: Route,
+    ) -> Result<[u8; 32]> {
+        let mut last_error = None;
+        for server in self.servers() {
+            let checkpoint = self.load_checkpoint(manifest, file).await?;
+            match self.download_file(server, file, route, checkpoint).await {
+                Err(e) if mirror::should_fail_over(&e) => {
+                    self.mirrors.record_failure(server, Instant::now());
+                    last_error = Some(e);
+                }
+                result => {
+                    if result.is_ok() {
+                        self.mirrors.record_success(server);
+                    }
+                    return result;
+                }
+            }
+        }
+        // Nothing to try only while the primary is yet to be discovered and there are no mirrors
+        Err(last_error.unwrap_or(ConfigError::ServerNotDiscovered.into()))
+    }
+    
+    /// Stream a file from the update into its staging area, returning its SHA256 digest
+    async fn download_file(
+        &mut self,
+        server: usize,
+        file: &UpdateFile,
+        route: Route,
+        checkpoint: DownloadCheckpoint,
+    ) -> Result<[u8; 32]> {
+        let file_url = self.build_file_url(server, &file.url)?;
         
-        let mut rx_buffer = [0; MAX_RESPONSE_SIZE];
-        let mut response: Vec<u8, MAX_RESPONSE_SIZE> = Vec::new();
+        let mut staging = match route {
+            Route::Firmware => StagingArea::Firmware(&mut self.storage),
+            Route::Handler => StagingArea::Handler(self.handler.stage(file).await?),
+        };
+        let mut writer = FirmwareWriter::new(
+            &mut staging,
+            &mut self.state,
+            checkpoint,
+            file.size,
+            self.progress.as_mut(),
+        )
+        .await?;
         
-        // Parse URL components (simplified)
-        let (host, path) = self.parse_url(url)?;
+        // Ask only for the part we do not have yet
+        let mut range: String<32> = String::new();
+        let resume_from = writer.written();
+        if resume_from > 0 {
+            write!(range, "bytes={}-", resume_from)
+                .map_err(|_| NetworkError::RequestTooLarge)?;
+        }
+        let range_header = [("Range", range.as_str())];
+        let headers: &[(&str, &str)] = if resume_from > 0 { &range_header } else { &[] };
         
-        // Create TLS client
-        let tls_config = TlsConfig::<Aes128GcmSha256>::new()
-            .with_server_name(&host);
+        let mut final_url = String::new();
+        let mut auth = request_auth(&mut self.signer, &self.clock, &self.config.device_id)?;
+        let result = redirect::fetch_following(
+            &mut self.transport,
+            &self.config.redirect_policy,
+            &Request::get(&file_url).with_headers(headers),
+            auth.as_mut(),
+            &mut writer,
+            &mut final_url,
+        )
+        .await;
         
-        // In a real implementation, you'd:
-        // 1. Resolve DNS for the host
-        // 2. Connect TCP socket
-        // 3. Perform TLS handshake
-        // 4. Send HTTP request
-        // 5. Parse response
+        // A body that ended early is a network problem, not a corrupt image
+        let result = match result {
+            Ok(_) if !writer.is_complete() => Err(NetworkError::Truncated.into()),
+            other => other,
+        };
         
-        // Placeholder for actual implementation
-        Err(NetworkError::ConnectionFailed.into())
+        let digest = match result {
+            Ok(_) => writer.finish().await,
+            Err(e) => {
+                // Keep what made it to flash for the next attempt
+                let _ = writer.save_checkpoint().await;
+                drop(writer);
+                Err(e)
+            }
+        };
+        self.record_final_url(&final_url);
+        digest
     }
     
-    /// Write firmware to storage
-    async fn write_firmware(&mut self, data: &[u8]) -> Result<()> {
-        // Erase target partition
-        let erase_size = self.storage.erase_size();
-        let blocks_needed = (data.len() as u32 + erase_size - 1) / erase_size;
-        self.storage.erase(0, blocks_needed * erase_size).await?;
-        
-        // Write firmware in chunks
-        const CHUNK_SIZE: usize = 4096;
-        for (offset, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
-            self.storage
-                .write((offset * CHUNK_SIZE) as u32, chunk)
-                .await?;
+    /// Send `queue` oldest first, removing delivered reports
+    async fn send_reports(&mut self, queue: &mut ReportQueue) -> Result<usize> {
+        let mut sent = 0;
+        while let Some(report) = queue.front().copied() {
+            match self.send_report(&report).await {
+                Ok(()) => sent += 1,
+                // Sending it again will not help
+                Err(e) if is_refused(&e) => {}
+                Err(e) => return Err(e),
+            }
+            queue.pop_front();
         }
+        Ok(sent)
+    }
+    
+    /// POST a single report to the preferred server
+    async fn send_report(&mut self, report: &InstallReport) -> Result<()> {
+        let report_url = self.build_report_url(self.mirrors.preferred())?;
+        let mut buffer = [0u8; MAX_REPORT_SIZE];
+        let body = postcard::to_slice(&(self.config.device_id.as_str(), report), &mut buffer)
+            .map_err(|_| NetworkError::RequestTooLarge)?;
+        let headers = [("Content-Type", REPORT_CONTENT_TYPE)];
         
+        let mut final_url = String::new();
+        let mut auth = request_auth(&mut self.signer, &self.clock, &self.config.device_id)?;
+        redirect::fetch_following(
+            &mut self.transport,
+            &self.config.redirect_policy,
+            &Request::post(&report_url, body).with_headers(&headers),
+            auth.as_mut(),
+            &mut Discard,
+            &mut final_url,
+        )
+        .await?;
         Ok(())
     }
     
+    /// Persist the report queue, dropping the record once it is empty
+    async fn save_reports(&mut self, queue: &ReportQueue) -> Result<()> {
+        if queue.is_empty() {
+            self.state.remove(StateKey::ReportQueue).await
+        } else {
+            state::store_record(&mut self.state, StateKey::ReportQueue, queue).await
+        }
+    }
+    
     /// Finalize the update process
     async fn finalize_update(&mut self, manifest: &UpdateManifest) -> Result<()> {
-        // Update configuration with new version
+        // The version names the release rather than the firmware image, so it
+        // moves on even when every file went to the handler; otherwise a
+        // release without firmware would be offered again forever
         self.config.current_version = manifest.version;
         
         // In a real implementation, you would:
@@ -216,56 +855,78 @@ where
         Ok(())
     }
     
-    /// Build manifest URL
-    fn build_manifest_url(&self) -> Result<String<256>> {
-        let mut url = String::new();
-        url.push_str(&self.config.server_url)
-            .map_err(|_| Error::Config(crate::error::ConfigError::InvalidUrl))?;
-        url.push_str("/manifest.json")
-            .map_err(|_| Error::Config(crate::error::ConfigError::InvalidUrl))?;
-        url.push_str("?device_id=")
-            .map_err(|_| Error::Config(crate::error::ConfigError::InvalidUrl))?;
-        url.push_str(&self.config.device_id)
-            .map_err(|_| Error::Config(crate::error::ConfigError::InvalidUrl))?;
-        Ok(url)
-    }
-    
-    /// Build file download URL
-    fn build_file_url(&self, file_path: &str) -> Result<String<256>> {
-        let mut url = String::new();
-        url.push_str(&self.config.server_url)
-            .map_err(|_| Error::Config(crate::error::ConfigError::InvalidUrl))?;
-        url.push_str("/")
-            .map_err(|_| Error::Config(crate::error::ConfigError::InvalidUrl))?;
-        url.push_str(file_path)
-            .map_err(|_| Error::Config(crate::error::ConfigError::InvalidUrl))?;
-        Ok(url)
-    }
-    
-    /// Parse URL into host and path
-    fn parse_url(&self, url: &str) -> Result<(String<64>, String<128>)> {
-        // Simplified URL parsing
-        // In production, use a proper URL parser
-        let url = url.trim_start_matches("https://");
-        let parts: Vec<&str, 2> = url.splitn(2, '/').collect();
-        
-        if parts.is_empty() {
-            return Err(Error::Config(crate::error::ConfigError::InvalidUrl));
+    /// Build manifest URL on server `server`
+    ///
+    /// Any query in the server URL (e.g. a fleet token) is kept.
+    fn build_manifest_url(&self, server: usize) -> Result<String<MAX_URL_LENGTH>> {
+        let base = self.server_url(server)?;
+        let mut url = base.join("manifest.json")?;
+        url.query = base.query;
+        url.append_query_param("device_id", &self.config.device_id)?;
+        url.render()
+    }
+    
+    /// Build file download URL, resolving the manifest's file URL against server `server`
+    fn build_file_url(&self, server: usize, file_path: &str) -> Result<String<MAX_URL_LENGTH>> {
+        self.server_url(server)?.join(file_path)?.render()
+    }
+    
+    /// Build the report URL, resolving the configured one against server `server`
+    fn build_report_url(&self, server: usize) -> Result<String<MAX_URL_LENGTH>> {
+        let report_url = self.config.report_url.as_deref().ok_or(ConfigError::InvalidUrl)?;
+        self.server_url(server)?.join(report_url)?.render()
+    }
+    
+    /// Check whether the response from `url` came from a server whose certificate was checked
+    ///
+    /// Only such a server may set the clock: the time decides maintenance
+    /// windows, the timestamps of signed requests and which push notices are
+    /// too old.
+    fn is_authenticated(&self, url: &str) -> bool {
+        self.config.tls_trust != TlsTrust::None
+            && Url::parse(url).is_ok_and(|url| url.scheme == Scheme::Https)
+    }
+    
+    /// Server indices in the order they should be tried
+    ///
+    /// The primary is left out while it is still to be discovered, so checks
+    /// go to the mirrors meanwhile.
+    fn servers(&self) -> Vec<usize, MAX_SERVERS> {
+        let mut order = self.mirrors.order(Instant::now());
+        if self.config.needs_discovery() {
+            order.retain(|server| *server != 0);
+        }
+        order
+    }
+    
+    /// Parse the base URL of server `server` (0 is the primary)
+    fn server_url(&self, server: usize) -> Result<Url> {
+        if server == 0 && self.config.needs_discovery() {
+            return Err(ConfigError::ServerNotDiscovered.into());
+        }
+        Url::parse(self.config.server(server).ok_or(ConfigError::InvalidUrl)?)
+    }
+    
+    /// Create backoff state for a retried operation
+    fn backoff(&self) -> Backoff {
+        Backoff::new(
+            self.config.retry_config,
+            retry::seed_from_id(&self.config.device_id),
+        )
+    }
+    
+    /// Report the attempt number of the current operation
+    fn record_attempt(&mut self, attempt: u8) {
+        if let Some(progress) = &mut self.progress {
+            progress.record_attempt(attempt);
+        }
+    }
+    
+    /// Report the URL the last response was served from
+    fn record_final_url(&mut self, url: &str) {
+        if let Some(progress) = &mut self.progress {
+            progress.set_final_url(url);
         }
-        
-        let host = String::try_from(parts[0])
-            .map_err(|_| Error::Config(crate::error::ConfigError::InvalidUrl))?;
-        let path = if parts.len() > 1 {
-            {
-                let mut path = String::try_from("/").unwrap();
-                path.push_str(parts[1]).unwrap();
-                path
-            }
-        } else {
-            String::try_from("/").unwrap()
-        };
-        
-        Ok((host, path))
     }
     
     /// Update progress tracking
@@ -274,4 +935,594 @@ where
             progress.update(bytes, operation);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Signing parameters for the requests of one operation, if requests are signed
+fn request_auth<'a>(
+    signer: &'a mut Option<RequestSigner>,
+    clock: &WallClock,
+    device_id: &'a str,
+) -> Result<Option<RequestAuth<'a>>> {
+    match signer {
+        Some(signer) => {
+            let unix_time = clock.now().ok_or(OtaError::ClockNotSet)?;
+            Ok(Some(RequestAuth::new(signer, device_id, unix_time)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Check whether the server refused a report outright
+///
+/// Authentication failures are excluded: they come from the device's clock
+/// or key, and the report may be accepted once those are fixed.
+fn is_refused(error: &Error) -> bool {
+    match error {
+        Error::Network(NetworkError::HttpError(401 | 403)) => false,
+        Error::Network(NetworkError::HttpError(_)) => {
+            retry::classify(error) == RetryDecision::Fatal
+        }
+        _ => false,
+    }
+}
+
+/// Check whether an install was only held back by the user or a maintenance window
+fn is_held_back(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Ota(
+            OtaError::OutsideMaintenanceWindow | OtaError::ConsentDeferred | OtaError::UpdateRejected
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::config::{RetryConfig, DISCOVER_SERVER};
+    use crate::error::VerificationError;
+    use crate::manifest::{CompressionType, FileType, RollbackInfo, Signature, SignatureAlgorithm, UpdateUrgency};
+    use crate::testing::{block_on, RamState, RamStorage};
+    use crate::transport::mock::{MockResponse, MockTransport};
+    use ed25519_dalek::{Signer, SigningKey};
+    use sha2::{Digest, Sha256};
+    use std::vec;
+
+    const SERVER: &str = "https://ota.example.com/fw/";
+    const UPDATE_KEY: [u8; 32] = [0x5A; 32];
+
+    /// File handler staging every file in one RAM area and noting what it is told
+    struct RamHandler {
+        storage: RamStorage,
+        committed: std::vec::Vec<std::string::String>,
+        aborted: std::vec::Vec<std::string::String>,
+    }
+
+    impl Default for RamHandler {
+        fn default() -> Self {
+            Self {
+                storage: RamStorage::new(16 * 1024),
+                committed: vec![],
+                aborted: vec![],
+            }
+        }
+    }
+
+    impl FileHandler for RamHandler {
+        type Storage = RamStorage;
+
+        fn accepts(&self, _file: &UpdateFile) -> bool {
+            true
+        }
+
+        async fn stage(&mut self, _file: &UpdateFile) -> Result<&mut RamStorage> {
+            Ok(&mut self.storage)
+        }
+
+        async fn commit(&mut self, file: &UpdateFile) -> Result<()> {
+            self.committed.push(file.target.as_str().into());
+            Ok(())
+        }
+
+        async fn abort(&mut self, file: &UpdateFile) -> Result<()> {
+            self.aborted.push(file.target.as_str().into());
+            Ok(())
+        }
+    }
+
+    fn firmware(len: usize) -> std::vec::Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Entry for `data` served at `url`
+    fn file(file_type: FileType, url: &str, data: &[u8]) -> UpdateFile {
+        UpdateFile {
+            file_type,
+            target: String::try_from(url).unwrap(),
+            url: String::try_from(url).unwrap(),
+            size: data.len() as u32,
+            sha256: Sha256::digest(data).into(),
+            compression: CompressionType::None,
+        }
+    }
+
+    /// Manifest for version 1.0.0 with `files`, signed with `key`
+    fn release(files: &[UpdateFile], key: &SigningKey) -> std::vec::Vec<u8> {
+        release_as(UpdateUrgency::Normal, files, key)
+    }
+
+    /// Manifest for version 1.0.0 of the given urgency with `files`, signed with `key`
+    fn release_as(urgency: UpdateUrgency, files: &[UpdateFile], key: &SigningKey) -> std::vec::Vec<u8> {
+        let mut manifest = UpdateManifest {
+            manifest_version: 1,
+            version: Version::new(1, 0, 0, 0),
+            timestamp: 1_700_000_000,
+            description: String::try_from("Test release").unwrap(),
+            min_version: None,
+            files: Vec::from_slice(files).unwrap(),
+            signature: Signature {
+                algorithm: SignatureAlgorithm::Ed25519,
+                key_id: [0; 8],
+                data: Vec::new(),
+            },
+            urgency,
+            rollback: RollbackInfo::default(),
+        };
+        let mut buffer = [0u8; MAX_MANIFEST_SIZE];
+        let signature = key.sign(manifest.signed_bytes(&mut buffer).unwrap());
+        manifest.signature.data = Vec::from_slice(&signature.to_bytes()).unwrap();
+        postcard::to_slice(&manifest, &mut buffer).unwrap().to_vec()
+    }
+
+    /// Manifest for version 1.0.0 with one firmware file, signed with `key`
+    fn signed_manifest(image: &[u8], key: &SigningKey) -> std::vec::Vec<u8> {
+        release(&[file(FileType::Firmware, "firmware.bin", image)], key)
+    }
+
+    /// Consent handler giving `answers` in turn and noting the stages it was asked about
+    #[derive(Default)]
+    struct Scripted {
+        answers: std::vec::Vec<ConsentAnswer>,
+        asked: std::vec::Vec<ConsentStage>,
+    }
+
+    impl Scripted {
+        fn new(answers: &[ConsentAnswer]) -> Self {
+            Self {
+                answers: answers.to_vec(),
+                asked: vec![],
+            }
+        }
+    }
+
+    impl ConsentHandler for Scripted {
+        async fn consent(&mut self, stage: ConsentStage, _summary: &UpdateSummary) -> ConsentAnswer {
+            self.asked.push(stage);
+            self.answers.remove(0)
+        }
+    }
+
+    fn config() -> OtaConfig {
+        OtaConfig::new(SERVER)
+            .unwrap()
+            .with_device_id("test-device")
+            .unwrap()
+            .with_report_url("report")
+            .unwrap()
+            .with_retry_config(RetryConfig {
+                max_attempts: 0,
+                ..RetryConfig::default()
+            })
+    }
+
+    fn client(transport: MockTransport<'_>) -> OtaClient<RamStorage, MockTransport<'_>, RamState> {
+        client_with(config(), transport)
+    }
+
+    fn client_with(
+        config: OtaConfig,
+        transport: MockTransport<'_>,
+    ) -> OtaClient<RamStorage, MockTransport<'_>, RamState> {
+        let public_key = PublicKey::ed25519_from_bytes(
+            SigningKey::from_bytes(&UPDATE_KEY).verifying_key().as_bytes(),
+        )
+        .unwrap();
+        OtaClient::new(config, RamStorage::new(64 * 1024), public_key, transport)
+            .with_state_store(RamState::default())
+    }
+
+    #[test]
+    fn check_and_apply_installs_release() {
+        let image = firmware(10_000);
+        let manifest = signed_manifest(&image, &SigningKey::from_bytes(&UPDATE_KEY));
+        let transport = MockTransport::new()
+            .with_response(MockResponse::ok("/fw/manifest.json", &manifest))
+            .with_response(MockResponse::ok("/fw/firmware.bin", &image))
+            .with_response(MockResponse::ok("/fw/report", b""));
+        let mut client = client(transport);
+
+        let manifest = match block_on(client.check_update()) {
+            UpdateStatus::Available(manifest) => manifest,
+            other => panic!("unexpected status {other:?}"),
+        };
+        assert_eq!(manifest.version, Version::new(1, 0, 0, 0));
+
+        block_on(client.download_and_apply(manifest)).unwrap();
+        assert_eq!(&client.storage.0[..image.len()], &image[..]);
+        assert_eq!(client.config().current_version, Version::new(1, 0, 0, 0));
+
+        let progress = client.progress().unwrap();
+        assert_eq!(progress.operation, UpdateOperation::Complete);
+        assert_eq!(progress.percentage(), 100);
+        assert_eq!(progress.final_url.as_deref(), Some("https://ota.example.com/fw/firmware.bin"));
+
+        // Manifest, firmware and the report, which left nothing queued
+        assert_eq!(client.transport().requests(), 3);
+        assert!(client.state.0[StateKey::ReportQueue as usize].is_none());
+
+        // The same release is not offered again
+        assert!(matches!(block_on(client.check_update()), UpdateStatus::UpToDate));
+    }
+
+    #[test]
+    fn manifest_signed_with_other_key_is_rejected() {
+        let image = firmware(1000);
+        let manifest = signed_manifest(&image, &SigningKey::from_bytes(&[0xA5; 32]));
+        let transport = MockTransport::new()
+            .with_response(MockResponse::ok("/fw/manifest.json", &manifest))
+            .with_response(MockResponse::ok("/fw/firmware.bin", &image));
+        let mut client = client(transport);
+
+        assert!(matches!(
+            block_on(client.check_update()),
+            UpdateStatus::CheckFailed(Error::Verification(VerificationError::InvalidSignature))
+        ));
+        assert_eq!(client.transport().requests(), 1);
+    }
+
+    #[test]
+    fn corrupt_file_is_not_installed() {
+        let image = firmware(10_000);
+        let manifest = signed_manifest(&image, &SigningKey::from_bytes(&UPDATE_KEY));
+        let mut served = image.clone();
+        served[5000] ^= 0xFF;
+        let transport = MockTransport::new()
+            .with_response(MockResponse::ok("/fw/manifest.json", &manifest))
+            .with_response(MockResponse::ok("/fw/firmware.bin", &served))
+            .with_response(MockResponse::ok("/fw/report", b""));
+        let mut client = client(transport);
+
+        let UpdateStatus::Available(manifest) = block_on(client.check_update()) else {
+            panic!("update not offered");
+        };
+        assert_eq!(
+            block_on(client.download_and_apply(manifest)),
+            Err(VerificationError::HashMismatch.into())
+        );
+        assert_eq!(client.config().current_version, Version::new(0, 1, 0, 0));
+    }
+
+    #[test]
+    fn interrupted_download_resumes() {
+        let image = firmware(20_000);
+        let manifest = signed_manifest(&image, &SigningKey::from_bytes(&UPDATE_KEY));
+        let transport = MockTransport::new()
+            .with_response(MockResponse::ok("/fw/manifest.json", &manifest))
+            .with_response(MockResponse::ok("/fw/firmware.bin", &image).with_fail_after(9000))
+            .with_response(MockResponse::ok("/fw/report", b""));
+        let mut client = client(transport);
+
+        let UpdateStatus::Available(manifest) = block_on(client.check_update()) else {
+            panic!("update not offered");
+        };
+        assert_eq!(
+            block_on(client.download_and_apply(manifest.clone())),
+            Err(NetworkError::ConnectionFailed.into())
+        );
+
+        // Only whole write buffers reach flash and the checkpoint
+        let checkpoint: DownloadCheckpoint =
+            block_on(state::load_record(&mut client.state, StateKey::DownloadCheckpoint))
+                .unwrap()
+                .unwrap();
+        assert_eq!(checkpoint.bytes_written, 8192);
+
+        client
+            .transport_mut()
+            .set_response(MockResponse::ok("/fw/firmware.bin", &image));
+        block_on(client.download_and_apply(manifest)).unwrap();
+        assert_eq!(&client.storage.0[..image.len()], &image[..]);
+        assert!(client.state.0[StateKey::DownloadCheckpoint as usize].is_none());
+    }
+
+    #[test]
+    fn mirrors_serve_until_primary_is_discovered() {
+        let image = firmware(10_000);
+        let manifest = signed_manifest(&image, &SigningKey::from_bytes(&UPDATE_KEY));
+        let transport = MockTransport::new()
+            .with_response(MockResponse::ok("/backup/manifest.json", &manifest))
+            .with_response(MockResponse::ok("/backup/firmware.bin", &image))
+            .with_response(MockResponse::ok("/backup/report", b""));
+        let config = OtaConfig {
+            server_url: String::try_from(DISCOVER_SERVER).unwrap(),
+            ..config()
+        }
+        .with_mirror("https://mirror.example.com/backup/")
+        .unwrap();
+        let mut client = client_with(config, transport);
+
+        let UpdateStatus::Available(manifest) = block_on(client.check_update()) else {
+            panic!("update not offered");
+        };
+        block_on(client.download_and_apply(manifest)).unwrap();
+        assert_eq!(
+            client.progress().unwrap().final_url.as_deref(),
+            Some("https://mirror.example.com/backup/firmware.bin")
+        );
+        // Nothing was spent on the primary, and it is not held against it later
+        assert_eq!(client.transport().requests(), 3);
+        assert!(!client.mirror_health().is_cooling_down(0, Instant::now()));
+    }
+
+    #[test]
+    fn undiscovered_primary_without_mirrors_fails_check() {
+        let config = OtaConfig {
+            server_url: String::try_from(DISCOVER_SERVER).unwrap(),
+            ..config()
+        };
+        let mut client = client_with(config, MockTransport::new());
+        assert!(matches!(
+            block_on(client.check_update()),
+            UpdateStatus::CheckFailed(Error::Config(ConfigError::ServerNotDiscovered))
+        ));
+        assert_eq!(client.transport().requests(), 0);
+    }
+
+    #[test]
+    fn attempts_are_counted_over_all_files() {
+        let image = firmware(10_000);
+        let settings = firmware(3000);
+        let manifest = release(
+            &[
+                file(FileType::Firmware, "firmware.bin", &image),
+                file(FileType::Config, "settings.bin", &settings),
+            ],
+            &SigningKey::from_bytes(&UPDATE_KEY),
+        );
+        let transport = MockTransport::new()
+            .with_response(MockResponse::ok("/fw/manifest.json", &manifest))
+            .with_response(MockResponse::ok("/fw/firmware.bin", &image).with_failures(1))
+            .with_response(MockResponse::ok("/fw/settings.bin", &settings).with_failures(2))
+            // Keep the report queued where the test can read it
+            .with_response(MockResponse::ok("/fw/report", b"").with_failures(u8::MAX));
+        let config = OtaConfig {
+            retry_config: RetryConfig {
+                max_attempts: 3,
+                initial_delay_ms: 1,
+                max_delay_ms: 1,
+                ..RetryConfig::default()
+            },
+            ..config()
+        };
+        let mut client = client_with(config, transport).with_file_handler(RamHandler::default());
+
+        let UpdateStatus::Available(manifest) = block_on(client.check_update()) else {
+            panic!("update not offered");
+        };
+        block_on(client.download_and_apply(manifest)).unwrap();
+        assert_eq!(client.handler.committed, ["settings.bin"]);
+
+        // Two tries for the firmware and three for the settings
+        let queue: ReportQueue = block_on(state::load_record(&mut client.state, StateKey::ReportQueue))
+            .unwrap()
+            .unwrap();
+        let report = queue.front().unwrap();
+        assert_eq!(report.outcome, InstallOutcome::Installed);
+        assert_eq!(report.attempts, 5);
+    }
+
+    #[test]
+    fn failed_release_drops_files_staged_before() {
+        let image = firmware(20_000);
+        let settings = firmware(3000);
+        let manifest = release(
+            &[
+                file(FileType::Config, "settings.bin", &settings),
+                file(FileType::Firmware, "firmware.bin", &image),
+            ],
+            &SigningKey::from_bytes(&UPDATE_KEY),
+        );
+        let transport = MockTransport::new()
+            .with_response(MockResponse::ok("/fw/manifest.json", &manifest))
+            .with_response(MockResponse::ok("/fw/settings.bin", &settings))
+            .with_response(MockResponse::ok("/fw/firmware.bin", &image).with_fail_after(9000))
+            .with_response(MockResponse::ok("/fw/report", b""));
+        let mut client = client(transport).with_file_handler(RamHandler::default());
+
+        let UpdateStatus::Available(manifest) = block_on(client.check_update()) else {
+            panic!("update not offered");
+        };
+        assert_eq!(
+            block_on(client.download_and_apply(manifest.clone())),
+            Err(NetworkError::ConnectionFailed.into())
+        );
+        assert_eq!(client.handler.aborted, ["settings.bin"]);
+        assert!(client.handler.committed.is_empty());
+        let checkpoint: DownloadCheckpoint =
+            block_on(state::load_record(&mut client.state, StateKey::DownloadCheckpoint))
+                .unwrap()
+                .unwrap();
+        assert_eq!(checkpoint.file_hash, manifest.files[1].sha256);
+
+        // The next attempt stages the settings again, replacing the checkpoint
+        client
+            .transport_mut()
+            .set_response(MockResponse::ok("/fw/firmware.bin", &image));
+        let requests = client.transport().requests();
+        block_on(client.download_and_apply(manifest)).unwrap();
+        assert_eq!(client.transport().requests(), requests + 3);
+        assert_eq!(client.handler.committed, ["settings.bin"]);
+        assert_eq!(&client.storage.0[..image.len()], &image[..]);
+    }
+
+    #[test]
+    fn release_without_firmware_moves_version_on() {
+        let settings = firmware(3000);
+        let manifest = release(
+            &[file(FileType::Config, "settings.bin", &settings)],
+            &SigningKey::from_bytes(&UPDATE_KEY),
+        );
+        let transport = MockTransport::new()
+            .with_response(MockResponse::ok("/fw/manifest.json", &manifest))
+            .with_response(MockResponse::ok("/fw/settings.bin", &settings))
+            .with_response(MockResponse::ok("/fw/report", b""));
+        let mut client = client(transport).with_file_handler(RamHandler::default());
+
+        let UpdateStatus::Available(manifest) = block_on(client.check_update()) else {
+            panic!("update not offered");
+        };
+        block_on(client.download_and_apply(manifest)).unwrap();
+        assert_eq!(client.handler.committed, ["settings.bin"]);
+        assert!(client.storage.0.iter().all(|byte| *byte == 0xFF));
+        assert_eq!(client.config().current_version, Version::new(1, 0, 0, 0));
+        assert!(matches!(block_on(client.check_update()), UpdateStatus::UpToDate));
+    }
+
+    #[test]
+    fn server_date_is_trusted_only_from_authenticated_server() {
+        let image = firmware(1000);
+        let manifest = signed_manifest(&image, &SigningKey::from_bytes(&UPDATE_KEY));
+        let transport = || {
+            MockTransport::new()
+                .with_response(MockResponse::ok("/fw/manifest.json", &manifest).with_date(1_700_000_000))
+        };
+
+        // Any certificate goes, so anyone in the middle could pick the time
+        let mut client = client(transport());
+        assert!(matches!(block_on(client.check_update()), UpdateStatus::Available(_)));
+        assert_eq!(client.unix_time(), None);
+
+        let mut client = client_with(config().with_spki_pin([0x11; 32]), transport());
+        assert!(matches!(block_on(client.check_update()), UpdateStatus::Available(_)));
+        assert!(client.unix_time().is_some_and(|now| now >= 1_700_000_000));
+
+        // A clock set otherwise is left alone
+        let mut client = client_with(config().with_spki_pin([0x11; 32]), transport());
+        client.set_unix_time(1_800_000_000);
+        block_on(client.check_update());
+        assert!(client.unix_time().is_some_and(|now| now >= 1_800_000_000));
+
+        // Plain HTTP is never authenticated
+        let config = OtaConfig {
+            server_url: String::try_from("http://ota.example.com/fw/").unwrap(),
+            ..config().with_spki_pin([0x11; 32])
+        };
+        let mut client = client_with(config, transport());
+        assert!(matches!(block_on(client.check_update()), UpdateStatus::Available(_)));
+        assert_eq!(client.unix_time(), None);
+    }
+
+    /// Check for the release and try to install it
+    fn offer<C: ConsentHandler>(
+        client: &mut OtaClient<RamStorage, MockTransport<'_>, RamState, NoFileHandler, C>,
+    ) -> Result<()> {
+        let UpdateStatus::Available(manifest) = block_on(client.check_update()) else {
+            panic!("update not offered");
+        };
+        block_on(client.download_and_apply(manifest))
+    }
+
+    fn consent_record(state: &mut RamState) -> ConsentRecord {
+        block_on(state::load_record(state, StateKey::Consent)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn deferral_holds_across_a_restart() {
+        const NOW: u64 = 1_800_000_000;
+        let image = firmware(1000);
+        let manifest = signed_manifest(&image, &SigningKey::from_bytes(&UPDATE_KEY));
+        let transport = || {
+            MockTransport::new()
+                .with_response(MockResponse::ok("/fw/manifest.json", &manifest))
+                .with_response(MockResponse::ok("/fw/firmware.bin", &image))
+                .with_response(MockResponse::ok("/fw/report", b""))
+        };
+        let mut client = client(transport())
+            .with_consent_handler(Scripted::new(&[ConsentAnswer::Defer(Duration::from_secs(3600))]));
+        client.set_unix_time(NOW);
+
+        assert_eq!(offer(&mut client), Err(OtaError::ConsentDeferred.into()));
+        // Asked once; the deferral stands for the next check
+        assert_eq!(offer(&mut client), Err(OtaError::ConsentDeferred.into()));
+        assert_eq!(client.consent.asked, [ConsentStage::Download]);
+        let record = consent_record(&mut client.state);
+        assert_eq!(record.deferred_secs, 3600);
+        assert!(record.deferred_until.is_some_and(|until| (NOW + 3600..NOW + 3610).contains(&until)));
+
+        // After a restart, the stored deferral is all that is left
+        let state = core::mem::take(&mut client.state);
+        let mut client = client_with(config(), transport())
+            .with_state_store(state)
+            .with_consent_handler(Scripted::new(&[ConsentAnswer::Proceed, ConsentAnswer::Proceed]));
+        client.set_unix_time(NOW + 60);
+        assert_eq!(offer(&mut client), Err(OtaError::ConsentDeferred.into()));
+        assert!(client.consent.asked.is_empty());
+
+        client.set_unix_time(NOW + 3610);
+        offer(&mut client).unwrap();
+        assert_eq!(client.consent.asked, [ConsentStage::Download, ConsentStage::Install]);
+        assert_eq!(&client.storage.0[..image.len()], &image[..]);
+    }
+
+    #[test]
+    fn rejected_release_is_not_asked_about_again() {
+        let image = firmware(1000);
+        let manifest = signed_manifest(&image, &SigningKey::from_bytes(&UPDATE_KEY));
+        let transport = MockTransport::new().with_response(MockResponse::ok("/fw/manifest.json", &manifest));
+        let mut client = client(transport).with_consent_handler(Scripted::new(&[ConsentAnswer::Reject]));
+
+        assert_eq!(offer(&mut client), Err(OtaError::UpdateRejected.into()));
+        assert_eq!(offer(&mut client), Err(OtaError::UpdateRejected.into()));
+        assert_eq!(client.consent.asked, [ConsentStage::Download]);
+        assert!(consent_record(&mut client.state).rejected);
+    }
+
+    #[test]
+    fn critical_release_goes_ahead_once_the_cap_is_used_up() {
+        let image = firmware(1000);
+        let manifest = release_as(
+            UpdateUrgency::Critical,
+            &[file(FileType::Firmware, "firmware.bin", &image)],
+            &SigningKey::from_bytes(&UPDATE_KEY),
+        );
+        let transport = || {
+            MockTransport::new()
+                .with_response(MockResponse::ok("/fw/manifest.json", &manifest))
+                .with_response(MockResponse::ok("/fw/firmware.bin", &image))
+                .with_response(MockResponse::ok("/fw/report", b""))
+        };
+        let config = || config().with_critical_deferral_cap(600);
+
+        // Rejecting it only defers it, for all of the cap
+        let mut client =
+            client_with(config(), transport()).with_consent_handler(Scripted::new(&[ConsentAnswer::Reject]));
+        assert_eq!(offer(&mut client), Err(OtaError::ConsentDeferred.into()));
+        let record = consent_record(&mut client.state);
+        assert!(!record.rejected);
+        assert_eq!(record.deferred_secs, 600);
+        assert_eq!(record.deferred_until, None);
+
+        // Without a clock the deferral ends with the restart, and nothing is left of the cap
+        let state = core::mem::take(&mut client.state);
+        let answers = [ConsentAnswer::Defer(Duration::from_secs(3600)), ConsentAnswer::Reject];
+        let mut client = client_with(config(), transport())
+            .with_state_store(state)
+            .with_consent_handler(Scripted::new(&answers));
+        offer(&mut client).unwrap();
+        assert_eq!(client.consent.asked, [ConsentStage::Download, ConsentStage::Install]);
+        assert_eq!(&client.storage.0[..image.len()], &image[..]);
+        assert_eq!(client.config().current_version, Version::new(1, 0, 0, 0));
+    }
+}
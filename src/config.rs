@@ -1,18 +1,45 @@
 //! Configuration management for OTA updates
 
 use crate::error::{ConfigError, Result};
-use heapless::String;
+use crate::url::Url;
+use heapless::{String, Vec};
 use serde::{Deserialize, Serialize};
 
 /// Maximum URL length for OTA endpoints
 pub const MAX_URL_LENGTH: usize = 256;
 
+/// Maximum number of mirrors besides the primary server
+pub const MAX_MIRRORS: usize = 3;
+
+/// `server_url` value asking for the server to be found on the local network
+pub const DISCOVER_SERVER: &str = "discover";
+
+/// Maximum size of a DER-encoded CA certificate
+pub const MAX_CA_CERT_SIZE: usize = 2048;
+
+/// Maximum number of maintenance windows
+pub const MAX_MAINTENANCE_WINDOWS: usize = 4;
+
+/// Minutes in a day; windows start before it and end no later than it
+pub const MINUTES_PER_DAY: u16 = 24 * 60;
+
 /// OTA client configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OtaConfig {
-    /// Base URL for OTA server (e.g., "https://solari.local/ota")
+    /// Base URL for OTA server (e.g., "https://solari.local/ota"), or
+    /// [`DISCOVER_SERVER`] until the server has been discovered
     pub server_url: String<MAX_URL_LENGTH>,
     
+    /// Fallback base URLs, tried in order when the primary server fails
+    pub mirrors: Vec<String<MAX_URL_LENGTH>, MAX_MIRRORS>,
+    
+    /// How long a failed server is tried only as a last resort, in seconds
+    pub mirror_cooldown_secs: u32,
+    
+    /// Where install-status reports are POSTed, relative to the server URL
+    /// or absolute; reporting is off when unset
+    pub report_url: Option<String<MAX_URL_LENGTH>>,
+    
     /// Current firmware version
     pub current_version: Version,
     
@@ -27,6 +54,35 @@ pub struct OtaConfig {
     
     /// Enable automatic updates
     pub auto_update: bool,
+    
+    /// How the server's TLS certificate is authenticated
+    pub tls_trust: TlsTrust,
+    
+    /// Which HTTP redirects the client follows
+    pub redirect_policy: RedirectPolicy,
+    
+    /// Network timeouts for each phase of a request
+    pub timeouts: TimeoutConfig,
+    
+    /// When updates may be installed; any time if empty
+    pub maintenance_windows: Vec<MaintenanceWindow, MAX_MAINTENANCE_WINDOWS>,
+    
+    /// Offset of local time from UTC in minutes, for maintenance windows
+    pub utc_offset_minutes: i16,
+    
+    /// How long the user may put off a critical release in total, in seconds
+    pub critical_deferral_cap_secs: u32,
+}
+
+/// Server certificate verification mode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TlsTrust {
+    /// Accept any certificate; only manifest signatures protect the update
+    None,
+    /// Verify the server's certificate chain against a trusted CA (DER)
+    CaCertificate(Vec<u8, MAX_CA_CERT_SIZE>),
+    /// Accept only a server key whose SubjectPublicKeyInfo has this SHA256 hash
+    SpkiSha256([u8; 32]),
 }
 
 /// Firmware version representation
@@ -54,6 +110,55 @@ pub struct RetryConfig {
     pub backoff_multiplier: f32,
 }
 
+/// Timeouts for the phases of a network request, in milliseconds
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TimeoutConfig {
+    /// Resolving the server's host name
+    pub dns_ms: u32,
+    
+    /// Establishing the TCP connection
+    pub connect_ms: u32,
+    
+    /// Completing the TLS handshake
+    pub tls_handshake_ms: u32,
+    
+    /// Receiving the first byte of the response after sending the request
+    pub first_byte_ms: u32,
+    
+    /// Longest pause between two reads of the response
+    pub stall_ms: u32,
+}
+
+/// Redirect handling for manifest and file requests
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RedirectPolicy {
+    /// Maximum number of redirects followed for one request (0 disables them)
+    pub max_redirects: u8,
+    
+    /// Allow redirects to a different host or port (e.g. a CDN)
+    pub allow_cross_origin: bool,
+    
+    /// Allow redirects from https to plain http
+    pub allow_downgrade: bool,
+}
+
+/// Recurring local time range in which updates may be installed
+///
+/// A window whose end is before its start runs past midnight into the next
+/// day; its weekdays are the days it opens on. Equal start and end mean the
+/// whole day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// Days the window opens on, bit 0 for Monday through bit 6 for Sunday
+    pub weekdays: u8,
+    
+    /// Local opening time in minutes after midnight
+    pub start_minute: u16,
+    
+    /// Local closing time in minutes after midnight (exclusive)
+    pub end_minute: u16,
+}
+
 /// Configuration manager for persistent storage
 pub struct ConfigManager<S> {
     storage: S,
@@ -62,20 +167,93 @@ pub struct ConfigManager<S> {
 
 impl OtaConfig {
     /// Create a new OTA configuration with defaults
+    ///
+    /// `server_url` must be an absolute URL. An empty URL or "discover"
+    /// leaves the server to be found with DNS-SD (`mdns` feature).
     pub fn new(server_url: &str) -> Result<Self> {
-        let url = String::try_from(server_url)
-            .map_err(|_| ConfigError::InvalidUrl)?;
+        let server_url = server_url.trim();
+        let url = if server_url.is_empty() || server_url.eq_ignore_ascii_case(DISCOVER_SERVER) {
+            String::try_from(DISCOVER_SERVER).unwrap()
+        } else {
+            Url::parse(server_url)?;
+            String::try_from(server_url)
+                .map_err(|_| ConfigError::UrlTooLong)?
+        };
         
         Ok(Self {
             server_url: url,
+            mirrors: Vec::new(),
+            mirror_cooldown_secs: 300, // 5 minutes
+            report_url: None,
             current_version: Version::new(0, 1, 0, 0),
             device_id: String::try_from("unknown").unwrap(),
             check_interval: 3600, // 1 hour
             retry_config: RetryConfig::default(),
             auto_update: false,
+            tls_trust: TlsTrust::None,
+            redirect_policy: RedirectPolicy::default(),
+            timeouts: TimeoutConfig::default(),
+            maintenance_windows: Vec::new(),
+            utc_offset_minutes: 0,
+            critical_deferral_cap_secs: 86_400, // 1 day
         })
     }
     
+    /// Check whether the server still has to be discovered
+    pub fn needs_discovery(&self) -> bool {
+        self.server_url == DISCOVER_SERVER
+    }
+    
+    /// Replace the server URL, e.g. with one found by discovery
+    pub fn set_server_url(&mut self, server_url: &str) -> Result<()> {
+        Url::parse(server_url)?;
+        self.server_url = String::try_from(server_url)
+            .map_err(|_| ConfigError::UrlTooLong)?;
+        Ok(())
+    }
+    
+    /// Number of servers: the primary plus its mirrors
+    pub fn server_count(&self) -> usize {
+        1 + self.mirrors.len()
+    }
+    
+    /// Base URL of server `index`; 0 is the primary, then the mirrors in order
+    pub fn server(&self, index: usize) -> Option<&str> {
+        match index {
+            0 => Some(&self.server_url),
+            _ => self.mirrors.get(index - 1).map(|url| url.as_str()),
+        }
+    }
+    
+    /// Add a mirror serving the same releases as the primary server
+    ///
+    /// Manifests and files may be fetched from different servers; their
+    /// signature and hashes are checked no matter where they came from.
+    pub fn with_mirror(mut self, url: &str) -> Result<Self> {
+        Url::parse(url)?;
+        let url = String::try_from(url)
+            .map_err(|_| ConfigError::UrlTooLong)?;
+        self.mirrors
+            .push(url)
+            .map_err(|_| ConfigError::TooManyMirrors)?;
+        Ok(self)
+    }
+    
+    /// Set how long a failed server is avoided, in seconds
+    pub fn with_mirror_cooldown(mut self, seconds: u32) -> Self {
+        self.mirror_cooldown_secs = seconds;
+        self
+    }
+    
+    /// Report install outcomes to `url`, e.g. "report" next to the manifest
+    pub fn with_report_url(mut self, url: &str) -> Result<Self> {
+        if url.contains("://") {
+            Url::parse(url)?;
+        }
+        self.report_url = Some(String::try_from(url).map_err(|_| ConfigError::UrlTooLong)?);
+        Ok(self)
+    }
+    
     /// Set the device ID
     pub fn with_device_id(mut self, device_id: &str) -> Result<Self> {
         self.device_id = String::try_from(device_id)
@@ -94,6 +272,137 @@ impl OtaConfig {
         self.auto_update = enabled;
         self
     }
+    
+    /// Set the retry configuration for network operations
+    pub fn with_retry_config(mut self, retry_config: RetryConfig) -> Self {
+        self.retry_config = retry_config;
+        self
+    }
+    
+    /// Set the redirect policy for manifest and file requests
+    pub fn with_redirect_policy(mut self, redirect_policy: RedirectPolicy) -> Self {
+        self.redirect_policy = redirect_policy;
+        self
+    }
+    
+    /// Set the network timeouts
+    pub fn with_timeouts(mut self, timeouts: TimeoutConfig) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+    
+    /// Verify the server certificate against a trusted CA certificate (DER)
+    pub fn with_ca_certificate(mut self, der: &[u8]) -> Result<Self> {
+        let cert = Vec::from_slice(der)
+            .map_err(|_| ConfigError::InvalidCertificate)?;
+        self.tls_trust = TlsTrust::CaCertificate(cert);
+        Ok(self)
+    }
+    
+    /// Pin the server's public key by the SHA256 hash of its SubjectPublicKeyInfo
+    pub fn with_spki_pin(mut self, spki_sha256: [u8; 32]) -> Self {
+        self.tls_trust = TlsTrust::SpkiSha256(spki_sha256);
+        self
+    }
+    
+    /// Only install updates inside `window` (or any other window added)
+    ///
+    /// Downloads still happen whenever updates are found; installing and the
+    /// reboot that follows wait for a window.
+    pub fn with_maintenance_window(mut self, window: MaintenanceWindow) -> Result<Self> {
+        self.maintenance_windows
+            .push(window)
+            .map_err(|_| ConfigError::TooManyWindows)?;
+        Ok(self)
+    }
+    
+    /// Set the offset of local time from UTC in minutes (e.g. -300 for UTC-5)
+    ///
+    /// Daylight saving time is not followed; update the offset when it changes.
+    pub fn with_utc_offset(mut self, minutes: i16) -> Self {
+        self.utc_offset_minutes = minutes;
+        self
+    }
+    
+    /// Set how long the user may put off a critical release in total, in seconds
+    ///
+    /// Once used up, the consent handler's deferrals and rejections of that
+    /// release are overruled (see [`crate::consent`]).
+    pub fn with_critical_deferral_cap(mut self, seconds: u32) -> Self {
+        self.critical_deferral_cap_secs = seconds;
+        self
+    }
+    
+    /// Check whether updates may be installed at Unix time `unix_secs`
+    ///
+    /// Always true without windows. With windows but no known time, it is
+    /// false: better late than rebooting in the middle of the evening.
+    pub fn in_maintenance_window(&self, unix_secs: Option<u64>) -> bool {
+        if self.maintenance_windows.is_empty() {
+            return true;
+        }
+        let Some(unix_secs) = unix_secs else {
+            return false;
+        };
+        
+        let local = unix_secs as i64 + self.utc_offset_minutes as i64 * 60;
+        let days = local.div_euclid(86_400);
+        let minute = (local.rem_euclid(86_400) / 60) as u16;
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 3).rem_euclid(7) as u8;
+        self.maintenance_windows
+            .iter()
+            .any(|window| window.contains(weekday, minute))
+    }
+}
+
+impl MaintenanceWindow {
+    pub const MONDAY: u8 = 1 << 0;
+    pub const TUESDAY: u8 = 1 << 1;
+    pub const WEDNESDAY: u8 = 1 << 2;
+    pub const THURSDAY: u8 = 1 << 3;
+    pub const FRIDAY: u8 = 1 << 4;
+    pub const SATURDAY: u8 = 1 << 5;
+    pub const SUNDAY: u8 = 1 << 6;
+    pub const WORKDAYS: u8 = 0x1F;
+    pub const WEEKEND: u8 = 0x60;
+    pub const EVERY_DAY: u8 = 0x7F;
+    
+    /// Create a window opening on `weekdays` from `start` to `end`, given as
+    /// local (hour, minute)
+    pub fn new(weekdays: u8, start: (u8, u8), end: (u8, u8)) -> Result<Self> {
+        // Only the end may be midnight of the next day, 24:00
+        let minutes = |(hour, minute): (u8, u8), latest: u16| {
+            let minutes = hour as u16 * 60 + minute as u16;
+            if minute > 59 || minutes > latest {
+                return Err(ConfigError::InvalidWindow);
+            }
+            Ok(minutes)
+        };
+        if weekdays == 0 || weekdays & !Self::EVERY_DAY != 0 {
+            return Err(ConfigError::InvalidWindow.into());
+        }
+        Ok(Self {
+            weekdays,
+            start_minute: minutes(start, MINUTES_PER_DAY - 1)?,
+            end_minute: minutes(end, MINUTES_PER_DAY)?,
+        })
+    }
+    
+    /// Check whether the window is open on `weekday` (0 is Monday) at local `minute`
+    pub fn contains(&self, weekday: u8, minute: u16) -> bool {
+        let opens_on = |day: u8| self.weekdays & (1 << day) != 0;
+        let yesterday = (weekday + 6) % 7;
+        
+        if self.start_minute == self.end_minute {
+            opens_on(weekday)
+        } else if self.start_minute < self.end_minute {
+            opens_on(weekday) && (self.start_minute..self.end_minute).contains(&minute)
+        } else {
+            (opens_on(weekday) && minute >= self.start_minute)
+                || (opens_on(yesterday) && minute < self.end_minute)
+        }
+    }
 }
 
 impl Version {
@@ -138,6 +447,28 @@ impl Default for RetryConfig {
     }
 }
 
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            dns_ms: 5000,
+            connect_ms: 10000,
+            tls_handshake_ms: 15000,
+            first_byte_ms: 15000,
+            stall_ms: 30000,
+        }
+    }
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        Self {
+            max_redirects: 5,
+            allow_cross_origin: true,
+            allow_downgrade: false,
+        }
+    }
+}
+
 impl<S> ConfigManager<S> 
 where
     S: embedded_storage_async::nor_flash::NorFlash,
@@ -168,4 +499,98 @@ where
     pub fn config_mut(&mut self) -> &mut OtaConfig {
         &mut self.config
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Thursday 1970-01-01 00:00 UTC
+    const THURSDAY: u64 = 0;
+    const DAY: u64 = 86_400;
+    const HOUR: u64 = 3_600;
+
+    fn config(window: MaintenanceWindow) -> OtaConfig {
+        OtaConfig::new("https://ota.example.com/fw/")
+            .unwrap()
+            .with_maintenance_window(window)
+            .unwrap()
+    }
+
+    #[test]
+    fn weekdays_count_from_monday() {
+        let window = MaintenanceWindow::new(MaintenanceWindow::MONDAY, (0, 0), (1, 0)).unwrap();
+        let config = config(window);
+
+        assert!(!config.in_maintenance_window(Some(THURSDAY)));
+        assert!(config.in_maintenance_window(Some(THURSDAY + 4 * DAY)));
+        assert!(!config.in_maintenance_window(Some(THURSDAY + 4 * DAY + HOUR)));
+        assert!(config.in_maintenance_window(Some(THURSDAY + 11 * DAY + HOUR / 2)));
+        assert_eq!((0..7).filter(|&day| config.in_maintenance_window(Some(day * DAY))).count(), 1);
+    }
+
+    #[test]
+    fn window_past_midnight_belongs_to_the_day_it_opens() {
+        let window = MaintenanceWindow::new(MaintenanceWindow::FRIDAY, (22, 0), (2, 0)).unwrap();
+        let friday = THURSDAY + DAY;
+        let config = config(window);
+
+        assert!(!config.in_maintenance_window(Some(friday + HOUR)));
+        assert!(!config.in_maintenance_window(Some(friday + 21 * HOUR)));
+        assert!(config.in_maintenance_window(Some(friday + 22 * HOUR)));
+        assert!(config.in_maintenance_window(Some(friday + DAY + HOUR)));
+        assert!(!config.in_maintenance_window(Some(friday + DAY + 2 * HOUR)));
+        assert!(!config.in_maintenance_window(Some(friday + DAY + 23 * HOUR)));
+    }
+
+    #[test]
+    fn window_follows_the_utc_offset() {
+        let window = MaintenanceWindow::new(MaintenanceWindow::THURSDAY, (2, 0), (3, 0)).unwrap();
+        let ahead = config(window).with_utc_offset(120);
+        let behind = config(window).with_utc_offset(-60);
+
+        assert!(ahead.in_maintenance_window(Some(THURSDAY)));
+        assert!(!ahead.in_maintenance_window(Some(THURSDAY + 2 * HOUR)));
+        assert!(behind.in_maintenance_window(Some(THURSDAY + 3 * HOUR)));
+        // 01:00 UTC on Friday is still Thursday evening two hours behind
+        let window = MaintenanceWindow::new(MaintenanceWindow::THURSDAY, (23, 0), (24, 0)).unwrap();
+        assert!(config(window).with_utc_offset(-120).in_maintenance_window(Some(THURSDAY + DAY + HOUR)));
+    }
+
+    #[test]
+    fn equal_start_and_end_is_the_whole_day() {
+        let window = MaintenanceWindow::new(MaintenanceWindow::WEEKEND, (4, 0), (4, 0)).unwrap();
+        let config = config(window);
+        let saturday = THURSDAY + 2 * DAY;
+
+        assert!(config.in_maintenance_window(Some(saturday)));
+        assert!(config.in_maintenance_window(Some(saturday + DAY + 23 * HOUR)));
+        assert!(!config.in_maintenance_window(Some(saturday + 2 * DAY)));
+    }
+
+    #[test]
+    fn without_windows_or_time() {
+        let always = OtaConfig::new("https://ota.example.com/fw/").unwrap();
+        assert!(always.in_maintenance_window(None));
+        assert!(always.in_maintenance_window(Some(THURSDAY)));
+
+        let window = MaintenanceWindow::new(MaintenanceWindow::EVERY_DAY, (0, 0), (0, 0)).unwrap();
+        assert!(!config(window).in_maintenance_window(None));
+    }
+
+    #[test]
+    fn invalid_windows_are_rejected() {
+        let invalid = Err(ConfigError::InvalidWindow.into());
+        let every_day = MaintenanceWindow::EVERY_DAY;
+
+        assert_eq!(MaintenanceWindow::new(every_day, (24, 0), (2, 0)), invalid);
+        assert_eq!(MaintenanceWindow::new(every_day, (22, 0), (24, 1)), invalid);
+        assert_eq!(MaintenanceWindow::new(every_day, (22, 60), (23, 0)), invalid);
+        assert_eq!(MaintenanceWindow::new(every_day, (25, 0), (23, 0)), invalid);
+        assert_eq!(MaintenanceWindow::new(0, (22, 0), (23, 0)), invalid);
+        assert_eq!(MaintenanceWindow::new(0x80, (22, 0), (23, 0)), invalid);
+
+        let window = MaintenanceWindow::new(every_day, (23, 59), (24, 0)).unwrap();
+        assert_eq!((window.start_minute, window.end_minute), (MINUTES_PER_DAY - 1, MINUTES_PER_DAY));
+    }
+}
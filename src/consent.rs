@@ -0,0 +1,196 @@
+//! Asking the user before an update
+//!
+//! Some devices must not update without the user's say-so, given through a
+//! display and a button. The client asks a [`ConsentHandler`] at each
+//! [`ConsentStage`] of a release, showing it an [`UpdateSummary`]; the
+//! handler answers [`Proceed`](ConsentAnswer::Proceed),
+//! [`Defer`](ConsentAnswer::Defer) or [`Reject`](ConsentAnswer::Reject).
+//!
+//! Deferrals and rejections are kept in the state store as a
+//! [`ConsentRecord`], so the user is not asked again about the same release
+//! until the deferral runs out, reboot or not. A release is only rejected
+//! until a newer one comes out. Answers at the reboot stage are not kept: a
+//! restart boots into the installed release, so nothing is left to ask about.
+//!
+//! Critical releases cannot be put off for good: deferrals of one add up to
+//! at most `OtaConfig::critical_deferral_cap_secs`, and a rejection counts as
+//! deferring for whatever is left. Once that is used up the release goes
+//! ahead; the handler is still asked, so it can tell the user.
+
+use crate::config::Version;
+use crate::manifest::{UpdateManifest, UpdateUrgency};
+use embassy_time::Duration;
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+/// Point in an update at which the user is asked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsentStage {
+    /// Before downloading the release
+    Download,
+    /// Before installing the downloaded release
+    Install,
+    /// Before rebooting into the installed release
+    Reboot,
+}
+
+/// What the user is shown about a release
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateSummary {
+    /// Version of the release
+    pub version: Version,
+
+    /// Release notes
+    pub description: String<256>,
+
+    /// How urgent the release is
+    pub urgency: UpdateUrgency,
+
+    /// Total size of its files in bytes
+    pub size: u32,
+}
+
+/// The user's answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsentAnswer {
+    /// Go ahead
+    Proceed,
+    /// Ask again after this long, at least a second
+    Defer(Duration),
+    /// Do not install this release
+    Reject,
+}
+
+/// Asks the user whether an update may go ahead
+pub trait ConsentHandler {
+    /// Ask about `stage` of the release described by `summary`
+    ///
+    /// May take as long as the user does; the update waits for the answer.
+    async fn consent(&mut self, stage: ConsentStage, summary: &UpdateSummary) -> ConsentAnswer;
+}
+
+/// Handler for devices that update without asking
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AutoConsent;
+
+impl ConsentHandler for AutoConsent {
+    async fn consent(&mut self, _stage: ConsentStage, _summary: &UpdateSummary) -> ConsentAnswer {
+        ConsentAnswer::Proceed
+    }
+}
+
+/// The user's standing answers about one release, as persisted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsentRecord {
+    /// Version of the release answered about
+    pub version: Version,
+
+    /// Whether the user declined it
+    pub rejected: bool,
+
+    /// Unix time until which it is deferred, if the clock was set
+    pub deferred_until: Option<u64>,
+
+    /// Seconds it has been deferred so far, counted towards the cap
+    pub deferred_secs: u32,
+}
+
+impl UpdateSummary {
+    /// Summarize `manifest`
+    pub fn of(manifest: &UpdateManifest) -> Self {
+        Self {
+            version: manifest.version,
+            description: manifest.description.clone(),
+            urgency: manifest.urgency,
+            size: manifest.total_size(),
+        }
+    }
+
+    /// Check whether deferrals of this release are capped
+    pub fn is_critical(&self) -> bool {
+        self.urgency == UpdateUrgency::Critical
+    }
+}
+
+impl ConsentRecord {
+    /// Create a record for a release nobody has answered about yet
+    pub fn new(version: Version) -> Self {
+        Self {
+            version,
+            rejected: false,
+            deferred_until: None,
+            deferred_secs: 0,
+        }
+    }
+}
+
+/// Seconds to wait after `answer`, or `None` to go ahead
+///
+/// Deferrals of a critical release are cut to what is left of `cap_secs`
+/// after `used_secs`, and rejecting one defers it for all of that. Callers
+/// handle `Reject` of other releases themselves.
+pub(crate) fn deferral_secs(
+    answer: ConsentAnswer,
+    critical: bool,
+    used_secs: u32,
+    cap_secs: u32,
+) -> Option<u32> {
+    let requested = match answer {
+        ConsentAnswer::Proceed => return None,
+        // Anything shorter would ask again at once, over and over
+        ConsentAnswer::Defer(duration) => duration.as_secs().clamp(1, u32::MAX as u64) as u32,
+        ConsentAnswer::Reject => u32::MAX,
+    };
+    if !critical {
+        return Some(requested);
+    }
+    match cap_secs.saturating_sub(used_secs) {
+        0 => None,
+        left => Some(requested.min(left)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAP: u32 = 600;
+
+    fn secs(secs: u64) -> ConsentAnswer {
+        ConsentAnswer::Defer(Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn deferrals_of_normal_releases_are_kept() {
+        assert_eq!(deferral_secs(ConsentAnswer::Proceed, false, 0, CAP), None);
+        assert_eq!(deferral_secs(secs(90), false, 0, CAP), Some(90));
+        assert_eq!(deferral_secs(secs(3600), false, CAP, CAP), Some(3600));
+        assert_eq!(deferral_secs(secs(u32::MAX as u64 + 1), false, 0, CAP), Some(u32::MAX));
+        assert_eq!(deferral_secs(ConsentAnswer::Reject, false, 0, CAP), Some(u32::MAX));
+    }
+
+    #[test]
+    fn deferrals_last_at_least_a_second() {
+        assert_eq!(deferral_secs(ConsentAnswer::Defer(Duration::from_secs(0)), false, 0, CAP), Some(1));
+        assert_eq!(deferral_secs(ConsentAnswer::Defer(Duration::from_millis(500)), false, 0, CAP), Some(1));
+        assert_eq!(deferral_secs(ConsentAnswer::Defer(Duration::from_secs(0)), true, 0, CAP), Some(1));
+    }
+
+    #[test]
+    fn deferrals_of_critical_releases_are_capped() {
+        assert_eq!(deferral_secs(secs(60), true, 0, CAP), Some(60));
+        assert_eq!(deferral_secs(secs(3600), true, 0, CAP), Some(CAP));
+        assert_eq!(deferral_secs(secs(3600), true, 500, CAP), Some(100));
+        assert_eq!(deferral_secs(secs(3600), true, CAP, CAP), None);
+        assert_eq!(deferral_secs(secs(3600), true, CAP + 1, CAP), None);
+        assert_eq!(deferral_secs(secs(3600), true, 0, 0), None);
+        assert_eq!(deferral_secs(ConsentAnswer::Proceed, true, 0, CAP), None);
+    }
+
+    #[test]
+    fn rejecting_a_critical_release_uses_up_the_cap() {
+        assert_eq!(deferral_secs(ConsentAnswer::Reject, true, 0, CAP), Some(CAP));
+        assert_eq!(deferral_secs(ConsentAnswer::Reject, true, 200, CAP), Some(CAP - 200));
+        assert_eq!(deferral_secs(ConsentAnswer::Reject, true, CAP, CAP), None);
+    }
+}
@@ -0,0 +1,635 @@
+//! Finding the OTA server on the local network (mDNS and DNS-SD)
+//!
+//! `.local` names are not served by the network's DNS server; hosts answer for
+//! themselves over multicast DNS (RFC 6762). Queries are sent from an
+//! ephemeral port, which responders answer by unicast, so no multicast group
+//! membership is needed. Only IPv4 addresses are resolved.
+//!
+//! OTA servers announce themselves as [`OTA_SERVICE`] instances (RFC 6763).
+//! The SRV record gives host and port; the TXT record may carry the base path
+//! (`path=/ota`, "/" if absent) and the scheme (`scheme=http`, https if absent).
+//!
+//! Lookups keep repeating their query until answered, so callers bound them
+//! with a timeout.
+
+use crate::config::MAX_URL_LENGTH;
+use crate::error::{ConfigError, NetworkError, Result};
+use crate::url::{Scheme, Url, MAX_HOST_LENGTH};
+
+use core::fmt::Write as _;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{with_deadline, Duration, Instant};
+use heapless::{String, Vec};
+
+/// DNS-SD service type announced by OTA servers
+pub const OTA_SERVICE: &str = "_genesis-ota._tcp.local";
+
+/// mDNS multicast group and port
+const MDNS_ADDRESS: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+/// Largest message sent or received
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// Longest domain name in wire format
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Compression pointers followed in one name before it is considered a loop
+const MAX_POINTERS: u8 = 16;
+
+/// Delay before the first repeat of an unanswered query; doubled each time
+const FIRST_RETRY_MS: u64 = 1000;
+const MAX_RETRY_MS: u64 = 8000;
+
+// Record types
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+
+/// Class IN with the "unicast response requested" bit set
+const CLASS_IN_UNICAST: u16 = 0x8001;
+
+/// QR bit of the header flags
+const FLAG_RESPONSE: u16 = 0x8000;
+
+/// Domain name in wire format (uncompressed labels ending with the root label)
+type Name = Vec<u8, MAX_NAME_LENGTH>;
+
+/// An OTA server found on the local network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInstance {
+    /// Host name from the SRV record (e.g. "solari.local")
+    pub host: String<MAX_HOST_LENGTH>,
+
+    /// Port from the SRV record
+    pub port: u16,
+
+    /// Scheme from the TXT record
+    pub scheme: Scheme,
+
+    /// Base path from the TXT record
+    pub path: String<MAX_URL_LENGTH>,
+}
+
+/// Buffers backing the UDP socket of one lookup
+struct SocketBuffers {
+    rx_meta: [PacketMetadata; 4],
+    tx_meta: [PacketMetadata; 2],
+    rx: [u8; MAX_MESSAGE_SIZE * 2],
+    tx: [u8; MAX_MESSAGE_SIZE],
+}
+
+/// Sends a query and collects responses until it is time to repeat it
+struct Querier<'s> {
+    socket: UdpSocket<'s>,
+    retry_ms: u64,
+    deadline: Instant,
+}
+
+/// A resource record within a received message
+struct Record<'m> {
+    name: Name,
+    rtype: u16,
+    data: &'m [u8],
+    /// Offset of `data` in `message`, for names compressed against it
+    data_pos: usize,
+    message: &'m [u8],
+}
+
+/// What a browse has learned so far
+#[derive(Default)]
+struct Browse {
+    instance: Option<Name>,
+    target: Option<(Name, u16)>,
+    txt: Option<(Scheme, String<MAX_URL_LENGTH>)>,
+}
+
+impl ServiceInstance {
+    /// Base URL of the server, suitable as [`OtaConfig::server_url`](crate::config::OtaConfig::server_url)
+    pub fn url(&self) -> Result<String<MAX_URL_LENGTH>> {
+        let mut base: String<MAX_URL_LENGTH> = String::new();
+        write!(base, "{}://{}:{}", self.scheme.as_str(), self.host, self.port)
+            .map_err(|_| ConfigError::UrlTooLong)?;
+        let mut url = Url::parse(&base)?;
+        url.set_path(&self.path)?;
+        url.render()
+    }
+}
+
+/// Check whether `host` is a link-local name that only mDNS can resolve
+pub fn is_local_name(host: &str) -> bool {
+    let host = host.trim_end_matches('.').as_bytes();
+    host.len() > 6 && host[host.len() - 6..].eq_ignore_ascii_case(b".local")
+}
+
+/// Resolve a `.local` host name to its IPv4 address
+pub async fn resolve(stack: Stack<'_>, host: &str) -> Result<IpAddress> {
+    let name = encode_name(host)?;
+    let mut buffers = SocketBuffers::new();
+    let mut querier = Querier::new(buffers.socket(stack)?);
+    let mut message = [0u8; MAX_MESSAGE_SIZE];
+
+    loop {
+        querier.ask(&[(&name[..], TYPE_A)]).await?;
+        while let Some(len) = querier.receive(&mut message).await? {
+            let mut address = None;
+            // Malformed responses are ignored; another responder may do better
+            let _ = for_each_record(&message[..len], |record| {
+                if record.rtype == TYPE_A && record.data.len() == 4 && names_equal(&record.name, &name) {
+                    let d = record.data;
+                    address = Some(IpAddress::Ipv4(Ipv4Address::new(d[0], d[1], d[2], d[3])));
+                }
+            });
+            if let Some(address) = address {
+                return Ok(address);
+            }
+        }
+    }
+}
+
+/// Browse for instances of `service`, returning the first that fully answers
+pub async fn browse(stack: Stack<'_>, service: &str) -> Result<ServiceInstance> {
+    let service = encode_name(service)?;
+    let mut buffers = SocketBuffers::new();
+    let mut querier = Querier::new(buffers.socket(stack)?);
+    let mut message = [0u8; MAX_MESSAGE_SIZE];
+    let mut found = Browse::default();
+
+    loop {
+        // Ask for whatever is still missing; responders usually send it all at once
+        match &found.instance {
+            None => querier.ask(&[(&service[..], TYPE_PTR)]).await?,
+            Some(instance) => querier.ask(&[(&instance[..], TYPE_SRV), (&instance[..], TYPE_TXT)]).await?,
+        }
+
+        while let Some(len) = querier.receive(&mut message).await? {
+            let _ = found.learn(&message[..len], &service);
+            if let Some(instance) = found.complete()? {
+                return Ok(instance);
+            }
+        }
+    }
+}
+
+impl SocketBuffers {
+    fn new() -> Self {
+        Self {
+            rx_meta: [PacketMetadata::EMPTY; 4],
+            tx_meta: [PacketMetadata::EMPTY; 2],
+            rx: [0; MAX_MESSAGE_SIZE * 2],
+            tx: [0; MAX_MESSAGE_SIZE],
+        }
+    }
+
+    /// Open a socket on an ephemeral port
+    fn socket<'s>(&'s mut self, stack: Stack<'s>) -> Result<UdpSocket<'s>> {
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut self.rx_meta,
+            &mut self.rx,
+            &mut self.tx_meta,
+            &mut self.tx,
+        );
+        socket
+            .bind(0)
+            .map_err(|_| NetworkError::ConnectionFailed)?;
+        Ok(socket)
+    }
+}
+
+impl<'s> Querier<'s> {
+    fn new(socket: UdpSocket<'s>) -> Self {
+        Self {
+            socket,
+            retry_ms: FIRST_RETRY_MS,
+            deadline: Instant::now(),
+        }
+    }
+
+    /// Send a query, and schedule its repetition with exponential backoff
+    async fn ask(&mut self, questions: &[(&[u8], u16)]) -> Result<()> {
+        let mut query: Vec<u8, MAX_MESSAGE_SIZE> = Vec::new();
+        let mut header = [0u8; 12];
+        header[4..6].copy_from_slice(&(questions.len() as u16).to_be_bytes());
+        query
+            .extend_from_slice(&header)
+            .map_err(|_| NetworkError::RequestTooLarge)?;
+        for (name, qtype) in questions {
+            query
+                .extend_from_slice(name)
+                .and_then(|_| query.extend_from_slice(&qtype.to_be_bytes()))
+                .and_then(|_| query.extend_from_slice(&CLASS_IN_UNICAST.to_be_bytes()))
+                .map_err(|_| NetworkError::RequestTooLarge)?;
+        }
+
+        let destination = IpEndpoint::new(IpAddress::Ipv4(MDNS_ADDRESS), MDNS_PORT);
+        self.socket
+            .send_to(&query, destination)
+            .await
+            .map_err(|_| NetworkError::ConnectionFailed)?;
+
+        self.deadline = Instant::now() + Duration::from_millis(self.retry_ms);
+        self.retry_ms = (self.retry_ms * 2).min(MAX_RETRY_MS);
+        Ok(())
+    }
+
+    /// Receive the next message, or `None` once the query should be repeated
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<Option<usize>> {
+        loop {
+            match with_deadline(self.deadline, self.socket.recv_from(buf)).await {
+                Ok(Ok((len, _))) => return Ok(Some(len)),
+                // A datagram too large for `buf`; keep listening
+                Ok(Err(_)) => continue,
+                Err(_) => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Browse {
+    /// Take note of the records in a response
+    fn learn(&mut self, message: &[u8], service: &Name) -> Result<()> {
+        // Answers (PTR) come before the additional records that describe them
+        for_each_record(message, |record| {
+            if record.rtype == TYPE_PTR && self.instance.is_none() && names_equal(&record.name, service) {
+                self.instance = read_name(record.message, record.data_pos).ok().map(|(name, _)| name);
+            }
+        })?;
+        let Some(instance) = self.instance.clone() else {
+            return Ok(());
+        };
+
+        for_each_record(message, |record| {
+            if !names_equal(&record.name, &instance) {
+                return;
+            }
+            match record.rtype {
+                TYPE_SRV if record.data.len() > 6 => {
+                    let port = u16::from_be_bytes([record.data[4], record.data[5]]);
+                    if let Ok((target, _)) = read_name(record.message, record.data_pos + 6) {
+                        self.target = Some((target, port));
+                    }
+                }
+                TYPE_TXT => self.txt = Some(parse_txt(record.data)),
+                _ => {}
+            }
+        })
+    }
+
+    /// The service instance, once both SRV and TXT records have arrived
+    fn complete(&self) -> Result<Option<ServiceInstance>> {
+        let (Some((target, port)), Some((scheme, path))) = (&self.target, &self.txt) else {
+            return Ok(None);
+        };
+        Ok(Some(ServiceInstance {
+            host: name_to_host(target)?,
+            port: *port,
+            scheme: *scheme,
+            path: path.clone(),
+        }))
+    }
+}
+
+/// Call `visit` for every resource record of a response
+fn for_each_record<'m>(message: &'m [u8], mut visit: impl FnMut(&Record<'m>)) -> Result<()> {
+    let header = message.get(..12).ok_or(NetworkError::InvalidResponse)?;
+    let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+    // Other hosts' queries are of no interest
+    if field(2) & FLAG_RESPONSE == 0 {
+        return Ok(());
+    }
+    let questions = field(4);
+    let records = field(6) as usize + field(8) as usize + field(10) as usize;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        let (_, next) = read_name(message, pos)?;
+        pos = next + 4;
+    }
+    for _ in 0..records {
+        let (name, next) = read_name(message, pos)?;
+        let fixed = message
+            .get(next..next + 10)
+            .ok_or(NetworkError::InvalidResponse)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let data_pos = next + 10;
+        let data = message
+            .get(data_pos..data_pos + len)
+            .ok_or(NetworkError::InvalidResponse)?;
+        visit(&Record {
+            name,
+            rtype,
+            data,
+            data_pos,
+            message,
+        });
+        pos = data_pos + len;
+    }
+    Ok(())
+}
+
+/// Read a possibly compressed name at `pos`, returning it and the offset after it
+fn read_name(message: &[u8], mut pos: usize) -> Result<(Name, usize)> {
+    let mut name = Name::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *message.get(pos).ok_or(NetworkError::InvalidResponse)? as usize;
+        match len {
+            0 => {
+                name.push(0).map_err(|_| NetworkError::InvalidResponse)?;
+                return Ok((name, end.unwrap_or(pos + 1)));
+            }
+            len if len & 0xC0 == 0xC0 => {
+                let low = *message.get(pos + 1).ok_or(NetworkError::InvalidResponse)? as usize;
+                end.get_or_insert(pos + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(NetworkError::InvalidResponse.into());
+                }
+                pos = ((len & 0x3F) << 8) | low;
+            }
+            len if len < 64 => {
+                let label = message
+                    .get(pos..pos + 1 + len)
+                    .ok_or(NetworkError::InvalidResponse)?;
+                name.extend_from_slice(label)
+                    .map_err(|_| NetworkError::InvalidResponse)?;
+                pos += 1 + len;
+            }
+            _ => return Err(NetworkError::InvalidResponse.into()),
+        }
+    }
+}
+
+/// Encode a dotted name such as "solari.local" in wire format
+fn encode_name(name: &str) -> Result<Name> {
+    let mut wire = Name::new();
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(ConfigError::InvalidHost.into());
+        }
+        wire.push(label.len() as u8)
+            .map_err(|_| ConfigError::InvalidHost)?;
+        wire.extend_from_slice(label.as_bytes())
+            .map_err(|_| ConfigError::InvalidHost)?;
+    }
+    wire.push(0).map_err(|_| ConfigError::InvalidHost)?;
+    Ok(wire)
+}
+
+/// Render a wire-format name as a dotted host name
+fn name_to_host(name: &Name) -> Result<String<MAX_HOST_LENGTH>> {
+    let mut host = String::new();
+    let mut pos = 0;
+    while let Some(&len) = name.get(pos).filter(|len| **len != 0) {
+        let label = name
+            .get(pos + 1..pos + 1 + len as usize)
+            .and_then(|label| core::str::from_utf8(label).ok())
+            .ok_or(ConfigError::InvalidHost)?;
+        if !host.is_empty() {
+            host.push('.').map_err(|_| ConfigError::InvalidHost)?;
+        }
+        host.push_str(label).map_err(|_| ConfigError::InvalidHost)?;
+        pos += 1 + len as usize;
+    }
+    Ok(host)
+}
+
+/// DNS names compare case-insensitively; label lengths are never letters
+fn names_equal(a: &[u8], b: &[u8]) -> bool {
+    a.eq_ignore_ascii_case(b)
+}
+
+/// Read the scheme and base path from a TXT record
+fn parse_txt(data: &[u8]) -> (Scheme, String<MAX_URL_LENGTH>) {
+    let mut scheme = Scheme::Https;
+    let mut path = String::new();
+    let mut pos = 0;
+    while let Some(&len) = data.get(pos) {
+        let Some(entry) = data.get(pos + 1..pos + 1 + len as usize) else {
+            break;
+        };
+        pos += 1 + len as usize;
+
+        let (key, value) = match entry.iter().position(|b| *b == b'=') {
+            Some(i) => (&entry[..i], &entry[i + 1..]),
+            None => (entry, &[][..]),
+        };
+        if key.eq_ignore_ascii_case(b"path") {
+            if let Ok(value) = core::str::from_utf8(value) {
+                path = String::try_from(value).unwrap_or_default();
+            }
+        } else if key.eq_ignore_ascii_case(b"scheme") && value.eq_ignore_ascii_case(b"http") {
+            scheme = Scheme::Http;
+        }
+    }
+    if path.is_empty() {
+        let _ = path.push('/');
+    }
+    (scheme, path)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec as StdVec;
+
+    /// Response from an Avahi responder to a PTR query for [`OTA_SERVICE`]
+    ///
+    /// One answer and three additional records, with names compressed the
+    /// way responders do.
+    #[rustfmt::skip]
+    const RESPONSE: [u8; 132] = [
+        // Header: response, authoritative; 0 questions, 1 answer, 3 additional
+        0x00, 0x00, 0x84, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03,
+        // 12: _genesis-ota._tcp.local PTR
+        12, b'_', b'g', b'e', b'n', b'e', b's', b'i', b's', b'-', b'o', b't', b'a',
+        4, b'_', b't', b'c', b'p',
+        5, b'l', b'o', b'c', b'a', b'l', 0,
+        0x00, 0x0C, 0x00, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x09,
+        // 47: solari + pointer to 12
+        6, b's', b'o', b'l', b'a', b'r', b'i', 0xC0, 0x0C,
+        // 56: pointer to 47, SRV priority 0, weight 0, port 8080
+        0xC0, 0x2F, 0x00, 0x21, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x0F,
+        0x00, 0x00, 0x00, 0x00, 0x1F, 0x90,
+        // 74: target solari + pointer to local at 30
+        6, b's', b'o', b'l', b'a', b'r', b'i', 0xC0, 0x1E,
+        // 83: pointer to 47, TXT
+        0xC0, 0x2F, 0x00, 0x10, 0x80, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x15,
+        8, b'p', b'a', b't', b'h', b'=', b'/', b'f', b'w',
+        11, b's', b'c', b'h', b'e', b'm', b'e', b'=', b'h', b't', b't', b'p',
+        // 116: pointer to 74, A 192.168.1.10
+        0xC0, 0x4A, 0x00, 0x01, 0x80, 0x01, 0x00, 0x00, 0x00, 0x78, 0x00, 0x04,
+        192, 168, 1, 10,
+    ];
+
+    fn name(dotted: &str) -> Name {
+        encode_name(dotted).unwrap()
+    }
+
+    fn records(message: &[u8]) -> Result<StdVec<(Name, u16, StdVec<u8>)>> {
+        let mut records = StdVec::new();
+        for_each_record(message, |record| {
+            records.push((record.name.clone(), record.rtype, record.data.to_vec()))
+        })?;
+        Ok(records)
+    }
+
+    fn txt(entries: &[&[u8]]) -> StdVec<u8> {
+        let mut data = StdVec::new();
+        for entry in entries {
+            data.push(entry.len() as u8);
+            data.extend_from_slice(entry);
+        }
+        data
+    }
+
+    #[test]
+    fn compressed_names_are_expanded() {
+        let (instance, next) = read_name(&RESPONSE, 47).unwrap();
+        assert_eq!(instance, name("solari._genesis-ota._tcp.local"));
+        assert_eq!(next, 56);
+
+        // A name that is only a pointer ends right after it
+        assert_eq!(read_name(&RESPONSE, 56).unwrap(), (instance, 58));
+
+        let (target, next) = read_name(&RESPONSE, 74).unwrap();
+        assert_eq!(name_to_host(&target).unwrap(), "solari.local");
+        assert_eq!(next, 83);
+    }
+
+    #[test]
+    fn pointer_loops_are_cut_short() {
+        // Root label at 12, then pointers each leading to the one before
+        let mut message = [0u8; 12].to_vec();
+        message.push(0);
+        for hop in 0..=MAX_POINTERS as usize {
+            let target = if hop == 0 { 12 } else { 13 + 2 * (hop - 1) };
+            message.extend_from_slice(&[0xC0, target as u8]);
+        }
+        let root = Name::from_slice(&[0]).unwrap();
+        let after = |pointers: usize| 13 + 2 * (pointers - 1);
+        assert_eq!(read_name(&message, after(1)).unwrap(), (root.clone(), after(1) + 2));
+        let pointers = MAX_POINTERS as usize;
+        assert_eq!(read_name(&message, after(pointers)).unwrap(), (root, after(pointers) + 2));
+        assert!(read_name(&message, after(pointers + 1)).is_err());
+
+        // Pointing at itself, or two pointers at each other
+        let mut message = [0u8; 12].to_vec();
+        message.extend_from_slice(&[0xC0, 12]);
+        assert!(read_name(&message, 12).is_err());
+        message.extend_from_slice(&[1, b'a', 0xC0, 14]);
+        assert!(read_name(&message, 14).is_err());
+    }
+
+    #[test]
+    fn malformed_names_are_errors() {
+        // A label running past the end, a pointer missing its second byte, no root label
+        assert!(read_name(b"\x05local", 0).is_err());
+        assert!(read_name(b"\x05local\xC0", 0).is_err());
+        assert!(read_name(b"\x05local", 6).is_err());
+        // Label types 0x40 and 0x80 are reserved
+        assert!(read_name(b"\x40local\0", 0).is_err());
+        // Longer than a name can be
+        let mut long = StdVec::new();
+        for _ in 0..5 {
+            long.push(63);
+            long.extend_from_slice(&[b'a'; 63]);
+        }
+        long.push(0);
+        assert!(read_name(&long, 0).is_err());
+    }
+
+    #[test]
+    fn records_are_visited_in_order() {
+        let visited = records(&RESPONSE).unwrap();
+        let types: StdVec<u16> = visited.iter().map(|(_, rtype, _)| *rtype).collect();
+        assert_eq!(types, [TYPE_PTR, TYPE_SRV, TYPE_TXT, TYPE_A]);
+        assert_eq!(visited[0].0, name(OTA_SERVICE));
+        assert_eq!(visited[1].0, name("solari._genesis-ota._tcp.local"));
+        assert_eq!(visited[3].0, name("solari.local"));
+        assert_eq!(visited[3].2, [192, 168, 1, 10]);
+    }
+
+    #[test]
+    fn questions_are_skipped_and_queries_ignored() {
+        // The question echoed ahead of the answer, which points back at it
+        let mut message = [0x00, 0x00, 0x84, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00].to_vec();
+        message.extend_from_slice(&name(OTA_SERVICE));
+        message.extend_from_slice(&[0x00, 0x0C, 0x00, 0x01]);
+        message.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x0C, 0x00, 0x01, 0x00, 0x00, 0x11, 0x94, 0x00, 0x09]);
+        message.extend_from_slice(b"\x06solari\xC0\x0C");
+        let mut instances = StdVec::new();
+        for_each_record(&message, |record| {
+            assert_eq!(record.name, name(OTA_SERVICE));
+            instances.push(read_name(record.message, record.data_pos).unwrap().0);
+        })
+        .unwrap();
+        assert_eq!(instances, [name("solari._genesis-ota._tcp.local")]);
+
+        // Another host asking
+        let mut query = RESPONSE;
+        query[2] = 0x00;
+        assert!(records(&query).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_responses_are_errors() {
+        for len in 0..RESPONSE.len() {
+            assert!(records(&RESPONSE[..len]).is_err(), "{} bytes", len);
+        }
+
+        // RDATA longer than the message
+        let mut message = RESPONSE;
+        message[127] = 0x05;
+        assert!(records(&message).is_err());
+    }
+
+    #[test]
+    fn browse_assembles_instance() {
+        let mut browse = Browse::default();
+        browse.learn(&RESPONSE, &name(OTA_SERVICE)).unwrap();
+        let instance = browse.complete().unwrap().unwrap();
+        assert_eq!(instance.host, "solari.local");
+        assert_eq!(instance.port, 8080);
+        assert_eq!(instance.scheme, Scheme::Http);
+        assert_eq!(instance.path, "/fw");
+        assert_eq!(instance.url().unwrap(), "http://solari.local:8080/fw");
+
+        // Another service's answer teaches nothing
+        let mut browse = Browse::default();
+        browse.learn(&RESPONSE, &name("_http._tcp.local")).unwrap();
+        assert_eq!(browse.complete().unwrap(), None);
+    }
+
+    #[test]
+    fn txt_without_path_defaults_to_root() {
+        let (scheme, path) = parse_txt(&txt(&[b"version=3"]));
+        assert_eq!((scheme, path.as_str()), (Scheme::Https, "/"));
+        // An empty TXT record holds a single empty string
+        assert_eq!(parse_txt(&[0]).1, "/");
+        assert_eq!(parse_txt(&[]).1, "/");
+        // A key without a value, or an empty one
+        assert_eq!(parse_txt(&txt(&[b"path"])).1, "/");
+        assert_eq!(parse_txt(&txt(&[b"path="])).1, "/");
+    }
+
+    #[test]
+    fn txt_entries_are_read() {
+        let (scheme, path) = parse_txt(&txt(&[b"txtvers=1", b"PATH=/ota/v2", b"Scheme=HTTP"]));
+        assert_eq!((scheme, path.as_str()), (Scheme::Http, "/ota/v2"));
+        // Only http overrides the default
+        assert_eq!(parse_txt(&txt(&[b"scheme=ftp"])).0, Scheme::Https);
+
+        // An entry running past the record ends it
+        let mut data = txt(&[b"path=/ota"]);
+        data.extend_from_slice(b"\x20scheme=http");
+        assert_eq!(parse_txt(&data), (Scheme::Https, String::try_from("/ota").unwrap()));
+    }
+}
@@ -25,9 +25,32 @@ pub enum Error {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NetworkError {
     ConnectionFailed,
-    Timeout,
+    ConnectionClosed, // Closed by the server before any response arrived
+    DnsFailed,
+    TlsFailed,
+    CertificateRejected,
+    ClientAuthRejected,
+    Timeout(TimeoutPhase),
     InvalidResponse,
+    Truncated, // Connection closed before the complete body arrived
+    RequestTooLarge,
+    ResponseTooLarge,
+    MethodNotSupported, // The transport cannot send this kind of request
+    TooManyRedirects,
+    BrokerRejected(u8), // MQTT CONNACK/SUBACK failure code
+    RedirectRejected, // Forbidden by the redirect policy
     HttpError(u16), // HTTP status code
+    RetryAfter(u32), // 429/503 with Retry-After, in seconds
+}
+
+/// Stage of a request that ran out of time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutPhase {
+    Dns,
+    Connect,
+    TlsHandshake,
+    FirstByte, // Waiting for the response after sending the request
+    Stall,     // No data between reads
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -45,13 +68,30 @@ pub enum VerificationError {
     InvalidPublicKey,
     HashMismatch,
     MissingSignature,
+    SizeMismatch, // The image is longer than the manifest declares
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ConfigError {
     InvalidUrl,
+    UnsupportedScheme,
+    UserinfoNotSupported,
+    MissingHost,
+    InvalidHost,
+    InvalidPort,
+    InvalidPath,
+    InvalidQuery,
+    PathTraversal,
+    UrlTooLong,
+    InvalidTopic,
+    InvalidCredentials,
     InvalidVersion,
+    InvalidCertificate,
     MissingField,
+    TooManyMirrors,
+    TooManyWindows,
+    InvalidWindow,
+    ServerNotDiscovered, // server_url is "discover" and no server was found yet
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -68,6 +108,11 @@ pub enum OtaError {
     NoUpdateAvailable,
     RollbackFailed,
     InvalidState,
+    ClockNotSet, // Signed requests need the wall-clock time
+    UnsupportedFile, // No handler installs a file of the release
+    OutsideMaintenanceWindow, // Staged, waiting for a window to install
+    ConsentDeferred, // The user asked to be asked again later
+    UpdateRejected, // The user declined the release
 }
 
 // Implement fmt::Display for better error messages
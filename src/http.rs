@@ -0,0 +1,1034 @@
+//! Minimal HTTP/1.1 request writing and response parsing
+
+use crate::config::{TimeoutConfig, MAX_URL_LENGTH};
+use crate::error::{Error, NetworkError, Result, TimeoutPhase};
+use crate::time;
+use core::fmt::Write as _;
+use core::ops::Range;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+
+/// Maximum size of an outgoing request head
+pub const MAX_REQUEST_HEAD_SIZE: usize = 1024;
+
+/// User-Agent sent with every request
+pub const USER_AGENT: &str = concat!("genesis/", env!("CARGO_PKG_VERSION"));
+
+/// Size of the buffer used to read responses from a connection
+///
+/// The complete response head must fit in a single buffer.
+pub const RX_CHUNK_SIZE: usize = 2048;
+
+/// Maximum length of an ETag value kept for conditional requests
+pub const MAX_ETAG_LENGTH: usize = 64;
+
+/// Maximum length of an HTTP date (e.g. a Last-Modified value)
+pub const MAX_HTTP_DATE_LENGTH: usize = 32;
+
+/// HTTP request methods used by the OTA client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+/// An HTTP request issued by the OTA client
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'r> {
+    /// Request method
+    pub method: Method,
+
+    /// Absolute request URL
+    pub url: &'r str,
+
+    /// Additional request headers
+    pub headers: &'r [(&'r str, &'r str)],
+
+    /// Request body (empty for GET)
+    pub body: &'r [u8],
+}
+
+/// Parsed HTTP response status line and headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    /// HTTP status code
+    pub status: u16,
+
+    /// Value of the Content-Length header, if present
+    pub content_length: Option<u32>,
+
+    /// Value of the Content-Range header of a partial response, if present
+    pub content_range: Option<ContentRange>,
+
+    /// Value of the Retry-After header in seconds, if present
+    pub retry_after: Option<u32>,
+
+    /// Value of the ETag header, if present and short enough to keep
+    pub etag: Option<String<MAX_ETAG_LENGTH>>,
+
+    /// Value of the Last-Modified header, if present
+    pub last_modified: Option<String<MAX_HTTP_DATE_LENGTH>>,
+
+    /// Value of the Location header of a redirect, if present
+    pub location: Option<String<MAX_URL_LENGTH>>,
+
+    /// Value of the Date header as Unix time, if present and well-formed
+    pub date: Option<u64>,
+
+    /// Whether the body is sent with `Transfer-Encoding: chunked`
+    pub chunked: bool,
+
+    /// Whether the server keeps the connection open after this response
+    ///
+    /// False for `Connection: close` and for HTTP/1.0 responses.
+    pub keep_alive: bool,
+}
+
+/// How the end of a response body is recognised
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFraming {
+    /// Exactly this many bytes follow the head
+    Length(u32),
+    /// A sequence of chunks, ended by a zero-size chunk and optional trailers
+    Chunked,
+    /// Everything until the server closes the connection
+    Close,
+}
+
+/// Incremental decoder turning raw body bytes into payload for a [`BodySink`]
+///
+/// Input may be split at any byte, so it can be fed straight from socket reads.
+pub struct BodyDecoder {
+    framing: BodyFraming,
+    state: DecodeState,
+}
+
+/// Position of a [`BodyDecoder`] within the body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DecodeState {
+    /// Payload bytes still expected (for `Length` framing or within a chunk)
+    Data(u32),
+    /// Reading the hex size of the next chunk
+    ChunkSize { size: u32, digits: u8 },
+    /// Skipping a chunk extension up to the end of the size line
+    ChunkExtension { size: u32 },
+    /// Expecting the LF ending a chunk size line
+    ChunkSizeLf { size: u32 },
+    /// Expecting the CRLF after chunk data
+    ChunkDataCr,
+    ChunkDataLf,
+    /// Skipping trailer fields; `line` counts bytes in the current line
+    Trailer { line: u32 },
+    /// Expecting the LF ending a trailer line
+    TrailerLf { line: u32 },
+    /// Reading until the connection closes
+    UntilClose,
+    /// The complete body has been received
+    Done,
+}
+
+/// Byte range carried by a `206 Partial Content` response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    /// Offset of the first byte in the body
+    pub start: u32,
+
+    /// Offset of the last byte in the body (inclusive)
+    pub end: u32,
+
+    /// Complete length of the resource, if known
+    pub total: Option<u32>,
+}
+
+/// Destination for a streamed response body
+pub trait BodySink {
+    /// Inspect the response head before any body data is delivered
+    async fn begin(&mut self, _head: &ResponseHead) -> Result<()> {
+        Ok(())
+    }
+
+    /// Consume the next chunk of the response body
+    async fn write(&mut self, data: &[u8]) -> Result<()>;
+}
+
+impl<'r> Request<'r> {
+    /// Create a GET request without extra headers
+    pub fn get(url: &'r str) -> Self {
+        Self {
+            method: Method::Get,
+            url,
+            headers: &[],
+            body: &[],
+        }
+    }
+
+    /// Create a POST request sending `body`
+    pub fn post(url: &'r str, body: &'r [u8]) -> Self {
+        Self {
+            method: Method::Post,
+            url,
+            headers: &[],
+            body,
+        }
+    }
+
+    /// Attach additional request headers
+    pub fn with_headers(mut self, headers: &'r [(&'r str, &'r str)]) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Look up a request header by name (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&'r str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| *v)
+    }
+
+    /// Start offset of an open-ended `Range: bytes=N-` header, if present
+    ///
+    /// For transports that translate resumed downloads into their own protocol.
+    pub fn range_start(&self) -> Option<u32> {
+        self.header("Range")?
+            .strip_prefix("bytes=")?
+            .strip_suffix('-')?
+            .parse()
+            .ok()
+    }
+}
+
+impl Method {
+    /// Get the method name as sent on the wire
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+        }
+    }
+}
+
+/// Write an HTTP/1.1 request head into `buf`, returning the number of bytes written
+///
+/// POST requests announce a body of `body_len` bytes, to be sent after the head.
+/// With `keep_alive` the server is asked to leave the connection open for
+/// further requests, otherwise to close it after the response.
+pub fn write_request(
+    buf: &mut [u8],
+    method: Method,
+    host: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body_len: usize,
+    keep_alive: bool,
+) -> Result<usize> {
+    let mut writer = SliceWriter::new(buf);
+
+    writer.push(method.as_str())?;
+    writer.push(" ")?;
+    writer.push(path)?;
+    writer.push(" HTTP/1.1\r\n")?;
+
+    writer.header("Host", host)?;
+    writer.header("User-Agent", USER_AGENT)?;
+    writer.header("Accept", "*/*")?;
+    writer.header("Connection", if keep_alive { "keep-alive" } else { "close" })?;
+    if method == Method::Post {
+        let mut length: String<10> = String::new();
+        write!(length, "{}", body_len).map_err(|_| NetworkError::RequestTooLarge)?;
+        writer.header("Content-Length", &length)?;
+    }
+    for (name, value) in headers {
+        writer.header(name, value)?;
+    }
+    writer.push("\r\n")?;
+
+    Ok(writer.len())
+}
+
+impl ResponseHead {
+    /// Parse a response head from the start of `data`
+    ///
+    /// Returns `Ok(None)` if the head is not complete yet, otherwise the parsed
+    /// head and the number of bytes it occupies (including the blank line).
+    pub fn parse(data: &[u8]) -> Result<Option<(Self, usize)>> {
+        let head_len = match find_head_end(data) {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let head = core::str::from_utf8(&data[..head_len])
+            .map_err(|_| NetworkError::InvalidResponse)?;
+        let mut lines = head.split("\r\n");
+
+        let status_line = lines.next().unwrap_or("");
+        let status = parse_status_line(status_line)?;
+        let mut response = Self::new(status);
+        // HTTP/1.0 connections only persist on request, which we never make
+        response.keep_alive = !status_line.starts_with("HTTP/1.0");
+
+        for line in lines.filter(|l| !l.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(NetworkError::InvalidResponse)?;
+            response.parse_header(name, value)?;
+        }
+
+        Ok(Some((response, head_len)))
+    }
+
+    /// Create a head with the given status and no headers
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            content_length: None,
+            content_range: None,
+            retry_after: None,
+            etag: None,
+            last_modified: None,
+            location: None,
+            date: None,
+            chunked: false,
+            keep_alive: true,
+        }
+    }
+
+    /// Record a response header the client cares about; others are ignored
+    ///
+    /// Validators and locations too long to keep are dropped, which only
+    /// disables conditional requests or redirects for that response.
+    pub fn parse_header(&mut self, name: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+
+        if name.eq_ignore_ascii_case("Content-Length") {
+            self.content_length = Some(value.parse().map_err(|_| NetworkError::InvalidResponse)?);
+        } else if name.eq_ignore_ascii_case("Content-Range") {
+            self.content_range = Some(ContentRange::parse(value)?);
+        } else if name.eq_ignore_ascii_case("Retry-After") {
+            // Only the delay-seconds form is supported; HTTP dates are ignored
+            self.retry_after = value.parse().ok();
+        } else if name.eq_ignore_ascii_case("ETag") {
+            self.etag = String::try_from(value).ok();
+        } else if name.eq_ignore_ascii_case("Last-Modified") {
+            self.last_modified = String::try_from(value).ok();
+        } else if name.eq_ignore_ascii_case("Location") {
+            self.location = String::try_from(value).ok();
+        } else if name.eq_ignore_ascii_case("Date") {
+            self.date = time::parse_http_date(value);
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            // Chunked must be the final coding; anything else is read until close
+            let last = value.rsplit(',').next().unwrap_or("").trim();
+            self.chunked = last.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("Connection")
+            && value.split(',').any(|option| option.trim().eq_ignore_ascii_case("close"))
+        {
+            self.keep_alive = false;
+        }
+        Ok(())
+    }
+
+    /// Determine how the body of this response is delimited
+    ///
+    /// Transfer-Encoding takes precedence over Content-Length (RFC 9112).
+    pub fn framing(&self) -> BodyFraming {
+        if matches!(self.status, 204 | 304) {
+            BodyFraming::Length(0)
+        } else if self.chunked {
+            BodyFraming::Chunked
+        } else if let Some(length) = self.content_length {
+            BodyFraming::Length(length)
+        } else {
+            BodyFraming::Close
+        }
+    }
+
+    /// Check whether the connection can carry another request once the body is read
+    ///
+    /// A body delimited by the connection closing uses the connection up.
+    pub fn is_reusable(&self) -> bool {
+        self.keep_alive && self.framing() != BodyFraming::Close
+    }
+
+    /// Check whether the status code is in the 2xx range
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Check whether this is a `206 Partial Content` response
+    pub fn is_partial(&self) -> bool {
+        self.status == 206
+    }
+
+    /// Check whether this is a `304 Not Modified` answer to a conditional request
+    pub fn is_not_modified(&self) -> bool {
+        self.status == 304
+    }
+
+    /// Check whether this is a redirect the client can follow
+    pub fn is_redirect(&self) -> bool {
+        matches!(self.status, 301 | 302 | 303 | 307 | 308)
+    }
+
+    /// Check whether the response body should be passed on to the sink
+    ///
+    /// Redirects and `304 Not Modified` responses carry nothing of interest.
+    pub fn expects_body(&self) -> bool {
+        self.is_success()
+    }
+
+    /// Convert a non-2xx status into an error
+    ///
+    /// Redirects and `304 Not Modified` are passed through to the caller.
+    pub fn check_status(&self) -> Result<()> {
+        if self.is_success() || self.is_redirect() || self.is_not_modified() {
+            return Ok(());
+        }
+
+        match (self.status, self.retry_after) {
+            (429 | 503, Some(seconds)) => Err(NetworkError::RetryAfter(seconds).into()),
+            (status, _) => Err(NetworkError::HttpError(status).into()),
+        }
+    }
+}
+
+impl ContentRange {
+    /// Parse a Content-Range value such as "bytes 1024-2047/4096"
+    pub fn parse(value: &str) -> Result<Self> {
+        let range = value
+            .strip_prefix("bytes ")
+            .ok_or(NetworkError::InvalidResponse)?;
+        let (span, total) = range
+            .split_once('/')
+            .ok_or(NetworkError::InvalidResponse)?;
+        let (start, end) = span
+            .split_once('-')
+            .ok_or(NetworkError::InvalidResponse)?;
+
+        let total = match total {
+            "*" => None,
+            t => Some(t.parse().map_err(|_| NetworkError::InvalidResponse)?),
+        };
+
+        Ok(Self {
+            start: start.parse().map_err(|_| NetworkError::InvalidResponse)?,
+            end: end.parse().map_err(|_| NetworkError::InvalidResponse)?,
+            total,
+        })
+    }
+}
+
+impl BodyDecoder {
+    /// Create a decoder for a body with the given framing
+    pub fn new(framing: BodyFraming) -> Self {
+        let state = match framing {
+            BodyFraming::Length(0) => DecodeState::Done,
+            BodyFraming::Length(length) => DecodeState::Data(length),
+            BodyFraming::Chunked => DecodeState::ChunkSize { size: 0, digits: 0 },
+            BodyFraming::Close => DecodeState::UntilClose,
+        };
+        Self { framing, state }
+    }
+
+    /// Check whether the complete body has been received
+    pub fn is_complete(&self) -> bool {
+        self.state == DecodeState::Done
+    }
+
+    /// Decode the next piece of raw body data into `sink`
+    ///
+    /// Bytes after the end of the body are ignored.
+    pub async fn feed<K: BodySink>(&mut self, mut data: &[u8], sink: &mut K) -> Result<()> {
+        while !data.is_empty() {
+            match self.state {
+                DecodeState::Data(remaining) => {
+                    let take = data.len().min(remaining as usize);
+                    sink.write(&data[..take]).await?;
+                    data = &data[take..];
+
+                    let remaining = remaining - take as u32;
+                    self.state = match (remaining, self.framing) {
+                        (0, BodyFraming::Chunked) => DecodeState::ChunkDataCr,
+                        (0, _) => DecodeState::Done,
+                        (remaining, _) => DecodeState::Data(remaining),
+                    };
+                }
+                DecodeState::UntilClose => {
+                    sink.write(data).await?;
+                    data = &[];
+                }
+                DecodeState::Done => data = &[],
+                _ => {
+                    self.state = self.step(data[0])?;
+                    data = &data[1..];
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the body once the connection has closed
+    pub fn finish(&self) -> Result<()> {
+        match self.state {
+            DecodeState::Done | DecodeState::UntilClose => Ok(()),
+            _ => Err(NetworkError::Truncated.into()),
+        }
+    }
+
+    /// Advance the chunk framing state machine by one byte
+    fn step(&self, byte: u8) -> Result<DecodeState> {
+        let invalid = || Error::from(NetworkError::InvalidResponse);
+
+        let next = match self.state {
+            DecodeState::ChunkSize { size, digits } => match byte {
+                b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F' => {
+                    let digit = (byte as char).to_digit(16).unwrap_or(0);
+                    if digits >= 8 {
+                        return Err(invalid());
+                    }
+                    DecodeState::ChunkSize {
+                        size: (size << 4) | digit,
+                        digits: digits + 1,
+                    }
+                }
+                _ if digits == 0 => return Err(invalid()),
+                b'\r' => DecodeState::ChunkSizeLf { size },
+                b';' | b' ' | b'\t' => DecodeState::ChunkExtension { size },
+                _ => return Err(invalid()),
+            },
+            DecodeState::ChunkExtension { size } => match byte {
+                b'\r' => DecodeState::ChunkSizeLf { size },
+                _ => DecodeState::ChunkExtension { size },
+            },
+            DecodeState::ChunkSizeLf { size } => match (byte, size) {
+                (b'\n', 0) => DecodeState::Trailer { line: 0 },
+                (b'\n', size) => DecodeState::Data(size),
+                _ => return Err(invalid()),
+            },
+            DecodeState::ChunkDataCr => match byte {
+                b'\r' => DecodeState::ChunkDataLf,
+                _ => return Err(invalid()),
+            },
+            DecodeState::ChunkDataLf => match byte {
+                b'\n' => DecodeState::ChunkSize { size: 0, digits: 0 },
+                _ => return Err(invalid()),
+            },
+            DecodeState::Trailer { line } => match byte {
+                b'\r' => DecodeState::TrailerLf { line },
+                _ => DecodeState::Trailer { line: line.saturating_add(1) },
+            },
+            DecodeState::TrailerLf { line } => match (byte, line) {
+                (b'\n', 0) => DecodeState::Done,
+                (b'\n', _) => DecodeState::Trailer { line: 0 },
+                _ => return Err(invalid()),
+            },
+            state => state,
+        };
+        Ok(next)
+    }
+}
+
+/// Collect small bodies (e.g. manifests) into a bounded buffer
+impl<const N: usize> BodySink for Vec<u8, N> {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.extend_from_slice(data)
+            .map_err(|_| NetworkError::ResponseTooLarge.into())
+    }
+}
+
+/// Sink for responses whose body does not matter (e.g. to a status report)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Discard;
+
+impl BodySink for Discard {
+    async fn write(&mut self, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Send a request head and `body` over `conn` and stream the response into `sink`
+///
+/// Non-2xx responses are rejected before anything reaches the sink, and
+/// redirects and `304 Not Modified` are returned without passing on their
+/// body. The body of a redirect is still read (and dropped) when the server
+/// keeps the connection open, so it is ready for the next request. The body is
+/// framed as described by [`ResponseHead::framing`]; a connection that closes
+/// or fails before the body is complete yields [`NetworkError::Truncated`].
+///
+/// A connection that fails or closes before a single response byte arrives
+/// yields [`NetworkError::ConnectionClosed`]: on a kept-alive connection this
+/// usually means the server dropped it while idle, and the request can be sent
+/// again on a new one.
+///
+/// The first response byte must arrive within the time-to-first-byte timeout,
+/// and every later read (and the request write) within the stall timeout.
+pub async fn exchange<C, K>(
+    conn: &mut C,
+    request: &[u8],
+    body: &[u8],
+    sink: &mut K,
+    timeouts: &TimeoutConfig,
+) -> Result<ResponseHead>
+where
+    C: Read + Write,
+    K: BodySink,
+{
+    let stall = Duration::from_millis(timeouts.stall_ms as u64);
+    let sent = with_timeout(stall, async {
+        conn.write_all(request).await?;
+        conn.write_all(body).await?;
+        conn.flush().await
+    })
+    .await
+    .map_err(|_| NetworkError::Timeout(TimeoutPhase::Stall))?;
+    sent.map_err(|_| NetworkError::ConnectionClosed)?;
+
+    // Read until the complete response head has arrived
+    let mut rx_buffer = [0u8; RX_CHUNK_SIZE];
+    let mut received = 0;
+    let (response, head_len) = loop {
+        if received == rx_buffer.len() {
+            return Err(NetworkError::ResponseTooLarge.into());
+        }
+        let (timeout, phase) = if received == 0 {
+            (timeouts.first_byte_ms, TimeoutPhase::FirstByte)
+        } else {
+            (timeouts.stall_ms, TimeoutPhase::Stall)
+        };
+        let n = match read_within(conn, &mut rx_buffer[received..], timeout, phase).await {
+            Ok(0) | Err(Error::Network(NetworkError::ConnectionFailed)) if received == 0 => {
+                return Err(NetworkError::ConnectionClosed.into());
+            }
+            Ok(0) => return Err(NetworkError::InvalidResponse.into()),
+            other => other?,
+        };
+        received += n;
+
+        if let Some(head) = ResponseHead::parse(&rx_buffer[..received])? {
+            break head;
+        }
+    };
+    response.check_status()?;
+    if !response.expects_body() {
+        if response.is_reusable() {
+            let mut discard = Discard;
+            read_body(conn, &response, &mut rx_buffer, head_len..received, &mut discard, timeouts).await?;
+        }
+        return Ok(response);
+    }
+    sink.begin(&response).await?;
+    read_body(conn, &response, &mut rx_buffer, head_len..received, sink, timeouts).await?;
+
+    Ok(response)
+}
+
+/// Stream the body of `response` into `sink`
+///
+/// `buffered` is the part of `rx_buffer` that arrived alongside the head.
+async fn read_body<C, K>(
+    conn: &mut C,
+    response: &ResponseHead,
+    rx_buffer: &mut [u8],
+    buffered: Range<usize>,
+    sink: &mut K,
+    timeouts: &TimeoutConfig,
+) -> Result<()>
+where
+    C: Read,
+    K: BodySink,
+{
+    let mut body = BodyDecoder::new(response.framing());
+    body.feed(&rx_buffer[buffered], sink).await?;
+    while !body.is_complete() {
+        let n = match read_within(conn, rx_buffer, timeouts.stall_ms, TimeoutPhase::Stall).await {
+            // Part of the response has arrived, so the rest of it is missing
+            Err(Error::Network(NetworkError::ConnectionFailed)) => {
+                return Err(NetworkError::Truncated.into());
+            }
+            other => other?,
+        };
+        if n == 0 {
+            break;
+        }
+        body.feed(&rx_buffer[..n], sink).await?;
+    }
+    body.finish()
+}
+
+/// Read from `conn`, failing with a timeout tagged `phase` after `timeout_ms`
+async fn read_within<C: Read>(
+    conn: &mut C,
+    buf: &mut [u8],
+    timeout_ms: u32,
+    phase: TimeoutPhase,
+) -> Result<usize> {
+    with_timeout(Duration::from_millis(timeout_ms as u64), conn.read(buf))
+        .await
+        .map_err(|_| NetworkError::Timeout(phase))?
+        .map_err(|_| NetworkError::ConnectionFailed.into())
+}
+
+/// Find the end of the response head (after the terminating blank line)
+fn find_head_end(data: &[u8]) -> Option<usize> {
+    data.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+/// Parse a status line such as "HTTP/1.1 200 OK"
+fn parse_status_line(line: &str) -> Result<u16> {
+    let mut parts = line.splitn(3, ' ');
+
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") {
+        return Err(NetworkError::InvalidResponse.into());
+    }
+
+    let code = parts.next().unwrap_or("");
+    if code.len() != 3 {
+        return Err(NetworkError::InvalidResponse.into());
+    }
+
+    code.parse().map_err(|_| NetworkError::InvalidResponse.into())
+}
+
+/// Bounded writer over a byte slice
+struct SliceWriter<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> SliceWriter<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn push(&mut self, s: &str) -> Result<()> {
+        let end = self.pos + s.len();
+        if end > self.buf.len() {
+            return Err(NetworkError::RequestTooLarge.into());
+        }
+        self.buf[self.pos..end].copy_from_slice(s.as_bytes());
+        self.pos = end;
+        Ok(())
+    }
+
+    fn header(&mut self, name: &str, value: &str) -> Result<()> {
+        self.push(name)?;
+        self.push(": ")?;
+        self.push(value)?;
+        self.push("\r\n")
+    }
+
+    fn len(&self) -> usize {
+        self.pos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing::block_on;
+    use embedded_io_async::ErrorKind;
+    use std::vec::Vec as StdVec;
+
+    const CHUNKED: &[u8] = b"4;name=value\r\nWiki\r\n5 \r\npedia\r\nE;a=1;b\r\n in\r\n\r\nchunks.\r\n\
+        0\r\nExpires: never\r\nX-Checksum: abc\r\n\r\n";
+    const CHUNKED_PAYLOAD: &[u8] = b"Wikipedia in\r\n\r\nchunks.";
+
+    /// What a [`Replay`] does once its data is used up
+    #[derive(Clone, Copy, PartialEq)]
+    enum Then {
+        Close,
+        Fail,
+        /// Never answer again
+        Stall,
+    }
+
+    /// Connection replaying a canned response a few bytes at a time
+    struct Replay {
+        data: StdVec<u8>,
+        pos: usize,
+        step: usize,
+        then: Then,
+        /// Never take the request
+        stuck_writes: bool,
+    }
+
+    impl Replay {
+        fn new(data: &[u8]) -> Self {
+            Self {
+                data: data.to_vec(),
+                pos: 0,
+                step: 7,
+                then: Then::Close,
+                stuck_writes: false,
+            }
+        }
+
+        fn failing(data: &[u8]) -> Self {
+            Self {
+                then: Then::Fail,
+                ..Self::new(data)
+            }
+        }
+
+        fn stalling(data: &[u8]) -> Self {
+            Self {
+                then: Then::Stall,
+                ..Self::new(data)
+            }
+        }
+    }
+
+    impl embedded_io_async::ErrorType for Replay {
+        type Error = ErrorKind;
+    }
+
+    impl Read for Replay {
+        async fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, ErrorKind> {
+            if self.pos == self.data.len() {
+                match self.then {
+                    Then::Close => {}
+                    Then::Fail => return Err(ErrorKind::ConnectionReset),
+                    Then::Stall => core::future::pending().await,
+                }
+            }
+            let n = buf.len().min(self.step).min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    impl Write for Replay {
+        async fn write(&mut self, buf: &[u8]) -> core::result::Result<usize, ErrorKind> {
+            if self.stuck_writes {
+                core::future::pending().await
+            }
+            Ok(buf.len())
+        }
+    }
+
+    fn decode(framing: BodyFraming, pieces: &[&[u8]]) -> Result<(StdVec<u8>, bool)> {
+        let mut decoder = BodyDecoder::new(framing);
+        let mut sink: Vec<u8, 256> = Vec::new();
+        for piece in pieces {
+            block_on(decoder.feed(piece, &mut sink))?;
+        }
+        Ok((sink.to_vec(), decoder.is_complete()))
+    }
+
+    fn head(response: &str) -> ResponseHead {
+        ResponseHead::parse(response.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn exchange_with(conn: &mut Replay) -> (Result<ResponseHead>, StdVec<u8>) {
+        let mut sink: Vec<u8, 256> = Vec::new();
+        let result = block_on(exchange(conn, b"GET / HTTP/1.1\r\n\r\n", &[], &mut sink, &TimeoutConfig::default()));
+        (result, sink.to_vec())
+    }
+
+    #[test]
+    fn chunked_body_with_extensions_and_trailers() {
+        let (payload, complete) = decode(BodyFraming::Chunked, &[CHUNKED]).unwrap();
+        assert_eq!(payload, CHUNKED_PAYLOAD);
+        assert!(complete);
+
+        // Bytes after the last chunk belong to the next response
+        let mut data = CHUNKED.to_vec();
+        data.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
+        assert_eq!(decode(BodyFraming::Chunked, &[&data]).unwrap().0, CHUNKED_PAYLOAD);
+    }
+
+    #[test]
+    fn input_may_be_split_anywhere() {
+        for at in 0..=CHUNKED.len() {
+            let (payload, complete) = decode(BodyFraming::Chunked, &[&CHUNKED[..at], &CHUNKED[at..]]).unwrap();
+            assert_eq!(payload, CHUNKED_PAYLOAD, "split at {}", at);
+            assert!(complete);
+        }
+        let bytes: StdVec<&[u8]> = CHUNKED.chunks(1).collect();
+        assert_eq!(decode(BodyFraming::Chunked, &bytes).unwrap().0, CHUNKED_PAYLOAD);
+
+        let body = b"0123456789";
+        for at in 0..=body.len() {
+            let (payload, complete) = decode(BodyFraming::Length(10), &[&body[..at], &body[at..]]).unwrap();
+            assert_eq!(payload, body);
+            assert!(complete);
+        }
+    }
+
+    #[test]
+    fn invalid_chunk_sizes_are_rejected() {
+        let invalid: [&[u8]; 6] = [
+            b"g\r\n",
+            b"\r\n",
+            b";ext\r\n",
+            // Does not fit in 32 bits
+            b"100000000\r\n",
+            b"5\rx",
+            // Chunk data not followed by CRLF
+            b"5\r\nhelloXX",
+        ];
+        for data in invalid {
+            assert_eq!(
+                decode(BodyFraming::Chunked, &[data]),
+                Err(NetworkError::InvalidResponse.into()),
+                "{:?}",
+                core::str::from_utf8(data)
+            );
+        }
+        // The largest size that fits is accepted
+        let mut decoder = BodyDecoder::new(BodyFraming::Chunked);
+        block_on(decoder.feed(b"FFFFFFFF\r\n", &mut Discard)).unwrap();
+        assert_eq!(decoder.state, DecodeState::Data(u32::MAX));
+    }
+
+    #[test]
+    fn incomplete_bodies_are_truncated() {
+        let mut decoder = BodyDecoder::new(BodyFraming::Length(10));
+        block_on(decoder.feed(b"01234", &mut Discard)).unwrap();
+        assert_eq!(decoder.finish(), Err(NetworkError::Truncated.into()));
+
+        let mut decoder = BodyDecoder::new(BodyFraming::Chunked);
+        block_on(decoder.feed(&CHUNKED[..CHUNKED.len() - 2], &mut Discard)).unwrap();
+        assert_eq!(decoder.finish(), Err(NetworkError::Truncated.into()));
+
+        // Closed, or failed, part-way through the body
+        for response in [
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n01234"[..],
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+        ] {
+            assert_eq!(exchange_with(&mut Replay::new(response)).0, Err(NetworkError::Truncated.into()));
+            assert_eq!(exchange_with(&mut Replay::failing(response)).0, Err(NetworkError::Truncated.into()));
+        }
+    }
+
+    #[test]
+    fn close_delimited_body_runs_to_close() {
+        let (result, body) = exchange_with(&mut Replay::new(b"HTTP/1.1 200 OK\r\n\r\nuntil the end"));
+        let head = result.unwrap();
+        assert_eq!(head.framing(), BodyFraming::Close);
+        assert!(!head.is_reusable());
+        assert_eq!(body, b"until the end");
+    }
+
+    #[test]
+    fn exchange_delivers_only_the_body() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nETag: \"v1\"\r\n\r\nhelloHTTP/1.1";
+        let (result, body) = exchange_with(&mut Replay::new(response));
+        let head = result.unwrap();
+        assert_eq!(head.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(body, b"hello");
+
+        // A redirect's body is read off the connection but not delivered
+        let response = b"HTTP/1.1 302 Found\r\nLocation: /x\r\nContent-Length: 4\r\n\r\nmoveHTTP";
+        let (result, body) = exchange_with(&mut Replay::new(response));
+        assert_eq!(result.unwrap().location.as_deref(), Some("/x"));
+        assert!(body.is_empty());
+
+        // Nothing at all is a closed connection, to be retried on a new one
+        assert_eq!(exchange_with(&mut Replay::new(b"")).0, Err(NetworkError::ConnectionClosed.into()));
+        assert_eq!(exchange_with(&mut Replay::failing(b"")).0, Err(NetworkError::ConnectionClosed.into()));
+    }
+
+    #[test]
+    fn reusable_connections() {
+        let cases = [
+            ("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n", true),
+            ("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n", true),
+            ("HTTP/1.1 304 Not Modified\r\n\r\n", true),
+            ("HTTP/1.1 204 No Content\r\n\r\n", true),
+            ("HTTP/1.1 200 OK\r\n\r\n", false),
+            ("HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: keep-alive, close\r\n\r\n", false),
+            ("HTTP/1.0 200 OK\r\nContent-Length: 3\r\n\r\n", false),
+            // Chunked must come last; otherwise the body runs to the close
+            ("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked, gzip\r\n\r\n", false),
+        ];
+        for (response, reusable) in cases {
+            assert_eq!(head(response).is_reusable(), reusable, "{}", response);
+        }
+    }
+
+    #[test]
+    fn retry_after_is_reported() {
+        let status = |response: &str| head(response).check_status();
+        assert_eq!(
+            status("HTTP/1.1 429 Too Many Requests\r\nRetry-After: 120\r\n\r\n"),
+            Err(NetworkError::RetryAfter(120).into())
+        );
+        assert_eq!(
+            status("HTTP/1.1 503 Service Unavailable\r\nretry-after:  5 \r\n\r\n"),
+            Err(NetworkError::RetryAfter(5).into())
+        );
+        // Without a usable delay, or on other statuses, the status is all there is
+        assert_eq!(
+            status("HTTP/1.1 503 Service Unavailable\r\nRetry-After: Fri, 31 Dec 1999 23:59:59 GMT\r\n\r\n"),
+            Err(NetworkError::HttpError(503).into())
+        );
+        assert_eq!(status("HTTP/1.1 429 Too Many Requests\r\n\r\n"), Err(NetworkError::HttpError(429).into()));
+        assert_eq!(
+            status("HTTP/1.1 500 Internal Server Error\r\nRetry-After: 5\r\n\r\n"),
+            Err(NetworkError::HttpError(500).into())
+        );
+
+        let response = b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 30\r\nContent-Length: 4\r\n\r\nbusy";
+        let (result, body) = exchange_with(&mut Replay::new(response));
+        assert_eq!(result, Err(NetworkError::RetryAfter(30).into()));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn heads_are_parsed() {
+        assert_eq!(ResponseHead::parse(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n"), Ok(None));
+        let response = b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 10-19/40\r\n\r\nxy";
+        let (head, len) = ResponseHead::parse(response).unwrap().unwrap();
+        assert_eq!(len, response.len() - 2);
+        assert_eq!(
+            head.content_range,
+            Some(ContentRange {
+                start: 10,
+                end: 19,
+                total: Some(40)
+            })
+        );
+
+        for response in [
+            &b"HTTP/2 200 OK\r\n\r\n"[..],
+            b"HTTP/1.1 20 OK\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nno colon\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: many\r\n\r\n",
+            b"HTTP/1.1 206 OK\r\nContent-Range: 10-19/40\r\n\r\n",
+        ] {
+            assert_eq!(ResponseHead::parse(response), Err(NetworkError::InvalidResponse.into()));
+        }
+    }
+
+    #[test]
+    fn stalls_report_their_phase() {
+        let timeouts = TimeoutConfig {
+            first_byte_ms: 30,
+            stall_ms: 30,
+            ..TimeoutConfig::default()
+        };
+        let cases = [
+            // The request never goes out
+            (Replay { stuck_writes: true, ..Replay::new(b"") }, TimeoutPhase::Stall),
+            (Replay::stalling(b""), TimeoutPhase::FirstByte),
+            // Part of the head, then nothing
+            (Replay::stalling(b"HTTP/1.1 200 OK\r\nContent-Le"), TimeoutPhase::Stall),
+            // Part of the body, then nothing
+            (Replay::stalling(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc"), TimeoutPhase::Stall),
+            (Replay::stalling(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc"), TimeoutPhase::Stall),
+        ];
+
+        for (mut conn, phase) in cases {
+            let mut sink: Vec<u8, 16> = Vec::new();
+            let result = block_on(exchange(&mut conn, b"GET / HTTP/1.1\r\n\r\n", &[], &mut sink, &timeouts));
+            assert_eq!(result, Err(NetworkError::Timeout(phase).into()), "after {} bytes", conn.pos);
+        }
+    }
+}
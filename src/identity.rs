@@ -0,0 +1,157 @@
+//! Per-device credentials used to authenticate to the update server
+
+use crate::error::{ConfigError, Result, StorageError};
+use ed25519_dalek::{Signer, SigningKey};
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+use zeroize::Zeroize;
+
+/// Maximum size of a DER-encoded client certificate
+pub const MAX_CLIENT_CERT_SIZE: usize = 1024;
+
+/// Marker at the start of an identity record in flash
+const IDENTITY_MAGIC: u32 = 0x4749_4431; // "GID1"
+
+/// Size of the identity record header (magic, certificate length, reserved)
+const IDENTITY_HEADER_SIZE: usize = 8;
+
+/// Size of a P-256 private key scalar
+const PRIVATE_KEY_SIZE: usize = 32;
+
+/// Marker at the start of a device key record in flash
+const DEVICE_KEY_MAGIC: u32 = 0x4744_4B31; // "GDK1"
+
+/// Size of an Ed25519 secret key
+const DEVICE_KEY_SIZE: usize = 32;
+
+/// Client certificate and private key presented during mutual TLS
+///
+/// The key is a raw P-256 scalar. It is wiped from RAM when the identity is
+/// dropped, but the flash region it is loaded from should be covered by flash
+/// encryption on production devices.
+pub struct ClientIdentity {
+    certificate: Vec<u8, MAX_CLIENT_CERT_SIZE>,
+    private_key: [u8; PRIVATE_KEY_SIZE],
+}
+
+impl ClientIdentity {
+    /// Create an identity from a DER certificate and a P-256 private key
+    pub fn new(certificate_der: &[u8], private_key: [u8; PRIVATE_KEY_SIZE]) -> Result<Self> {
+        let certificate = Vec::from_slice(certificate_der)
+            .map_err(|_| ConfigError::InvalidCertificate)?;
+        Ok(Self {
+            certificate,
+            private_key,
+        })
+    }
+
+    /// Load an identity provisioned into flash at `offset`
+    ///
+    /// Record layout: magic (u32 LE), certificate length (u16 LE), two reserved
+    /// bytes, the 32-byte private key, then the DER certificate.
+    pub async fn load<F: NorFlash>(flash: &mut F, offset: u32) -> Result<Self> {
+        let mut header = [0u8; IDENTITY_HEADER_SIZE + PRIVATE_KEY_SIZE];
+        flash
+            .read(offset, &mut header)
+            .await
+            .map_err(|_| StorageError::ReadFailed)?;
+
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let cert_len = u16::from_le_bytes([header[4], header[5]]) as usize;
+        if magic != IDENTITY_MAGIC || cert_len == 0 || cert_len > MAX_CLIENT_CERT_SIZE {
+            return Err(ConfigError::InvalidCertificate.into());
+        }
+
+        let mut private_key = [0u8; PRIVATE_KEY_SIZE];
+        private_key.copy_from_slice(&header[IDENTITY_HEADER_SIZE..]);
+        header.zeroize();
+
+        // Reads must cover whole flash words
+        let mut certificate = Vec::new();
+        let padded = cert_len.next_multiple_of(F::READ_SIZE).min(MAX_CLIENT_CERT_SIZE);
+        let _ = certificate.resize(padded, 0);
+        flash
+            .read(offset + header.len() as u32, &mut certificate)
+            .await
+            .map_err(|_| StorageError::ReadFailed)?;
+        certificate.truncate(cert_len);
+
+        Ok(Self {
+            certificate,
+            private_key,
+        })
+    }
+
+    /// DER-encoded client certificate
+    pub fn certificate(&self) -> &[u8] {
+        &self.certificate
+    }
+
+    /// Raw P-256 private key scalar
+    pub(crate) fn private_key(&self) -> &[u8] {
+        &self.private_key
+    }
+}
+
+impl Drop for ClientIdentity {
+    fn drop(&mut self) {
+        self.private_key.zeroize();
+    }
+}
+
+/// Ed25519 identity key used to sign requests (see [`crate::auth`])
+///
+/// The server knows the device by the matching public key, registered when
+/// the device is provisioned. Like [`ClientIdentity`], the secret is wiped
+/// from RAM on drop, as is the signing key expanded from it for each use.
+pub struct DeviceKey {
+    secret: [u8; DEVICE_KEY_SIZE],
+}
+
+impl DeviceKey {
+    /// Create a key from its 32-byte Ed25519 secret
+    pub fn new(secret: [u8; DEVICE_KEY_SIZE]) -> Self {
+        Self { secret }
+    }
+
+    /// Load a key provisioned into flash at `offset`
+    ///
+    /// Record layout: magic (u32 LE), four reserved bytes, then the 32-byte secret.
+    pub async fn load<F: NorFlash>(flash: &mut F, offset: u32) -> Result<Self> {
+        let mut record = [0u8; IDENTITY_HEADER_SIZE + DEVICE_KEY_SIZE];
+        flash
+            .read(offset, &mut record)
+            .await
+            .map_err(|_| StorageError::ReadFailed)?;
+
+        let magic = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        if magic != DEVICE_KEY_MAGIC {
+            return Err(ConfigError::InvalidCredentials.into());
+        }
+
+        let mut secret = [0u8; DEVICE_KEY_SIZE];
+        secret.copy_from_slice(&record[IDENTITY_HEADER_SIZE..]);
+        record.zeroize();
+        Ok(Self { secret })
+    }
+
+    /// Public key to register with the server
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing_key().verifying_key().to_bytes()
+    }
+
+    /// Sign `message`
+    pub(crate) fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.signing_key().sign(message).to_bytes()
+    }
+
+    fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.secret)
+    }
+}
+
+impl Drop for DeviceKey {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
@@ -0,0 +1,165 @@
+//! Installing releases made of several files
+//!
+//! A release may carry more than the application image: configuration data,
+//! a bootloader or a file system image (see [`FileType`]). The client installs
+//! the first `Firmware` file into its own [`UpdateStorage`] and hands every
+//! other file to a [`FileHandler`], which picks where it goes from its
+//! `file_type` and `target`.
+//!
+//! Installs are all-or-nothing. Every file is first staged and checked against
+//! its SHA-256; only once all of them are good are they committed, handler
+//! files first and the firmware last. If anything fails before that, the
+//! staged files are aborted and the running system is left as it was.
+
+use crate::error::{OtaError, Result};
+use crate::manifest::{FileType, UpdateFile, UpdateManifest};
+use crate::storage::UpdateStorage;
+
+/// Installs the files of a release that are not the application image
+///
+/// A device with several destinations (say a config partition and a file
+/// system) implements this once and dispatches on `file.target`.
+pub trait FileHandler {
+    /// Storage the files are staged in
+    type Storage: UpdateStorage;
+
+    /// Check whether this handler can install `file`
+    fn accepts(&self, file: &UpdateFile) -> bool;
+
+    /// Storage to stage `file` in until the whole release is verified
+    ///
+    /// Called again for every download attempt, and must return the same
+    /// area each time so an interrupted download can resume.
+    async fn stage(&mut self, file: &UpdateFile) -> Result<&mut Self::Storage>;
+
+    /// Make a staged and verified file take effect
+    ///
+    /// Commits cannot be undone once made, so this should be a small atomic
+    /// step (e.g. flipping a flag) rather than copying the file around.
+    async fn commit(&mut self, file: &UpdateFile) -> Result<()>;
+
+    /// Drop a staged file because the release as a whole failed
+    async fn abort(&mut self, _file: &UpdateFile) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Handler for devices that only take firmware images
+///
+/// Accepts nothing, so a release carrying other files is refused before
+/// anything is downloaded.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoFileHandler;
+
+impl FileHandler for NoFileHandler {
+    type Storage = NoStorage;
+
+    fn accepts(&self, _file: &UpdateFile) -> bool {
+        false
+    }
+
+    async fn stage(&mut self, _file: &UpdateFile) -> Result<&mut NoStorage> {
+        Err(OtaError::UnsupportedFile.into())
+    }
+
+    async fn commit(&mut self, _file: &UpdateFile) -> Result<()> {
+        Err(OtaError::UnsupportedFile.into())
+    }
+}
+
+/// Storage of [`NoFileHandler`], which never hands it out
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoStorage;
+
+impl UpdateStorage for NoStorage {
+    async fn read(&mut self, _offset: u32, _buffer: &mut [u8]) -> Result<()> {
+        Err(OtaError::UnsupportedFile.into())
+    }
+
+    async fn write(&mut self, _offset: u32, _data: &[u8]) -> Result<()> {
+        Err(OtaError::UnsupportedFile.into())
+    }
+
+    async fn erase(&mut self, _offset: u32, _length: u32) -> Result<()> {
+        Err(OtaError::UnsupportedFile.into())
+    }
+
+    fn capacity(&self) -> u32 {
+        0
+    }
+
+    fn erase_size(&self) -> u32 {
+        1
+    }
+}
+
+/// Where a file of a release is installed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// The application image, into the client's update storage
+    Firmware,
+    /// Anything else, through the [`FileHandler`]
+    Handler,
+}
+
+impl Route {
+    /// Route the file at `index` of `manifest`
+    pub fn of(manifest: &UpdateManifest, index: usize) -> Self {
+        let firmware = manifest
+            .files
+            .iter()
+            .position(|f| f.file_type == FileType::Firmware);
+        if firmware == Some(index) {
+            Route::Firmware
+        } else {
+            Route::Handler
+        }
+    }
+}
+
+/// Storage a file is being staged in, either the firmware's or a handler's
+pub(crate) enum StagingArea<'s, S, F> {
+    Firmware(&'s mut S),
+    Handler(&'s mut F),
+}
+
+impl<S, F> UpdateStorage for StagingArea<'_, S, F>
+where
+    S: UpdateStorage,
+    F: UpdateStorage,
+{
+    async fn read(&mut self, offset: u32, buffer: &mut [u8]) -> Result<()> {
+        match self {
+            StagingArea::Firmware(storage) => storage.read(offset, buffer).await,
+            StagingArea::Handler(storage) => storage.read(offset, buffer).await,
+        }
+    }
+
+    async fn write(&mut self, offset: u32, data: &[u8]) -> Result<()> {
+        match self {
+            StagingArea::Firmware(storage) => storage.write(offset, data).await,
+            StagingArea::Handler(storage) => storage.write(offset, data).await,
+        }
+    }
+
+    async fn erase(&mut self, offset: u32, length: u32) -> Result<()> {
+        match self {
+            StagingArea::Firmware(storage) => storage.erase(offset, length).await,
+            StagingArea::Handler(storage) => storage.erase(offset, length).await,
+        }
+    }
+
+    fn capacity(&self) -> u32 {
+        match self {
+            StagingArea::Firmware(storage) => storage.capacity(),
+            StagingArea::Handler(storage) => storage.capacity(),
+        }
+    }
+
+    fn erase_size(&self) -> u32 {
+        match self {
+            StagingArea::Firmware(storage) => storage.erase_size(),
+            StagingArea::Handler(storage) => storage.erase_size(),
+        }
+    }
+}
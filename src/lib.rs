@@ -13,12 +13,18 @@
 //! ## Example
 //! ```no_run
 //! use genesis::{OtaClient, OtaConfig};
+//! use genesis::client::UpdateStatus;
+//! use genesis::transport::{TlsLink, TlsTransport};
 //! 
-//! let config = OtaConfig::new("https://solari.local/ota");
-//! let mut client = OtaClient::new(config, public_key);
+//! static TLS: TlsLink = TlsLink::new();
+//! 
+//! let config = OtaConfig::new("https://solari.local/ota")?;
+//! let (transport, runner) = TlsTransport::new(&TLS, stack, rng, (&mut tcp_rx, &mut tcp_tx), (&mut tls_rx, &mut tls_tx));
+//! spawner.spawn(tls_task(runner))?;
+//! let mut client = OtaClient::new(config, storage, public_key, transport);
 //! 
 //! // Check for updates
-//! if let Some(manifest) = client.check_update().await? {
+//! if let UpdateStatus::Available(manifest) = client.check_update().await {
 //!     client.download_and_apply(manifest).await?;
 //! }
 //! ```
@@ -29,15 +35,41 @@ pub use crate::config::{ConfigManager, OtaConfig};
 pub use crate::error::{Error, Result};
 pub use crate::manifest::{Manifest, UpdateManifest};
 pub use crate::storage::UpdateStorage;
+pub use crate::transport::OtaTransport;
 pub use crate::verification::SignatureVerifier;
 
 // Module declarations
+pub mod auth;
 pub mod client;
 pub mod config;
+pub mod consent;
+#[cfg(feature = "mdns")]
+pub mod discovery;
 pub mod error;
+pub mod http;
+pub mod identity;
+pub mod install;
 pub mod manifest;
+pub mod mirror;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod policy;
+pub mod redirect;
+pub mod report;
+pub mod retry;
+pub mod scheduler;
+#[cfg(feature = "sntp")]
+pub mod sntp;
+pub mod state;
 pub mod storage;
+pub mod time;
+pub mod transport;
+pub mod url;
 pub mod verification;
+pub mod writer;
+
+#[cfg(test)]
+mod testing;
 
 // Version information
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -2,12 +2,31 @@
 
 use crate::config::Version;
 use crate::error::{ManifestError, Result};
+use crate::http::{MAX_ETAG_LENGTH, MAX_HTTP_DATE_LENGTH};
 use heapless::{String, Vec};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 /// Maximum number of files in a single update
 pub const MAX_UPDATE_FILES: usize = 8;
 
+/// Maximum serialized size of a manifest
+pub const MAX_MANIFEST_SIZE: usize = 4096;
+
+/// Validators of the last verified manifest, kept for conditional polling
+///
+/// Only recorded when that manifest offered nothing for `version`, so a
+/// `304 Not Modified` can be answered with "up to date" without re-verifying.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestCache {
+    /// Firmware version the manifest was evaluated against
+    pub version: Version,
+    /// ETag of the manifest response
+    pub etag: Option<String<MAX_ETAG_LENGTH>>,
+    /// Last-Modified date of the manifest response
+    pub last_modified: Option<String<MAX_HTTP_DATE_LENGTH>>,
+}
+
 /// Update manifest describing available firmware
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateManifest {
@@ -29,7 +48,7 @@ pub struct UpdateManifest {
     /// Files included in this update
     pub files: Vec<UpdateFile, MAX_UPDATE_FILES>,
     
-    /// GPG signature of the manifest
+    /// Signature of the manifest over [`UpdateManifest::signed_bytes`]
     pub signature: Signature,
     
     /// Update urgency level
@@ -202,6 +221,26 @@ impl UpdateManifest {
     pub fn total_size(&self) -> u32 {
         self.files.iter().map(|f| f.size).sum()
     }
+    
+    /// Serialize the manifest as covered by its signature
+    ///
+    /// The signature cannot cover itself, so it is computed over the
+    /// manifest with an empty `signature.data`.
+    pub fn signed_bytes<'b>(&self, buffer: &'b mut [u8]) -> Result<&'b [u8]> {
+        let mut unsigned = self.clone();
+        unsigned.signature.data.clear();
+        let bytes = postcard::to_slice(&unsigned, buffer)
+            .map_err(|_| ManifestError::InvalidFormat)?;
+        Ok(bytes)
+    }
+    
+    /// Compute a SHA256 digest identifying this manifest
+    pub fn digest(&self) -> Result<[u8; 32]> {
+        let mut buffer = [0u8; MAX_MANIFEST_SIZE];
+        let bytes = postcard::to_slice(self, &mut buffer)
+            .map_err(|_| ManifestError::InvalidFormat)?;
+        Ok(Sha256::digest(bytes).into())
+    }
 }
 
 impl Default for RollbackInfo {
@@ -0,0 +1,105 @@
+//! Failover between the primary server and its mirrors
+
+use crate::config::MAX_MIRRORS;
+use crate::error::{Error, NetworkError};
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/// Maximum number of servers, primary included
+pub const MAX_SERVERS: usize = MAX_MIRRORS + 1;
+
+/// Health of the configured servers
+///
+/// Servers are tried in this order: the one that last succeeded, then the
+/// others in configuration order, then those that failed within the cool-down
+/// period (soonest to recover first). Failed servers are still tried as a last
+/// resort, so an outage of every healthy server never stops an update.
+#[derive(Debug, Clone)]
+pub struct MirrorHealth {
+    count: usize,
+    preferred: usize,
+    cooldown: Duration,
+    cooling_until: [Option<Instant>; MAX_SERVERS],
+}
+
+impl MirrorHealth {
+    /// Track `count` servers, avoiding failed ones for `cooldown`
+    pub fn new(count: usize, cooldown: Duration) -> Self {
+        Self {
+            count: count.min(MAX_SERVERS),
+            preferred: 0,
+            cooldown,
+            cooling_until: [None; MAX_SERVERS],
+        }
+    }
+
+    /// Index of the server that last succeeded (initially the primary)
+    pub fn preferred(&self) -> usize {
+        self.preferred
+    }
+
+    /// Check whether server `index` failed within the cool-down period
+    pub fn is_cooling_down(&self, index: usize, now: Instant) -> bool {
+        self.cooling_until
+            .get(index)
+            .copied()
+            .flatten()
+            .is_some_and(|until| now < until)
+    }
+
+    /// Server indices in the order they should be tried
+    pub fn order(&self, now: Instant) -> Vec<usize, MAX_SERVERS> {
+        let mut order: Vec<usize, MAX_SERVERS> = Vec::new();
+        let healthy = (0..self.count).filter(|i| !self.is_cooling_down(*i, now));
+        if !self.is_cooling_down(self.preferred, now) {
+            let _ = order.push(self.preferred);
+        }
+        for index in healthy.filter(|i| *i != self.preferred) {
+            let _ = order.push(index);
+        }
+
+        let first_cooling = order.len();
+        for index in (0..self.count).filter(|i| self.is_cooling_down(*i, now)) {
+            let _ = order.push(index);
+        }
+        order[first_cooling..].sort_unstable_by_key(|i| self.cooling_until[*i]);
+        order
+    }
+
+    /// Record a successful request to server `index`
+    pub fn record_success(&mut self, index: usize) {
+        if index < self.count {
+            self.preferred = index;
+            self.cooling_until[index] = None;
+        }
+    }
+
+    /// Record a failure of server `index` that another server might not have
+    pub fn record_failure(&mut self, index: usize, now: Instant) {
+        if index < self.count {
+            self.cooling_until[index] = Some(now + self.cooldown);
+        }
+    }
+}
+
+/// Check whether `error` is the server's fault, so another server may do better
+///
+/// Connection, TLS and timeout failures and 5xx responses count; errors in the
+/// content itself (bad signature, wrong hash) would be the same everywhere.
+pub fn should_fail_over(error: &Error) -> bool {
+    match error {
+        Error::Network(network) => match network {
+            NetworkError::ConnectionFailed
+            | NetworkError::ConnectionClosed
+            | NetworkError::DnsFailed
+            | NetworkError::TlsFailed
+            | NetworkError::CertificateRejected
+            | NetworkError::Timeout(_)
+            | NetworkError::Truncated
+            | NetworkError::RetryAfter(_) => true,
+            NetworkError::HttpError(status) => (500..=599).contains(status),
+            _ => false,
+        },
+        _ => false,
+    }
+}
@@ -0,0 +1,877 @@
+//! MQTT push notifications for new releases
+//!
+//! A minimal MQTT 3.1.1 / 5 subscriber listening on `ota/<device_id>` and,
+//! optionally, `ota/<group>` for signed [`ReleaseNotice`]s, so a device can
+//! check for an update as soon as one is published instead of waiting for the
+//! next poll. The session runs over any `embedded-io-async` connection to the
+//! broker, e.g. an embassy-net `TcpSocket` (optionally wrapped in TLS) or a
+//! host TCP stream when testing against a local broker.
+//!
+//! Only what receiving notices needs is implemented: CONNECT, SUBSCRIBE at
+//! QoS 1, incoming PUBLISH at QoS 0 and 1, and keep-alive pings.
+//!
+//! A notice is only acted on if it was issued after the last one accepted in
+//! the session and, once the device knows the time, no longer ago than the
+//! configured maximum age (a day by default), so a recorded notice cannot be
+//! replayed to trigger checks later on.
+
+use crate::config::Version;
+use crate::error::{ConfigError, Error, NetworkError, Result, TimeoutPhase};
+use crate::manifest::Signature;
+use crate::verification::SignatureVerifier;
+
+use embassy_time::{with_deadline, with_timeout, Duration, Instant};
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+/// Largest packet kept in memory; bigger packets are skipped
+pub const MAX_PACKET_SIZE: usize = 1024;
+
+/// Maximum length of a subscribed topic
+pub const MAX_TOPIC_LENGTH: usize = 64;
+
+/// Prefix of the release topics
+pub const TOPIC_PREFIX: &str = "ota/";
+
+/// Maximum length of the broker username and password
+pub const MAX_CREDENTIAL_LENGTH: usize = 64;
+
+/// Default keep-alive interval in seconds
+const DEFAULT_KEEP_ALIVE_SECS: u16 = 60;
+
+/// Default maximum age of an accepted release notice in seconds
+const DEFAULT_MAX_NOTICE_AGE_SECS: u32 = 86_400;
+
+/// How far in the future a notice may be dated, for clock differences
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+
+// Fixed header bytes of the packets used
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const DISCONNECT: u8 = 0xE0;
+
+/// MQTT protocol revision spoken to the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1.1 (protocol level 4)
+    V311,
+    /// MQTT 5.0 (protocol level 5)
+    V5,
+}
+
+/// Broker session settings
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    /// Device identifier; used as client ID and for the device topic
+    pub device_id: String<32>,
+
+    /// Device group; adds an `ota/<group>` subscription if set
+    pub group: Option<String<32>>,
+
+    /// Broker username
+    pub username: Option<String<MAX_CREDENTIAL_LENGTH>>,
+
+    /// Broker password
+    pub password: Option<String<MAX_CREDENTIAL_LENGTH>>,
+
+    /// Keep-alive interval in seconds (at least 1)
+    pub keep_alive_secs: u16,
+
+    /// Protocol revision
+    pub protocol: ProtocolVersion,
+
+    /// Oldest notice accepted when the time is known, in seconds
+    pub max_notice_age_secs: u32,
+}
+
+/// The signed part of a release notice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReleaseAnnouncement {
+    /// Version that has been released
+    pub version: Version,
+
+    /// Publication time (Unix timestamp)
+    pub issued_at: u32,
+}
+
+/// Payload of a "release available" message (postcard-encoded)
+///
+/// The signature covers the postcard encoding of the announcement and is made
+/// with the same key as the manifests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReleaseNotice {
+    /// What was released
+    pub announcement: ReleaseAnnouncement,
+
+    /// Signature over the announcement
+    pub signature: Signature,
+}
+
+/// Subscribed MQTT session delivering release notices
+pub struct MqttSession<C> {
+    conn: C,
+    protocol: ProtocolVersion,
+    keep_alive: Duration,
+    last_sent: Instant,
+    topics: Vec<String<MAX_TOPIC_LENGTH>, 2>,
+    packet: Vec<u8, MAX_PACKET_SIZE>,
+    next_packet_id: u16,
+    max_notice_age_secs: u32,
+    /// Issue time of the last notice accepted
+    newest: Option<u32>,
+}
+
+/// Reader over a received packet body
+struct Cursor<'p> {
+    data: &'p [u8],
+    pos: usize,
+}
+
+impl MqttConfig {
+    /// Create settings for the given device using MQTT 3.1.1
+    pub fn new(device_id: &str) -> Result<Self> {
+        Ok(Self {
+            device_id: topic_segment(device_id)?,
+            group: None,
+            username: None,
+            password: None,
+            keep_alive_secs: DEFAULT_KEEP_ALIVE_SECS,
+            protocol: ProtocolVersion::V311,
+            max_notice_age_secs: DEFAULT_MAX_NOTICE_AGE_SECS,
+        })
+    }
+
+    /// Also listen for releases announced to a device group
+    pub fn with_group(mut self, group: &str) -> Result<Self> {
+        self.group = Some(topic_segment(group)?);
+        Ok(self)
+    }
+
+    /// Authenticate to the broker with a username and password
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Result<Self> {
+        self.username = Some(String::try_from(username).map_err(|_| ConfigError::InvalidCredentials)?);
+        self.password = Some(String::try_from(password).map_err(|_| ConfigError::InvalidCredentials)?);
+        Ok(self)
+    }
+
+    /// Set the keep-alive interval
+    pub fn with_keep_alive(mut self, seconds: u16) -> Self {
+        self.keep_alive_secs = seconds.max(1);
+        self
+    }
+
+    /// Select the protocol revision
+    pub fn with_protocol(mut self, protocol: ProtocolVersion) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Ignore notices issued more than `seconds` ago
+    pub fn with_max_notice_age(mut self, seconds: u32) -> Self {
+        self.max_notice_age_secs = seconds;
+        self
+    }
+}
+
+impl ReleaseNotice {
+    /// Check the signature, returning the announcement if it is genuine
+    pub fn verify(&self, verifier: &SignatureVerifier) -> Result<ReleaseAnnouncement> {
+        let signed = postcard::to_vec::<_, 32>(&self.announcement)
+            .map_err(|_| NetworkError::InvalidResponse)?;
+        verifier.verify_message(&signed, &self.signature)?;
+        Ok(self.announcement)
+    }
+}
+
+impl<C> MqttSession<C>
+where
+    C: Read + Write,
+{
+    /// Connect to the broker over `conn` and subscribe to the release topics
+    pub async fn connect(conn: C, config: &MqttConfig) -> Result<Self> {
+        let mut topics = Vec::new();
+        let _ = topics.push(topic(&config.device_id)?);
+        if let Some(group) = &config.group {
+            let _ = topics.push(topic(group)?);
+        }
+
+        let mut session = Self {
+            conn,
+            protocol: config.protocol,
+            keep_alive: Duration::from_secs(config.keep_alive_secs.max(1) as u64),
+            last_sent: Instant::now(),
+            topics,
+            packet: Vec::new(),
+            next_packet_id: 1,
+            max_notice_age_secs: config.max_notice_age_secs,
+            newest: None,
+        };
+        session.send_connect(config).await?;
+        session.await_connack().await?;
+        session.subscribe().await?;
+        Ok(session)
+    }
+
+    /// Wait for the next genuine, fresh release notice
+    ///
+    /// `now` is the current Unix time, if known. Messages on other topics,
+    /// malformed payloads, notices with a bad signature and stale notices (see
+    /// the module documentation) are ignored. Errors mean the session is lost
+    /// and should be re-established.
+    pub async fn next_notice(
+        &mut self,
+        verifier: &SignatureVerifier,
+        now: Option<u64>,
+    ) -> Result<ReleaseAnnouncement> {
+        loop {
+            let header = self.receive().await?;
+            if header & 0xF0 != PUBLISH {
+                continue;
+            }
+            let Some((start, end)) = self.accept_publish(header).await? else {
+                continue;
+            };
+
+            let notice: Option<ReleaseNotice> = postcard::from_bytes(&self.packet[start..end]).ok();
+            let announcement = notice.and_then(|n| n.verify(verifier).ok());
+            if let Some(announcement) = announcement.filter(|a| self.is_fresh(a, now)) {
+                self.newest = Some(announcement.issued_at);
+                return Ok(announcement);
+            }
+        }
+    }
+
+    /// Check that a notice is newer than the last one accepted and, if the
+    /// time is known, neither too old nor dated in the future
+    fn is_fresh(&self, announcement: &ReleaseAnnouncement, now: Option<u64>) -> bool {
+        if self.newest.is_some_and(|newest| announcement.issued_at <= newest) {
+            return false;
+        }
+        let issued_at = announcement.issued_at as u64;
+        match now {
+            Some(now) => {
+                issued_at <= now + MAX_CLOCK_SKEW_SECS
+                    && now.saturating_sub(issued_at) <= self.max_notice_age_secs as u64
+            }
+            None => true,
+        }
+    }
+
+    /// Leave the session cleanly and hand back the connection
+    pub async fn disconnect(mut self) -> C {
+        let _ = self.send(DISCONNECT, &[]).await;
+        self.conn
+    }
+
+    async fn send_connect(&mut self, config: &MqttConfig) -> Result<()> {
+        let mut body: Vec<u8, MAX_PACKET_SIZE> = Vec::new();
+        put_str(&mut body, "MQTT")?;
+        put(&mut body, &[match self.protocol {
+            ProtocolVersion::V311 => 4,
+            ProtocolVersion::V5 => 5,
+        }])?;
+
+        // Clean session; no will
+        let mut flags = 0x02;
+        if config.username.is_some() {
+            flags |= 0x80;
+        }
+        if config.password.is_some() {
+            flags |= 0x40;
+        }
+        put(&mut body, &[flags])?;
+        put(&mut body, &config.keep_alive_secs.max(1).to_be_bytes())?;
+        self.put_no_properties(&mut body)?;
+
+        put_str(&mut body, &config.device_id)?;
+        if let Some(username) = &config.username {
+            put_str(&mut body, username)?;
+        }
+        if let Some(password) = &config.password {
+            put_str(&mut body, password)?;
+        }
+
+        self.send(CONNECT, &body).await
+    }
+
+    async fn await_connack(&mut self) -> Result<()> {
+        let header = self.receive().await?;
+        if header != CONNACK {
+            return Err(NetworkError::InvalidResponse.into());
+        }
+
+        // Session-present flags, then the return (3.1.1) or reason (5) code
+        let mut cursor = Cursor::new(&self.packet);
+        cursor.u8()?;
+        let code = cursor.u8()?;
+        let rejected = match self.protocol {
+            ProtocolVersion::V311 => code != 0,
+            ProtocolVersion::V5 => code >= 0x80,
+        };
+        if rejected {
+            return Err(NetworkError::BrokerRejected(code).into());
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&mut self) -> Result<()> {
+        let packet_id = self.packet_id();
+
+        let mut body: Vec<u8, MAX_PACKET_SIZE> = Vec::new();
+        put(&mut body, &packet_id.to_be_bytes())?;
+        self.put_no_properties(&mut body)?;
+        for topic in &self.topics {
+            put_str(&mut body, topic)?;
+            // Maximum QoS 1
+            put(&mut body, &[0x01])?;
+        }
+        self.send(SUBSCRIBE, &body).await?;
+
+        // Retained notices are delivered after the SUBACK, so anything else
+        // arriving first can be skipped
+        loop {
+            let header = self.receive().await?;
+            if header != SUBACK {
+                continue;
+            }
+
+            let mut cursor = Cursor::new(&self.packet);
+            if cursor.u16()? != packet_id {
+                continue;
+            }
+            if self.protocol == ProtocolVersion::V5 {
+                cursor.skip_properties()?;
+            }
+            while let Some(code) = cursor.byte() {
+                if code >= 0x80 {
+                    return Err(NetworkError::BrokerRejected(code).into());
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    /// Acknowledge a PUBLISH and locate its payload if it is on a release topic
+    async fn accept_publish(&mut self, header: u8) -> Result<Option<(usize, usize)>> {
+        let qos = (header >> 1) & 0x03;
+
+        let mut cursor = Cursor::new(&self.packet);
+        let topic = cursor.str()?;
+        let subscribed = self.topics.iter().any(|t| t.as_str() == topic);
+        let packet_id = match qos {
+            0 => None,
+            1 => Some(cursor.u16()?),
+            _ => return Err(NetworkError::InvalidResponse.into()),
+        };
+        if self.protocol == ProtocolVersion::V5 {
+            cursor.skip_properties()?;
+        }
+        let payload = (cursor.pos, self.packet.len());
+
+        if let Some(packet_id) = packet_id {
+            self.send(PUBACK, &packet_id.to_be_bytes()).await?;
+        }
+        Ok(subscribed.then_some(payload))
+    }
+
+    /// Receive the next packet other than a ping response, pinging as needed
+    ///
+    /// Returns the fixed header byte; the body is left in `self.packet`.
+    async fn receive(&mut self) -> Result<u8> {
+        let mut ping_pending = false;
+        loop {
+            // Only the wait for a packet's first byte may time out, so no
+            // packet is ever left half-read
+            let mut first = [0u8; 1];
+            let deadline = self.last_sent + self.keep_alive;
+            match with_deadline(deadline, self.conn.read(&mut first)).await {
+                Ok(Ok(1)) => {}
+                Ok(_) => return Err(NetworkError::ConnectionFailed.into()),
+                Err(_) if ping_pending => {
+                    return Err(NetworkError::Timeout(TimeoutPhase::Stall).into());
+                }
+                Err(_) => {
+                    self.send(PINGREQ, &[]).await?;
+                    ping_pending = true;
+                    continue;
+                }
+            }
+
+            if !self.read_body().await? {
+                continue;
+            }
+            if first[0] == PINGRESP {
+                ping_pending = false;
+                continue;
+            }
+            return Ok(first[0]);
+        }
+    }
+
+    /// Read the remaining length and body of a packet into `self.packet`
+    ///
+    /// Returns `false` if the packet was too large and has been skipped.
+    async fn read_body(&mut self) -> Result<bool> {
+        let mut length = 0usize;
+        for shift in (0..28).step_by(7) {
+            let byte = self.read_byte().await?;
+            length |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if shift == 21 {
+                return Err(NetworkError::InvalidResponse.into());
+            }
+        }
+
+        self.packet.clear();
+        if length > MAX_PACKET_SIZE {
+            let mut scratch = [0u8; 64];
+            let mut left = length;
+            while left > 0 {
+                let n = left.min(scratch.len());
+                self.read_exact_within(n, Some(&mut scratch[..])).await?;
+                left -= n;
+            }
+            return Ok(false);
+        }
+
+        let _ = self.packet.resize(length, 0);
+        self.read_exact_within(length, None).await?;
+        Ok(true)
+    }
+
+    async fn read_byte(&mut self) -> Result<u8> {
+        let mut byte = [0u8; 1];
+        self.read_exact_within(1, Some(&mut byte[..])).await?;
+        Ok(byte[0])
+    }
+
+    /// Read exactly `len` bytes into `scratch`, or into `self.packet` if `None`
+    async fn read_exact_within(&mut self, len: usize, scratch: Option<&mut [u8]>) -> Result<()> {
+        let buf = match scratch {
+            Some(scratch) => &mut scratch[..len],
+            None => &mut self.packet[..len],
+        };
+        with_timeout(self.keep_alive, self.conn.read_exact(buf))
+            .await
+            .map_err(|_| NetworkError::Timeout(TimeoutPhase::Stall))?
+            .map_err(|_| NetworkError::ConnectionFailed.into())
+    }
+
+    async fn send(&mut self, header: u8, body: &[u8]) -> Result<()> {
+        let mut fixed: Vec<u8, 5> = Vec::new();
+        let _ = fixed.push(header);
+        put_varint(&mut fixed, body.len())?;
+
+        with_timeout(self.keep_alive, async {
+            self.conn.write_all(&fixed).await?;
+            self.conn.write_all(body).await?;
+            self.conn.flush().await
+        })
+        .await
+        .map_err(|_| NetworkError::Timeout(TimeoutPhase::Stall))?
+        .map_err(|_| NetworkError::ConnectionFailed)?;
+
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Empty property list, present only in MQTT 5
+    fn put_no_properties<const N: usize>(&self, body: &mut Vec<u8, N>) -> Result<()> {
+        match self.protocol {
+            ProtocolVersion::V311 => Ok(()),
+            ProtocolVersion::V5 => put(body, &[0]),
+        }
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        let id = self.next_packet_id;
+        // Packet identifiers must be non-zero
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        id
+    }
+}
+
+impl<'p> Cursor<'p> {
+    fn new(data: &'p [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        self.byte().ok_or_else(invalid)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn str(&mut self) -> Result<&'p str> {
+        let len = self.u16()? as usize;
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(invalid)?;
+        self.pos += len;
+        core::str::from_utf8(bytes).map_err(|_| invalid())
+    }
+
+    /// Skip an MQTT 5 property list
+    fn skip_properties(&mut self) -> Result<()> {
+        let mut length = 0usize;
+        for shift in (0..28).step_by(7) {
+            let byte = self.u8()?;
+            length |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        if self.pos + length > self.data.len() {
+            return Err(invalid());
+        }
+        self.pos += length;
+        Ok(())
+    }
+}
+
+fn invalid() -> Error {
+    NetworkError::InvalidResponse.into()
+}
+
+/// Release topic for a device or group
+fn topic(name: &str) -> Result<String<MAX_TOPIC_LENGTH>> {
+    let mut topic = String::new();
+    topic.push_str(TOPIC_PREFIX).map_err(|_| ConfigError::InvalidTopic)?;
+    topic.push_str(name).map_err(|_| ConfigError::InvalidTopic)?;
+    Ok(topic)
+}
+
+/// Validate a device ID or group name for use as a topic level
+fn topic_segment(name: &str) -> Result<String<32>> {
+    if name.is_empty() || name.contains(['+', '#', '/', '\0']) {
+        return Err(ConfigError::InvalidTopic.into());
+    }
+    String::try_from(name).map_err(|_| ConfigError::InvalidTopic.into())
+}
+
+fn put<const N: usize>(buf: &mut Vec<u8, N>, data: &[u8]) -> Result<()> {
+    buf.extend_from_slice(data)
+        .map_err(|_| NetworkError::RequestTooLarge.into())
+}
+
+fn put_str<const N: usize>(buf: &mut Vec<u8, N>, s: &str) -> Result<()> {
+    let len = u16::try_from(s.len()).map_err(|_| NetworkError::RequestTooLarge)?;
+    put(buf, &len.to_be_bytes())?;
+    put(buf, s.as_bytes())
+}
+
+/// Append an MQTT variable byte integer
+fn put_varint<const N: usize>(buf: &mut Vec<u8, N>, mut value: usize) -> Result<()> {
+    loop {
+        let mut byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        put(buf, &[byte])?;
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::manifest::SignatureAlgorithm;
+    use crate::testing::{block_on, join, End, Pipe};
+    use crate::verification::PublicKey;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::vec;
+    use std::vec::Vec as StdVec;
+
+    const UPDATE_KEY: [u8; 32] = [0x5A; 32];
+    const NOW: u64 = 1_700_000_000;
+
+    fn verifier() -> SignatureVerifier {
+        let key = SigningKey::from_bytes(&UPDATE_KEY).verifying_key();
+        SignatureVerifier::new(PublicKey::ed25519_from_bytes(key.as_bytes()).unwrap())
+    }
+
+    fn config() -> MqttConfig {
+        MqttConfig::new("dev-1")
+            .unwrap()
+            .with_group("lab")
+            .unwrap()
+            .with_credentials("user", "pass")
+            .unwrap()
+    }
+
+    /// Payload of a notice for `version` signed with `key`
+    fn notice(key: &[u8; 32], minor: u8, issued_at: u64) -> StdVec<u8> {
+        let announcement = ReleaseAnnouncement {
+            version: Version::new(1, minor, 0, 0),
+            issued_at: issued_at as u32,
+        };
+        let mut buffer = [0u8; 512];
+        let signed = postcard::to_slice(&announcement, &mut buffer).unwrap();
+        let signature = SigningKey::from_bytes(key).sign(signed).to_bytes();
+        let notice = ReleaseNotice {
+            announcement,
+            signature: Signature {
+                algorithm: SignatureAlgorithm::Ed25519,
+                key_id: [0; 8],
+                data: Vec::from_slice(&signature).unwrap(),
+            },
+        };
+        postcard::to_slice(&notice, &mut buffer).unwrap().to_vec()
+    }
+
+    fn packet(header: u8, body: &[u8]) -> StdVec<u8> {
+        let mut packet = vec![header];
+        let mut length = body.len();
+        loop {
+            let byte = (length & 0x7F) as u8;
+            length >>= 7;
+            if length == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn publish(protocol: ProtocolVersion, topic: &str, packet_id: Option<u16>, payload: &[u8]) -> StdVec<u8> {
+        let mut body = StdVec::new();
+        body.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+        body.extend_from_slice(topic.as_bytes());
+        if let Some(packet_id) = packet_id {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+        }
+        if protocol == ProtocolVersion::V5 {
+            body.push(0);
+        }
+        body.extend_from_slice(payload);
+        let qos = if packet_id.is_some() { 0x02 } else { 0x00 };
+        packet(PUBLISH | qos, &body)
+    }
+
+    /// Read the next packet the device sent to the broker
+    async fn read_packet(pipe: &Pipe) -> (u8, StdVec<u8>) {
+        let mut header = [0u8];
+        pipe.read_exact(&mut header).await;
+        let mut length = 0;
+        for shift in (0..28).step_by(7) {
+            let mut byte = [0u8];
+            pipe.read_exact(&mut byte).await;
+            length |= ((byte[0] & 0x7F) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; length];
+        pipe.read_exact(&mut body).await;
+        (header[0], body)
+    }
+
+    /// Broker side of CONNECT and SUBSCRIBE, returning both packet bodies
+    async fn accept(rx: &Pipe, tx: &Pipe, protocol: ProtocolVersion) -> (StdVec<u8>, StdVec<u8>) {
+        let (header, connect) = read_packet(rx).await;
+        assert_eq!(header, CONNECT);
+        match protocol {
+            ProtocolVersion::V311 => tx.push(&packet(CONNACK, &[0, 0])),
+            ProtocolVersion::V5 => tx.push(&packet(CONNACK, &[0, 0, 0])),
+        }
+
+        let (header, subscribe) = read_packet(rx).await;
+        assert_eq!(header, SUBSCRIBE);
+        let mut suback = subscribe[..2].to_vec();
+        if protocol == ProtocolVersion::V5 {
+            suback.push(0);
+        }
+        suback.extend_from_slice(&[0x01, 0x01]);
+        tx.push(&packet(SUBACK, &suback));
+        (connect, subscribe)
+    }
+
+    fn connect<'a>(to_device: &'a Pipe, to_broker: &'a Pipe, config: &MqttConfig) -> MqttSession<End<'a>> {
+        let conn = End {
+            rx: to_device,
+            tx: to_broker,
+        };
+        let (session, _) = block_on(join(
+            MqttSession::connect(conn, config),
+            accept(to_broker, to_device, config.protocol),
+        ));
+        session.unwrap()
+    }
+
+    #[test]
+    fn connects_and_subscribes_v311() {
+        let (to_device, to_broker) = (Pipe::default(), Pipe::default());
+        let conn = End {
+            rx: &to_device,
+            tx: &to_broker,
+        };
+        let (session, (connect, subscribe)) = block_on(join(
+            MqttSession::connect(conn, &config()),
+            accept(&to_broker, &to_device, ProtocolVersion::V311),
+        ));
+        let mut session = session.unwrap();
+
+        // Protocol level 4, clean session with username and password, 60 s keep-alive
+        let mut expected = b"\0\x04MQTT\x04\xC2\0\x3C".to_vec();
+        expected.extend_from_slice(b"\0\x05dev-1\0\x04user\0\x04pass");
+        assert_eq!(connect, expected);
+        assert_eq!(subscribe, b"\0\x01\0\x09ota/dev-1\x01\0\x07ota/lab\x01");
+
+        // QoS 1 notices are acknowledged
+        to_device.push(&publish(ProtocolVersion::V311, "ota/dev-1", Some(7), &notice(&UPDATE_KEY, 2, NOW)));
+        let announcement = block_on(session.next_notice(&verifier(), Some(NOW))).unwrap();
+        assert_eq!(announcement.version, Version::new(1, 2, 0, 0));
+        assert_eq!(to_broker.pending(), [PUBACK, 2, 0, 7]);
+    }
+
+    #[test]
+    fn connects_and_subscribes_v5() {
+        let (to_device, to_broker) = (Pipe::default(), Pipe::default());
+        let config = config().with_protocol(ProtocolVersion::V5).with_keep_alive(30);
+        let conn = End {
+            rx: &to_device,
+            tx: &to_broker,
+        };
+        let (session, (connect, subscribe)) = block_on(join(
+            MqttSession::connect(conn, &config),
+            accept(&to_broker, &to_device, ProtocolVersion::V5),
+        ));
+        let mut session = session.unwrap();
+
+        // Empty property lists after the keep-alive and the packet identifier
+        assert_eq!(&connect[..11], b"\0\x04MQTT\x05\xC2\0\x1E\0");
+        assert_eq!(&subscribe[..3], b"\0\x01\0");
+
+        to_device.push(&publish(ProtocolVersion::V5, "ota/lab", Some(9), &notice(&UPDATE_KEY, 3, NOW)));
+        let announcement = block_on(session.next_notice(&verifier(), Some(NOW))).unwrap();
+        assert_eq!(announcement.version, Version::new(1, 3, 0, 0));
+        assert_eq!(to_broker.pending(), [PUBACK, 2, 0, 9]);
+    }
+
+    #[test]
+    fn badly_signed_notices_are_ignored() {
+        let (to_device, to_broker) = (Pipe::default(), Pipe::default());
+        let mut session = connect(&to_device, &to_broker, &config());
+        let v311 = ProtocolVersion::V311;
+
+        // Signed with another key
+        to_device.push(&publish(v311, "ota/dev-1", Some(1), &notice(&[0xA5; 32], 4, NOW)));
+        // Signature of one announcement attached to another
+        let mut forged = notice(&UPDATE_KEY, 4, NOW);
+        forged[1] = 9;
+        to_device.push(&publish(v311, "ota/dev-1", Some(2), &forged));
+        // Not a notice at all, and a genuine one on a topic we did not subscribe to
+        to_device.push(&publish(v311, "ota/dev-1", None, b"release!"));
+        to_device.push(&publish(v311, "ota/other", None, &notice(&UPDATE_KEY, 4, NOW)));
+        // Genuine
+        to_device.push(&publish(v311, "ota/dev-1", Some(3), &notice(&UPDATE_KEY, 5, NOW)));
+
+        let announcement = block_on(session.next_notice(&verifier(), Some(NOW))).unwrap();
+        assert_eq!(announcement.version, Version::new(1, 5, 0, 0));
+        // Every QoS 1 message is acknowledged, genuine or not
+        assert_eq!(to_broker.pending(), [PUBACK, 2, 0, 1, PUBACK, 2, 0, 2, PUBACK, 2, 0, 3]);
+    }
+
+    #[test]
+    fn stale_and_replayed_notices_are_ignored() {
+        let (to_device, to_broker) = (Pipe::default(), Pipe::default());
+        let mut session = connect(&to_device, &to_broker, &config().with_max_notice_age(3600));
+        let v311 = ProtocolVersion::V311;
+
+        let current = notice(&UPDATE_KEY, 2, NOW - 60);
+        to_device.push(&publish(v311, "ota/dev-1", None, &notice(&UPDATE_KEY, 1, NOW - 7200)));
+        to_device.push(&publish(v311, "ota/dev-1", None, &notice(&UPDATE_KEY, 9, NOW + 3600)));
+        to_device.push(&publish(v311, "ota/dev-1", None, &current));
+        let announcement = block_on(session.next_notice(&verifier(), Some(NOW))).unwrap();
+        assert_eq!(announcement.version, Version::new(1, 2, 0, 0));
+
+        // The same notice again, and one issued before it
+        to_device.push(&publish(v311, "ota/dev-1", None, &current));
+        to_device.push(&publish(v311, "ota/dev-1", None, &notice(&UPDATE_KEY, 3, NOW - 120)));
+        to_device.push(&publish(v311, "ota/dev-1", None, &notice(&UPDATE_KEY, 4, NOW - 10)));
+        let announcement = block_on(session.next_notice(&verifier(), Some(NOW))).unwrap();
+        assert_eq!(announcement.version, Version::new(1, 4, 0, 0));
+    }
+
+    #[test]
+    fn age_is_not_checked_without_clock() {
+        let (to_device, to_broker) = (Pipe::default(), Pipe::default());
+        let mut session = connect(&to_device, &to_broker, &config());
+        let v311 = ProtocolVersion::V311;
+
+        let old = notice(&UPDATE_KEY, 1, NOW - 7 * 86_400);
+        to_device.push(&publish(v311, "ota/dev-1", None, &old));
+        let announcement = block_on(session.next_notice(&verifier(), None)).unwrap();
+        assert_eq!(announcement.version, Version::new(1, 1, 0, 0));
+
+        // Replays are still caught
+        to_device.push(&publish(v311, "ota/dev-1", None, &old));
+        to_device.push(&publish(v311, "ota/dev-1", None, &notice(&UPDATE_KEY, 2, NOW)));
+        let announcement = block_on(session.next_notice(&verifier(), None)).unwrap();
+        assert_eq!(announcement.version, Version::new(1, 2, 0, 0));
+    }
+
+    #[test]
+    fn broker_rejections_are_errors() {
+        let (to_device, to_broker) = (Pipe::default(), Pipe::default());
+        let conn = End {
+            rx: &to_device,
+            tx: &to_broker,
+        };
+        // 5: not authorized
+        to_device.push(&packet(CONNACK, &[0, 5]));
+        let result = block_on(MqttSession::connect(conn, &config()));
+        assert!(matches!(result, Err(Error::Network(NetworkError::BrokerRejected(5)))));
+
+        let (to_device, to_broker) = (Pipe::default(), Pipe::default());
+        let conn = End {
+            rx: &to_device,
+            tx: &to_broker,
+        };
+        to_device.push(&packet(CONNACK, &[0, 0]));
+        to_device.push(&packet(SUBACK, &[0, 1, 0x01, 0x80]));
+        let result = block_on(MqttSession::connect(conn, &config()));
+        assert!(matches!(result, Err(Error::Network(NetworkError::BrokerRejected(0x80)))));
+    }
+
+    #[test]
+    fn idle_session_pings_and_notices_lost_connection() {
+        let (to_device, to_broker) = (Pipe::default(), Pipe::default());
+        let mut session = connect(&to_device, &to_broker, &config().with_keep_alive(1));
+
+        let (announcement, _) = block_on(join(session.next_notice(&verifier(), Some(NOW)), async {
+            assert_eq!(read_packet(&to_broker).await, (PINGREQ, vec![]));
+            to_device.push(&packet(PINGRESP, &[]));
+            to_device.push(&publish(ProtocolVersion::V311, "ota/dev-1", None, &notice(&UPDATE_KEY, 2, NOW)));
+        }));
+        assert_eq!(announcement.unwrap().version, Version::new(1, 2, 0, 0));
+
+        to_device.close();
+        assert_eq!(
+            block_on(session.next_notice(&verifier(), Some(NOW))),
+            Err(NetworkError::ConnectionFailed.into())
+        );
+    }
+}
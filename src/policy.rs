@@ -0,0 +1,305 @@
+//! Deciding when an available update gets installed
+//!
+//! Before installing anything on its own, the [`UpdateScheduler`] asks an
+//! [`UpdatePolicy`]. [`DefaultPolicy`] goes by the release's urgency:
+//!
+//! | Urgency    | Installed                                        |
+//! |------------|--------------------------------------------------|
+//! | `Low`      | inside a maintenance window                      |
+//! | `Normal`   | once the device is idle                          |
+//! | `High`     | right away, unless the user postponed updates    |
+//! | `Critical` | right away, whatever the user said; checks for   |
+//! |            | updates also come more often until it is in      |
+//!
+//! Every decision is kept in a small [`DecisionLog`], so "why hasn't it
+//! updated yet?" has an answer.
+//!
+//! [`UpdateScheduler`]: crate::scheduler::UpdateScheduler
+
+use crate::config::Version;
+use crate::manifest::{UpdateManifest, UpdateUrgency};
+use embassy_time::Instant;
+use heapless::Deque;
+
+/// Number of decisions kept in a [`DecisionLog`]
+pub const MAX_LOGGED_DECISIONS: usize = 8;
+
+/// Check interval used by [`DefaultPolicy`] while a critical release is out
+pub const CRITICAL_CHECK_INTERVAL_SECS: u32 = 900;
+
+/// Circumstances an update decision is made in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PolicyContext {
+    /// Whether the device has nothing better to do
+    pub idle: bool,
+
+    /// Whether the user has asked to postpone updates
+    pub deferred_by_user: bool,
+
+    /// Whether now is a good time for disruptive work
+    pub in_maintenance_window: bool,
+}
+
+/// Outcome of asking an [`UpdatePolicy`] about a release
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyDecision {
+    /// Download and install now
+    Install,
+    /// Not now; the release is considered again later
+    Defer(DeferReason),
+}
+
+/// Why an update was put off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeferReason {
+    /// Waiting for a maintenance window
+    MaintenanceWindow,
+    /// Waiting for the device to become idle
+    Busy,
+    /// The user asked to postpone updates
+    UserDeferred,
+}
+
+/// Decides when available updates are installed
+pub trait UpdatePolicy {
+    /// Decide what to do about `manifest` right now
+    fn decide(&self, manifest: &UpdateManifest, context: &PolicyContext) -> PolicyDecision;
+
+    /// Seconds until the next check, given the configured interval and the
+    /// urgency of the release the last check found, if any
+    fn check_interval(&self, configured_secs: u32, _pending: Option<UpdateUrgency>) -> u32 {
+        configured_secs
+    }
+}
+
+/// Urgency-based policy described in the [module documentation](self)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultPolicy;
+
+impl UpdatePolicy for DefaultPolicy {
+    fn decide(&self, manifest: &UpdateManifest, context: &PolicyContext) -> PolicyDecision {
+        match manifest.urgency {
+            UpdateUrgency::Critical => PolicyDecision::Install,
+            _ if context.deferred_by_user => PolicyDecision::Defer(DeferReason::UserDeferred),
+            UpdateUrgency::High => PolicyDecision::Install,
+            UpdateUrgency::Normal if context.idle => PolicyDecision::Install,
+            UpdateUrgency::Normal => PolicyDecision::Defer(DeferReason::Busy),
+            UpdateUrgency::Low if context.in_maintenance_window => PolicyDecision::Install,
+            UpdateUrgency::Low => PolicyDecision::Defer(DeferReason::MaintenanceWindow),
+        }
+    }
+
+    fn check_interval(&self, configured_secs: u32, pending: Option<UpdateUrgency>) -> u32 {
+        match pending {
+            Some(UpdateUrgency::Critical) => configured_secs.min(CRITICAL_CHECK_INTERVAL_SECS),
+            _ => configured_secs,
+        }
+    }
+}
+
+/// One decision, as kept for diagnostics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecisionRecord {
+    /// Version of the release decided on
+    pub version: Version,
+
+    /// Urgency of that release
+    pub urgency: UpdateUrgency,
+
+    /// What was decided
+    pub decision: PolicyDecision,
+
+    /// Circumstances it was decided in
+    pub context: PolicyContext,
+
+    /// When it was decided
+    pub at: Instant,
+}
+
+/// The most recent policy decisions, oldest first
+///
+/// A decision repeating the previous one for the same release is not logged
+/// again, so a release waiting for a window does not push out older entries.
+#[derive(Debug, Clone, Default)]
+pub struct DecisionLog {
+    records: Deque<DecisionRecord, MAX_LOGGED_DECISIONS>,
+}
+
+impl DecisionLog {
+    /// Create an empty log
+    pub const fn new() -> Self {
+        Self { records: Deque::new() }
+    }
+
+    /// Log a decision, dropping the oldest one if the log is full
+    pub fn record(&mut self, record: DecisionRecord) {
+        let repeated = self.records.back().is_some_and(|last| {
+            last.version == record.version && last.decision == record.decision
+        });
+        if repeated {
+            return;
+        }
+        if self.records.is_full() {
+            self.records.pop_front();
+        }
+        // Cannot fail: room was made above
+        let _ = self.records.push_back(record);
+    }
+
+    /// Most recent decision, if any
+    pub fn latest(&self) -> Option<&DecisionRecord> {
+        self.records.back()
+    }
+
+    /// Logged decisions, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &DecisionRecord> {
+        self.records.iter()
+    }
+
+    /// Number of logged decisions
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Check whether nothing has been logged
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{RollbackInfo, Signature, SignatureAlgorithm};
+    use heapless::{String, Vec};
+
+    const IDLE: PolicyContext = PolicyContext {
+        idle: true,
+        deferred_by_user: false,
+        in_maintenance_window: false,
+    };
+    const BUSY: PolicyContext = PolicyContext { idle: false, ..IDLE };
+    const WINDOW: PolicyContext = PolicyContext {
+        in_maintenance_window: true,
+        ..BUSY
+    };
+    const POSTPONED: PolicyContext = PolicyContext {
+        deferred_by_user: true,
+        in_maintenance_window: true,
+        ..IDLE
+    };
+
+    const INSTALL: Option<PolicyDecision> = Some(PolicyDecision::Install);
+    const fn defer(reason: DeferReason) -> Option<PolicyDecision> {
+        Some(PolicyDecision::Defer(reason))
+    }
+
+    fn release(version: Version, min_version: Option<Version>, urgency: UpdateUrgency) -> UpdateManifest {
+        UpdateManifest {
+            manifest_version: 1,
+            version,
+            timestamp: 1_700_000_000,
+            description: String::new(),
+            min_version,
+            files: Vec::new(),
+            signature: Signature {
+                algorithm: SignatureAlgorithm::Ed25519,
+                key_id: [0; 8],
+                data: Vec::new(),
+            },
+            urgency,
+            rollback: RollbackInfo::default(),
+        }
+    }
+
+    /// What becomes of `release` on a device running `current`
+    ///
+    /// `None` when it is not offered at all: `OtaClient::check_update` only
+    /// hands the scheduler releases applicable to the running version.
+    fn outcome(current: Version, release: &UpdateManifest, context: &PolicyContext) -> Option<PolicyDecision> {
+        release
+            .is_applicable(&current)
+            .then(|| DefaultPolicy.decide(release, context))
+    }
+
+    #[test]
+    fn default_policy_decisions() {
+        use UpdateUrgency::*;
+        let v = Version::new;
+        #[rustfmt::skip]
+        let table = [
+            // Downgrades and the running version are never offered, however urgent
+            (v(2, 0, 0, 0), v(1, 9, 0, 0), None, Critical, IDLE, None),
+            (v(1, 0, 0, 0), v(1, 0, 0, 0), None, Critical, WINDOW, None),
+            (v(1, 0, 0, 7), v(1, 0, 0, 7), None, Normal, IDLE, None),
+            // A newer build of the same version is a newer release
+            (v(1, 0, 0, 7), v(1, 0, 0, 8), None, Normal, IDLE, INSTALL),
+            // Releases that need a newer running version are left for later
+            (v(1, 0, 0, 0), v(2, 0, 0, 0), Some(v(1, 5, 0, 0)), Critical, IDLE, None),
+            (v(1, 5, 0, 0), v(2, 0, 0, 0), Some(v(1, 5, 0, 0)), Critical, IDLE, INSTALL),
+            // Urgency against circumstances
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, Low, IDLE, defer(DeferReason::MaintenanceWindow)),
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, Low, WINDOW, INSTALL),
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, Low, POSTPONED, defer(DeferReason::UserDeferred)),
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, Normal, IDLE, INSTALL),
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, Normal, BUSY, defer(DeferReason::Busy)),
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, Normal, WINDOW, defer(DeferReason::Busy)),
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, Normal, POSTPONED, defer(DeferReason::UserDeferred)),
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, High, BUSY, INSTALL),
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, High, POSTPONED, defer(DeferReason::UserDeferred)),
+            // Critical releases go in whatever the user or the device is up to
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, Critical, BUSY, INSTALL),
+            (v(1, 0, 0, 0), v(1, 1, 0, 0), None, Critical, PolicyContext { idle: false, ..POSTPONED }, INSTALL),
+        ];
+
+        for (current, version, min_version, urgency, context, expected) in table {
+            let manifest = release(version, min_version, urgency);
+            assert_eq!(
+                outcome(current, &manifest, &context),
+                expected,
+                "{version:?} ({urgency:?}) on {current:?} in {context:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn critical_release_shortens_the_check_interval() {
+        let table = [
+            (3600, None, 3600),
+            (3600, Some(UpdateUrgency::Low), 3600),
+            (3600, Some(UpdateUrgency::High), 3600),
+            (3600, Some(UpdateUrgency::Critical), CRITICAL_CHECK_INTERVAL_SECS),
+            (600, Some(UpdateUrgency::Critical), 600),
+        ];
+
+        for (configured, pending, expected) in table {
+            assert_eq!(DefaultPolicy.check_interval(configured, pending), expected, "{pending:?}");
+        }
+    }
+
+    #[test]
+    fn log_skips_repeats_and_keeps_the_latest() {
+        let record = |minor: u8, decision: PolicyDecision| DecisionRecord {
+            version: Version::new(1, minor, 0, 0),
+            urgency: UpdateUrgency::Low,
+            decision,
+            context: IDLE,
+            at: Instant::from_secs(0),
+        };
+        let waiting = PolicyDecision::Defer(DeferReason::MaintenanceWindow);
+        let mut log = DecisionLog::new();
+
+        log.record(record(1, waiting));
+        log.record(record(1, waiting));
+        assert_eq!(log.len(), 1);
+        log.record(record(1, PolicyDecision::Install));
+        assert_eq!(log.len(), 2);
+
+        for minor in 2..12 {
+            log.record(record(minor, waiting));
+        }
+        assert_eq!(log.len(), MAX_LOGGED_DECISIONS);
+        assert_eq!(log.iter().next().unwrap().version, Version::new(1, 4, 0, 0));
+        assert_eq!(log.latest().unwrap().version, Version::new(1, 11, 0, 0));
+    }
+}
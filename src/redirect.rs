@@ -0,0 +1,298 @@
+//! Following HTTP redirects under the configured policy
+
+use crate::auth::RequestAuth;
+use crate::config::{RedirectPolicy, MAX_URL_LENGTH};
+use crate::error::{ConfigError, NetworkError, Result};
+use crate::http::{BodySink, Method, Request, ResponseHead};
+use crate::transport::OtaTransport;
+use crate::url::{Scheme, Url};
+use heapless::{String, Vec};
+
+/// Maximum number of headers on a request, including `Authorization`
+const MAX_HEADERS: usize = 8;
+
+/// Fetch `request`, following the redirects `policy` allows
+///
+/// Every hop is sent with the original method, headers and body, except
+/// that a 303, or a 301 or 302 answering a POST, is followed with a GET
+/// without the body and its `Content-*` headers (RFC 9110 §15.4). Only the
+/// final response reaches `sink`. `final_url` receives the URL that response came
+/// from, including when the request fails part-way through the chain.
+///
+/// With `auth`, every hop to the original origin is signed; hops to other
+/// origins (e.g. a CDN) are sent without the `Authorization` header.
+pub async fn fetch_following<T, K>(
+    transport: &mut T,
+    policy: &RedirectPolicy,
+    request: &Request<'_>,
+    mut auth: Option<&mut RequestAuth<'_>>,
+    sink: &mut K,
+    final_url: &mut String<MAX_URL_LENGTH>,
+) -> Result<ResponseHead>
+where
+    T: OtaTransport,
+    K: BodySink,
+{
+    let origin = Url::parse(request.url)?;
+    let mut current = origin.clone();
+    *final_url = String::try_from(request.url).map_err(|_| ConfigError::UrlTooLong)?;
+    
+    let mut method = request.method;
+    let mut body = request.body;
+    let mut redirects = 0;
+    loop {
+        let authorization = match auth.as_deref_mut() {
+            Some(auth) if origin.same_origin(&current) => {
+                Some(auth.authorize(method, &current.request_target()?)?)
+            }
+            _ => None,
+        };
+        let mut headers: Vec<(&str, &str), MAX_HEADERS> = Vec::new();
+        for header in request.headers {
+            // The body is gone once the method changed to GET
+            if method != request.method && is_content_header(header.0) {
+                continue;
+            }
+            headers
+                .push(*header)
+                .map_err(|_| NetworkError::RequestTooLarge)?;
+        }
+        if let Some(authorization) = &authorization {
+            headers
+                .push(("Authorization", authorization.as_str()))
+                .map_err(|_| NetworkError::RequestTooLarge)?;
+        }
+        
+        let hop = Request {
+            method,
+            url: final_url.as_str(),
+            headers: &headers,
+            body,
+        };
+        let head = transport.fetch(&hop, sink).await?;
+        if !head.is_redirect() {
+            return Ok(head);
+        }
+        
+        if redirects >= policy.max_redirects {
+            return Err(NetworkError::TooManyRedirects.into());
+        }
+        redirects += 1;
+        
+        let location = head.location.as_deref().ok_or(NetworkError::InvalidResponse)?;
+        let next = current
+            .resolve(location)
+            .map_err(|_| NetworkError::InvalidResponse)?;
+        check_hop(policy, &origin, &current, &next)?;
+        if switches_to_get(head.status, method) {
+            method = Method::Get;
+            body = &[];
+        }
+        
+        *final_url = next.render()?;
+        current = next;
+    }
+}
+
+/// Check whether a redirect with `status` is followed with a GET
+///
+/// 303 always is. For 301 and 302 the RFC allows it only for POST, which is
+/// what clients have always done.
+fn switches_to_get(status: u16, method: Method) -> bool {
+    match status {
+        303 => true,
+        301 | 302 => method == Method::Post,
+        _ => false,
+    }
+}
+
+/// Check whether a header describes the request body
+fn is_content_header(name: &str) -> bool {
+    name.get(..8)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("content-"))
+}
+
+/// Check a single redirect against the policy
+fn check_hop(policy: &RedirectPolicy, origin: &Url, current: &Url, next: &Url) -> Result<()> {
+    if current.scheme == Scheme::Https && next.scheme == Scheme::Http && !policy.allow_downgrade {
+        return Err(NetworkError::RedirectRejected.into());
+    }
+    if !origin.same_origin(next) && !policy.allow_cross_origin {
+        return Err(NetworkError::RedirectRejected.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::auth::RequestSigner;
+    use crate::http::Discard;
+    use crate::identity::DeviceKey;
+    use crate::testing::block_on;
+    use std::string::{String as StdString, ToString};
+    use std::vec::Vec as StdVec;
+
+    const REPORT: &str = "https://ota.example.com/fw/report";
+
+    /// A request as the server saw it
+    #[derive(Debug)]
+    struct Seen {
+        method: Method,
+        url: StdString,
+        headers: StdVec<(StdString, StdString)>,
+        body: StdVec<u8>,
+    }
+
+    impl Seen {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// Transport answering with the given statuses in turn, redirecting to
+    /// the given locations in turn, or else to `/next/<n>`
+    struct Hops {
+        statuses: StdVec<u16>,
+        locations: StdVec<&'static str>,
+        seen: StdVec<Seen>,
+    }
+
+    impl OtaTransport for Hops {
+        async fn fetch<K: BodySink>(&mut self, request: &Request<'_>, _sink: &mut K) -> Result<ResponseHead> {
+            self.seen.push(Seen {
+                method: request.method,
+                url: request.url.to_string(),
+                headers: request
+                    .headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                body: request.body.to_vec(),
+            });
+            let mut head = ResponseHead::new(self.statuses.remove(0));
+            if head.is_redirect() {
+                let location = if self.locations.is_empty() {
+                    std::format!("/next/{}", self.seen.len())
+                } else {
+                    self.locations.remove(0).to_string()
+                };
+                head.location = Some(String::try_from(location.as_str()).unwrap());
+            }
+            Ok(head)
+        }
+    }
+
+    fn follow(request: &Request<'_>, statuses: &[u16]) -> (Result<ResponseHead>, StdVec<Seen>) {
+        follow_signed(request, statuses, &[], &RedirectPolicy::default(), None)
+    }
+
+    fn follow_signed(
+        request: &Request<'_>,
+        statuses: &[u16],
+        locations: &[&'static str],
+        policy: &RedirectPolicy,
+        auth: Option<&mut RequestAuth<'_>>,
+    ) -> (Result<ResponseHead>, StdVec<Seen>) {
+        let mut transport = Hops {
+            statuses: statuses.to_vec(),
+            locations: locations.to_vec(),
+            seen: StdVec::new(),
+        };
+        let mut final_url = String::new();
+        let result = block_on(fetch_following(
+            &mut transport,
+            policy,
+            request,
+            auth,
+            &mut Discard,
+            &mut final_url,
+        ));
+        (result, transport.seen)
+    }
+
+    fn post() -> Request<'static> {
+        Request::post(REPORT, b"report")
+            .with_headers(&[("Content-Type", "application/octet-stream"), ("X-Fleet", "a")])
+    }
+
+    #[test]
+    fn see_other_switches_to_get() {
+        let (result, seen) = follow(&post(), &[303, 200]);
+        assert_eq!(result.unwrap().status, 200);
+        assert_eq!(seen[1].method, Method::Get);
+        assert_eq!(seen[1].url, "https://ota.example.com/next/1");
+        assert!(seen[1].body.is_empty());
+        assert_eq!(seen[1].header("Content-Type"), None);
+
+        // A GET stays a GET
+        let (_, seen) = follow(&Request::get(REPORT), &[303, 200]);
+        assert_eq!(seen[1].method, Method::Get);
+    }
+
+    #[test]
+    fn moved_post_switches_to_get() {
+        for status in [301, 302] {
+            let (_, seen) = follow(&post(), &[status, 200]);
+            assert_eq!(seen[1].method, Method::Get, "{}", status);
+            assert!(seen[1].body.is_empty());
+            assert_eq!(seen[1].header("Content-Type"), None);
+        }
+    }
+
+    #[test]
+    fn temporary_and_permanent_redirects_keep_method_and_body() {
+        for status in [307, 308] {
+            let (_, seen) = follow(&post(), &[status, status, 200]);
+            for hop in &seen {
+                assert_eq!(hop.method, Method::Post, "{}", status);
+                assert_eq!(hop.body, b"report");
+                assert_eq!(hop.header("Content-Type"), Some("application/octet-stream"));
+            }
+        }
+    }
+
+    #[test]
+    fn switched_request_stays_get() {
+        // Once the body is dropped, a later 307 has nothing to send again
+        let (_, seen) = follow(&post(), &[302, 307, 200]);
+        let methods: StdVec<Method> = seen.iter().map(|hop| hop.method).collect();
+        assert_eq!(methods, [Method::Post, Method::Get, Method::Get]);
+        assert!(seen[2].body.is_empty());
+    }
+
+    #[test]
+    fn other_headers_are_kept() {
+        let (_, seen) = follow(&post(), &[303, 200]);
+        assert_eq!(seen[1].header("X-Fleet"), Some("a"));
+    }
+
+    #[test]
+    fn authorization_stays_with_the_origin() {
+        let mut signer = RequestSigner::new(DeviceKey::new([9; 32]));
+        let mut auth = RequestAuth::new(&mut signer, "device-1", 1_700_000_000);
+        let downgrade = RedirectPolicy {
+            allow_downgrade: true,
+            ..RedirectPolicy::default()
+        };
+        let locations = [
+            "/fw/moved",
+            "https://cdn.example.com/fw/report",
+            "https://ota.example.com/fw/back",
+            "http://ota.example.com:443/fw/plain",
+        ];
+        let statuses = [302, 302, 302, 302, 200];
+        let (result, seen) = follow_signed(&Request::get(REPORT), &statuses, &locations, &downgrade, Some(&mut auth));
+
+        result.unwrap();
+        let signed: StdVec<bool> = seen.iter().map(|hop| hop.header("Authorization").is_some()).collect();
+        // Same scheme, host and port only: not the CDN, nor plain HTTP on the same port
+        assert_eq!(signed, [true, true, false, true, false]);
+        assert_eq!(seen[4].url, "http://ota.example.com:443/fw/plain");
+    }
+}
@@ -0,0 +1,154 @@
+//! Install-status reports sent back to the update server
+//!
+//! Reports are POSTed as postcard-encoded `(device_id, InstallReport)` tuples
+//! with `Content-Type: application/octet-stream`, the same encoding the server
+//! already uses for manifests. Any 2xx answer counts as delivered.
+//!
+//! Reports that cannot be sent are kept in a small queue in the state store
+//! and sent before anything else on the next successful connection.
+
+use crate::config::Version;
+use crate::error::Error;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of reports waiting to be sent; the oldest is dropped first
+pub const MAX_QUEUED_REPORTS: usize = 8;
+
+/// Maximum size of an encoded report on the wire
+pub const MAX_REPORT_SIZE: usize = 96;
+
+/// Media type of report bodies
+pub const REPORT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// What happened to a release on this device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InstallOutcome {
+    /// Written to the inactive partition and ready to boot
+    Installed,
+    /// Download, verification or installation failed
+    Failed,
+    /// The new firmware started
+    Booted,
+    /// The new firmware passed its self-test and was marked valid
+    Confirmed,
+    /// The new firmware was rolled back to the previous one
+    RolledBack,
+}
+
+/// Broad class of the error behind a failed install
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCategory {
+    Network,
+    Storage,
+    Verification,
+    Config,
+    Manifest,
+    Ota,
+}
+
+/// Status of one install, as reported to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstallReport {
+    /// Version running before the install
+    pub from_version: Version,
+
+    /// Version being installed
+    pub to_version: Version,
+
+    /// Outcome of the install
+    pub outcome: InstallOutcome,
+
+    /// Category of the error, for failed installs
+    pub error: Option<ErrorCategory>,
+
+    /// Download attempts made
+    pub attempts: u8,
+
+    /// Time from the start of the download to the outcome, in seconds
+    pub duration_secs: u32,
+}
+
+/// Reports waiting to be sent, oldest first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReportQueue {
+    reports: Vec<InstallReport, MAX_QUEUED_REPORTS>,
+}
+
+impl InstallReport {
+    /// Create a report of `outcome` for the move from one version to another
+    pub fn new(from_version: Version, to_version: Version, outcome: InstallOutcome) -> Self {
+        Self {
+            from_version,
+            to_version,
+            outcome,
+            error: None,
+            attempts: 1,
+            duration_secs: 0,
+        }
+    }
+
+    /// Record the error behind a failed install
+    pub fn with_error(mut self, error: &Error) -> Self {
+        self.error = Some(ErrorCategory::from(error));
+        self
+    }
+
+    /// Set the number of download attempts
+    pub fn with_attempts(mut self, attempts: u8) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// Set how long the install took, in seconds
+    pub fn with_duration(mut self, duration_secs: u32) -> Self {
+        self.duration_secs = duration_secs;
+        self
+    }
+}
+
+impl ReportQueue {
+    /// Add a report, dropping the oldest one if the queue is full
+    pub fn push(&mut self, report: InstallReport) {
+        if self.reports.is_full() {
+            self.reports.remove(0);
+        }
+        // Cannot fail: room was made above
+        let _ = self.reports.push(report);
+    }
+
+    /// Oldest report, if any
+    pub fn front(&self) -> Option<&InstallReport> {
+        self.reports.first()
+    }
+
+    /// Remove the oldest report
+    pub fn pop_front(&mut self) {
+        if !self.reports.is_empty() {
+            self.reports.remove(0);
+        }
+    }
+
+    /// Number of queued reports
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    /// Check whether no reports are queued
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+}
+
+impl From<&Error> for ErrorCategory {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::Network(_) => ErrorCategory::Network,
+            Error::Storage(_) => ErrorCategory::Storage,
+            Error::Verification(_) => ErrorCategory::Verification,
+            Error::Config(_) => ErrorCategory::Config,
+            Error::Manifest(_) => ErrorCategory::Manifest,
+            Error::Ota(_) => ErrorCategory::Ota,
+        }
+    }
+}
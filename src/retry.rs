@@ -0,0 +1,230 @@
+//! Retry and exponential backoff for network operations
+
+use crate::config::RetryConfig;
+use crate::error::{Error, NetworkError};
+use embassy_time::{Duration, Instant};
+
+/// Whether a failed operation is worth trying again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Give up immediately; retrying cannot help
+    Fatal,
+    /// Retry after the regular backoff delay
+    Backoff,
+    /// Retry no earlier than the delay requested by the server (in seconds)
+    After(u32),
+}
+
+/// Backoff state for one retried operation
+pub struct Backoff {
+    config: RetryConfig,
+    retries: u8,
+    next_delay_ms: u32,
+    jitter: Jitter,
+}
+
+/// Small xorshift generator used to spread retries across the fleet
+///
+/// Not cryptographically secure; it only needs to keep devices from retrying
+/// in lockstep.
+pub(crate) struct Jitter(u32);
+
+/// Classify an error as retryable or fatal
+pub fn classify(error: &Error) -> RetryDecision {
+    match error {
+        Error::Network(network) => match network {
+            NetworkError::ConnectionFailed
+            | NetworkError::ConnectionClosed
+            | NetworkError::DnsFailed
+            | NetworkError::TlsFailed
+            | NetworkError::Timeout(_)
+            | NetworkError::Truncated => RetryDecision::Backoff,
+            NetworkError::RetryAfter(seconds) => RetryDecision::After(*seconds),
+            NetworkError::HttpError(status) => match status {
+                408 | 429 | 500..=599 => RetryDecision::Backoff,
+                _ => RetryDecision::Fatal,
+            },
+            NetworkError::CertificateRejected
+            | NetworkError::ClientAuthRejected
+            | NetworkError::InvalidResponse
+            | NetworkError::RequestTooLarge
+            | NetworkError::ResponseTooLarge
+            | NetworkError::MethodNotSupported
+            | NetworkError::TooManyRedirects
+            | NetworkError::BrokerRejected(_)
+            | NetworkError::RedirectRejected => RetryDecision::Fatal,
+        },
+        // Integrity, storage and configuration failures will not fix themselves
+        _ => RetryDecision::Fatal,
+    }
+}
+
+impl Backoff {
+    /// Create backoff state from the client's retry configuration
+    ///
+    /// `seed` should differ between devices (e.g. derived from the device ID).
+    pub fn new(config: RetryConfig, seed: u32) -> Self {
+        let ticks = Instant::now().as_ticks();
+        Self {
+            config,
+            retries: 0,
+            next_delay_ms: config.initial_delay_ms,
+            jitter: Jitter::new(seed ^ ticks as u32 ^ (ticks >> 32) as u32),
+        }
+    }
+
+    /// Number of retries performed so far
+    pub fn retries(&self) -> u8 {
+        self.retries
+    }
+
+    /// Decide whether to retry after `error`, returning the delay to wait first
+    ///
+    /// Gives up when the attempts are used up, when the error is fatal, and
+    /// when the server asks to wait longer than `max_delay_ms`: an operation
+    /// is not held up that long, a later check can try again.
+    pub fn next_delay(&mut self, error: &Error) -> Option<Duration> {
+        if self.retries >= self.config.max_attempts {
+            return None;
+        }
+
+        let decision = classify(error);
+        if decision == RetryDecision::Fatal {
+            return None;
+        }
+
+        // Equal jitter: keep half of the delay, randomize the other half
+        let base = self.next_delay_ms;
+        let mut delay_ms = base / 2 + self.jitter.below(base / 2 + 1);
+
+        // Honour the server's Retry-After in full, or not at all
+        if let RetryDecision::After(seconds) = decision {
+            let requested = seconds.saturating_mul(1000);
+            if requested > self.config.max_delay_ms {
+                return None;
+            }
+            delay_ms = delay_ms.max(requested);
+        }
+
+        self.retries += 1;
+        let grown = base as f32 * self.config.backoff_multiplier;
+        self.next_delay_ms = if grown >= self.config.max_delay_ms as f32 {
+            self.config.max_delay_ms
+        } else {
+            grown as u32
+        };
+
+        Some(Duration::from_millis(delay_ms as u64))
+    }
+}
+
+impl Jitter {
+    pub(crate) fn new(seed: u32) -> Self {
+        // xorshift must not be seeded with zero
+        Self(if seed == 0 { 0x9E37_79B9 } else { seed })
+    }
+
+    pub(crate) fn next(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    /// Random value in `0..bound` (or 0 if `bound` is 0)
+    pub(crate) fn below(&mut self, bound: u32) -> u32 {
+        if bound == 0 {
+            return 0;
+        }
+        self.next() % bound
+    }
+}
+
+/// Derive a per-device jitter seed from a device identifier (FNV-1a)
+pub fn seed_from_id(id: &str) -> u32 {
+    id.bytes()
+        .fold(0x811C_9DC5u32, |hash, b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::error::TimeoutPhase;
+
+    const CONFIG: RetryConfig = RetryConfig {
+        max_attempts: 8,
+        initial_delay_ms: 1000,
+        max_delay_ms: 10_000,
+        backoff_multiplier: 2.0,
+    };
+
+    fn dropped() -> Error {
+        NetworkError::ConnectionClosed.into()
+    }
+
+    fn ms(delay: Option<Duration>) -> u64 {
+        delay.expect("retry").as_millis()
+    }
+
+    #[test]
+    fn delays_grow_up_to_the_cap() {
+        let mut backoff = Backoff::new(CONFIG, 1);
+        for base in [1000, 2000, 4000, 8000, 10_000, 10_000] {
+            // Equal jitter: between half of the delay and all of it
+            let delay = ms(backoff.next_delay(&dropped()));
+            assert!((base / 2..=base).contains(&delay), "{delay} for {base}");
+        }
+        assert_eq!(backoff.retries(), 6);
+    }
+
+    #[test]
+    fn jitter_spreads_the_delay() {
+        let delays: std::vec::Vec<u64> = (0..200)
+            .map(|seed| ms(Backoff::new(CONFIG, seed).next_delay(&dropped())))
+            .collect();
+        assert!(delays.iter().all(|delay| (500..=1000).contains(delay)));
+        assert!(delays.iter().any(|&delay| delay < 600));
+        assert!(delays.iter().any(|&delay| delay > 900));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut backoff = Backoff::new(RetryConfig { max_attempts: 2, ..CONFIG }, 1);
+        assert!(backoff.next_delay(&dropped()).is_some());
+        assert!(backoff.next_delay(&NetworkError::Timeout(TimeoutPhase::Stall).into()).is_some());
+        assert_eq!(backoff.next_delay(&dropped()), None);
+        assert_eq!(backoff.retries(), 2);
+
+        let mut backoff = Backoff::new(RetryConfig { max_attempts: 0, ..CONFIG }, 1);
+        assert_eq!(backoff.next_delay(&dropped()), None);
+    }
+
+    #[test]
+    fn fatal_errors_are_not_retried() {
+        let mut backoff = Backoff::new(CONFIG, 1);
+        assert_eq!(backoff.next_delay(&NetworkError::HttpError(404).into()), None);
+        assert_eq!(backoff.next_delay(&NetworkError::CertificateRejected.into()), None);
+        assert_eq!(backoff.retries(), 0);
+        assert!(backoff.next_delay(&NetworkError::HttpError(503).into()).is_some());
+    }
+
+    #[test]
+    fn retry_after_is_honoured_in_full_or_not_at_all() {
+        let mut backoff = Backoff::new(CONFIG, 1);
+        assert_eq!(ms(backoff.next_delay(&NetworkError::RetryAfter(7).into())), 7000);
+        assert_eq!(ms(backoff.next_delay(&NetworkError::RetryAfter(10).into())), 10_000);
+        // Shorter than the backoff: the backoff wins
+        let delay = ms(backoff.next_delay(&NetworkError::RetryAfter(0).into()));
+        assert!((2000..=4000).contains(&delay));
+        assert_eq!(backoff.retries(), 3);
+
+        // Longer than the cap: left to a later check
+        assert_eq!(backoff.next_delay(&NetworkError::RetryAfter(11).into()), None);
+        assert_eq!(backoff.next_delay(&NetworkError::RetryAfter(u32::MAX).into()), None);
+        assert_eq!(backoff.retries(), 3);
+    }
+}
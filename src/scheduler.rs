@@ -0,0 +1,649 @@
+//! Periodic update checks
+//!
+//! [`UpdateScheduler`] puts `OtaConfig::check_interval` and `auto_update` to
+//! work. Embassy tasks cannot be generic, so the application wraps it in a
+//! small task of its own:
+//!
+//! ```no_run
+//! use genesis::scheduler::{ScheduledCheck, SchedulerControl, UpdateScheduler};
+//!
+//! static CONTROL: SchedulerControl = SchedulerControl::new();
+//!
+//! #[embassy_executor::task]
+//! async fn ota_task(mut client: MyClient) {
+//!     let mut scheduler = UpdateScheduler::new(&CONTROL, client.config());
+//!     loop {
+//!         if let ScheduledCheck::Applied(_) = scheduler.next_check(&mut client).await {
+//!             software_reset();
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Other tasks use `CONTROL` to check right away (a button, a push notice),
+//! to hold checks off altogether, to say whether the device is busy, or to
+//! postpone updates on the user's behalf.
+//!
+//! With `auto_update` set, an available release is installed when the
+//! scheduler's [`UpdatePolicy`] allows it (by default [`DefaultPolicy`],
+//! going by urgency). Until then it is kept pending and considered again
+//! whenever the control state changes, and at least every
+//! [`PENDING_RECHECK_SECS`]. Decisions are logged in [`UpdateScheduler::decisions`].
+//!
+//! Outside the configured maintenance windows, a release the policy lets
+//! through is downloaded and staged but not installed; it stays pending,
+//! reported as deferred for [`DeferReason::MaintenanceWindow`], until a
+//! window opens. Releases the user defers through the client's consent
+//! handler wait the same way, for [`DeferReason::UserDeferred`], and an
+//! applied release is only returned once the user agrees to reboot.
+//!
+//! Every interval is stretched or shrunk at random by up to a tenth, so
+//! devices that powered up together drift apart instead of polling the server
+//! in step. The schedule is kept in the state store as Unix time, so after a
+//! reboot the next check comes when it was due anyway; this needs the
+//! client's clock to be set before the first call.
+
+use crate::client::{OtaClient, UpdateStatus};
+use crate::config::{OtaConfig, Version};
+use crate::consent::ConsentHandler;
+use crate::error::{Error, OtaError};
+use crate::install::FileHandler;
+use crate::manifest::{UpdateManifest, UpdateUrgency};
+use crate::policy::{
+    DecisionLog, DecisionRecord, DefaultPolicy, DeferReason, PolicyContext, PolicyDecision,
+    UpdatePolicy,
+};
+use crate::retry::{self, Jitter};
+use crate::state::{self, StateKey, StateStore};
+use crate::storage::UpdateStorage;
+use crate::transport::OtaTransport;
+
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_deadline, Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+/// Shortest interval between scheduled checks, whatever the configuration says
+pub const MIN_CHECK_INTERVAL_SECS: u32 = 60;
+
+/// Longest time a deferred release waits before the policy is asked again
+pub const PENDING_RECHECK_SECS: u64 = 60;
+
+/// What a scheduled check led to
+#[derive(Debug)]
+pub enum ScheduledCheck {
+    /// Nothing newer on the server
+    UpToDate,
+    /// An update is available and `auto_update` is off
+    Available(UpdateManifest),
+    /// An update is available but the policy put it off
+    Deferred(Version, DeferReason),
+    /// An update was downloaded and applied; reboot to run it
+    Applied(Version),
+    /// An update was applied, but the user would rather not reboot now
+    RebootDeclined(Version),
+    /// The user declined the update
+    Rejected(Version),
+    /// The check or the install failed
+    Failed(Error),
+}
+
+/// Persisted schedule, in Unix seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleRecord {
+    /// When the last check ran
+    pub last_check: u64,
+
+    /// When the next check is due
+    pub next_check: u64,
+}
+
+/// Handle for steering an [`UpdateScheduler`] from other tasks
+///
+/// Usually a `static` shared by the scheduler and whoever controls it.
+pub struct SchedulerControl {
+    state: Mutex<CriticalSectionRawMutex, Cell<ControlState>>,
+    wake: Signal<CriticalSectionRawMutex, ()>,
+}
+
+/// Requests made through a [`SchedulerControl`]
+#[derive(Debug, Clone, Copy)]
+struct ControlState {
+    paused: bool,
+    check_requested: bool,
+    busy: bool,
+    deferred_until: Option<Instant>,
+}
+
+/// Runs update checks every `check_interval`, with jitter
+pub struct UpdateScheduler<'c, U = DefaultPolicy> {
+    control: &'c SchedulerControl,
+    policy: U,
+    decisions: DecisionLog,
+    jitter: Jitter,
+    next_due: Option<Instant>,
+    /// Release found by the last check that the policy put off
+    pending: Option<UpdateManifest>,
+    /// Whether the pending release is staged and only waits for a window
+    held: bool,
+    /// Urgency of the release found by the last check
+    last_urgency: Option<UpdateUrgency>,
+}
+
+impl SchedulerControl {
+    /// Create a handle with checks running
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(Cell::new(ControlState {
+                paused: false,
+                check_requested: false,
+                busy: false,
+                deferred_until: None,
+            })),
+            wake: Signal::new(),
+        }
+    }
+
+    /// Check as soon as possible, even while paused
+    pub fn check_now(&self) {
+        self.update(|state| state.check_requested = true);
+    }
+
+    /// Hold off scheduled checks until [`Self::resume`]
+    ///
+    /// A check already under way runs to completion.
+    pub fn pause(&self) {
+        self.update(|state| state.paused = true);
+    }
+
+    /// Let scheduled checks run again
+    ///
+    /// A check that came due while paused runs right away.
+    pub fn resume(&self) {
+        self.update(|state| state.paused = false);
+    }
+
+    /// Check whether scheduled checks are paused
+    pub fn is_paused(&self) -> bool {
+        self.state.lock(|state| state.get().paused)
+    }
+
+    /// Tell the policy whether the device is busy with its actual job
+    pub fn set_busy(&self, busy: bool) {
+        self.update(|state| state.busy = busy);
+    }
+
+    /// Postpone updates for `duration` on the user's behalf
+    ///
+    /// Checks go on; the default policy still installs critical releases.
+    pub fn defer_updates(&self, duration: Duration) {
+        self.update(|state| state.deferred_until = Some(Instant::now() + duration));
+    }
+
+    /// Withdraw a postponement made with [`Self::defer_updates`]
+    pub fn cancel_deferral(&self) {
+        self.update(|state| state.deferred_until = None);
+    }
+
+    /// Circumstances for the policy, given whether a window is open
+    fn policy_context(&self, in_maintenance_window: bool) -> PolicyContext {
+        let state = self.state.lock(|state| state.get());
+        PolicyContext {
+            idle: !state.busy,
+            deferred_by_user: state.deferred_until.is_some_and(|until| Instant::now() < until),
+            in_maintenance_window,
+        }
+    }
+
+    /// Consume a pending "check now" request
+    fn take_check_request(&self) -> bool {
+        self.state.lock(|cell| {
+            let mut state = cell.get();
+            let requested = state.check_requested;
+            state.check_requested = false;
+            cell.set(state);
+            requested
+        })
+    }
+
+    /// Change the requests and wake the scheduler to look at them
+    fn update(&self, change: impl FnOnce(&mut ControlState)) {
+        self.state.lock(|cell| {
+            let mut state = cell.get();
+            change(&mut state);
+            cell.set(state);
+        });
+        self.wake.signal(());
+    }
+}
+
+impl Default for SchedulerControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'c> UpdateScheduler<'c> {
+    /// Create a scheduler for the client configured by `config`
+    pub fn new(control: &'c SchedulerControl, config: &OtaConfig) -> Self {
+        let ticks = Instant::now().as_ticks();
+        Self {
+            control,
+            policy: DefaultPolicy,
+            decisions: DecisionLog::new(),
+            jitter: Jitter::new(retry::seed_from_id(&config.device_id) ^ ticks as u32),
+            next_due: None,
+            pending: None,
+            held: false,
+            last_urgency: None,
+        }
+    }
+}
+
+impl<'c, U> UpdateScheduler<'c, U>
+where
+    U: UpdatePolicy,
+{
+    /// Decide when to install with `policy` instead of [`DefaultPolicy`]
+    pub fn with_policy<V: UpdatePolicy>(self, policy: V) -> UpdateScheduler<'c, V> {
+        UpdateScheduler {
+            control: self.control,
+            policy,
+            decisions: self.decisions,
+            jitter: self.jitter,
+            next_due: self.next_due,
+            pending: self.pending,
+            held: self.held,
+            last_urgency: self.last_urgency,
+        }
+    }
+
+    /// Recent policy decisions, for diagnostics
+    pub fn decisions(&self) -> &DecisionLog {
+        &self.decisions
+    }
+
+    /// Release waiting for the policy's go-ahead, if any
+    pub fn pending(&self) -> Option<&UpdateManifest> {
+        self.pending.as_ref()
+    }
+
+    /// Wait for the next check, run it and return what came of it
+    ///
+    /// With `auto_update` set, an available update is downloaded and applied
+    /// before returning if the policy allows it. A deferred release is
+    /// installed by a later call, as soon as the policy lets it through.
+    pub async fn next_check<S, T, P, H, C>(&mut self, client: &mut OtaClient<S, T, P, H, C>) -> ScheduledCheck
+    where
+        S: UpdateStorage,
+        T: OtaTransport,
+        P: StateStore,
+        H: FileHandler,
+        C: ConsentHandler,
+    {
+        loop {
+            if self.wait_until_due(client).await {
+                break;
+            }
+            // A staged release has nothing to do until a window opens
+            if self.held && !client.in_maintenance_window() {
+                continue;
+            }
+            // Woken to look at the pending release again
+            if let Some(manifest) = self.pending.take() {
+                if self.decide(client, &manifest) == PolicyDecision::Install {
+                    return self.install(client, manifest).await;
+                }
+                self.pending = Some(manifest);
+            }
+        }
+
+        let outcome = match client.check_update().await {
+            UpdateStatus::UpToDate => {
+                self.pending = None;
+                self.held = false;
+                self.last_urgency = None;
+                ScheduledCheck::UpToDate
+            }
+            UpdateStatus::CheckFailed(e) => ScheduledCheck::Failed(e),
+            UpdateStatus::Available(manifest) => {
+                self.last_urgency = Some(manifest.urgency);
+                self.pending = None;
+                self.held = false;
+                if !client.config().auto_update {
+                    ScheduledCheck::Available(manifest)
+                } else {
+                    match self.decide(client, &manifest) {
+                        PolicyDecision::Install => self.install(client, manifest).await,
+                        PolicyDecision::Defer(reason) => {
+                            let version = manifest.version;
+                            self.pending = Some(manifest);
+                            ScheduledCheck::Deferred(version, reason)
+                        }
+                    }
+                }
+            }
+        };
+
+        self.schedule_next(client).await;
+        outcome
+    }
+
+    /// Ask the policy about `manifest` and log the answer
+    fn decide<S, T, P, H, C>(&mut self, client: &OtaClient<S, T, P, H, C>, manifest: &UpdateManifest) -> PolicyDecision
+    where
+        S: UpdateStorage,
+        T: OtaTransport,
+        P: StateStore,
+        H: FileHandler,
+        C: ConsentHandler,
+    {
+        let context = self.control.policy_context(client.in_maintenance_window());
+        let decision = self.policy.decide(manifest, &context);
+        self.decisions.record(DecisionRecord {
+            version: manifest.version,
+            urgency: manifest.urgency,
+            decision,
+            context,
+            at: Instant::now(),
+        });
+        decision
+    }
+
+    /// Download and apply `manifest`, keeping it pending if it has to wait
+    /// for a maintenance window or the user
+    async fn install<S, T, P, H, C>(&mut self, client: &mut OtaClient<S, T, P, H, C>, manifest: UpdateManifest) -> ScheduledCheck
+    where
+        S: UpdateStorage,
+        T: OtaTransport,
+        P: StateStore,
+        H: FileHandler,
+        C: ConsentHandler,
+    {
+        let version = manifest.version;
+        match client.download_and_apply(manifest.clone()).await {
+            Ok(()) => {
+                self.held = false;
+                match client.await_reboot_consent().await {
+                    Ok(()) => ScheduledCheck::Applied(version),
+                    Err(_) => ScheduledCheck::RebootDeclined(version),
+                }
+            }
+            Err(Error::Ota(OtaError::OutsideMaintenanceWindow)) => {
+                self.pending = Some(manifest);
+                self.held = true;
+                ScheduledCheck::Deferred(version, DeferReason::MaintenanceWindow)
+            }
+            Err(Error::Ota(OtaError::ConsentDeferred)) => {
+                self.pending = Some(manifest);
+                self.held = false;
+                ScheduledCheck::Deferred(version, DeferReason::UserDeferred)
+            }
+            Err(Error::Ota(OtaError::UpdateRejected)) => {
+                self.held = false;
+                ScheduledCheck::Rejected(version)
+            }
+            Err(e) => {
+                self.held = false;
+                ScheduledCheck::Failed(e)
+            }
+        }
+    }
+
+    /// Sleep until a check is due or requested, staying asleep while paused
+    ///
+    /// Returns `false` instead when a pending release should be considered
+    /// again.
+    async fn wait_until_due<S, T, P, H, C>(&mut self, client: &mut OtaClient<S, T, P, H, C>) -> bool
+    where
+        S: UpdateStorage,
+        T: OtaTransport,
+        P: StateStore,
+        H: FileHandler,
+        C: ConsentHandler,
+    {
+        loop {
+            if self.control.take_check_request() {
+                return true;
+            }
+            if self.control.is_paused() {
+                self.control.wake.wait().await;
+                continue;
+            }
+
+            let due = match self.next_due {
+                Some(due) => due,
+                None => {
+                    let due = self.restore(client).await;
+                    self.next_due = Some(due);
+                    due
+                }
+            };
+            let recheck = Instant::now() + Duration::from_secs(PENDING_RECHECK_SECS);
+            let deadline = if self.pending.is_some() { due.min(recheck) } else { due };
+
+            let woken = with_deadline(deadline, self.control.wake.wait()).await.is_ok();
+            if !woken && Instant::now() >= due {
+                return true;
+            }
+            if self.pending.is_some() {
+                return false;
+            }
+        }
+    }
+
+    /// Work out when the first check after boot is due
+    async fn restore<S, T, P, H, C>(&mut self, client: &mut OtaClient<S, T, P, H, C>) -> Instant
+    where
+        S: UpdateStorage,
+        T: OtaTransport,
+        P: StateStore,
+        H: FileHandler,
+        C: ConsentHandler,
+    {
+        let interval = check_interval(client.config());
+        let record: Option<ScheduleRecord> = state::load_record(client.state_store(), StateKey::Schedule)
+            .await
+            .ok()
+            .flatten();
+
+        let delay = match (record, client.unix_time()) {
+            // Resume the schedule; a record from a clock that was far ahead
+            // never postpones the check by more than one interval
+            (Some(record), Some(now)) => record.next_check.saturating_sub(now).min(interval as u64),
+            // Checked before, but there is no telling when
+            (Some(_), None) => self.jittered(interval) as u64,
+            // Never checked: soon, but not all at once
+            (None, _) => self.jitter.below(interval / 10) as u64,
+        };
+        Instant::now() + Duration::from_secs(delay)
+    }
+
+    /// Plan the next check one jittered interval from now and persist it
+    async fn schedule_next<S, T, P, H, C>(&mut self, client: &mut OtaClient<S, T, P, H, C>)
+    where
+        S: UpdateStorage,
+        T: OtaTransport,
+        P: StateStore,
+        H: FileHandler,
+        C: ConsentHandler,
+    {
+        let interval = self
+            .policy
+            .check_interval(check_interval(client.config()), self.last_urgency)
+            .max(MIN_CHECK_INTERVAL_SECS);
+        let delay = self.jittered(interval) as u64;
+        self.next_due = Some(Instant::now() + Duration::from_secs(delay));
+
+        if let Some(now) = client.unix_time() {
+            let record = ScheduleRecord {
+                last_check: now,
+                next_check: now + delay,
+            };
+            // Losing the record only means the schedule starts over after a reboot
+            let _ = state::store_record(client.state_store(), StateKey::Schedule, &record).await;
+        }
+    }
+
+    /// `interval` seconds, give or take up to a tenth
+    fn jittered(&mut self, interval: u32) -> u32 {
+        let spread = interval / 10;
+        (interval - spread).saturating_add(self.jitter.below(2 * spread + 1))
+    }
+}
+
+/// Configured check interval in seconds, within sane bounds
+fn check_interval(config: &OtaConfig) -> u32 {
+    config.check_interval.max(MIN_CHECK_INTERVAL_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing::{block_on, join, RamState, RamStorage};
+    use crate::transport::mock::MockTransport;
+    use crate::verification::PublicKey;
+    use ed25519_dalek::SigningKey;
+    use embassy_time::{with_timeout, Timer};
+
+    const NOW: u64 = 1_800_000_000;
+    const INTERVAL: u32 = 3600;
+    /// Delays one jittered interval may come to
+    const JITTERED: core::ops::RangeInclusive<u64> = 3240..=3960;
+
+    type Client = OtaClient<RamStorage, MockTransport<'static>, RamState>;
+
+    fn client(state: RamState) -> Client {
+        let mut config = OtaConfig::new("https://ota.example.com/fw/").unwrap();
+        config.check_interval = INTERVAL;
+        let public_key =
+            PublicKey::ed25519_from_bytes(SigningKey::from_bytes(&[0x5A; 32]).verifying_key().as_bytes()).unwrap();
+        OtaClient::new(config, RamStorage::new(4096), public_key, MockTransport::new()).with_state_store(state)
+    }
+
+    fn saved(next_check: u64) -> RamState {
+        let mut state = RamState::default();
+        let record = ScheduleRecord {
+            last_check: NOW - 3000,
+            next_check,
+        };
+        block_on(state::store_record(&mut state, StateKey::Schedule, &record)).unwrap();
+        state
+    }
+
+    /// Seconds from boot until the first check, given the saved `state` and the time
+    fn restored_delay(state: RamState, now: Option<u64>) -> u64 {
+        let mut client = client(state);
+        if let Some(now) = now {
+            client.set_unix_time(now);
+        }
+        let control = SchedulerControl::new();
+        let mut scheduler = UpdateScheduler::new(&control, client.config());
+        let before = Instant::now();
+        (block_on(scheduler.restore(&mut client)) - before).as_secs()
+    }
+
+    #[test]
+    fn intervals_vary_by_up_to_a_tenth() {
+        let control = SchedulerControl::new();
+        let mut scheduler = UpdateScheduler::new(&control, client(RamState::default()).config());
+
+        let delays: std::vec::Vec<u64> = (0..1000).map(|_| scheduler.jittered(INTERVAL) as u64).collect();
+        assert!(delays.iter().all(|delay| JITTERED.contains(delay)));
+        // Both ends of the range are reached
+        assert!(delays.iter().any(|&delay| delay < 3300));
+        assert!(delays.iter().any(|&delay| delay > 3900));
+        // Too short to spread
+        assert_eq!(scheduler.jittered(9), 9);
+    }
+
+    #[test]
+    fn saved_schedule_is_resumed_after_a_restart() {
+        assert!((600..=601).contains(&restored_delay(saved(NOW + 600), Some(NOW))));
+        // Overdue: check at once
+        assert_eq!(restored_delay(saved(NOW - 60), Some(NOW)), 0);
+        // A clock that was far ahead postpones the check by one interval at most
+        assert!((3600..=3601).contains(&restored_delay(saved(NOW + 100_000), Some(NOW))));
+        // Without the time, the saved schedule cannot be placed
+        assert!(JITTERED.contains(&restored_delay(saved(NOW + 600), None)));
+        // Never checked: within a tenth of an interval
+        assert!(restored_delay(RamState::default(), Some(NOW)) <= 360);
+    }
+
+    #[test]
+    fn next_check_is_saved() {
+        let control = SchedulerControl::new();
+        let mut client = client(RamState::default());
+        let mut scheduler = UpdateScheduler::new(&control, client.config());
+
+        // Without the time there is nothing to save
+        block_on(scheduler.schedule_next(&mut client));
+        assert!(scheduler.next_due.is_some());
+        assert!(client.state_store().0[StateKey::Schedule as usize].is_none());
+
+        client.set_unix_time(NOW);
+        block_on(scheduler.schedule_next(&mut client));
+        let record: ScheduleRecord = block_on(state::load_record(client.state_store(), StateKey::Schedule))
+            .unwrap()
+            .unwrap();
+        assert!((NOW..=NOW + 1).contains(&record.last_check));
+        assert!(JITTERED.contains(&(record.next_check - record.last_check)));
+    }
+
+    #[test]
+    fn check_now_runs_a_check_even_while_paused() {
+        let control = SchedulerControl::new();
+        let mut client = client(RamState::default());
+        let mut scheduler = UpdateScheduler::new(&control, client.config());
+        scheduler.next_due = Some(Instant::now() + Duration::from_secs(3600));
+
+        control.pause();
+        control.check_now();
+        let outcome = block_on(with_timeout(Duration::from_secs(1), scheduler.next_check(&mut client)));
+
+        // The mock has no manifest to serve
+        assert!(matches!(outcome, Ok(ScheduledCheck::Failed(_))));
+        assert_eq!(client.transport().requests(), 1);
+        assert!(control.is_paused());
+        // The request was used up
+        assert!(!control.take_check_request());
+    }
+
+    #[test]
+    fn paused_checks_wait_for_resume() {
+        let control = SchedulerControl::new();
+        let mut client = client(RamState::default());
+        let mut scheduler = UpdateScheduler::new(&control, client.config());
+        scheduler.next_due = Some(Instant::now());
+
+        control.pause();
+        let waited = block_on(with_timeout(Duration::from_millis(100), scheduler.wait_until_due(&mut client)));
+        assert!(waited.is_err());
+
+        let resume = async {
+            Timer::after_millis(50).await;
+            control.resume();
+        };
+        let (due, ()) = block_on(join(scheduler.wait_until_due(&mut client), resume));
+        assert!(due);
+    }
+
+    #[test]
+    fn checks_come_when_due() {
+        let control = SchedulerControl::new();
+        let mut client = client(RamState::default());
+        let mut scheduler = UpdateScheduler::new(&control, client.config());
+
+        scheduler.next_due = Some(Instant::now() + Duration::from_millis(50));
+        let due = block_on(with_timeout(Duration::from_secs(1), scheduler.wait_until_due(&mut client)));
+        assert_eq!(due, Ok(true));
+
+        scheduler.next_due = Some(Instant::now() + Duration::from_secs(3600));
+        let due = block_on(with_timeout(Duration::from_millis(100), scheduler.wait_until_due(&mut client)));
+        assert!(due.is_err());
+    }
+}
@@ -0,0 +1,352 @@
+//! Setting the clock from an NTP server (SNTPv4, RFC 4330)
+//!
+//! One request, one reply: good to within the round trip, which is all the
+//! client needs for signed requests and maintenance windows.
+
+use crate::error::{NetworkError, Result, TimeoutPhase};
+use crate::time::TimeSource;
+
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+use embassy_time::{with_deadline, Duration, Instant};
+
+/// Port NTP servers listen on
+pub const NTP_PORT: u16 = 123;
+
+/// Size of an NTP packet without extensions
+const PACKET_SIZE: usize = 48;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Leap indicator 0, version 4, mode 3 (client)
+const CLIENT_REQUEST: u8 = 0x23;
+
+/// Mode of a server reply
+const MODE_SERVER: u8 = 4;
+
+/// Leap indicator of a server whose clock is not synchronized
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+
+/// Offsets of the originate and transmit timestamps in a packet
+const ORIGINATE_TIMESTAMP: usize = 24;
+const TRANSMIT_TIMESTAMP: usize = 40;
+
+/// Fetches the time from an NTP server
+pub struct SntpClient<'a> {
+    stack: Stack<'a>,
+    server: &'a str,
+    timeout: Duration,
+}
+
+impl<'a> SntpClient<'a> {
+    /// Create a client asking `server` (a host name or IP address)
+    pub fn new(stack: Stack<'a>, server: &'a str) -> Self {
+        Self {
+            stack,
+            server,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Give up on the server after `timeout` (5 seconds by default)
+    ///
+    /// The time runs from the name lookup to the reply; running out reports
+    /// the phase it ran out in.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send one request and wait for its reply, all within the timeout
+    async fn query(&self) -> Result<u64> {
+        let deadline = Instant::now() + self.timeout;
+        let mut rx_meta = [PacketMetadata::EMPTY; 2];
+        let mut tx_meta = [PacketMetadata::EMPTY; 1];
+        let mut rx = [0u8; PACKET_SIZE * 2];
+        let mut tx = [0u8; PACKET_SIZE];
+        let mut socket = UdpSocket::new(self.stack, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        socket
+            .bind(0)
+            .map_err(|_| NetworkError::ConnectionFailed)?;
+
+        // The transmit timestamp is only echoed back, so any unique value does
+        // to match the reply to this request
+        let nonce = Instant::now().as_ticks().to_be_bytes();
+        let mut net = Udp {
+            stack: self.stack,
+            socket,
+        };
+        exchange(&mut net, self.server, &nonce, deadline).await
+    }
+}
+
+/// What a query needs from the network stack
+///
+/// Lets the steps of a query, and the timeout phase each runs out in, be
+/// tried without a network.
+trait Datagrams {
+    /// Look up the address of `host`
+    async fn resolve(&mut self, host: &str) -> Result<IpAddress>;
+
+    /// Send `packet` to `to`
+    async fn send(&mut self, packet: &[u8], to: IpEndpoint) -> Result<()>;
+
+    /// Wait for a datagram, returning its length and sender
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<(usize, IpEndpoint)>;
+}
+
+/// The network stack and a bound socket
+struct Udp<'a> {
+    stack: Stack<'a>,
+    socket: UdpSocket<'a>,
+}
+
+impl Datagrams for Udp<'_> {
+    async fn resolve(&mut self, host: &str) -> Result<IpAddress> {
+        let addresses = self
+            .stack
+            .dns_query(host, DnsQueryType::A)
+            .await
+            .map_err(|_| NetworkError::DnsFailed)?;
+        Ok(*addresses.first().ok_or(NetworkError::DnsFailed)?)
+    }
+
+    async fn send(&mut self, packet: &[u8], to: IpEndpoint) -> Result<()> {
+        self.socket
+            .send_to(packet, to)
+            .await
+            .map_err(|_| NetworkError::ConnectionFailed.into())
+    }
+
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<(usize, IpEndpoint)> {
+        let (len, from) = self
+            .socket
+            .recv_from(buf)
+            .await
+            .map_err(|_| NetworkError::InvalidResponse)?;
+        Ok((len, from.endpoint))
+    }
+}
+
+/// Look up `server`, send it a request carrying `nonce` and wait for the
+/// reply, all before `deadline`
+///
+/// Running out of time reports the phase it ran out in.
+async fn exchange<N: Datagrams>(net: &mut N, server: &str, nonce: &[u8; 8], deadline: Instant) -> Result<u64> {
+    let address = with_deadline(deadline, net.resolve(server))
+        .await
+        .map_err(|_| NetworkError::Timeout(TimeoutPhase::Dns))??;
+    let server = IpEndpoint::new(address, NTP_PORT);
+
+    let mut request = [0u8; PACKET_SIZE];
+    request[0] = CLIENT_REQUEST;
+    request[TRANSMIT_TIMESTAMP..].copy_from_slice(nonce);
+    // There is no connection over UDP; getting the request out stands in for it
+    with_deadline(deadline, net.send(&request, server))
+        .await
+        .map_err(|_| NetworkError::Timeout(TimeoutPhase::Connect))??;
+
+    with_deadline(deadline, receive_reply(net, server, nonce))
+        .await
+        .map_err(|_| NetworkError::Timeout(TimeoutPhase::FirstByte))?
+}
+
+/// Wait for the reply to the request carrying `nonce`
+async fn receive_reply<N: Datagrams>(net: &mut N, server: IpEndpoint, nonce: &[u8; 8]) -> Result<u64> {
+    let mut reply = [0u8; PACKET_SIZE];
+    loop {
+        // Oversized datagrams are not NTP replies; keep listening
+        let Ok((len, from)) = net.receive(&mut reply).await else {
+            continue;
+        };
+        // Stray packets are ignored; the deadline bounds the wait
+        if from != server || len < PACKET_SIZE {
+            continue;
+        }
+        if reply[ORIGINATE_TIMESTAMP..ORIGINATE_TIMESTAMP + 8] != *nonce {
+            continue;
+        }
+        return parse_reply(&reply);
+    }
+}
+
+impl TimeSource for SntpClient<'_> {
+    async fn unix_time(&mut self) -> Result<u64> {
+        self.query().await
+    }
+}
+
+/// Unix time in a server reply
+fn parse_reply(reply: &[u8; PACKET_SIZE]) -> Result<u64> {
+    let leap = reply[0] >> 6;
+    let mode = reply[0] & 0x07;
+    let stratum = reply[1];
+    // Stratum 0 is a "kiss-o'-death" telling the client to go away
+    if mode != MODE_SERVER || stratum == 0 || leap == LEAP_UNSYNCHRONIZED {
+        return Err(NetworkError::InvalidResponse.into());
+    }
+
+    let mut seconds = [0u8; 4];
+    seconds.copy_from_slice(&reply[TRANSMIT_TIMESTAMP..TRANSMIT_TIMESTAMP + 4]);
+    let mut seconds = u32::from_be_bytes(seconds) as u64;
+    // Timestamps wrap in 2036; anything before 1970 is in the next era
+    if seconds < NTP_UNIX_OFFSET {
+        seconds += 1 << 32;
+    }
+    Ok(seconds - NTP_UNIX_OFFSET)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing::block_on;
+    use embassy_net::Ipv4Address;
+    use std::vec::Vec as StdVec;
+
+    const NONCE: [u8; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+    /// 2024-01-01 00:00:00 UTC
+    const UNIX_TIME: u64 = 1_704_067_200;
+
+    fn endpoint(last: u8, port: u16) -> IpEndpoint {
+        IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::new(192, 0, 2, last)), port)
+    }
+
+    /// Reply from a synchronized stratum 2 server to the request carrying `nonce`
+    fn reply(nonce: &[u8; 8]) -> [u8; PACKET_SIZE] {
+        let mut reply = [0u8; PACKET_SIZE];
+        reply[0] = 0x24;
+        reply[1] = 2;
+        reply[ORIGINATE_TIMESTAMP..ORIGINATE_TIMESTAMP + 8].copy_from_slice(nonce);
+        let seconds = (UNIX_TIME + NTP_UNIX_OFFSET) as u32;
+        reply[TRANSMIT_TIMESTAMP..TRANSMIT_TIMESTAMP + 4].copy_from_slice(&seconds.to_be_bytes());
+        reply
+    }
+
+    /// Network answering from `inbox` in turn, or never in the phase `stall`
+    struct Net {
+        stall: Option<TimeoutPhase>,
+        inbox: StdVec<(StdVec<u8>, IpEndpoint)>,
+        sent: StdVec<(StdVec<u8>, IpEndpoint)>,
+    }
+
+    impl Net {
+        fn new(inbox: &[(&[u8], IpEndpoint)]) -> Self {
+            Self {
+                stall: None,
+                inbox: inbox.iter().map(|(data, from)| (data.to_vec(), *from)).collect(),
+                sent: StdVec::new(),
+            }
+        }
+
+        fn stalling(phase: TimeoutPhase) -> Self {
+            Self {
+                stall: Some(phase),
+                ..Self::new(&[])
+            }
+        }
+
+        async fn stall_in(&self, phase: TimeoutPhase) {
+            if self.stall == Some(phase) {
+                core::future::pending::<()>().await;
+            }
+        }
+    }
+
+    impl Datagrams for Net {
+        async fn resolve(&mut self, host: &str) -> Result<IpAddress> {
+            self.stall_in(TimeoutPhase::Dns).await;
+            match host {
+                "pool.ntp.org" => Ok(endpoint(1, NTP_PORT).addr),
+                _ => Err(NetworkError::DnsFailed.into()),
+            }
+        }
+
+        async fn send(&mut self, packet: &[u8], to: IpEndpoint) -> Result<()> {
+            self.stall_in(TimeoutPhase::Connect).await;
+            self.sent.push((packet.to_vec(), to));
+            Ok(())
+        }
+
+        async fn receive(&mut self, buf: &mut [u8]) -> Result<(usize, IpEndpoint)> {
+            self.stall_in(TimeoutPhase::FirstByte).await;
+            if self.inbox.is_empty() {
+                core::future::pending::<()>().await;
+            }
+            let (data, from) = self.inbox.remove(0);
+            if data.len() > buf.len() {
+                return Err(NetworkError::InvalidResponse.into());
+            }
+            buf[..data.len()].copy_from_slice(&data);
+            Ok((data.len(), from))
+        }
+    }
+
+    fn run(net: &mut Net) -> Result<u64> {
+        let deadline = Instant::now() + Duration::from_millis(50);
+        block_on(exchange(net, "pool.ntp.org", &NONCE, deadline))
+    }
+
+    #[test]
+    fn reply_to_the_request_sets_the_time() {
+        let mut net = Net::new(&[(&reply(&NONCE), endpoint(1, NTP_PORT))]);
+        assert_eq!(run(&mut net), Ok(UNIX_TIME));
+
+        let (request, to) = &net.sent[0];
+        assert_eq!(*to, endpoint(1, NTP_PORT));
+        assert_eq!(request.len(), PACKET_SIZE);
+        assert_eq!(request[0], CLIENT_REQUEST);
+        assert_eq!(request[TRANSMIT_TIMESTAMP..], NONCE);
+    }
+
+    #[test]
+    fn stray_packets_are_ignored() {
+        let oversized = [0u8; PACKET_SIZE + 1];
+        let mut net = Net::new(&[
+            (&reply(&NONCE), endpoint(9, NTP_PORT)),
+            (&reply(&NONCE), endpoint(1, 4000)),
+            (&reply(&[0; 8]), endpoint(1, NTP_PORT)),
+            (&reply(&NONCE)[..40], endpoint(1, NTP_PORT)),
+            (&oversized, endpoint(1, NTP_PORT)),
+            (&reply(&NONCE), endpoint(1, NTP_PORT)),
+        ]);
+        assert_eq!(run(&mut net), Ok(UNIX_TIME));
+        assert!(net.inbox.is_empty());
+    }
+
+    #[test]
+    fn unsynchronized_server_is_rejected() {
+        let mut unsynchronized = reply(&NONCE);
+        unsynchronized[0] |= LEAP_UNSYNCHRONIZED << 6;
+        let mut kiss_of_death = reply(&NONCE);
+        kiss_of_death[1] = 0;
+        for reply in [unsynchronized, kiss_of_death] {
+            let mut net = Net::new(&[(&reply, endpoint(1, NTP_PORT))]);
+            assert_eq!(run(&mut net), Err(NetworkError::InvalidResponse.into()));
+        }
+    }
+
+    #[test]
+    fn timeouts_report_their_phase() {
+        for phase in [TimeoutPhase::Dns, TimeoutPhase::Connect, TimeoutPhase::FirstByte] {
+            let mut net = Net::stalling(phase);
+            assert_eq!(run(&mut net), Err(NetworkError::Timeout(phase).into()), "{phase:?}");
+        }
+        // A server that never answers runs out waiting for the reply
+        let mut net = Net::new(&[(&reply(&[0; 8]), endpoint(1, NTP_PORT))]);
+        assert_eq!(run(&mut net), Err(NetworkError::Timeout(TimeoutPhase::FirstByte).into()));
+        assert_eq!(net.sent.len(), 1);
+    }
+
+    #[test]
+    fn unknown_server_fails_lookup() {
+        let mut net = Net::new(&[]);
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let result = block_on(exchange(&mut net, "nowhere.invalid", &NONCE, deadline));
+        assert_eq!(result, Err(NetworkError::DnsFailed.into()));
+        assert!(net.sent.is_empty());
+    }
+}
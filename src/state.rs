@@ -0,0 +1,187 @@
+//! Persistent client state (download checkpoints and similar small records)
+
+use crate::error::{Result, StorageError};
+use embedded_storage_async::nor_flash::NorFlash;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Maximum serialized size of a single state record
+pub const MAX_RECORD_SIZE: usize = 256;
+
+/// Marker written in front of every valid record
+const RECORD_MAGIC: u32 = 0x4753_5431; // "GST1"
+
+/// Size of the record header (magic + length, padded to flash word size)
+const HEADER_SIZE: usize = 8;
+
+/// Keys identifying the records kept by the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateKey {
+    /// Checkpoint of an interrupted firmware download
+    DownloadCheckpoint,
+    /// Validators of the last manifest, for conditional polling
+    ManifestCache,
+    /// Install-status reports not yet delivered to the server
+    ReportQueue,
+    /// When the scheduler last checked and will check next
+    Schedule,
+    /// The user's answers about the latest release
+    Consent,
+}
+
+/// Small key/value store for state that must survive reboots
+pub trait StateStore {
+    /// Load a record into `buffer`, returning its length if present
+    async fn load(&mut self, key: StateKey, buffer: &mut [u8]) -> Result<Option<usize>>;
+
+    /// Store a record, replacing any previous value
+    async fn store(&mut self, key: StateKey, data: &[u8]) -> Result<()>;
+
+    /// Remove a record
+    async fn remove(&mut self, key: StateKey) -> Result<()>;
+}
+
+/// Load and deserialize a record
+pub async fn load_record<P, T>(store: &mut P, key: StateKey) -> Result<Option<T>>
+where
+    P: StateStore,
+    T: DeserializeOwned,
+{
+    let mut buffer = [0u8; MAX_RECORD_SIZE];
+    match store.load(key, &mut buffer).await? {
+        // A record that no longer deserializes is treated as missing
+        Some(len) => Ok(postcard::from_bytes(&buffer[..len]).ok()),
+        None => Ok(None),
+    }
+}
+
+/// Serialize and store a record
+pub async fn store_record<P, T>(store: &mut P, key: StateKey, value: &T) -> Result<()>
+where
+    P: StateStore,
+    T: Serialize,
+{
+    let mut buffer = [0u8; MAX_RECORD_SIZE];
+    let data = postcard::to_slice(value, &mut buffer)
+        .map_err(|_| StorageError::InsufficientSpace)?;
+    store.store(key, data).await
+}
+
+/// State store that keeps nothing
+///
+/// Used when the application does not provide persistent storage; features
+/// that depend on persisted state simply start from scratch on every attempt.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoStateStore;
+
+impl StateStore for NoStateStore {
+    async fn load(&mut self, _key: StateKey, _buffer: &mut [u8]) -> Result<Option<usize>> {
+        Ok(None)
+    }
+
+    async fn store(&mut self, _key: StateKey, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    async fn remove(&mut self, _key: StateKey) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// State store backed by a reserved flash region
+///
+/// Each key occupies one erase block starting at `offset`. A record is written
+/// payload first and header last, so an interrupted write leaves no valid record.
+pub struct FlashStateStore<F> {
+    flash: F,
+    offset: u32,
+}
+
+impl<F> FlashStateStore<F>
+where
+    F: NorFlash,
+{
+    /// Create a store using the flash region starting at `offset`
+    pub fn new(flash: F, offset: u32) -> Self {
+        Self { flash, offset }
+    }
+
+    /// Offset of the erase block holding `key`
+    fn slot_offset(&self, key: StateKey) -> u32 {
+        self.offset + key as u32 * F::ERASE_SIZE as u32
+    }
+}
+
+impl<F> StateStore for FlashStateStore<F>
+where
+    F: NorFlash,
+{
+    async fn load(&mut self, key: StateKey, buffer: &mut [u8]) -> Result<Option<usize>> {
+        let slot = self.slot_offset(key);
+
+        let mut header = [0u8; HEADER_SIZE];
+        self.flash
+            .read(slot, &mut header)
+            .await
+            .map_err(|_| StorageError::ReadFailed)?;
+
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+        if magic != RECORD_MAGIC || len > MAX_RECORD_SIZE || len > buffer.len() {
+            return Ok(None);
+        }
+
+        // Reads must cover whole flash words
+        let mut record = [0u8; MAX_RECORD_SIZE];
+        let padded = len.next_multiple_of(F::READ_SIZE).min(MAX_RECORD_SIZE);
+        self.flash
+            .read(slot + HEADER_SIZE as u32, &mut record[..padded])
+            .await
+            .map_err(|_| StorageError::ReadFailed)?;
+        buffer[..len].copy_from_slice(&record[..len]);
+
+        Ok(Some(len))
+    }
+
+    async fn store(&mut self, key: StateKey, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_RECORD_SIZE {
+            return Err(StorageError::InsufficientSpace.into());
+        }
+
+        let slot = self.slot_offset(key);
+        self.flash
+            .erase(slot, slot + F::ERASE_SIZE as u32)
+            .await
+            .map_err(|_| StorageError::EraseFailed)?;
+
+        // Payload first, padded to the flash write granularity
+        let mut record = [0xFFu8; MAX_RECORD_SIZE];
+        record[..data.len()].copy_from_slice(data);
+        let padded = data.len().next_multiple_of(F::WRITE_SIZE).min(MAX_RECORD_SIZE);
+        if padded > 0 {
+            self.flash
+                .write(slot + HEADER_SIZE as u32, &record[..padded])
+                .await
+                .map_err(|_| StorageError::WriteFailed)?;
+        }
+
+        // Header last commits the record
+        let mut header = [0xFFu8; HEADER_SIZE];
+        header[..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        self.flash
+            .write(slot, &header)
+            .await
+            .map_err(|_| StorageError::WriteFailed)?;
+
+        Ok(())
+    }
+
+    async fn remove(&mut self, key: StateKey) -> Result<()> {
+        let slot = self.slot_offset(key);
+        self.flash
+            .erase(slot, slot + F::ERASE_SIZE as u32)
+            .await
+            .map_err(|_| StorageError::EraseFailed)?;
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 //! Storage abstraction for OTA updates
 
+use crate::config::MAX_URL_LENGTH;
 use crate::error::{Result, StorageError};
 use esp_storage::FlashStorage;
+use heapless::String;
 
 /// Storage trait for OTA operations
 pub trait UpdateStorage {
@@ -119,7 +121,7 @@ impl UpdateStorage for Esp32C3Storage {
 }
 
 /// Update progress tracking
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct UpdateProgress {
     /// Total bytes to download/write
     pub total_bytes: u32,
@@ -129,6 +131,30 @@ pub struct UpdateProgress {
     
     /// Current operation
     pub operation: UpdateOperation,
+    
+    /// Attempt number of the current operation (1 for the first try)
+    pub attempt: u8,
+    
+    /// Total number of retries performed during this update
+    pub retries: u16,
+    
+    /// URL the last response was served from, after following redirects
+    pub final_url: Option<String<MAX_URL_LENGTH>>,
+    
+    /// Position of the file being processed within the release (0-based)
+    pub file_index: u8,
+    
+    /// Number of files in the release
+    pub file_count: u8,
+    
+    /// Size of the file being processed
+    pub file_total_bytes: u32,
+    
+    /// Bytes of the file being processed completed so far
+    pub file_completed_bytes: u32,
+    
+    /// Bytes of the files before the current one
+    earlier_bytes: u32,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -143,6 +169,8 @@ pub enum UpdateOperation {
     Writing,
     /// Finalizing update
     Finalizing,
+    /// Staged and verified, waiting for a maintenance window to install
+    AwaitingWindow,
     /// Update complete
     Complete,
 }
@@ -154,15 +182,65 @@ impl UpdateProgress {
             total_bytes,
             completed_bytes: 0,
             operation: UpdateOperation::Checking,
+            attempt: 1,
+            retries: 0,
+            final_url: None,
+            file_index: 0,
+            file_count: 0,
+            file_total_bytes: 0,
+            file_completed_bytes: 0,
+            earlier_bytes: 0,
         }
     }
     
+    /// Track a release of `file_count` files
+    pub fn with_file_count(mut self, file_count: u8) -> Self {
+        self.file_count = file_count;
+        self
+    }
+    
     /// Update progress
     pub fn update(&mut self, bytes: u32, operation: UpdateOperation) {
         self.completed_bytes = bytes;
         self.operation = operation;
     }
     
+    /// Start on file `index` of `size` bytes, `offset` bytes into the release
+    pub fn start_file(&mut self, index: u8, offset: u32, size: u32) {
+        self.file_index = index;
+        self.file_total_bytes = size;
+        self.file_completed_bytes = 0;
+        self.earlier_bytes = offset;
+        self.completed_bytes = offset;
+    }
+    
+    /// Update progress within the current file
+    pub fn update_file(&mut self, bytes: u32) {
+        self.file_completed_bytes = bytes;
+        self.completed_bytes = self.earlier_bytes.saturating_add(bytes);
+    }
+    
+    /// Get progress of the current file as a percentage (0-100)
+    pub fn file_percentage(&self) -> u8 {
+        if self.file_total_bytes == 0 {
+            return 0;
+        }
+        ((self.file_completed_bytes as u64 * 100) / self.file_total_bytes as u64) as u8
+    }
+    
+    /// Record the start of another attempt at the current operation
+    pub fn record_attempt(&mut self, attempt: u8) {
+        if attempt > 1 {
+            self.retries = self.retries.saturating_add(1);
+        }
+        self.attempt = attempt;
+    }
+    
+    /// Record the URL a response was finally served from
+    pub fn set_final_url(&mut self, url: &str) {
+        self.final_url = String::try_from(url).ok();
+    }
+    
     /// Get progress percentage (0-100)
     pub fn percentage(&self) -> u8 {
         if self.total_bytes == 0 {
@@ -0,0 +1,221 @@
+//! Helpers for running async code in host tests
+//!
+//! Tests drive the client and a stand-in for the other end (server, broker,
+//! sender) on one thread, joined into a single future. Stream transports talk
+//! to their stand-in through a pair of [`Pipe`]s. Flash and the state store
+//! are stood in for by [`RamStorage`] and [`RamState`].
+
+extern crate std;
+
+use crate::error;
+use crate::state::{StateKey, StateStore};
+use crate::storage::UpdateStorage;
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::task::Wake;
+use std::thread::{self, Thread};
+use std::vec;
+use std::vec::Vec;
+
+/// Waker unparking the thread blocked in [`block_on`]
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` to completion on the current thread
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Arc::new(ThreadWaker(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+/// Run two futures concurrently until both are done
+pub async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+    let mut a_out = None;
+    let mut b_out = None;
+    poll_fn(|cx| {
+        if a_out.is_none() {
+            a_out = match a.as_mut().poll(cx) {
+                Poll::Ready(out) => Some(out),
+                Poll::Pending => None,
+            };
+        }
+        if b_out.is_none() {
+            b_out = match b.as_mut().poll(cx) {
+                Poll::Ready(out) => Some(out),
+                Poll::Pending => None,
+            };
+        }
+        if a_out.is_some() && b_out.is_some() {
+            Poll::Ready((a_out.take().unwrap(), b_out.take().unwrap()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// One direction of an in-memory byte stream
+#[derive(Default)]
+pub struct Pipe {
+    bytes: RefCell<VecDeque<u8>>,
+    waker: RefCell<Option<Waker>>,
+    closed: Cell<bool>,
+}
+
+/// One end of an in-memory connection, reading from `rx` and writing to `tx`
+pub struct End<'a> {
+    pub rx: &'a Pipe,
+    pub tx: &'a Pipe,
+}
+
+impl Pipe {
+    /// Append `data` for the reader
+    pub fn push(&self, data: &[u8]) {
+        self.bytes.borrow_mut().extend(data);
+        self.wake();
+    }
+
+    /// Let the reader see the end of the stream once the data is used up
+    pub fn close(&self) {
+        self.closed.set(true);
+        self.wake();
+    }
+
+    /// Read what is available into `buf`, waiting for at least one byte
+    ///
+    /// Returns 0 at the end of a closed stream.
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        poll_fn(|cx| {
+            let mut bytes = self.bytes.borrow_mut();
+            if bytes.is_empty() {
+                if self.closed.get() {
+                    return Poll::Ready(0);
+                }
+                *self.waker.borrow_mut() = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let n = buf.len().min(bytes.len());
+            for (slot, byte) in buf.iter_mut().zip(bytes.drain(..n)) {
+                *slot = byte;
+            }
+            Poll::Ready(n)
+        })
+        .await
+    }
+
+    /// Fill `buf` completely
+    pub async fn read_exact(&self, buf: &mut [u8]) {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = self.read(&mut buf[filled..]).await;
+            assert!(n > 0, "stream closed");
+            filled += n;
+        }
+    }
+
+    /// Bytes written but not read yet
+    pub fn pending(&self) -> Vec<u8> {
+        self.bytes.borrow().iter().copied().collect()
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.borrow_mut().take() {
+            waker.wake();
+        }
+    }
+}
+
+impl embedded_io_async::ErrorType for End<'_> {
+    type Error = Infallible;
+}
+
+impl embedded_io_async::Read for End<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        Ok(self.rx.read(buf).await)
+    }
+}
+
+impl embedded_io_async::Write for End<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        self.tx.push(buf);
+        Ok(buf.len())
+    }
+}
+
+/// Update storage held in RAM, erased to 0xFF
+pub struct RamStorage(pub Vec<u8>);
+
+impl RamStorage {
+    /// Create `size` bytes of erased storage
+    pub fn new(size: usize) -> Self {
+        Self(vec![0xFF; size])
+    }
+}
+
+impl UpdateStorage for RamStorage {
+    async fn read(&mut self, offset: u32, buffer: &mut [u8]) -> error::Result<()> {
+        let offset = offset as usize;
+        buffer.copy_from_slice(&self.0[offset..offset + buffer.len()]);
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, data: &[u8]) -> error::Result<()> {
+        let offset = offset as usize;
+        self.0[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    async fn erase(&mut self, offset: u32, length: u32) -> error::Result<()> {
+        self.0[offset as usize..(offset + length) as usize].fill(0xFF);
+        Ok(())
+    }
+
+    fn capacity(&self) -> u32 {
+        self.0.len() as u32
+    }
+
+    fn erase_size(&self) -> u32 {
+        4096
+    }
+}
+
+/// State store keeping records in RAM
+#[derive(Default)]
+pub struct RamState(pub [Option<Vec<u8>>; 5]);
+
+impl StateStore for RamState {
+    async fn load(&mut self, key: StateKey, buffer: &mut [u8]) -> error::Result<Option<usize>> {
+        Ok(self.0[key as usize].as_ref().map(|record| {
+            buffer[..record.len()].copy_from_slice(record);
+            record.len()
+        }))
+    }
+
+    async fn store(&mut self, key: StateKey, data: &[u8]) -> error::Result<()> {
+        self.0[key as usize] = Some(data.to_vec());
+        Ok(())
+    }
+
+    async fn remove(&mut self, key: StateKey) -> error::Result<()> {
+        self.0[key as usize] = None;
+        Ok(())
+    }
+}
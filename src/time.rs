@@ -0,0 +1,158 @@
+//! Wall-clock time for timestamps the server checks and maintenance windows
+
+use crate::error::Result;
+use embassy_time::Instant;
+
+/// Seconds in a day
+const SECS_PER_DAY: u64 = 86_400;
+
+/// Where the current time can be fetched from (e.g. an SNTP server)
+pub trait TimeSource {
+    /// Fetch the current Unix time in seconds
+    async fn unix_time(&mut self) -> Result<u64>;
+}
+
+/// Unix time anchored to the monotonic clock
+///
+/// The device has no battery-backed clock, so the time is unknown until the
+/// application sets it (e.g. from SNTP). It then advances with [`Instant`].
+/// Until then, the `Date` header of authenticated server responses stands in.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WallClock {
+    anchor: Option<(u64, Instant)>,
+    /// Whether the time only comes from a server's `Date` header
+    approximate: bool,
+}
+
+impl WallClock {
+    /// Create a clock whose time is not known yet
+    pub const fn new() -> Self {
+        Self {
+            anchor: None,
+            approximate: false,
+        }
+    }
+
+    /// Set the current Unix time in seconds
+    pub fn set(&mut self, unix_secs: u64) {
+        self.anchor = Some((unix_secs, Instant::now()));
+        self.approximate = false;
+    }
+
+    /// Take the time from a server's `Date` header, unless it was set otherwise
+    ///
+    /// Good to a second or so, which is plenty for maintenance windows.
+    pub fn observe_server_date(&mut self, unix_secs: u64) {
+        if self.anchor.is_none() || self.approximate {
+            self.anchor = Some((unix_secs, Instant::now()));
+            self.approximate = true;
+        }
+    }
+
+    /// Check whether the time has been set
+    pub fn is_set(&self) -> bool {
+        self.anchor.is_some()
+    }
+
+    /// Current Unix time in seconds, if known
+    pub fn now(&self) -> Option<u64> {
+        self.anchor
+            .map(|(unix_secs, at)| unix_secs + at.elapsed().as_secs())
+    }
+}
+
+/// Parse an HTTP date (`Sun, 06 Nov 1994 08:49:37 GMT`) into Unix seconds
+///
+/// Only the IMF-fixdate form servers are required to send is understood.
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let mut fields = value.split_ascii_whitespace();
+    let _weekday = fields.next()?.strip_suffix(',')?;
+    let day: u32 = fields.next()?.parse().ok()?;
+    let month = match fields.next()? {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let year: u32 = fields.next()?.parse().ok()?;
+    let mut clock = fields.next()?.split(':');
+    let hour: u64 = clock.next()?.parse().ok()?;
+    let minute: u64 = clock.next()?.parse().ok()?;
+    let second: u64 = clock.next()?.parse().ok()?;
+    if fields.next()? != "GMT" || fields.next().is_some() || clock.next().is_some() {
+        return None;
+    }
+    // Leap seconds (:60) are let through and land on the next minute
+    if year < 1970 || !(1..=days_in_month(year, month)).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    Some(days * SECS_PER_DAY + hour * 3600 + minute * 60 + second)
+}
+
+/// Number of days in `month` (1 is January) of `year`
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar
+fn days_from_civil(year: u32, month: u32, day: u32) -> u64 {
+    // Count years from March, so the leap day ends the year
+    let year = if month <= 2 { year - 1 } else { year } as u64;
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_from_march = (month as u64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_dates_are_parsed() {
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 12:00:00 GMT"), Some(1_709_208_000));
+        assert_eq!(parse_http_date("Tue, 29 Feb 2000 00:00:00 GMT"), Some(951_782_400));
+        assert_eq!(parse_http_date("Wed, 31 Dec 2025 23:59:59 GMT"), Some(1_767_225_599));
+    }
+
+    #[test]
+    fn days_past_the_end_of_the_month_are_rejected() {
+        assert_eq!(parse_http_date("Sat, 31 Feb 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Wed, 29 Feb 2023 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Mon, 29 Feb 2100 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Thu, 31 Apr 2025 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Tue, 31 Sep 2025 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sat, 00 Jan 2025 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Thu, 30 Apr 2025 00:00:00 GMT"), Some(1_745_971_200));
+    }
+
+    #[test]
+    fn other_forms_are_rejected() {
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1969 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49 GMT"), None);
+    }
+}
@@ -0,0 +1,47 @@
+//! Transports used by the OTA client to fetch manifests and files
+//!
+//! The client only needs "GET this URL into a sink"; everything below that
+//! (network stack, TLS, HTTP library) lives behind [`OtaTransport`] so the
+//! update flow can run against any stack, or against canned responses in tests.
+
+#[cfg(feature = "coap")]
+pub mod coap;
+pub mod mock;
+pub mod reqwless;
+#[cfg(feature = "serial")]
+pub mod serial;
+pub mod tls;
+pub mod trust;
+
+#[cfg(feature = "coap")]
+pub use self::coap::CoapTransport;
+pub use self::mock::{MockResponse, MockTransport};
+pub use self::reqwless::ReqwlessTransport;
+#[cfg(feature = "serial")]
+pub use self::serial::SerialTransport;
+pub use self::tls::{TlsLink, TlsRunner, TlsTransport};
+
+use crate::config::OtaConfig;
+use crate::error::Result;
+use crate::http::{BodySink, Request, ResponseHead};
+use crate::identity::ClientIdentity;
+
+/// Transport able to perform HTTP requests for the OTA client
+pub trait OtaTransport {
+    /// Apply the transport-related parts of the client configuration
+    ///
+    /// Called by the client when it is created. Transports that do not
+    /// support a setting (e.g. certificate pinning on the mock) ignore it.
+    fn configure(&mut self, _config: &OtaConfig) {}
+    
+    /// Present `identity` to the server (mutual TLS)
+    ///
+    /// Transports without client certificate support ignore the identity.
+    fn set_client_identity(&mut self, _identity: ClientIdentity) {}
+    
+    /// Perform `request`, streaming the response body into `sink`
+    ///
+    /// Implementations reject non-2xx responses with `NetworkError::HttpError`
+    /// and call [`BodySink::begin`] before delivering any body data.
+    async fn fetch<K: BodySink>(&mut self, request: &Request<'_>, sink: &mut K) -> Result<ResponseHead>;
+}
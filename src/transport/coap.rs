@@ -0,0 +1,1257 @@
+//! CoAP transport with block-wise transfer (RFC 7252, RFC 7959)
+//!
+//! Meant for constrained networks (Thread, 6LoWPAN) where TCP and TLS are too
+//! heavy. Every request is confirmable, retransmitted with exponential
+//! backoff, and GET bodies are fetched one Block2 block at a time. POST
+//! (status reports) carries its payload in a single datagram. Responses are
+//! translated into HTTP terms (2.05 Content → 200 or 206, 2.03 Valid → 304,
+//! 4.04 Not Found → 404, ...) so manifests and firmware go through the same
+//! signature check and writer as over HTTPS.
+//!
+//! Conditional polling and resumed downloads carry over as well:
+//! `If-None-Match` is sent as the ETag option (CoAP ETags are opaque bytes,
+//! exchanged with the client as hex strings) and `Range: bytes=N-` starts the
+//! transfer at the block containing byte N.
+
+use super::OtaTransport;
+use crate::config::{OtaConfig, TimeoutConfig};
+use crate::error::{ConfigError, NetworkError, Result, TimeoutPhase};
+use crate::http::{BodySink, ContentRange, MAX_ETAG_LENGTH, Method, Request, ResponseHead};
+use crate::retry::{self, Jitter};
+use crate::url::{Scheme, Url};
+
+use core::fmt::Write as _;
+use core::net::{IpAddr, SocketAddr};
+use core::ops::Range;
+use embassy_time::{with_deadline, with_timeout, Duration, Instant};
+use embedded_nal_async::{AddrType, ConnectedUdp, Dns, UdpStack};
+use heapless::{String, Vec};
+
+/// Default block size, small enough for a single 6LoWPAN-friendly datagram
+pub const DEFAULT_BLOCK_SIZE: u16 = 512;
+
+/// Largest block size defined by RFC 7959
+const MAX_BLOCK_SIZE: u16 = 1024;
+
+/// Size of the buffer an encoded request is built in
+const MAX_REQUEST_SIZE: usize = 512;
+
+/// Largest Uri-Path or Uri-Query option value
+const MAX_OPTION_LENGTH: usize = 255;
+
+/// Largest ETag option value
+const MAX_COAP_ETAG_LENGTH: usize = 8;
+
+/// Largest token allowed in a message
+const MAX_TOKEN_LENGTH: usize = 8;
+
+/// Initial retransmission timeout (ACK_TIMEOUT)
+const ACK_TIMEOUT_MS: u32 = 2000;
+
+/// Retransmissions of a request before giving up (MAX_RETRANSMIT)
+const MAX_RETRANSMIT: u8 = 4;
+
+/// Protocol version 1 in the first header byte
+const VERSION: u8 = 0x40;
+
+// Message types
+const TYPE_CON: u8 = 0;
+const TYPE_ACK: u8 = 2;
+const TYPE_RST: u8 = 3;
+
+/// Byte separating options from the payload
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+// Method and response codes (class << 5 | detail)
+const CODE_EMPTY: u8 = 0x00;
+const CODE_GET: u8 = 0x01;
+const CODE_POST: u8 = 0x02;
+const CODE_VALID: u8 = 0x43;
+const CODE_CONTENT: u8 = 0x45;
+const CODE_SERVICE_UNAVAILABLE: u8 = 0xA3;
+
+// Option numbers
+const OPTION_URI_HOST: u16 = 3;
+const OPTION_ETAG: u16 = 4;
+const OPTION_URI_PATH: u16 = 11;
+const OPTION_MAX_AGE: u16 = 14;
+const OPTION_URI_QUERY: u16 = 15;
+const OPTION_BLOCK2: u16 = 23;
+const OPTION_SIZE2: u16 = 28;
+
+/// CoAP transport over a connected UDP socket
+///
+/// `rx_buffer` receives whole datagrams, so it must hold one block plus the
+/// message header and options; 64 bytes on top of the block size is plenty.
+/// Retransmissions follow RFC 7252, bounded by the first-byte timeout for the
+/// first block and by the stall timeout for the following ones.
+pub struct CoapTransport<'a, U, D>
+where
+    U: UdpStack + 'a,
+    D: Dns + 'a,
+{
+    stack: &'a U,
+    dns: &'a D,
+    rx_buffer: &'a mut [u8],
+    block_szx: u8,
+    timeouts: TimeoutConfig,
+    jitter: Jitter,
+    message_id: u16,
+}
+
+/// Value of a Block2 option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Block {
+    /// Block number
+    num: u32,
+    /// Whether more blocks follow
+    more: bool,
+    /// Size exponent; the block holds `16 << szx` bytes
+    szx: u8,
+}
+
+/// A received message, with the options the transport cares about
+#[derive(Debug)]
+struct Message {
+    kind: u8,
+    code: u8,
+    message_id: u16,
+    token: Vec<u8, MAX_TOKEN_LENGTH>,
+    etag: Option<Vec<u8, MAX_COAP_ETAG_LENGTH>>,
+    max_age: Option<u32>,
+    block2: Option<Block>,
+    size2: Option<u32>,
+    /// Position of the payload in the receive buffer
+    payload: Range<usize>,
+}
+
+/// Writes a message into a byte buffer
+struct Encoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+    last_option: u16,
+}
+
+impl<'a, U, D> CoapTransport<'a, U, D>
+where
+    U: UdpStack + 'a,
+    D: Dns + 'a,
+{
+    /// Create a transport sending requests through `stack`
+    pub fn new(stack: &'a U, dns: &'a D, rx_buffer: &'a mut [u8]) -> Self {
+        let ticks = Instant::now().as_ticks();
+        let mut jitter = Jitter::new(ticks as u32 ^ (ticks >> 32) as u32);
+        let message_id = jitter.next() as u16;
+        Self {
+            stack,
+            dns,
+            rx_buffer,
+            block_szx: size_exponent(DEFAULT_BLOCK_SIZE),
+            timeouts: TimeoutConfig::default(),
+            jitter,
+            message_id,
+        }
+    }
+
+    /// Request blocks of `size` bytes
+    ///
+    /// Rounded down to a power of two between 16 and 1024. The server may
+    /// still answer with smaller blocks.
+    pub fn with_block_size(mut self, size: u16) -> Self {
+        self.block_szx = size_exponent(size);
+        self
+    }
+
+    /// Send `message` until it is answered, returning the response
+    ///
+    /// Handles both piggybacked responses and separate responses following
+    /// an empty acknowledgement.
+    async fn exchange<S: ConnectedUdp>(
+        &mut self,
+        socket: &mut S,
+        message: &[u8],
+        message_id: u16,
+        token: &[u8],
+        phase: TimeoutPhase,
+    ) -> Result<Message> {
+        let limit_ms = match phase {
+            TimeoutPhase::FirstByte => self.timeouts.first_byte_ms,
+            _ => self.timeouts.stall_ms,
+        };
+        let give_up = Instant::now() + millis(limit_ms);
+
+        // Initial timeout is randomized between ACK_TIMEOUT and 1.5 times that
+        let mut timeout_ms = ACK_TIMEOUT_MS + self.jitter.below(ACK_TIMEOUT_MS / 2);
+        let mut retransmissions = 0;
+        let mut acknowledged = false;
+
+        socket
+            .send(message)
+            .await
+            .map_err(|_| NetworkError::ConnectionFailed)?;
+        let mut retransmit_at = Instant::now() + millis(timeout_ms);
+
+        loop {
+            let wake = if acknowledged {
+                give_up
+            } else {
+                retransmit_at.min(give_up)
+            };
+            let len = match with_deadline(wake, socket.receive_into(self.rx_buffer)).await {
+                Ok(received) => {
+                    let len = received.map_err(|_| NetworkError::ConnectionFailed)?;
+                    // A datagram larger than the buffer is cut short but reports its full size
+                    if len > self.rx_buffer.len() {
+                        return Err(NetworkError::ResponseTooLarge.into());
+                    }
+                    len
+                }
+                Err(_) if acknowledged || Instant::now() >= give_up => {
+                    return Err(NetworkError::Timeout(phase).into());
+                }
+                Err(_) => {
+                    if retransmissions == MAX_RETRANSMIT {
+                        return Err(NetworkError::Timeout(phase).into());
+                    }
+                    retransmissions += 1;
+                    timeout_ms *= 2;
+                    socket
+                        .send(message)
+                        .await
+                        .map_err(|_| NetworkError::ConnectionFailed)?;
+                    retransmit_at = Instant::now() + millis(timeout_ms);
+                    continue;
+                }
+            };
+
+            // Stray or malformed datagrams are dropped, as RFC 7252 asks
+            let Ok(reply) = Message::parse(&self.rx_buffer[..len]) else {
+                continue;
+            };
+
+            match reply.kind {
+                TYPE_ACK | TYPE_RST if reply.message_id != message_id => continue,
+                TYPE_RST => return Err(NetworkError::ConnectionFailed.into()),
+                // Empty ACK: the response will follow in its own message
+                TYPE_ACK if reply.code == CODE_EMPTY => {
+                    acknowledged = true;
+                    continue;
+                }
+                TYPE_ACK => {}
+                _ => {
+                    if reply.token != token {
+                        continue;
+                    }
+                    if reply.kind == TYPE_CON {
+                        let mut ack = [0u8; 4];
+                        ack[0] = VERSION | (TYPE_ACK << 4);
+                        ack[2..].copy_from_slice(&reply.message_id.to_be_bytes());
+                        socket
+                            .send(&ack)
+                            .await
+                            .map_err(|_| NetworkError::ConnectionFailed)?;
+                    }
+                }
+            }
+
+            if reply.token != token {
+                return Err(NetworkError::InvalidResponse.into());
+            }
+            return Ok(reply);
+        }
+    }
+
+    fn next_message_id(&mut self) -> u16 {
+        self.message_id = self.message_id.wrapping_add(1);
+        self.message_id
+    }
+}
+
+impl<'a, U, D> OtaTransport for CoapTransport<'a, U, D>
+where
+    U: UdpStack + 'a,
+    D: Dns + 'a,
+{
+    fn configure(&mut self, config: &OtaConfig) {
+        self.timeouts = config.timeouts;
+        let ticks = Instant::now().as_ticks();
+        self.jitter = Jitter::new(retry::seed_from_id(&config.device_id) ^ ticks as u32);
+    }
+
+    async fn fetch<K: BodySink>(&mut self, request: &Request<'_>, sink: &mut K) -> Result<ResponseHead> {
+        let code = match request.method {
+            Method::Get => CODE_GET,
+            Method::Post => CODE_POST,
+        };
+        let url = Url::parse(request.url)?;
+        if url.scheme != Scheme::Coap {
+            return Err(ConfigError::UnsupportedScheme.into());
+        }
+
+        let ip = match url.host.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => with_timeout(
+                millis(self.timeouts.dns_ms),
+                self.dns.get_host_by_name(&url.host, AddrType::Either),
+            )
+            .await
+            .map_err(|_| NetworkError::Timeout(TimeoutPhase::Dns))?
+            .map_err(|_| NetworkError::DnsFailed)?,
+        };
+        let (_, mut socket) = self
+            .stack
+            .connect(SocketAddr::new(ip, url.port))
+            .await
+            .map_err(|_| NetworkError::ConnectionFailed)?;
+
+        // Validators the server cannot have produced are not worth sending
+        let etag = request.header("If-None-Match").and_then(decode_etag);
+        let resume_from = request.range_start().unwrap_or(0);
+
+        let mut szx = self.block_szx;
+        let mut offset = resume_from - resume_from % block_size(szx);
+        let mut skip = 0;
+        let mut received: u32 = 0;
+        let mut head: Option<ResponseHead> = None;
+
+        loop {
+            let first = head.is_none();
+            let block = Block {
+                num: offset / block_size(szx),
+                more: false,
+                szx,
+            };
+            let message_id = self.next_message_id();
+            let token = self.jitter.next().to_be_bytes();
+            let mut message = [0u8; MAX_REQUEST_SIZE];
+            let len = encode_request(
+                &mut message,
+                code,
+                message_id,
+                &token,
+                &url,
+                etag.as_deref().filter(|_| first),
+                block,
+                first,
+                request.body,
+            )?;
+
+            let phase = if first {
+                TimeoutPhase::FirstByte
+            } else {
+                TimeoutPhase::Stall
+            };
+            let reply = self
+                .exchange(&mut socket, &message[..len], message_id, &token, phase)
+                .await?;
+
+            if let Some(block) = reply.block2 {
+                // A server choosing smaller blocks still answers at our offset
+                if block.offset() != offset {
+                    return Err(NetworkError::InvalidResponse.into());
+                }
+                if block.more && reply.payload.len() != block_size(block.szx) as usize {
+                    return Err(NetworkError::InvalidResponse.into());
+                }
+            }
+
+            if first {
+                let response = response_head(&reply, resume_from)?;
+                if !response.expects_body() {
+                    return Ok(response);
+                }
+                if response.is_partial() {
+                    skip = (resume_from - offset) as usize;
+                }
+                sink.begin(&response).await?;
+                head = Some(response);
+            } else if reply.code != CODE_CONTENT {
+                return Err(NetworkError::HttpError(http_status(reply.code)).into());
+            }
+
+            let payload = self.rx_buffer[reply.payload.clone()]
+                .get(skip..)
+                .ok_or(NetworkError::InvalidResponse)?;
+            skip = 0;
+            received = received.saturating_add(payload.len() as u32);
+            sink.write(payload).await?;
+
+            // Repeating a POST for the rest of its response would repeat its effect
+            match reply.block2 {
+                Some(block) if block.more && request.method == Method::Get => {
+                    offset = block.offset() + block_size(block.szx);
+                    szx = block.szx;
+                }
+                _ => break,
+            }
+        }
+
+        // Cannot fail: the first reply with a body always sets the head
+        let head = head.ok_or(NetworkError::InvalidResponse)?;
+        if head.content_length.is_some_and(|length| received < length) {
+            return Err(NetworkError::Truncated.into());
+        }
+        Ok(head)
+    }
+}
+
+impl Block {
+    fn decode(value: u32) -> Option<Self> {
+        let szx = (value & 0x07) as u8;
+        // SZX 7 is reserved
+        (szx < 7).then_some(Self {
+            num: value >> 4,
+            more: value & 0x08 != 0,
+            szx,
+        })
+    }
+
+    fn encode(&self) -> u32 {
+        (self.num << 4) | ((self.more as u32) << 3) | self.szx as u32
+    }
+
+    /// Offset of the first byte of this block in the body
+    fn offset(&self) -> u32 {
+        self.num.saturating_mul(block_size(self.szx))
+    }
+}
+
+impl Message {
+    /// Parse a datagram, recording the payload position within it
+    fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 4 || data[0] & 0xC0 != VERSION {
+            return Err(NetworkError::InvalidResponse.into());
+        }
+        let token_len = (data[0] & 0x0F) as usize;
+        let token = data
+            .get(4..4 + token_len)
+            .and_then(|token| Vec::from_slice(token).ok())
+            .ok_or(NetworkError::InvalidResponse)?;
+
+        let mut message = Self {
+            kind: (data[0] >> 4) & 0x03,
+            code: data[1],
+            message_id: u16::from_be_bytes([data[2], data[3]]),
+            token,
+            etag: None,
+            max_age: None,
+            block2: None,
+            size2: None,
+            payload: data.len()..data.len(),
+        };
+
+        let mut pos = 4 + token_len;
+        let mut number: u16 = 0;
+        while pos < data.len() {
+            let byte = data[pos];
+            pos += 1;
+            if byte == PAYLOAD_MARKER {
+                // A payload marker must be followed by a payload
+                if pos == data.len() {
+                    return Err(NetworkError::InvalidResponse.into());
+                }
+                message.payload = pos..data.len();
+                break;
+            }
+
+            let delta = option_field(byte >> 4, data, &mut pos)?;
+            let length = option_field(byte & 0x0F, data, &mut pos)? as usize;
+            number = number
+                .checked_add(delta)
+                .ok_or(NetworkError::InvalidResponse)?;
+            let value = data
+                .get(pos..pos + length)
+                .ok_or(NetworkError::InvalidResponse)?;
+            pos += length;
+
+            match number {
+                OPTION_ETAG => message.etag = Vec::from_slice(value).ok(),
+                OPTION_MAX_AGE => message.max_age = Some(option_uint(value)?),
+                OPTION_BLOCK2 => {
+                    let block = Block::decode(option_uint(value)?);
+                    message.block2 = Some(block.ok_or(NetworkError::InvalidResponse)?);
+                }
+                OPTION_SIZE2 => message.size2 = Some(option_uint(value)?),
+                // Unknown critical options make the message unusable
+                number if number & 1 == 1 => return Err(NetworkError::InvalidResponse.into()),
+                _ => {}
+            }
+        }
+
+        Ok(message)
+    }
+}
+
+impl<'b> Encoder<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            last_option: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) -> Result<()> {
+        let end = self.len + data.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(NetworkError::RequestTooLarge)?
+            .copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Append an option; options must be added in ascending order
+    fn option(&mut self, number: u16, value: &[u8]) -> Result<()> {
+        let (delta, delta_ext) = option_nibble(number - self.last_option);
+        let (length, length_ext) = option_nibble(value.len() as u16);
+        self.last_option = number;
+
+        self.push(&[(delta << 4) | length])?;
+        self.push(&delta_ext)?;
+        self.push(&length_ext)?;
+        self.push(value)
+    }
+
+    /// Append an option holding an unsigned integer in as few bytes as possible
+    fn uint_option(&mut self, number: u16, value: u32) -> Result<()> {
+        let bytes = value.to_be_bytes();
+        let skip = (value.leading_zeros() / 8) as usize;
+        self.option(number, &bytes[skip..])
+    }
+}
+
+/// Encode a confirmable request for one block of `url`
+#[allow(clippy::too_many_arguments)]
+fn encode_request(
+    buf: &mut [u8],
+    code: u8,
+    message_id: u16,
+    token: &[u8],
+    url: &Url,
+    etag: Option<&[u8]>,
+    block: Block,
+    ask_size: bool,
+    payload: &[u8],
+) -> Result<usize> {
+    let mut encoder = Encoder::new(buf);
+    encoder.push(&[VERSION | (TYPE_CON << 4) | token.len() as u8, code])?;
+    encoder.push(&message_id.to_be_bytes())?;
+    encoder.push(token)?;
+
+    // The host defaults to the destination address, so IP literals are omitted
+    if url.host.parse::<IpAddr>().is_err() {
+        encoder.option(OPTION_URI_HOST, url.host.as_bytes())?;
+    }
+    if let Some(etag) = etag {
+        encoder.option(OPTION_ETAG, etag)?;
+    }
+    if url.path != "/" {
+        for segment in url.path[1..].split('/') {
+            encoder.option(OPTION_URI_PATH, &percent_decode(segment)?)?;
+        }
+    }
+    if let Some(query) = &url.query {
+        for param in query.split('&') {
+            encoder.option(OPTION_URI_QUERY, &percent_decode(param)?)?;
+        }
+    }
+    encoder.uint_option(OPTION_BLOCK2, block.encode())?;
+    // Size2 of zero in a request asks the server for the total size
+    if ask_size {
+        encoder.uint_option(OPTION_SIZE2, 0)?;
+    }
+    if !payload.is_empty() {
+        encoder.push(&[PAYLOAD_MARKER])?;
+        encoder.push(payload)?;
+    }
+
+    Ok(encoder.len)
+}
+
+/// Build the HTTP-style head for the first response of a transfer
+fn response_head(reply: &Message, resume_from: u32) -> Result<ResponseHead> {
+    let mut head = ResponseHead::new(http_status(reply.code));
+    head.etag = reply.etag.as_deref().map(encode_etag);
+    if reply.code == CODE_SERVICE_UNAVAILABLE {
+        head.retry_after = reply.max_age;
+    }
+    head.check_status()?;
+    if reply.code == CODE_VALID {
+        return Ok(head);
+    }
+
+    // Without Block2 the payload is the whole representation
+    let total = match reply.block2 {
+        Some(_) => reply.size2,
+        None => reply.size2.or(Some(reply.payload.len() as u32)),
+    };
+    if resume_from > 0 && reply.block2.is_some() {
+        head.status = 206;
+        head.content_range = Some(ContentRange {
+            start: resume_from,
+            // The end is unknown when the server does not send Size2
+            end: total.map_or(u32::MAX, |total| total.saturating_sub(1)),
+            total,
+        });
+        head.content_length = total.map(|total| total.saturating_sub(resume_from));
+    } else {
+        head.content_length = total;
+    }
+
+    Ok(head)
+}
+
+/// Map a CoAP response code onto the equivalent HTTP status
+fn http_status(code: u8) -> u16 {
+    match code {
+        CODE_VALID => 304,
+        code if code >> 5 == 2 => 200,
+        code => (code >> 5) as u16 * 100 + (code & 0x1F) as u16,
+    }
+}
+
+/// Read an extended option delta or length (RFC 7252 §3.1)
+fn option_field(nibble: u8, data: &[u8], pos: &mut usize) -> Result<u16> {
+    let value = match nibble {
+        0..=12 => nibble as u16,
+        13 => {
+            let byte = *data.get(*pos).ok_or(NetworkError::InvalidResponse)?;
+            *pos += 1;
+            byte as u16 + 13
+        }
+        14 => {
+            let bytes = data
+                .get(*pos..*pos + 2)
+                .ok_or(NetworkError::InvalidResponse)?;
+            *pos += 2;
+            u16::from_be_bytes([bytes[0], bytes[1]])
+                .checked_add(269)
+                .ok_or(NetworkError::InvalidResponse)?
+        }
+        _ => return Err(NetworkError::InvalidResponse.into()),
+    };
+    Ok(value)
+}
+
+/// Split an option delta or length into its nibble and extension bytes
+fn option_nibble(value: u16) -> (u8, Vec<u8, 2>) {
+    let mut ext = Vec::new();
+    let nibble = match value {
+        0..=12 => value as u8,
+        13..=268 => {
+            let _ = ext.push((value - 13) as u8);
+            13
+        }
+        _ => {
+            let _ = ext.extend_from_slice(&(value - 269).to_be_bytes());
+            14
+        }
+    };
+    (nibble, ext)
+}
+
+/// Decode an unsigned integer option value
+fn option_uint(value: &[u8]) -> Result<u32> {
+    if value.len() > 4 {
+        return Err(NetworkError::InvalidResponse.into());
+    }
+    Ok(value.iter().fold(0, |acc, b| (acc << 8) | *b as u32))
+}
+
+/// Decode a percent-encoded path segment or query parameter into raw option bytes
+fn percent_decode(value: &str) -> Result<Vec<u8, MAX_OPTION_LENGTH>> {
+    let bytes = value.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = if bytes[i] == b'%' {
+            let hex = value
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .ok_or(ConfigError::InvalidPath)?;
+            i += 3;
+            // Cannot fail: both digits were checked above
+            u8::from_str_radix(hex, 16).unwrap_or(0)
+        } else {
+            i += 1;
+            bytes[i - 1]
+        };
+        out.push(byte).map_err(|_| NetworkError::RequestTooLarge)?;
+    }
+    Ok(out)
+}
+
+/// Render a CoAP ETag as the hex string handed to the client
+fn encode_etag(etag: &[u8]) -> String<MAX_ETAG_LENGTH> {
+    let mut out = String::new();
+    for byte in etag {
+        // Cannot fail: eight bytes give sixteen characters
+        let _ = write!(out, "{:02x}", byte);
+    }
+    out
+}
+
+/// Parse a hex ETag previously produced by [`encode_etag`]
+fn decode_etag(value: &str) -> Option<Vec<u8, MAX_COAP_ETAG_LENGTH>> {
+    if value.len() % 2 != 0 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut etag = Vec::new();
+    for i in (0..value.len()).step_by(2) {
+        let byte = u8::from_str_radix(&value[i..i + 2], 16).ok()?;
+        etag.push(byte).ok()?;
+    }
+    Some(etag)
+}
+
+/// Bytes in a block of size exponent `szx`
+fn block_size(szx: u8) -> u32 {
+    16 << szx
+}
+
+/// Size exponent for blocks of at most `size` bytes
+fn size_exponent(size: u16) -> u8 {
+    let size = size.clamp(16, MAX_BLOCK_SIZE);
+    (15 - size.leading_zeros() as u8) - 4
+}
+
+/// Convert a configured timeout into a duration
+fn millis(ms: u32) -> Duration {
+    Duration::from_millis(ms as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing::{block_on, join};
+    use core::cell::RefCell;
+    use core::future::poll_fn;
+    use core::task::{Poll, Waker};
+    use embedded_io_async::ErrorKind;
+    use embedded_nal_async::UnconnectedUdp;
+    use std::collections::VecDeque;
+    use std::vec::Vec as StdVec;
+
+    const URL: &str = "coap://192.0.2.1/fw/firmware.bin";
+
+    /// Datagrams travelling in one direction
+    #[derive(Default)]
+    struct Queue {
+        datagrams: RefCell<VecDeque<StdVec<u8>>>,
+        waker: RefCell<Option<Waker>>,
+    }
+
+    impl Queue {
+        fn push(&self, datagram: StdVec<u8>) {
+            self.datagrams.borrow_mut().push_back(datagram);
+            if let Some(waker) = self.waker.borrow_mut().take() {
+                waker.wake();
+            }
+        }
+
+        async fn pop(&self) -> StdVec<u8> {
+            poll_fn(|cx| match self.datagrams.borrow_mut().pop_front() {
+                Some(datagram) => Poll::Ready(datagram),
+                None => {
+                    *self.waker.borrow_mut() = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+            .await
+        }
+    }
+
+    /// Network between the transport and the stand-in server
+    #[derive(Default)]
+    struct Net {
+        to_server: Queue,
+        to_client: Queue,
+    }
+
+    /// Connected socket of the transport
+    struct Socket<'a>(&'a Net);
+
+    /// Socket type for the binds the transport never makes
+    struct Unbound;
+
+    impl<'a> UdpStack for &'a Net {
+        type Error = ErrorKind;
+        type Connected = Socket<'a>;
+        type UniquelyBound = Unbound;
+        type MultiplyBound = Unbound;
+
+        async fn connect_from(
+            &self,
+            local: SocketAddr,
+            _remote: SocketAddr,
+        ) -> core::result::Result<(SocketAddr, Socket<'a>), ErrorKind> {
+            Ok((local, Socket(self)))
+        }
+
+        async fn bind_single(
+            &self,
+            _local: SocketAddr,
+        ) -> core::result::Result<(SocketAddr, Unbound), ErrorKind> {
+            Err(ErrorKind::Unsupported)
+        }
+
+        async fn bind_multiple(
+            &self,
+            _local: SocketAddr,
+        ) -> core::result::Result<Unbound, ErrorKind> {
+            Err(ErrorKind::Unsupported)
+        }
+    }
+
+    impl Dns for Net {
+        type Error = ErrorKind;
+
+        async fn get_host_by_name(
+            &self,
+            _host: &str,
+            _addr_type: AddrType,
+        ) -> core::result::Result<IpAddr, ErrorKind> {
+            Err(ErrorKind::NotFound)
+        }
+
+        async fn get_host_by_address(
+            &self,
+            _addr: IpAddr,
+            _result: &mut [u8],
+        ) -> core::result::Result<usize, ErrorKind> {
+            Err(ErrorKind::Unsupported)
+        }
+    }
+
+    impl ConnectedUdp for Socket<'_> {
+        type Error = ErrorKind;
+
+        async fn send(&mut self, data: &[u8]) -> core::result::Result<(), ErrorKind> {
+            self.0.to_server.push(data.to_vec());
+            Ok(())
+        }
+
+        async fn receive_into(
+            &mut self,
+            buffer: &mut [u8],
+        ) -> core::result::Result<usize, ErrorKind> {
+            let datagram = self.0.to_client.pop().await;
+            let n = datagram.len().min(buffer.len());
+            buffer[..n].copy_from_slice(&datagram[..n]);
+            Ok(datagram.len())
+        }
+    }
+
+    impl UnconnectedUdp for Unbound {
+        type Error = ErrorKind;
+
+        async fn send(
+            &mut self,
+            _local: SocketAddr,
+            _remote: SocketAddr,
+            _data: &[u8],
+        ) -> core::result::Result<(), ErrorKind> {
+            Err(ErrorKind::Unsupported)
+        }
+
+        async fn receive_into(
+            &mut self,
+            _buffer: &mut [u8],
+        ) -> core::result::Result<(usize, SocketAddr, SocketAddr), ErrorKind> {
+            Err(ErrorKind::Unsupported)
+        }
+    }
+
+    /// Misbehaviour of the stand-in server, applied once
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Fault {
+        /// The request for this block is lost
+        Drop(u32),
+        /// The response to this block is delivered twice
+        Duplicate(u32),
+        /// This block is answered by an empty ACK and a separate response
+        Separate(u32),
+        /// An unrelated datagram arrives before this block's response
+        Stray(u32),
+    }
+
+    /// A request as seen by the server
+    #[derive(Debug, Clone, PartialEq)]
+    struct Seen {
+        message_id: u16,
+        block: Block,
+        size2: bool,
+    }
+
+    #[derive(Debug, Default)]
+    struct Log {
+        requests: StdVec<Seen>,
+        /// Message IDs of the empty ACKs the client sent
+        acks: StdVec<u16>,
+    }
+
+    /// A datagram received by the server
+    struct Incoming {
+        kind: u8,
+        code: u8,
+        message_id: u16,
+        token: StdVec<u8>,
+        options: StdVec<(u16, StdVec<u8>)>,
+    }
+
+    impl Incoming {
+        fn option(&self, number: u16) -> Option<&[u8]> {
+            self.options
+                .iter()
+                .find(|(n, _)| *n == number)
+                .map(|(_, value)| &value[..])
+        }
+    }
+
+    /// Split a request into header, token and options
+    fn parse_request(data: &[u8]) -> Incoming {
+        let token_len = (data[0] & 0x0F) as usize;
+        let mut options = StdVec::new();
+        let mut pos = 4 + token_len;
+        let mut number = 0;
+        while pos < data.len() && data[pos] != PAYLOAD_MARKER {
+            let byte = data[pos];
+            pos += 1;
+            number += option_field(byte >> 4, data, &mut pos).unwrap();
+            let length = option_field(byte & 0x0F, data, &mut pos).unwrap() as usize;
+            options.push((number, data[pos..pos + length].to_vec()));
+            pos += length;
+        }
+        Incoming {
+            kind: (data[0] >> 4) & 0x03,
+            code: data[1],
+            message_id: u16::from_be_bytes([data[2], data[3]]),
+            token: data[4..4 + token_len].to_vec(),
+            options,
+        }
+    }
+
+    fn encode_response(
+        kind: u8,
+        code: u8,
+        message_id: u16,
+        token: &[u8],
+        block: Option<Block>,
+        size2: Option<u32>,
+        payload: &[u8],
+    ) -> StdVec<u8> {
+        let mut buf = [0u8; 2048];
+        let mut encoder = Encoder::new(&mut buf);
+        encoder
+            .push(&[VERSION | (kind << 4) | token.len() as u8, code])
+            .unwrap();
+        encoder.push(&message_id.to_be_bytes()).unwrap();
+        encoder.push(token).unwrap();
+        if let Some(block) = block {
+            encoder.uint_option(OPTION_BLOCK2, block.encode()).unwrap();
+        }
+        if let Some(size2) = size2 {
+            encoder.uint_option(OPTION_SIZE2, size2).unwrap();
+        }
+        if !payload.is_empty() {
+            encoder.push(&[PAYLOAD_MARKER]).unwrap();
+            encoder.push(payload).unwrap();
+        }
+        let len = encoder.len;
+        buf[..len].to_vec()
+    }
+
+    /// Serve `body` block by block with blocks of at most `16 << max_szx` bytes
+    ///
+    /// Returns once the last block is served and acknowledged where needed.
+    async fn server(net: &Net, body: &[u8], max_szx: u8, faults: &[Fault]) -> Log {
+        let mut pending = faults.to_vec();
+        let mut log = Log::default();
+        let mut own_id = 0x7000u16;
+        let mut awaiting_ack = None;
+        let mut done = false;
+        loop {
+            let request = parse_request(&net.to_server.pop().await);
+            let (message_id, token) = (request.message_id, &request.token[..]);
+            if request.kind == TYPE_ACK {
+                log.acks.push(message_id);
+                if done && awaiting_ack == Some(message_id) {
+                    return log;
+                }
+                continue;
+            }
+            assert_eq!((request.kind, request.code), (TYPE_CON, CODE_GET));
+
+            let requested = request
+                .option(OPTION_BLOCK2)
+                .and_then(|value| option_uint(value).ok());
+            let requested = requested.and_then(Block::decode).unwrap();
+            let size2 = request.option(OPTION_SIZE2).is_some();
+            log.requests.push(Seen {
+                message_id,
+                block: requested,
+                size2,
+            });
+            let num = requested.num;
+            if take(&mut pending, Fault::Drop(num)) {
+                continue;
+            }
+
+            let szx = requested.szx.min(max_szx);
+            let start = requested.offset() as usize;
+            let end = (start + block_size(szx) as usize).min(body.len());
+            let block = Block {
+                num: start as u32 / block_size(szx),
+                more: end < body.len(),
+                szx,
+            };
+            let payload = &body[start..end];
+            let total = size2.then_some(body.len() as u32);
+
+            if take(&mut pending, Fault::Stray(num)) {
+                let stray = encode_response(
+                    TYPE_ACK,
+                    CODE_CONTENT,
+                    message_id ^ 0x5555,
+                    token,
+                    None,
+                    None,
+                    b"x",
+                );
+                net.to_client.push(stray);
+            }
+            if take(&mut pending, Fault::Separate(num)) {
+                net.to_client.push(encode_response(
+                    TYPE_ACK,
+                    CODE_EMPTY,
+                    message_id,
+                    &[],
+                    None,
+                    None,
+                    &[],
+                ));
+                own_id += 1;
+                awaiting_ack = Some(own_id);
+                let response = encode_response(
+                    TYPE_CON,
+                    CODE_CONTENT,
+                    own_id,
+                    token,
+                    Some(block),
+                    total,
+                    payload,
+                );
+                net.to_client.push(response);
+            } else {
+                let response = encode_response(
+                    TYPE_ACK,
+                    CODE_CONTENT,
+                    message_id,
+                    token,
+                    Some(block),
+                    total,
+                    payload,
+                );
+                if take(&mut pending, Fault::Duplicate(num)) {
+                    net.to_client.push(response.clone());
+                }
+                net.to_client.push(response);
+            }
+
+            if !block.more {
+                done = true;
+                if awaiting_ack.is_none_or(|id| log.acks.contains(&id)) {
+                    return log;
+                }
+            }
+        }
+    }
+
+    fn take(pending: &mut StdVec<Fault>, fault: Fault) -> bool {
+        match pending.iter().position(|f| *f == fault) {
+            Some(index) => {
+                pending.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn body() -> StdVec<u8> {
+        (0..1300u32).map(|i| (i * 31 % 256) as u8).collect()
+    }
+
+    fn fetch(
+        request: Request<'_>,
+        body: &[u8],
+        max_szx: u8,
+        faults: &[Fault],
+    ) -> (Result<ResponseHead>, heapless::Vec<u8, 2048>, Log) {
+        let net = Net::default();
+        let stack = &net;
+        let mut rx_buffer = [0u8; 1024 + 64];
+        let mut transport = CoapTransport::new(&stack, &net, &mut rx_buffer);
+        let mut sink = heapless::Vec::new();
+        let (result, log) = block_on(join(
+            transport.fetch(&request, &mut sink),
+            server(&net, body, max_szx, faults),
+        ));
+        (result, sink, log)
+    }
+
+    fn blocks(log: &Log) -> StdVec<(u32, u8)> {
+        log.requests
+            .iter()
+            .map(|seen| (seen.block.num, seen.block.szx))
+            .collect()
+    }
+
+    #[test]
+    fn blocks_are_fetched_in_order() {
+        let body = body();
+        let (head, sink, log) = fetch(Request::get(URL), &body, 6, &[]);
+        let head = head.unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(head.content_length, Some(1300));
+        assert_eq!(&sink[..], &body[..]);
+
+        // 512-byte blocks; only the first request asks for the size
+        assert_eq!(blocks(&log), [(0, 5), (1, 5), (2, 5)]);
+        let sizes: StdVec<bool> = log.requests.iter().map(|seen| seen.size2).collect();
+        assert_eq!(sizes, [true, false, false]);
+    }
+
+    #[test]
+    fn smaller_server_blocks_are_followed() {
+        let body = body();
+        let (head, sink, log) = fetch(Request::get(URL), &body, 4, &[]);
+        assert_eq!(head.unwrap().status, 200);
+        assert_eq!(&sink[..], &body[..]);
+        assert_eq!(
+            blocks(&log),
+            [(0, 5), (1, 4), (2, 4), (3, 4), (4, 4), (5, 4)]
+        );
+    }
+
+    #[test]
+    fn lost_requests_are_retransmitted() {
+        let body = body();
+        let faults = [Fault::Duplicate(0), Fault::Drop(1), Fault::Stray(2)];
+        let (head, sink, log) = fetch(Request::get(URL), &body, 6, &faults);
+        assert_eq!(head.unwrap().status, 200);
+        assert_eq!(&sink[..], &body[..]);
+
+        // The lost request is repeated with the same message ID; the rest are new
+        assert_eq!(blocks(&log), [(0, 5), (1, 5), (1, 5), (2, 5)]);
+        let ids: StdVec<u16> = log.requests.iter().map(|seen| seen.message_id).collect();
+        assert_eq!(ids[1], ids[2]);
+        assert_eq!(ids[2].wrapping_add(1), ids[3]);
+    }
+
+    #[test]
+    fn separate_responses_are_acknowledged() {
+        let body = body();
+        let (head, sink, log) = fetch(
+            Request::get(URL),
+            &body,
+            6,
+            &[Fault::Separate(1), Fault::Separate(2)],
+        );
+        assert_eq!(head.unwrap().status, 200);
+        assert_eq!(&sink[..], &body[..]);
+        assert_eq!(log.acks, [0x7001, 0x7002]);
+    }
+
+    #[test]
+    fn range_starts_at_containing_block() {
+        let body = body();
+        let headers = [("Range", "bytes=600-")];
+        let (head, sink, log) = fetch(Request::get(URL).with_headers(&headers), &body, 6, &[]);
+        let head = head.unwrap();
+        assert_eq!(head.status, 206);
+        assert_eq!(head.content_length, Some(700));
+        assert_eq!(
+            head.content_range.map(|r| (r.start, r.total)),
+            Some((600, Some(1300)))
+        );
+        assert_eq!(&sink[..], &body[600..]);
+        assert_eq!(blocks(&log), [(1, 5), (2, 5)]);
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        let net = Net::default();
+        let stack = &net;
+        let mut rx_buffer = [0u8; 576];
+        let mut transport = CoapTransport::new(&stack, &net, &mut rx_buffer);
+        transport.timeouts.first_byte_ms = 200;
+        let mut sink: heapless::Vec<u8, 16> = heapless::Vec::new();
+        assert_eq!(
+            block_on(transport.fetch(&Request::get(URL), &mut sink)),
+            Err(NetworkError::Timeout(TimeoutPhase::FirstByte).into())
+        );
+        assert_eq!(net.to_server.datagrams.borrow().len(), 1);
+    }
+
+    #[test]
+    fn oversized_datagram_is_an_error() {
+        let net = Net::default();
+        let stack = &net;
+        let mut rx_buffer = [0u8; 128];
+        let mut transport = CoapTransport::new(&stack, &net, &mut rx_buffer);
+        let mut sink: heapless::Vec<u8, 2048> = heapless::Vec::new();
+        let body = body();
+        let (result, _) = block_on(join(
+            transport.fetch(&Request::get(URL), &mut sink),
+            async {
+                let request = parse_request(&net.to_server.pop().await);
+                let (message_id, token) = (request.message_id, request.token);
+                let block = Block {
+                    num: 0,
+                    more: true,
+                    szx: 5,
+                };
+                net.to_client.push(encode_response(
+                    TYPE_ACK,
+                    CODE_CONTENT,
+                    message_id,
+                    &token,
+                    Some(block),
+                    None,
+                    &body[..512],
+                ));
+            },
+        ));
+        assert_eq!(result, Err(NetworkError::ResponseTooLarge.into()));
+    }
+
+    #[test]
+    fn error_codes_map_to_http_status() {
+        let net = Net::default();
+        let stack = &net;
+        let mut rx_buffer = [0u8; 576];
+        let mut transport = CoapTransport::new(&stack, &net, &mut rx_buffer);
+        let mut sink: heapless::Vec<u8, 16> = heapless::Vec::new();
+        let (result, _) = block_on(join(
+            transport.fetch(&Request::get(URL), &mut sink),
+            async {
+                let request = parse_request(&net.to_server.pop().await);
+                let (message_id, token) = (request.message_id, request.token);
+                // 4.04 Not Found
+                net.to_client.push(encode_response(
+                    TYPE_ACK,
+                    0x84,
+                    message_id,
+                    &token,
+                    None,
+                    None,
+                    &[],
+                ));
+            },
+        ));
+        assert_eq!(result, Err(NetworkError::HttpError(404).into()));
+    }
+}
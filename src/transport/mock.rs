@@ -0,0 +1,243 @@
+//! In-memory transport serving canned responses
+//!
+//! Lets the complete update flow run under `cargo test` on the host without a
+//! network stack. Responses are matched by URL suffix, honour `Range` and
+//! `If-None-Match` requests, can redirect elsewhere and can simulate a
+//! connection that drops part-way through the body or before any response.
+
+use super::OtaTransport;
+use crate::error::{NetworkError, Result};
+use crate::http::{BodySink, ContentRange, Request, ResponseHead};
+use heapless::Vec;
+
+/// Maximum number of canned responses held by a mock transport
+pub const MAX_MOCK_RESPONSES: usize = 8;
+
+/// Size of the chunks the mock delivers body data in
+const MOCK_CHUNK_SIZE: usize = 512;
+
+/// A canned response served by [`MockTransport`]
+#[derive(Debug, Clone, Copy)]
+pub struct MockResponse<'a> {
+    /// URL suffix this response is served for (e.g. "/manifest.json")
+    pub path: &'a str,
+
+    /// HTTP status code
+    pub status: u16,
+
+    /// Response body
+    pub body: &'a [u8],
+
+    /// Close the connection after this many body bytes, if set
+    pub fail_after: Option<usize>,
+
+    /// Fail this many requests with a dropped connection before answering
+    pub failures: u8,
+
+    /// Serve `Range` requests with `206 Partial Content`
+    pub supports_range: bool,
+
+    /// ETag sent with the response; a matching `If-None-Match` gets a 304
+    pub etag: Option<&'a str>,
+
+    /// Location header sent with a redirect
+    pub location: Option<&'a str>,
+
+    /// Date header sent with the response, as Unix time
+    pub date: Option<u64>,
+}
+
+/// Transport answering requests from a fixed table of responses
+pub struct MockTransport<'a> {
+    responses: Vec<MockResponse<'a>, MAX_MOCK_RESPONSES>,
+    requests: u32,
+}
+
+impl<'a> MockResponse<'a> {
+    /// Create a `200 OK` response with the given body
+    pub fn ok(path: &'a str, body: &'a [u8]) -> Self {
+        Self {
+            path,
+            status: 200,
+            body,
+            fail_after: None,
+            failures: 0,
+            supports_range: true,
+            etag: None,
+            location: None,
+            date: None,
+        }
+    }
+
+    /// Create an error response with an empty body
+    pub fn status(path: &'a str, status: u16) -> Self {
+        Self {
+            path,
+            status,
+            body: &[],
+            fail_after: None,
+            failures: 0,
+            supports_range: false,
+            etag: None,
+            location: None,
+            date: None,
+        }
+    }
+
+    /// Create a redirect to `location` with the given 3xx status
+    pub fn redirect(path: &'a str, status: u16, location: &'a str) -> Self {
+        Self {
+            location: Some(location),
+            ..Self::status(path, status)
+        }
+    }
+
+    /// Drop the connection after `bytes` bytes of body
+    pub fn with_fail_after(mut self, bytes: usize) -> Self {
+        self.fail_after = Some(bytes);
+        self
+    }
+
+    /// Drop the connection of the next `count` requests before answering them
+    pub fn with_failures(mut self, count: u8) -> Self {
+        self.failures = count;
+        self
+    }
+
+    /// Ignore `Range` headers and always send the full body
+    pub fn without_range_support(mut self) -> Self {
+        self.supports_range = false;
+        self
+    }
+
+    /// Send a Date header with the response
+    pub fn with_date(mut self, unix_secs: u64) -> Self {
+        self.date = Some(unix_secs);
+        self
+    }
+
+    /// Tag the response with an ETag
+    pub fn with_etag(mut self, etag: &'a str) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+}
+
+impl<'a> MockTransport<'a> {
+    /// Create a transport with no responses (every request gets a 404)
+    pub fn new() -> Self {
+        Self {
+            responses: Vec::new(),
+            requests: 0,
+        }
+    }
+
+    /// Add a canned response, replacing any existing one for the same path
+    pub fn with_response(mut self, response: MockResponse<'a>) -> Self {
+        self.set_response(response);
+        self
+    }
+
+    /// Add or replace a canned response
+    pub fn set_response(&mut self, response: MockResponse<'a>) {
+        if let Some(existing) = self.responses.iter_mut().find(|r| r.path == response.path) {
+            *existing = response;
+        } else {
+            // Silently ignored once the table is full; tests use a handful of paths
+            let _ = self.responses.push(response);
+        }
+    }
+
+    /// Number of requests served so far
+    pub fn requests(&self) -> u32 {
+        self.requests
+    }
+
+    /// Find the response for a URL, ignoring any query string
+    fn lookup(&mut self, url: &str) -> Option<&mut MockResponse<'a>> {
+        let path = url.split('?').next().unwrap_or(url);
+        self.responses.iter_mut().find(|r| path.ends_with(r.path))
+    }
+}
+
+impl Default for MockTransport<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OtaTransport for MockTransport<'_> {
+    async fn fetch<K: BodySink>(&mut self, request: &Request<'_>, sink: &mut K) -> Result<ResponseHead> {
+        self.requests += 1;
+
+        let response = match self.lookup(request.url) {
+            Some(response) if response.failures > 0 => {
+                response.failures -= 1;
+                return Err(NetworkError::ConnectionFailed.into());
+            }
+            Some(response) => *response,
+            None => MockResponse::status("", 404),
+        };
+
+        let mut head = ResponseHead::new(response.status);
+        head.content_length = Some(response.body.len() as u32);
+        head.etag = response.etag.and_then(|etag| etag.try_into().ok());
+        head.location = response.location.and_then(|location| location.try_into().ok());
+        head.date = response.date;
+
+        // Unchanged resources get an empty 304
+        if let (Some(etag), Some(expected)) = (response.etag, request.header("If-None-Match")) {
+            if head.is_success() && etag == expected {
+                head.status = 304;
+                head.content_length = Some(0);
+                return Ok(head);
+            }
+        }
+
+        // Serve the requested suffix if the response supports ranges
+        let mut body = response.body;
+        let mut start = 0;
+        if let Some(range) = request.header("Range") {
+            if response.supports_range && head.is_success() {
+                start = parse_range_start(range)?.min(body.len());
+                body = &body[start..];
+                head.status = 206;
+                head.content_length = Some(body.len() as u32);
+                head.content_range = Some(ContentRange {
+                    start: start as u32,
+                    end: response.body.len().saturating_sub(1) as u32,
+                    total: Some(response.body.len() as u32),
+                });
+            }
+        }
+
+        head.check_status()?;
+        if !head.expects_body() {
+            return Ok(head);
+        }
+        sink.begin(&head).await?;
+
+        // Deliver in chunks, dropping the connection where configured
+        let limit = response
+            .fail_after
+            .map(|n| n.saturating_sub(start).min(body.len()))
+            .unwrap_or(body.len());
+        for chunk in body[..limit].chunks(MOCK_CHUNK_SIZE) {
+            sink.write(chunk).await?;
+        }
+        if limit < body.len() {
+            return Err(NetworkError::ConnectionFailed.into());
+        }
+
+        Ok(head)
+    }
+}
+
+/// Parse the start offset of a "bytes=N-" range header
+fn parse_range_start(range: &str) -> Result<usize> {
+    range
+        .strip_prefix("bytes=")
+        .and_then(|r| r.strip_suffix('-'))
+        .and_then(|n| n.parse().ok())
+        .ok_or(NetworkError::InvalidResponse.into())
+}
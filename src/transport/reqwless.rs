@@ -0,0 +1,129 @@
+//! Transport built on the reqwless HTTP client
+
+use super::OtaTransport;
+use crate::config::{OtaConfig, TimeoutConfig};
+use crate::error::{NetworkError, Result, TimeoutPhase};
+use crate::http::{BodySink, Method, Request, ResponseHead};
+
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::Read;
+use embedded_nal_async::{Dns, TcpConnect};
+use reqwless::client::HttpClient;
+use reqwless::request::RequestBuilder;
+
+/// Size of the chunks copied from the response body into the sink
+const BODY_CHUNK_SIZE: usize = 1024;
+
+/// HTTP(S) transport using a reqwless [`HttpClient`]
+///
+/// TLS, DNS and connection handling are configured on the client by the
+/// application; `rx_buffer` holds the response head. reqwless resolves,
+/// connects and handshakes in one step, so those phases share a single
+/// timeout (the sum of the configured ones) reported as `Connect`.
+pub struct ReqwlessTransport<'a, T, D>
+where
+    T: TcpConnect + 'a,
+    D: Dns + 'a,
+{
+    client: HttpClient<'a, T, D>,
+    rx_buffer: &'a mut [u8],
+    timeouts: TimeoutConfig,
+}
+
+impl<'a, T, D> ReqwlessTransport<'a, T, D>
+where
+    T: TcpConnect + 'a,
+    D: Dns + 'a,
+{
+    /// Create a new transport around a configured client
+    pub fn new(client: HttpClient<'a, T, D>, rx_buffer: &'a mut [u8]) -> Self {
+        Self {
+            client,
+            rx_buffer,
+            timeouts: TimeoutConfig::default(),
+        }
+    }
+}
+
+impl<'a, T, D> OtaTransport for ReqwlessTransport<'a, T, D>
+where
+    T: TcpConnect + 'a,
+    D: Dns + 'a,
+{
+    fn configure(&mut self, config: &OtaConfig) {
+        self.timeouts = config.timeouts;
+    }
+    
+    async fn fetch<K: BodySink>(&mut self, request: &Request<'_>, sink: &mut K) -> Result<ResponseHead> {
+        let method = match request.method {
+            Method::Get => reqwless::request::Method::GET,
+            Method::Post => reqwless::request::Method::POST,
+        };
+        let timeouts = self.timeouts;
+        
+        let connect_ms = timeouts
+            .dns_ms
+            .saturating_add(timeouts.connect_ms)
+            .saturating_add(timeouts.tls_handshake_ms);
+        let mut handle = with_timeout(millis(connect_ms), self.client.request(method, request.url))
+            .await
+            .map_err(|_| NetworkError::Timeout(TimeoutPhase::Connect))?
+            .map_err(map_error)?
+            .headers(request.headers)
+            .body(request.body);
+        let response = with_timeout(millis(timeouts.first_byte_ms), handle.send(self.rx_buffer))
+            .await
+            .map_err(|_| NetworkError::Timeout(TimeoutPhase::FirstByte))?
+            .map_err(map_error)?;
+        
+        // reqwless does not follow redirects; they are handed back to the client
+        let mut head = ResponseHead::new(response.status.0);
+        for (name, value) in response.headers() {
+            let value = core::str::from_utf8(value).map_err(|_| NetworkError::InvalidResponse)?;
+            head.parse_header(name, value)?;
+        }
+        head.content_length = response.content_length.map(|len| len as u32);
+        head.check_status()?;
+        if !head.expects_body() {
+            return Ok(head);
+        }
+        sink.begin(&head).await?;
+        
+        // reqwless decodes chunked and length-delimited bodies itself
+        let mut reader = response.body().reader();
+        let mut chunk = [0u8; BODY_CHUNK_SIZE];
+        let mut received: u32 = 0;
+        loop {
+            let n = with_timeout(millis(timeouts.stall_ms), reader.read(&mut chunk))
+                .await
+                .map_err(|_| NetworkError::Timeout(TimeoutPhase::Stall))?
+                .map_err(map_error)?;
+            if n == 0 {
+                break;
+            }
+            received = received.saturating_add(n as u32);
+            sink.write(&chunk[..n]).await?;
+        }
+        if head.content_length.is_some_and(|length| received < length) {
+            return Err(NetworkError::Truncated.into());
+        }
+        
+        Ok(head)
+    }
+}
+
+/// Convert a configured timeout into a duration
+fn millis(ms: u32) -> Duration {
+    Duration::from_millis(ms as u64)
+}
+
+/// Map a reqwless error onto the library's network errors
+fn map_error(error: reqwless::Error) -> NetworkError {
+    match error {
+        reqwless::Error::Dns => NetworkError::DnsFailed,
+        reqwless::Error::Tls(_) => NetworkError::TlsFailed,
+        reqwless::Error::BufferTooSmall => NetworkError::ResponseTooLarge,
+        reqwless::Error::Codec => NetworkError::InvalidResponse,
+        _ => NetworkError::ConnectionFailed,
+    }
+}
@@ -0,0 +1,536 @@
+//! Update transport over a serial link (USB-serial or UART)
+//!
+//! For the production line and field service, where there is a cable but no
+//! Wi-Fi. The host-side sender (`tools/ota-sender`) serves a release directory
+//! laid out like the HTTP server, and the device runs the normal update flow,
+//! so manifest signatures, SHA-256 checks, checkpoints and resume all behave as
+//! over the network. Only the path and query of request URLs are sent; the
+//! host part is ignored.
+//!
+//! Frames are `A5 5A | kind | seq | len | payload | crc`, with little-endian
+//! 16-bit fields and a CRC-16/CCITT over kind through payload. The device sends
+//! `Get` (resume offset and request target) and the sender answers with `Head`
+//! (status and total length) followed by `Data` frames, stop-and-wait: every
+//! frame is acknowledged with `Ack`, and a corrupt, missing or out-of-order
+//! frame is answered with `Nak` naming the sequence number to resend.
+
+use super::OtaTransport;
+use crate::config::{OtaConfig, TimeoutConfig};
+use crate::error::{NetworkError, Result, TimeoutPhase};
+use crate::http::{BodySink, ContentRange, Method, Request, ResponseHead};
+use crate::url::Url;
+
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+
+/// Largest payload carried by a single frame
+pub const MAX_FRAME_PAYLOAD: usize = 512;
+
+/// Bytes marking the start of a frame
+const SYNC: [u8; 2] = [0xA5, 0x5A];
+
+/// Size of the kind, sequence number and length fields
+const HEADER_SIZE: usize = 5;
+
+/// Bytes read from the port at a time
+const RX_CHUNK_SIZE: usize = 64;
+
+/// Times a request or acknowledgement is repeated before giving up
+const MAX_RESENDS: u8 = 5;
+
+// Frame kinds
+const KIND_GET: u8 = 0x01;
+const KIND_HEAD: u8 = 0x02;
+const KIND_DATA: u8 = 0x03;
+const KIND_ACK: u8 = 0x04;
+const KIND_NAK: u8 = 0x05;
+
+/// Transport fetching updates from a host over a serial port
+pub struct SerialTransport<P> {
+    port: P,
+    timeouts: TimeoutConfig,
+    rx: [u8; RX_CHUNK_SIZE],
+    rx_pos: usize,
+    rx_len: usize,
+}
+
+/// Header of a received frame; the payload is left in the caller's buffer
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    kind: u8,
+    seq: u16,
+    len: usize,
+}
+
+impl<P> SerialTransport<P>
+where
+    P: Read + Write,
+{
+    /// Create a transport talking to the sender over `port`
+    ///
+    /// The port must already be configured (baud rate, 8N1, no flow control).
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            timeouts: TimeoutConfig::default(),
+            rx: [0; RX_CHUNK_SIZE],
+            rx_pos: 0,
+            rx_len: 0,
+        }
+    }
+
+    /// Give back the serial port
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+
+    async fn send(&mut self, kind: u8, seq: u16, payload: &[u8]) -> Result<()> {
+        let mut header = [0u8; SYNC.len() + HEADER_SIZE];
+        header[..2].copy_from_slice(&SYNC);
+        header[2] = kind;
+        header[3..5].copy_from_slice(&seq.to_le_bytes());
+        header[5..7].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        let crc = crc16(crc16(0xFFFF, &header[2..]), payload);
+
+        self.write_all(&header).await?;
+        self.write_all(payload).await?;
+        self.write_all(&crc.to_le_bytes()).await?;
+        self.port
+            .flush()
+            .await
+            .map_err(|_| NetworkError::ConnectionFailed)?;
+        Ok(())
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        self.port
+            .write_all(data)
+            .await
+            .map_err(|_| NetworkError::ConnectionFailed.into())
+    }
+
+    /// Receive the next frame into `payload`, or `None` if it was corrupt
+    ///
+    /// Bytes before the sync pattern are skipped. If the read is cancelled by
+    /// a timeout, the partial frame is dropped and the next call resyncs.
+    async fn receive(&mut self, payload: &mut [u8; MAX_FRAME_PAYLOAD]) -> Result<Option<Frame>> {
+        let mut previous = 0;
+        loop {
+            let byte = self.read_byte().await?;
+            if previous == SYNC[0] && byte == SYNC[1] {
+                break;
+            }
+            previous = byte;
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        self.read_exact(&mut header).await?;
+        let len = u16::from_le_bytes([header[3], header[4]]) as usize;
+        if len > MAX_FRAME_PAYLOAD {
+            return Ok(None);
+        }
+        self.read_exact(&mut payload[..len]).await?;
+        let mut crc = [0u8; 2];
+        self.read_exact(&mut crc).await?;
+
+        if crc16(crc16(0xFFFF, &header), &payload[..len]) != u16::from_le_bytes(crc) {
+            return Ok(None);
+        }
+        Ok(Some(Frame {
+            kind: header[0],
+            seq: u16::from_le_bytes([header[1], header[2]]),
+            len,
+        }))
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        for byte in buf {
+            *byte = self.read_byte().await?;
+        }
+        Ok(())
+    }
+
+    async fn read_byte(&mut self) -> Result<u8> {
+        if self.rx_pos == self.rx_len {
+            let n = self
+                .port
+                .read(&mut self.rx)
+                .await
+                .map_err(|_| NetworkError::ConnectionFailed)?;
+            if n == 0 {
+                return Err(NetworkError::ConnectionFailed.into());
+            }
+            self.rx_pos = 0;
+            self.rx_len = n;
+        }
+        let byte = self.rx[self.rx_pos];
+        self.rx_pos += 1;
+        Ok(byte)
+    }
+
+    /// Send `Get` until the sender answers with a `Head`
+    async fn request_head(&mut self, get: &[u8], frame: &mut [u8; MAX_FRAME_PAYLOAD]) -> Result<(u16, u32)> {
+        for _ in 0..=MAX_RESENDS {
+            self.send(KIND_GET, 0, get).await?;
+            let received = with_timeout(millis(self.timeouts.first_byte_ms), self.receive(frame)).await;
+            // Anything but a head (stale data from an aborted transfer, noise) means asking again
+            if let Ok(result) = received {
+                if let Some(head) = result? {
+                    if head.kind == KIND_HEAD && head.len == 6 {
+                        let status = u16::from_le_bytes([frame[0], frame[1]]);
+                        let total = u32::from_le_bytes([frame[2], frame[3], frame[4], frame[5]]);
+                        return Ok((status, total));
+                    }
+                }
+            }
+        }
+        Err(NetworkError::Timeout(TimeoutPhase::FirstByte).into())
+    }
+}
+
+impl<P> OtaTransport for SerialTransport<P>
+where
+    P: Read + Write,
+{
+    fn configure(&mut self, config: &OtaConfig) {
+        self.timeouts = config.timeouts;
+    }
+
+    async fn fetch<K: BodySink>(&mut self, request: &Request<'_>, sink: &mut K) -> Result<ResponseHead> {
+        // The sender only serves files
+        if request.method != Method::Get {
+            return Err(NetworkError::MethodNotSupported.into());
+        }
+        let target = Url::parse(request.url)?.request_target()?;
+        let offset = request.range_start().unwrap_or(0);
+
+        let mut get: Vec<u8, MAX_FRAME_PAYLOAD> = Vec::new();
+        get.extend_from_slice(&offset.to_le_bytes())
+            .and_then(|_| get.extend_from_slice(target.as_bytes()))
+            .map_err(|_| NetworkError::RequestTooLarge)?;
+
+        let mut frame = [0u8; MAX_FRAME_PAYLOAD];
+        let (status, total) = self.request_head(&get, &mut frame).await?;
+
+        let mut head = ResponseHead::new(status);
+        if head.is_partial() {
+            head.content_range = Some(ContentRange {
+                start: offset,
+                end: total.saturating_sub(1),
+                total: Some(total),
+            });
+            head.content_length = Some(total.saturating_sub(offset));
+        } else {
+            head.content_length = Some(total);
+        }
+        head.check_status()?;
+        if !head.expects_body() {
+            return Ok(head);
+        }
+        sink.begin(&head).await?;
+
+        let mut remaining = head.content_length.unwrap_or(0) as usize;
+        let mut expected: u16 = 0;
+        let mut silence = 0;
+        while remaining > 0 {
+            let received = with_timeout(millis(self.timeouts.stall_ms), self.receive(&mut frame)).await;
+            match received {
+                Ok(result) => match result? {
+                    Some(data) if data.kind == KIND_DATA && data.seq == expected => {
+                        if data.len > remaining {
+                            return Err(NetworkError::InvalidResponse.into());
+                        }
+                        sink.write(&frame[..data.len]).await?;
+                        remaining -= data.len;
+                        self.send(KIND_ACK, expected, &[]).await?;
+                        expected = expected.wrapping_add(1);
+                        silence = 0;
+                    }
+                    // Our acknowledgement was lost; the sender is repeating itself
+                    Some(data) if data.kind == KIND_DATA && data.seq == expected.wrapping_sub(1) => {
+                        self.send(KIND_ACK, data.seq, &[]).await?;
+                    }
+                    // A repeated `Get` was answered twice
+                    Some(data) if data.kind == KIND_HEAD => {}
+                    _ => self.send(KIND_NAK, expected, &[]).await?,
+                },
+                Err(_) => {
+                    silence += 1;
+                    if silence > MAX_RESENDS {
+                        return Err(NetworkError::Timeout(TimeoutPhase::Stall).into());
+                    }
+                    self.send(KIND_NAK, expected, &[]).await?;
+                }
+            }
+        }
+
+        Ok(head)
+    }
+}
+
+/// Update a CRC-16/CCITT-FALSE checksum with `data`
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Convert a configured timeout into a duration
+fn millis(ms: u32) -> Duration {
+    Duration::from_millis(ms as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing::{block_on, join, End, Pipe};
+    use std::vec::Vec as StdVec;
+
+    /// How long the stand-in sender waits for an acknowledgement
+    const ACK_TIMEOUT: Duration = Duration::from_millis(30);
+
+    /// Damage done to the first transmission of a frame
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Fault {
+        /// The `Head` never arrives
+        DropHead,
+        /// Data frame `seq` never arrives
+        Drop(u16),
+        /// Data frame `seq` arrives with a flipped payload byte
+        Corrupt(u16),
+        /// Line noise before data frame `seq`
+        Noise(u16),
+        /// The device's acknowledgement of `seq` never arrives
+        DropAck(u16),
+    }
+
+    /// What the stand-in sender saw
+    #[derive(Debug, Default)]
+    struct Log {
+        gets: usize,
+        data_frames: usize,
+        naks: usize,
+        /// Faults that never got to happen
+        unused: StdVec<Fault>,
+    }
+
+    fn encode(kind: u8, seq: u16, payload: &[u8]) -> StdVec<u8> {
+        let mut frame = StdVec::from(SYNC);
+        frame.push(kind);
+        frame.extend_from_slice(&seq.to_le_bytes());
+        frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        frame.extend_from_slice(payload);
+        let crc = crc16(0xFFFF, &frame[2..]);
+        frame.extend_from_slice(&crc.to_le_bytes());
+        frame
+    }
+
+    /// Read the device's next frame as (kind, seq, payload)
+    async fn read_frame(line: &Pipe) -> (u8, u16, StdVec<u8>) {
+        let mut byte = [0u8];
+        let mut previous = 0;
+        loop {
+            line.read(&mut byte).await;
+            if previous == SYNC[0] && byte[0] == SYNC[1] {
+                break;
+            }
+            previous = byte[0];
+        }
+        let mut rest = StdVec::new();
+        while rest.len() < HEADER_SIZE {
+            line.read(&mut byte).await;
+            rest.push(byte[0]);
+        }
+        let len = u16::from_le_bytes([rest[3], rest[4]]) as usize;
+        while rest.len() < HEADER_SIZE + len + 2 {
+            line.read(&mut byte).await;
+            rest.push(byte[0]);
+        }
+        let seq = u16::from_le_bytes([rest[1], rest[2]]);
+        (rest[0], seq, rest[HEADER_SIZE..HEADER_SIZE + len].to_vec())
+    }
+
+    /// Serve `body` like `tools/ota-sender`, damaging frames as listed in `faults`
+    ///
+    /// Answers `Get`s until nothing arrives for a while, then returns its log.
+    async fn sender(rx: &Pipe, tx: &Pipe, body: &[u8], faults: &[Fault]) -> Log {
+        let mut pending: StdVec<Fault> = faults.to_vec();
+        let mut log = Log::default();
+        let mut next = None;
+        'serve: loop {
+            let get = match next.take() {
+                Some(get) => get,
+                None => match with_timeout(Duration::from_millis(500), read_frame(rx)).await {
+                    Ok((KIND_GET, _, payload)) => payload,
+                    Ok(_) => continue,
+                    Err(_) => {
+                        log.unused = pending;
+                        return log;
+                    }
+                },
+            };
+            log.gets += 1;
+            let offset = u32::from_le_bytes(get[..4].try_into().unwrap()) as usize;
+            let mut head = [0u8; 6];
+            head[..2].copy_from_slice(&(if offset > 0 { 206u16 } else { 200 }).to_le_bytes());
+            head[2..].copy_from_slice(&(body.len() as u32).to_le_bytes());
+            if !take(&mut pending, Fault::DropHead) {
+                tx.push(&encode(KIND_HEAD, 0, &head));
+            }
+
+            for (seq, chunk) in body[offset..].chunks(MAX_FRAME_PAYLOAD).enumerate() {
+                let seq = seq as u16;
+                loop {
+                    log.data_frames += 1;
+                    let mut frame = encode(KIND_DATA, seq, chunk);
+                    if take(&mut pending, Fault::Noise(seq)) {
+                        tx.push(&[0x00, SYNC[0], 0x13, SYNC[0]]);
+                    }
+                    if take(&mut pending, Fault::Corrupt(seq)) {
+                        frame[9] ^= 0x40;
+                    }
+                    if !take(&mut pending, Fault::Drop(seq)) {
+                        tx.push(&frame);
+                    }
+
+                    match with_timeout(ACK_TIMEOUT, read_frame(rx)).await {
+                        Ok((KIND_ACK, acked, _))
+                            if acked == seq && !take(&mut pending, Fault::DropAck(seq)) =>
+                        {
+                            break;
+                        }
+                        Ok((KIND_NAK, _, _)) => log.naks += 1,
+                        Ok((KIND_GET, _, payload)) => {
+                            next = Some(payload);
+                            continue 'serve;
+                        }
+                        // Lost or late acknowledgement, or silence
+                        Ok(_) | Err(_) => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remove `fault` from the pending list, returning whether it was there
+    fn take(pending: &mut StdVec<Fault>, fault: Fault) -> bool {
+        match pending.iter().position(|f| *f == fault) {
+            Some(index) => {
+                pending.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn body() -> StdVec<u8> {
+        (0..3000u32).map(|i| (i * 13 % 256) as u8).collect()
+    }
+
+    fn transport<'a>(to_device: &'a Pipe, to_sender: &'a Pipe) -> SerialTransport<End<'a>> {
+        let mut transport = SerialTransport::new(End { rx: to_device, tx: to_sender });
+        transport.timeouts = TimeoutConfig {
+            first_byte_ms: 100,
+            stall_ms: 100,
+            ..TimeoutConfig::default()
+        };
+        transport
+    }
+
+    fn fetch(
+        headers: &[(&str, &str)],
+        body: &[u8],
+        faults: &[Fault],
+    ) -> (Result<ResponseHead>, heapless::Vec<u8, 4096>, Log) {
+        let (to_device, to_sender) = (Pipe::default(), Pipe::default());
+        let mut transport = transport(&to_device, &to_sender);
+        let mut sink = heapless::Vec::new();
+        let request = Request::get("http://sender/fw/firmware.bin").with_headers(headers);
+        let (result, log) = block_on(join(
+            transport.fetch(&request, &mut sink),
+            sender(&to_sender, &to_device, body, faults),
+        ));
+        (result, sink, log)
+    }
+
+    #[test]
+    fn clean_transfer() {
+        let body = body();
+        let (head, sink, log) = fetch(&[], &body, &[]);
+        let head = head.unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(head.content_length, Some(3000));
+        assert_eq!(&sink[..], &body[..]);
+        assert_eq!((log.gets, log.data_frames, log.naks), (1, 6, 0));
+    }
+
+    #[test]
+    fn dropped_and_corrupt_frames_are_resent() {
+        let body = body();
+        let faults = [
+            Fault::DropHead,
+            Fault::Drop(1),
+            Fault::Corrupt(2),
+            Fault::Noise(3),
+            Fault::DropAck(4),
+        ];
+        let (head, sink, log) = fetch(&[], &body, &faults);
+        assert_eq!(head.unwrap().status, 200);
+        assert_eq!(&sink[..], &body[..]);
+
+        // The lost head is asked for again, the corrupt frame is refused
+        assert!(log.gets >= 2, "{log:?}");
+        assert!(log.naks >= 1, "{log:?}");
+        assert!(log.data_frames > 6, "{log:?}");
+        assert!(log.unused.is_empty(), "{log:?}");
+    }
+
+    #[test]
+    fn range_resumes_mid_file() {
+        let body = body();
+        let (head, sink, _) = fetch(&[("Range", "bytes=1000-")], &body, &[Fault::Corrupt(0)]);
+        let head = head.unwrap();
+        assert_eq!(head.status, 206);
+        assert_eq!(head.content_length, Some(2000));
+        assert_eq!(head.content_range.map(|r| (r.start, r.total)), Some((1000, Some(3000))));
+        assert_eq!(&sink[..], &body[1000..]);
+    }
+
+    #[test]
+    fn silent_sender_times_out() {
+        let (to_device, to_sender) = (Pipe::default(), Pipe::default());
+        let mut transport = transport(&to_device, &to_sender);
+        let mut sink: heapless::Vec<u8, 4096> = heapless::Vec::new();
+        let request = Request::get("http://sender/fw/firmware.bin");
+        assert_eq!(
+            block_on(transport.fetch(&request, &mut sink)),
+            Err(NetworkError::Timeout(TimeoutPhase::FirstByte).into())
+        );
+        // The request was repeated before giving up
+        let get = encode(KIND_GET, 0, b"\0\0\0\0/fw/firmware.bin");
+        assert_eq!(to_sender.pending(), get.repeat(MAX_RESENDS as usize + 1));
+    }
+
+    #[test]
+    fn posts_are_refused() {
+        let (to_device, to_sender) = (Pipe::default(), Pipe::default());
+        let mut transport = transport(&to_device, &to_sender);
+        let mut sink: heapless::Vec<u8, 16> = heapless::Vec::new();
+        let request = Request::post("http://sender/report", b"{}");
+        assert_eq!(
+            block_on(transport.fetch(&request, &mut sink)),
+            Err(NetworkError::MethodNotSupported.into())
+        );
+    }
+}
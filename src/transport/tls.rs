@@ -0,0 +1,1132 @@
+//! Transport over embassy-net TCP sockets and embedded-tls
+//!
+//! The transport comes in two halves, as embassy-net's stack does. The
+//! [`TlsTransport`] handed to the client only speaks HTTP; the connection
+//! itself is opened, used and closed by a [`TlsRunner`] holding the socket
+//! and TLS buffers, which runs alongside it and takes requests through a
+//! [`TlsLink`]:
+//!
+//! ```no_run
+//! static TLS: TlsLink = TlsLink::new();
+//!
+//! let (transport, runner) = TlsTransport::new(&TLS, stack, rng, (&mut tcp_rx, &mut tcp_tx), (&mut tls_rx, &mut tls_tx));
+//! spawner.spawn(tls_task(runner))?;
+//!
+//! #[embassy_executor::task]
+//! async fn tls_task(runner: TlsRunner<'static, Rng>) -> ! {
+//!     runner.run().await
+//! }
+//! ```
+//!
+//! embedded-tls keeps a connection's buffers until the connection is
+//! dropped and never hands them back. The runner lends them to one
+//! connection at a time inside its own loop, so a connection the server has
+//! closed can be replaced by a new one over the same buffers.
+
+use super::trust::OtaProvider;
+use super::OtaTransport;
+use crate::config::{OtaConfig, TimeoutConfig, TlsTrust};
+#[cfg(feature = "mdns")]
+use crate::discovery;
+use crate::error::{ConfigError, Error, NetworkError, Result, TimeoutPhase};
+use crate::http::{self, BodySink, Request, ResponseHead};
+use crate::identity::ClientIdentity;
+use crate::url::{Scheme, Url};
+
+use core::future::{poll_fn, Future};
+use core::pin::pin;
+use core::task::Poll;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpAddress, Stack};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{with_timeout, Duration, Instant};
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_tls::alert::AlertDescription;
+use embedded_tls::{Aes128GcmSha256, Certificate, TlsConfig, TlsConnection, TlsContext, TlsError};
+use heapless::Vec;
+use rand_core::{CryptoRng, RngCore};
+
+/// Largest amount of data passed between transport and runner at once
+const LINK_CHUNK_SIZE: usize = 1024;
+
+/// HTTPS transport using embassy-net and embedded-tls
+///
+/// By default a fresh TCP connection and TLS session are set up for every
+/// request. With [`with_keep_alive`] the connection is kept open instead, so
+/// a manifest check and the downloads that follow share one handshake. It is
+/// replaced transparently when the server closes it, when a request goes to
+/// another origin, or after sitting idle.
+///
+/// The server certificate is checked according to [`OtaConfig::tls_trust`],
+/// and a client certificate is presented if a [`ClientIdentity`] has been set.
+///
+/// Requests only make progress while the [`TlsRunner`] created with the
+/// transport is running.
+///
+/// [`with_keep_alive`]: TlsTransport::with_keep_alive
+pub struct TlsTransport<'a> {
+    remote: Remote<'a>,
+    trust: TlsTrust,
+    identity: Option<ClientIdentity>,
+    timeouts: TimeoutConfig,
+    keep_alive: Option<Duration>,
+    session: Option<Session>,
+}
+
+/// Owner of the network side of a [`TlsTransport`]
+///
+/// Holds the socket and TLS buffers and opens connections on the
+/// transport's behalf. [`run`](TlsRunner::run) must be polled for as long as
+/// the transport is in use, typically in a task of its own.
+pub struct TlsRunner<'a, R> {
+    link: &'a TlsLink,
+    stack: Stack<'a>,
+    rng: R,
+    tcp_rx: &'a mut [u8],
+    tcp_tx: &'a mut [u8],
+    tls_rx: &'a mut [u8],
+    tls_tx: &'a mut [u8],
+    identity: Option<ClientIdentity>,
+}
+
+/// Channel between a [`TlsTransport`] and its [`TlsRunner`]
+///
+/// Usually a `static`, so the runner can be given to a task of its own.
+pub struct TlsLink {
+    commands: Channel<CriticalSectionRawMutex, Command, 1>,
+    replies: Channel<CriticalSectionRawMutex, Reply, 1>,
+}
+
+/// Connection kept open between requests, as seen by the transport
+struct Session {
+    origin: Url,
+    last_used: Instant,
+    /// Set while a request is in flight, so a request abandoned halfway
+    /// leaves the connection marked as unusable
+    busy: bool,
+}
+
+/// The transport's end of the link, numbering its commands
+struct Remote<'a> {
+    link: &'a TlsLink,
+    last_id: u32,
+}
+
+/// Request from the transport to the runner
+struct Command {
+    id: u32,
+    op: Op,
+}
+
+/// What the runner is asked to do
+// Passed by value through a one-slot channel; boxing would need an allocator
+#[allow(clippy::large_enum_variant)]
+enum Op {
+    /// Replace any open connection with one to `origin`
+    Connect(Connect),
+    /// Read up to this many bytes
+    Read(usize),
+    /// Write all of this
+    Write(Vec<u8, LINK_CHUNK_SIZE>),
+    /// Flush written data
+    Flush,
+    /// Close the open connection, if any
+    Close,
+}
+
+/// Everything the runner needs to open a connection
+struct Connect {
+    origin: Url,
+    trust: TlsTrust,
+    /// Identity to present from now on, handed over once
+    identity: Option<ClientIdentity>,
+    timeouts: TimeoutConfig,
+}
+
+/// Answer to the command with the same id
+struct Reply {
+    id: u32,
+    outcome: Outcome,
+}
+
+/// Result of a command
+#[allow(clippy::large_enum_variant)]
+enum Outcome {
+    Connected(core::result::Result<(), NetworkError>),
+    /// Data read (empty for writes and flushes, or at the end of the stream)
+    Io(core::result::Result<Vec<u8, LINK_CHUNK_SIZE>, TlsError>),
+    Closed,
+}
+
+/// Why the runner stopped relaying on an open connection
+enum Ending {
+    /// A command arrived while an operation was pending, abandoning it
+    Interrupted(Command),
+    /// The transport closed the connection with the command numbered this
+    Close(u32),
+    /// The transport wants a connection elsewhere
+    Reconnect(Command),
+}
+
+impl<'a> TlsTransport<'a> {
+    /// Create a new transport and the runner doing its networking
+    ///
+    /// The random number generator is used for the TLS handshake and must be
+    /// cryptographically secure (e.g. the ESP32-C3 hardware RNG). The TLS
+    /// receive buffer should be at least 16 KiB to hold a full TLS record.
+    pub fn new<R>(
+        link: &'a TlsLink,
+        stack: Stack<'a>,
+        rng: R,
+        tcp_buffers: (&'a mut [u8], &'a mut [u8]),
+        tls_buffers: (&'a mut [u8], &'a mut [u8]),
+    ) -> (Self, TlsRunner<'a, R>)
+    where
+        R: CryptoRng + RngCore,
+    {
+        let runner = TlsRunner {
+            link,
+            stack,
+            rng,
+            tcp_rx: tcp_buffers.0,
+            tcp_tx: tcp_buffers.1,
+            tls_rx: tls_buffers.0,
+            tls_tx: tls_buffers.1,
+            identity: None,
+        };
+        (Self::over(link), runner)
+    }
+    
+    /// Create the transport's half, talking to whatever serves `link`
+    fn over(link: &'a TlsLink) -> Self {
+        Self {
+            remote: Remote { link, last_id: 0 },
+            trust: TlsTrust::None,
+            identity: None,
+            timeouts: TimeoutConfig::default(),
+            keep_alive: None,
+            session: None,
+        }
+    }
+
+    /// Keep the connection open between requests
+    ///
+    /// A connection left idle for `idle` or longer is replaced rather than
+    /// reused; pick something below the server's own keep-alive timeout.
+    pub fn with_keep_alive(mut self, idle: Duration) -> Self {
+        self.keep_alive = Some(idle);
+        self
+    }
+}
+
+impl OtaTransport for TlsTransport<'_> {
+    fn configure(&mut self, config: &OtaConfig) {
+        self.trust = config.tls_trust.clone();
+        self.timeouts = config.timeouts;
+    }
+    
+    fn set_client_identity(&mut self, identity: ClientIdentity) {
+        self.identity = Some(identity);
+    }
+    
+    async fn fetch<K: BodySink>(&mut self, request: &Request<'_>, sink: &mut K) -> Result<ResponseHead> {
+        let url = Url::parse(request.url)?;
+        if url.scheme != Scheme::Https {
+            return Err(ConfigError::UnsupportedScheme.into());
+        }
+        
+        let mut head = [0u8; http::MAX_REQUEST_HEAD_SIZE];
+        let head_len = http::write_request(
+            &mut head,
+            request.method,
+            &url.authority()?,
+            &url.request_target()?,
+            request.headers,
+            request.body.len(),
+            self.keep_alive.is_some(),
+        )?;
+        let head = &head[..head_len];
+        
+        let reused = self.open_session(&url).await?;
+        let mut result = self.exchange(head, request.body, sink).await;
+        if reused && matches!(result, Err(Error::Network(NetworkError::ConnectionClosed))) {
+            // The server dropped the idle connection before answering; nothing
+            // reached the sink, so the request can go out again on a new one
+            self.connect(&url).await?;
+            result = self.exchange(head, request.body, sink).await;
+        }
+        
+        let reusable = self.keep_alive.is_some()
+            && result.as_ref().is_ok_and(ResponseHead::is_reusable);
+        if !reusable {
+            self.disconnect().await;
+        }
+        
+        result
+    }
+}
+
+impl TlsTransport<'_> {
+    /// Close the connection kept open between requests, if any
+    ///
+    /// Worth calling once the update work is done, so the server and the
+    /// network stack can let go of it.
+    pub async fn disconnect(&mut self) {
+        if self.session.take().is_some() {
+            self.remote.call(Op::Close).await;
+        }
+    }
+    
+    /// Make sure a connection to the origin of `url` is open
+    ///
+    /// Returns whether an already open connection is being reused.
+    async fn open_session(&mut self, url: &Url) -> Result<bool> {
+        let reusable = match (&self.session, self.keep_alive) {
+            (Some(session), Some(idle)) => {
+                !session.busy
+                    && session.origin.same_origin(url)
+                    && session.last_used.elapsed() < idle
+            }
+            _ => false,
+        };
+        if !reusable {
+            self.connect(url).await?;
+        }
+        Ok(reusable)
+    }
+    
+    /// Replace any open connection with a new one to the origin of `url`
+    async fn connect(&mut self, url: &Url) -> Result<()> {
+        self.session = None;
+        let connect = Connect {
+            origin: url.clone(),
+            trust: self.trust.clone(),
+            identity: self.identity.take(),
+            timeouts: self.timeouts,
+        };
+        match self.remote.call(Op::Connect(connect)).await {
+            Outcome::Connected(Ok(())) => {}
+            Outcome::Connected(Err(error)) => return Err(error.into()),
+            _ => return Err(NetworkError::ConnectionFailed.into()),
+        }
+        
+        self.session = Some(Session {
+            origin: url.clone(),
+            last_used: Instant::now(),
+            busy: false,
+        });
+        Ok(())
+    }
+    
+    /// Send a request over the open connection and read the response
+    async fn exchange<K: BodySink>(&mut self, head: &[u8], body: &[u8], sink: &mut K) -> Result<ResponseHead> {
+        let session = self.session.as_mut().ok_or(NetworkError::ConnectionFailed)?;
+        session.busy = true;
+        
+        // In TLS 1.3 a rejected client certificate is only reported after the
+        // handshake, so watch the exchange for the server's alert as well
+        let mut watched = AlertWatch::new(&mut self.remote);
+        let result = match http::exchange(&mut watched, head, body, sink, &self.timeouts).await {
+            Err(_) if watched.client_auth_rejected => {
+                Err(NetworkError::ClientAuthRejected.into())
+            }
+            other => other,
+        };
+        
+        if result.is_ok() {
+            session.busy = false;
+            session.last_used = Instant::now();
+        }
+        result
+    }
+}
+
+impl<R> TlsRunner<'_, R>
+where
+    R: CryptoRng + RngCore,
+{
+    /// Open, use and close connections as the transport asks
+    pub async fn run(mut self) -> ! {
+        let mut next = None;
+        loop {
+            let command = match next.take() {
+                Some(command) => command,
+                None => self.link.commands.receive().await,
+            };
+            next = match command.op {
+                Op::Connect(connect) => self.serve(command.id, connect).await,
+                Op::Close => {
+                    self.link.reply(command.id, Outcome::Closed).await;
+                    None
+                }
+                // Nothing is open to read from or write to
+                _ => {
+                    let closed = Outcome::Io(Err(TlsError::ConnectionClosed));
+                    self.link.reply(command.id, closed).await;
+                    None
+                }
+            };
+        }
+    }
+    
+    /// Open a connection and serve it until the transport is done with it
+    ///
+    /// The connection borrows the buffers for the duration of this call, so
+    /// the next one can have them again. Returns a command that interrupted
+    /// the connection, to be carried out next.
+    async fn serve(&mut self, id: u32, connect: Connect) -> Option<Command> {
+        let Self {
+            link,
+            stack,
+            rng,
+            tcp_rx,
+            tcp_tx,
+            tls_rx,
+            tls_tx,
+            identity,
+        } = self;
+        let link = *link;
+        if connect.identity.is_some() {
+            *identity = connect.identity;
+        }
+        let timeouts = &connect.timeouts;
+        let origin = &connect.origin;
+        
+        let address = match resolve(*stack, origin, timeouts).await {
+            Ok(address) => address,
+            Err(error) => {
+                link.reply(id, Outcome::Connected(Err(error))).await;
+                return None;
+            }
+        };
+        
+        // Connect the TCP socket
+        let mut socket = TcpSocket::new(*stack, tcp_rx, tcp_tx);
+        let connected = with_timeout(millis(timeouts.connect_ms), socket.connect((address, origin.port)))
+            .await
+            .map_err(|_| NetworkError::Timeout(TimeoutPhase::Connect))
+            .and_then(|result| result.map_err(|_| NetworkError::ConnectionFailed));
+        if let Err(error) = connected {
+            link.reply(id, Outcome::Connected(Err(error))).await;
+            return None;
+        }
+        
+        // TLS handshake, verifying the server and presenting our identity as configured
+        let mut tls_config = TlsConfig::new().with_server_name(&origin.host);
+        if let Some(identity) = identity {
+            tls_config = tls_config
+                .with_cert(Certificate::X509(identity.certificate()))
+                .with_priv_key(identity.private_key());
+        }
+        let mut tls = TlsConnection::new(socket, tls_rx, tls_tx);
+        let handshake = tls.open(TlsContext::new(
+            &tls_config,
+            OtaProvider::new(&mut *rng, &connect.trust),
+        ));
+        let opened = with_timeout(millis(timeouts.tls_handshake_ms), handshake)
+            .await
+            .map_err(|_| NetworkError::Timeout(TimeoutPhase::TlsHandshake))
+            .and_then(|result| result.map_err(map_tls_error));
+        let failed = opened.is_err();
+        link.reply(id, Outcome::Connected(opened)).await;
+        if failed {
+            return None;
+        }
+        
+        match relay(link, &mut tls).await {
+            Ending::Interrupted(next) => Some(next),
+            Ending::Close(id) => {
+                close(tls, timeouts).await;
+                link.reply(id, Outcome::Closed).await;
+                None
+            }
+            Ending::Reconnect(next) => {
+                close(tls, timeouts).await;
+                Some(next)
+            }
+        }
+    }
+}
+
+/// Carry out reads, writes and flushes on an open connection until asked for something else
+async fn relay<C>(link: &TlsLink, conn: &mut C) -> Ending
+where
+    C: Read + Write + ErrorType<Error = TlsError>,
+{
+    loop {
+        let command = link.commands.receive().await;
+        let result = match command.op {
+            Op::Read(len) => {
+                let mut data = Vec::new();
+                let _ = data.resize_default(len.min(LINK_CHUNK_SIZE));
+                match link.unless_interrupted(conn.read(&mut data)).await {
+                    Ok(read) => read.map(|n| {
+                        data.truncate(n);
+                        data
+                    }),
+                    Err(next) => return Ending::Interrupted(next),
+                }
+            }
+            Op::Write(data) => match link.unless_interrupted(conn.write_all(&data)).await {
+                Ok(written) => written.map(|_| Vec::new()),
+                Err(next) => return Ending::Interrupted(next),
+            },
+            Op::Flush => match link.unless_interrupted(conn.flush()).await {
+                Ok(flushed) => flushed.map(|_| Vec::new()),
+                Err(next) => return Ending::Interrupted(next),
+            },
+            Op::Close => return Ending::Close(command.id),
+            Op::Connect(_) => return Ending::Reconnect(command),
+        };
+        link.reply(command.id, Outcome::Io(result)).await;
+    }
+}
+
+impl TlsLink {
+    /// Create an idle link
+    pub const fn new() -> Self {
+        Self {
+            commands: Channel::new(),
+            replies: Channel::new(),
+        }
+    }
+    
+    /// Answer the command numbered `id`
+    async fn reply(&self, id: u32, outcome: Outcome) {
+        self.replies.send(Reply { id, outcome }).await;
+    }
+    
+    /// Run `op`, unless a new command arrives first
+    ///
+    /// A command arriving means the transport gave up waiting for `op`, so it
+    /// is abandoned and the command returned instead.
+    async fn unless_interrupted<F: Future>(&self, op: F) -> core::result::Result<F::Output, Command> {
+        let mut op = pin!(op);
+        let mut next = pin!(self.commands.receive());
+        poll_fn(|cx| {
+            if let Poll::Ready(output) = op.as_mut().poll(cx) {
+                return Poll::Ready(Ok(output));
+            }
+            next.as_mut().poll(cx).map(Err)
+        })
+        .await
+    }
+}
+
+impl Default for TlsLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Remote<'_> {
+    /// Hand `op` to the runner and wait for its outcome
+    async fn call(&mut self, op: Op) -> Outcome {
+        self.last_id = self.last_id.wrapping_add(1);
+        let id = self.last_id;
+        self.link.commands.send(Command { id, op }).await;
+        loop {
+            let reply = self.link.replies.receive().await;
+            // Anything else answers a command given up on earlier
+            if reply.id == id {
+                return reply.outcome;
+            }
+        }
+    }
+    
+    /// Carry out a read, write or flush on the open connection
+    async fn io(&mut self, op: Op) -> core::result::Result<Vec<u8, LINK_CHUNK_SIZE>, TlsError> {
+        match self.call(op).await {
+            Outcome::Io(result) => result,
+            _ => Err(TlsError::ConnectionClosed),
+        }
+    }
+}
+
+impl ErrorType for Remote<'_> {
+    type Error = TlsError;
+}
+
+impl Read for Remote<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, TlsError> {
+        let data = self.io(Op::Read(buf.len())).await?;
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
+
+impl Write for Remote<'_> {
+    async fn write(&mut self, buf: &[u8]) -> core::result::Result<usize, TlsError> {
+        let chunk = &buf[..buf.len().min(LINK_CHUNK_SIZE)];
+        // Cannot fail: the chunk fits
+        let data = Vec::from_slice(chunk).unwrap_or_default();
+        self.io(Op::Write(data)).await?;
+        Ok(chunk.len())
+    }
+    
+    async fn flush(&mut self) -> core::result::Result<(), TlsError> {
+        self.io(Op::Flush).await.map(|_| ())
+    }
+}
+
+/// Resolve the server address (IP literals are returned as-is)
+async fn resolve(stack: Stack<'_>, url: &Url, timeouts: &TimeoutConfig) -> core::result::Result<IpAddress, NetworkError> {
+    // Nobody serves `.local` names but the hosts themselves
+    #[cfg(feature = "mdns")]
+    if discovery::is_local_name(&url.host) {
+        return match with_timeout(millis(timeouts.dns_ms), discovery::resolve(stack, &url.host)).await {
+            Ok(Ok(address)) => Ok(address),
+            Ok(Err(Error::Network(error))) => Err(error),
+            Ok(Err(_)) => Err(NetworkError::DnsFailed),
+            Err(_) => Err(NetworkError::Timeout(TimeoutPhase::Dns)),
+        };
+    }
+    
+    let query_type = if url.is_ipv6() { DnsQueryType::Aaaa } else { DnsQueryType::A };
+    let addresses = with_timeout(
+        millis(timeouts.dns_ms),
+        stack.dns_query(&url.host, query_type),
+    )
+    .await
+    .map_err(|_| NetworkError::Timeout(TimeoutPhase::Dns))?
+    .map_err(|_| NetworkError::DnsFailed)?;
+    addresses.first().copied().ok_or(NetworkError::DnsFailed)
+}
+
+/// Close a TLS session and the socket beneath it
+async fn close(tls: TlsConnection<'_, TcpSocket<'_>, Aes128GcmSha256>, timeouts: &TimeoutConfig) {
+    // Closing is a courtesy; don't hang on a connection that is gone
+    let stall = millis(timeouts.stall_ms);
+    let mut socket = match with_timeout(stall, tls.close()).await {
+        Ok(Ok(socket)) | Ok(Err((socket, _))) => socket,
+        Err(_) => return,
+    };
+    socket.abort();
+    let _ = with_timeout(stall, socket.flush()).await;
+}
+
+/// Convert a configured timeout into a duration
+fn millis(ms: u32) -> Duration {
+    Duration::from_millis(ms as u64)
+}
+
+/// Connection wrapper noting whether the server rejected our client certificate
+struct AlertWatch<'c, C> {
+    inner: &'c mut C,
+    client_auth_rejected: bool,
+}
+
+impl<'c, C> AlertWatch<'c, C> {
+    fn new(inner: &'c mut C) -> Self {
+        Self {
+            inner,
+            client_auth_rejected: false,
+        }
+    }
+    
+    fn observe<T>(&mut self, result: core::result::Result<T, TlsError>) -> core::result::Result<T, TlsError> {
+        if let Err(error) = &result {
+            self.client_auth_rejected |= is_client_auth_alert(error);
+        }
+        result
+    }
+}
+
+impl<C> ErrorType for AlertWatch<'_, C>
+where
+    C: ErrorType<Error = TlsError>,
+{
+    type Error = TlsError;
+}
+
+impl<C> Read for AlertWatch<'_, C>
+where
+    C: Read + ErrorType<Error = TlsError>,
+{
+    async fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, TlsError> {
+        let result = self.inner.read(buf).await;
+        self.observe(result)
+    }
+}
+
+impl<C> Write for AlertWatch<'_, C>
+where
+    C: Write + ErrorType<Error = TlsError>,
+{
+    async fn write(&mut self, buf: &[u8]) -> core::result::Result<usize, TlsError> {
+        let result = self.inner.write(buf).await;
+        self.observe(result)
+    }
+    
+    async fn flush(&mut self) -> core::result::Result<(), TlsError> {
+        let result = self.inner.flush().await;
+        self.observe(result)
+    }
+}
+
+/// Check whether an error is the server refusing our client certificate
+fn is_client_auth_alert(error: &TlsError) -> bool {
+    matches!(
+        error,
+        TlsError::HandshakeAborted(
+            _,
+            AlertDescription::CertificateRequired
+                | AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::UnknownCa
+                | AlertDescription::AccessDenied
+        )
+    )
+}
+
+/// Map a handshake failure onto the library's network errors
+fn map_tls_error(error: TlsError) -> NetworkError {
+    if is_client_auth_alert(&error) {
+        return NetworkError::ClientAuthRejected;
+    }
+    
+    match error {
+        TlsError::InvalidCertificate
+        | TlsError::InvalidSignature
+        | TlsError::InvalidSignatureScheme => NetworkError::CertificateRejected,
+        _ => NetworkError::TlsFailed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::testing::{block_on, join, Pipe};
+    use core::cell::RefCell;
+    use embedded_tls::alert::AlertLevel;
+    use std::string::String as StdString;
+    use std::vec::Vec as StdVec;
+
+    const PIN: [u8; 32] = [7; 32];
+
+    /// How the stand-in handles one connection attempt
+    enum Conn<'r> {
+        /// Complete the handshake and answer requests with these responses in turn
+        Accept(&'r [&'r [u8]]),
+        /// Fail the handshake
+        Refuse(NetworkError),
+        /// Complete the handshake, then fail the first read with this alert
+        Alert(AlertDescription),
+        /// Complete the handshake, answer the request with these bytes, then go quiet
+        Stall(&'r [u8]),
+    }
+
+    /// What the stand-in saw
+    #[derive(Default)]
+    struct Log {
+        /// Host, port, pinned trust and identity of each connection attempt
+        connects: StdVec<(StdString, u16, bool, bool)>,
+        requests: StdVec<StdString>,
+        closes: usize,
+    }
+
+    /// Stand-in for the runner and the HTTPS server behind it
+    ///
+    /// Answers reads a few bytes at a time, so bodies reach the sink in many
+    /// pieces. Once a connection runs out of responses, reads report the
+    /// end of the stream. Returns when every connection has been used up.
+    async fn stand_in(link: &TlsLink, conns: &[Conn<'_>], log: &RefCell<Log>) {
+        let mut next_conn = 0;
+        let mut responses: &[&[u8]] = &[];
+        let mut alert = None;
+        let mut quiet = false;
+        let mut received = StdVec::new();
+        let mut unsent: &[u8] = &[];
+        loop {
+            let command = link.commands.receive().await;
+            let outcome = match command.op {
+                Op::Connect(connect) => {
+                    log.borrow_mut().connects.push((
+                        StdString::from(connect.origin.host.as_str()),
+                        connect.origin.port,
+                        matches!(connect.trust, TlsTrust::SpkiSha256(pin) if pin == PIN),
+                        connect.identity.is_some(),
+                    ));
+                    received.clear();
+                    unsent = &[];
+                    alert = None;
+                    quiet = false;
+                    next_conn += 1;
+                    match &conns[next_conn - 1] {
+                        Conn::Accept(list) => {
+                            responses = list;
+                            Outcome::Connected(Ok(()))
+                        }
+                        Conn::Alert(description) => {
+                            responses = &[];
+                            alert = Some(*description);
+                            Outcome::Connected(Ok(()))
+                        }
+                        Conn::Stall(partial) => {
+                            responses = core::slice::from_ref(partial);
+                            quiet = true;
+                            Outcome::Connected(Ok(()))
+                        }
+                        Conn::Refuse(error) => {
+                            link.reply(command.id, Outcome::Connected(Err(*error))).await;
+                            if next_conn == conns.len() {
+                                return;
+                            }
+                            continue;
+                        }
+                    }
+                }
+                Op::Write(data) => {
+                    received.extend_from_slice(&data);
+                    Outcome::Io(Ok(Vec::new()))
+                }
+                Op::Flush => {
+                    let request = StdString::from_utf8(core::mem::take(&mut received)).unwrap();
+                    log.borrow_mut().requests.push(request);
+                    if let Some((first, rest)) = responses.split_first() {
+                        unsent = first;
+                        responses = rest;
+                    }
+                    Outcome::Io(Ok(Vec::new()))
+                }
+                Op::Read(len) => match alert.take() {
+                    Some(description) => {
+                        Outcome::Io(Err(TlsError::HandshakeAborted(AlertLevel::Fatal, description)))
+                    }
+                    // Leave the read unanswered, as a runner waiting on the server would
+                    None if quiet && unsent.is_empty() => continue,
+                    None => {
+                        let n = len.min(7).min(unsent.len());
+                        let data = Vec::from_slice(&unsent[..n]).unwrap();
+                        unsent = &unsent[n..];
+                        Outcome::Io(Ok(data))
+                    }
+                },
+                Op::Close => {
+                    log.borrow_mut().closes += 1;
+                    link.reply(command.id, Outcome::Closed).await;
+                    if next_conn == conns.len() {
+                        return;
+                    }
+                    continue;
+                }
+            };
+            link.reply(command.id, outcome).await;
+        }
+    }
+
+    fn response(body: &[u8], keep_alive: bool) -> StdVec<u8> {
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        let mut response = std::format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+            body.len(),
+            connection
+        )
+        .into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    fn transport(link: &TlsLink, keep_alive: bool) -> TlsTransport<'_> {
+        let config = OtaConfig::new("https://ota.example.com:8443/ota")
+            .unwrap()
+            .with_spki_pin(PIN);
+        let mut transport = TlsTransport::over(link);
+        if keep_alive {
+            transport = transport.with_keep_alive(Duration::from_secs(10));
+        }
+        transport.configure(&config);
+        transport.set_client_identity(ClientIdentity::new(b"client certificate", [1; 32]).unwrap());
+        transport
+    }
+
+    #[test]
+    fn fetch_connects_sends_the_request_and_streams_the_body() {
+        let body: StdVec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        let served = response(&body, false);
+        let link = TlsLink::new();
+        let log = RefCell::new(Log::default());
+        let mut transport = transport(&link, false);
+        let mut sink: Vec<u8, 4096> = Vec::new();
+
+        let (result, ()) = block_on(join(
+            transport.fetch(&Request::get("https://ota.example.com:8443/ota/fw.bin"), &mut sink),
+            stand_in(&link, &[Conn::Accept(&[&served])], &log),
+        ));
+
+        let head = result.unwrap();
+        assert_eq!(head.status, 200);
+        assert_eq!(head.content_length, Some(3000));
+        assert_eq!(&sink[..], &body[..]);
+
+        let log = log.into_inner();
+        assert_eq!(log.connects, [(StdString::from("ota.example.com"), 8443, true, true)]);
+        assert!(log.requests[0].starts_with("GET /ota/fw.bin HTTP/1.1\r\n"));
+        assert!(log.requests[0].contains("Host: ota.example.com:8443\r\n"));
+        assert!(log.requests[0].contains("Connection: close\r\n"));
+        assert_eq!(log.closes, 1);
+    }
+
+    #[test]
+    fn post_sends_the_body_after_the_head() {
+        let served = response(b"", false);
+        let link = TlsLink::new();
+        let log = RefCell::new(Log::default());
+        let mut transport = transport(&link, false);
+        let mut sink: Vec<u8, 16> = Vec::new();
+
+        let (result, ()) = block_on(join(
+            transport.fetch(&Request::post("https://ota.example.com:8443/ota/report", b"{\"ok\":true}"), &mut sink),
+            stand_in(&link, &[Conn::Accept(&[&served])], &log),
+        ));
+
+        result.unwrap();
+        let request = &log.borrow().requests[0];
+        assert!(request.starts_with("POST /ota/report HTTP/1.1\r\n"));
+        assert!(request.ends_with("Content-Length: 11\r\n\r\n{\"ok\":true}"));
+    }
+
+    #[test]
+    fn keep_alive_reuses_the_connection_and_replaces_a_closed_one() {
+        let first = response(b"manifest", true);
+        let second = response(b"firmware", true);
+        let third = response(b"report", true);
+        let link = TlsLink::new();
+        let log = RefCell::new(Log::default());
+        let mut transport = transport(&link, true);
+
+        let client = async {
+            let mut bodies = StdVec::new();
+            for path in ["manifest.json", "fw.bin", "report"] {
+                let url = std::format!("https://ota.example.com:8443/ota/{}", path);
+                let mut sink: Vec<u8, 64> = Vec::new();
+                transport.fetch(&Request::get(&url), &mut sink).await.unwrap();
+                bodies.push(StdVec::from(&sink[..]));
+            }
+            transport.disconnect().await;
+            bodies
+        };
+        // The server drops the first connection after two responses
+        let conns = [Conn::Accept(&[&first, &second]), Conn::Accept(&[&third])];
+        let (bodies, ()) = block_on(join(client, stand_in(&link, &conns, &log)));
+
+        assert_eq!(bodies, [&b"manifest"[..], b"firmware", b"report"]);
+        let log = log.into_inner();
+        assert_eq!(log.connects.len(), 2);
+        // The request that found the connection closed went out again on the new one
+        assert_eq!(log.requests.len(), 4);
+        assert!(log.requests[2].starts_with("GET /ota/report "));
+        assert!(log.requests[3].starts_with("GET /ota/report "));
+        assert!(log.requests[0].contains("Connection: keep-alive\r\n"));
+        assert_eq!(log.closes, 1);
+    }
+
+    #[test]
+    fn failed_handshake_is_reported() {
+        let link = TlsLink::new();
+        let log = RefCell::new(Log::default());
+        let mut transport = transport(&link, false);
+        let mut sink: Vec<u8, 16> = Vec::new();
+
+        let (result, ()) = block_on(join(
+            transport.fetch(&Request::get("https://ota.example.com:8443/ota/fw.bin"), &mut sink),
+            stand_in(&link, &[Conn::Refuse(NetworkError::CertificateRejected)], &log),
+        ));
+
+        assert_eq!(result.unwrap_err(), NetworkError::CertificateRejected.into());
+        assert!(log.borrow().requests.is_empty());
+    }
+
+    #[test]
+    fn client_certificate_alert_after_handshake_is_reported() {
+        let link = TlsLink::new();
+        let log = RefCell::new(Log::default());
+        let mut transport = transport(&link, false);
+        let mut sink: Vec<u8, 16> = Vec::new();
+
+        let (result, ()) = block_on(join(
+            transport.fetch(&Request::get("https://ota.example.com:8443/ota/fw.bin"), &mut sink),
+            stand_in(&link, &[Conn::Alert(AlertDescription::CertificateRequired)], &log),
+        ));
+
+        assert_eq!(result.unwrap_err(), NetworkError::ClientAuthRejected.into());
+        assert_eq!(log.borrow().closes, 1);
+    }
+
+    #[test]
+    fn silent_server_times_out_in_its_phase() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n";
+        for (partial, phase) in [(&b""[..], TimeoutPhase::FirstByte), (head, TimeoutPhase::Stall)] {
+            let link = TlsLink::new();
+            let log = RefCell::new(Log::default());
+            let mut transport = transport(&link, false);
+            transport.timeouts.first_byte_ms = 30;
+            transport.timeouts.stall_ms = 30;
+            let mut sink: Vec<u8, 16> = Vec::new();
+
+            let (result, ()) = block_on(join(
+                transport.fetch(&Request::get("https://ota.example.com:8443/ota/fw.bin"), &mut sink),
+                stand_in(&link, &[Conn::Stall(partial)], &log),
+            ));
+
+            assert_eq!(result.unwrap_err(), NetworkError::Timeout(phase).into());
+            // The transport gave up on the read and closed the connection
+            assert_eq!(log.borrow().closes, 1);
+        }
+    }
+
+    #[test]
+    fn plain_http_is_refused() {
+        let link = TlsLink::new();
+        let mut transport = transport(&link, false);
+        let mut sink: Vec<u8, 16> = Vec::new();
+
+        let result = block_on(transport.fetch(&Request::get("http://ota.example.com/ota/fw.bin"), &mut sink));
+        assert_eq!(result.unwrap_err(), ConfigError::UnsupportedScheme.into());
+    }
+
+    // `TlsRunner::serve` itself is not run on host: resolving the name,
+    // connecting the TcpSocket within its timeout, the embedded-tls handshake
+    // and the close_notify on the way out all need an embassy-net stack and a
+    // TLS 1.3 server, and are only exercised on a device. Everything after the
+    // handshake is `relay`, which the tests below drive over an in-memory
+    // connection.
+
+    /// Open connection to an in-memory server
+    struct Wire<'a> {
+        from_server: &'a Pipe,
+        to_server: &'a Pipe,
+        /// Fail every read with this error
+        fail: Option<TlsError>,
+    }
+
+    impl ErrorType for Wire<'_> {
+        type Error = TlsError;
+    }
+
+    impl Read for Wire<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> core::result::Result<usize, TlsError> {
+            match self.fail {
+                Some(error) => Err(error),
+                None => Ok(self.from_server.read(buf).await),
+            }
+        }
+    }
+
+    impl Write for Wire<'_> {
+        async fn write(&mut self, buf: &[u8]) -> core::result::Result<usize, TlsError> {
+            self.to_server.push(buf);
+            Ok(buf.len())
+        }
+    }
+
+    /// Send `op` to the runner and wait for its answer
+    async fn call(link: &TlsLink, id: u32, op: Op) -> Outcome {
+        link.commands.send(Command { id, op }).await;
+        let reply = link.replies.receive().await;
+        assert_eq!(reply.id, id);
+        reply.outcome
+    }
+
+    fn io(outcome: Outcome) -> core::result::Result<StdVec<u8>, TlsError> {
+        match outcome {
+            Outcome::Io(result) => result.map(|data| StdVec::from(&data[..])),
+            _ => panic!("expected an I/O outcome"),
+        }
+    }
+
+    #[test]
+    fn relay_carries_io_until_closed() {
+        let link = TlsLink::new();
+        let (from_server, to_server) = (Pipe::default(), Pipe::default());
+        let mut wire = Wire { from_server: &from_server, to_server: &to_server, fail: None };
+        from_server.push(b"HTTP/1.1 200 OK\r\n");
+
+        let transport = async {
+            let request = Vec::from_slice(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            assert_eq!(io(call(&link, 1, Op::Write(request)).await), Ok(StdVec::new()));
+            assert_eq!(io(call(&link, 2, Op::Flush).await), Ok(StdVec::new()));
+            assert_eq!(io(call(&link, 3, Op::Read(8)).await).unwrap(), b"HTTP/1.1");
+            // Reads larger than a link chunk are cut down to one
+            from_server.push(&[0; 2 * LINK_CHUNK_SIZE]);
+            let read = io(call(&link, 4, Op::Read(usize::MAX)).await).unwrap();
+            assert_eq!(read.len(), LINK_CHUNK_SIZE);
+            link.commands.send(Command { id: 5, op: Op::Close }).await;
+        };
+        let (ending, ()) = block_on(join(relay(&link, &mut wire), transport));
+
+        assert!(matches!(ending, Ending::Close(5)));
+        assert_eq!(to_server.pending(), b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn relay_reports_the_end_of_the_stream_and_errors() {
+        let link = TlsLink::new();
+        let (from_server, to_server) = (Pipe::default(), Pipe::default());
+        from_server.close();
+
+        let mut wire = Wire { from_server: &from_server, to_server: &to_server, fail: None };
+        let transport = async {
+            assert_eq!(io(call(&link, 1, Op::Read(16)).await), Ok(StdVec::new()));
+            link.commands.send(Command { id: 2, op: Op::Close }).await;
+        };
+        block_on(join(relay(&link, &mut wire), transport));
+
+        wire.fail = Some(TlsError::ConnectionClosed);
+        let transport = async {
+            assert_eq!(io(call(&link, 1, Op::Read(16)).await), Err(TlsError::ConnectionClosed));
+            link.commands.send(Command { id: 2, op: Op::Close }).await;
+        };
+        block_on(join(relay(&link, &mut wire), transport));
+    }
+
+    #[test]
+    fn relay_abandons_a_pending_read_for_the_next_command() {
+        let link = TlsLink::new();
+        let (from_server, to_server) = (Pipe::default(), Pipe::default());
+        let mut wire = Wire { from_server: &from_server, to_server: &to_server, fail: None };
+
+        // Nothing arrives, so the transport gives up on the read and closes
+        let transport = async {
+            link.commands.send(Command { id: 1, op: Op::Read(16) }).await;
+            link.commands.send(Command { id: 2, op: Op::Close }).await;
+        };
+        let (ending, ()) = block_on(join(relay(&link, &mut wire), transport));
+
+        assert!(matches!(ending, Ending::Interrupted(Command { id: 2, op: Op::Close })));
+        // The abandoned read is never answered
+        assert!(link.replies.try_receive().is_err());
+    }
+
+    #[test]
+    fn relay_hands_back_a_connect_to_another_origin() {
+        let link = TlsLink::new();
+        let (from_server, to_server) = (Pipe::default(), Pipe::default());
+        let mut wire = Wire { from_server: &from_server, to_server: &to_server, fail: None };
+        let connect = Connect {
+            origin: Url::parse("https://mirror.example.com/ota").unwrap(),
+            trust: TlsTrust::None,
+            identity: None,
+            timeouts: TimeoutConfig::default(),
+        };
+
+        let (ending, ()) = block_on(join(
+            relay(&link, &mut wire),
+            link.commands.send(Command { id: 1, op: Op::Connect(connect) }),
+        ));
+
+        match ending {
+            Ending::Reconnect(Command { id: 1, op: Op::Connect(connect) }) => {
+                assert_eq!(connect.origin.host.as_str(), "mirror.example.com");
+            }
+            _ => panic!("expected the connect to be handed back"),
+        }
+    }
+}
@@ -0,0 +1,580 @@
+//! Certificate handling for the embedded-tls transport
+//!
+//! Two server verification modes are supported besides "no verification":
+//! - CA verification, delegated to embedded-tls' certificate verifier
+//! - SPKI pinning, where the leaf certificate's SubjectPublicKeyInfo must hash
+//!   to a configured value and the server must prove possession of that key
+//!   in the TLS 1.3 CertificateVerify message
+//!
+//! Pinned server keys must be ECDSA P-256 or Ed25519. Client certificates for
+//! mutual TLS must use an ECDSA P-256 key.
+
+use crate::config::TlsTrust;
+
+use embedded_tls::cert_verify::CertVerifier;
+use embedded_tls::{
+    Aes128GcmSha256, Certificate, CertificateEntryRef, CertificateRef, CryptoProvider,
+    HandshakeVerifyRef, NoClock, SignatureScheme, TlsCipherSuite, TlsError, TlsVerifier,
+};
+use p256::ecdsa::signature::SignerMut;
+use rand_core::{CryptoRng, CryptoRngCore, RngCore};
+use sha2::{Digest, Sha256};
+
+/// Maximum size of a server certificate kept for signature verification
+const MAX_SERVER_CERT_SIZE: usize = 4096;
+
+/// Context string prefixed to the transcript hash in a server CertificateVerify
+const SERVER_VERIFY_CONTEXT: &[u8] = b"TLS 1.3, server CertificateVerify";
+
+/// DER encoding of the id-ecPublicKey algorithm OID (1.2.840.10045.2.1)
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+
+/// DER encoding of the Ed25519 algorithm OID (1.3.101.112)
+const OID_ED25519: &[u8] = &[0x2B, 0x65, 0x70];
+
+/// Crypto provider used by the TLS transport
+///
+/// Verifies the server as configured and, if a client identity is set, signs
+/// the client CertificateVerify message with the device's P-256 key.
+pub struct OtaProvider<'a, R> {
+    rng: R,
+    verifier: ServerVerifier<'a>,
+}
+
+/// Server verification mode for one handshake
+pub enum ServerVerifier<'a> {
+    /// Accept any server
+    None,
+    /// Verify the chain against a trusted CA
+    Ca(CertVerifier<'a, Aes128GcmSha256, NoClock, MAX_SERVER_CERT_SIZE>),
+    /// Require a pinned server key
+    Pinned(SpkiPinVerifier),
+}
+
+/// Verifier checking the leaf certificate's SPKI hash and the handshake signature
+pub struct SpkiPinVerifier {
+    pin: [u8; 32],
+    key: Option<ServerKey>,
+    transcript_hash: [u8; 32],
+}
+
+/// Public key extracted from a pinned server certificate
+enum ServerKey {
+    P256(p256::ecdsa::VerifyingKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl<'a, R> OtaProvider<'a, R>
+where
+    R: CryptoRng + RngCore,
+{
+    /// Create a provider for the given trust configuration
+    pub fn new(rng: R, trust: &'a TlsTrust) -> Self {
+        let verifier = match trust {
+            TlsTrust::None => ServerVerifier::None,
+            TlsTrust::CaCertificate(ca) => {
+                ServerVerifier::Ca(CertVerifier::new(Certificate::X509(ca)))
+            }
+            TlsTrust::SpkiSha256(pin) => ServerVerifier::Pinned(SpkiPinVerifier::new(*pin)),
+        };
+
+        Self { rng, verifier }
+    }
+}
+
+impl<R> CryptoProvider for OtaProvider<'_, R>
+where
+    R: CryptoRng + RngCore,
+{
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = p256::ecdsa::DerSignature;
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+
+    fn signer(
+        &mut self,
+        key_der: &[u8],
+    ) -> Result<(impl SignerMut<Self::Signature>, SignatureScheme), TlsError> {
+        // The client key is stored as a raw P-256 scalar (see `ClientIdentity`)
+        let key = p256::ecdsa::SigningKey::from_slice(key_der)
+            .map_err(|_| TlsError::InvalidPrivateKey)?;
+        Ok((key, SignatureScheme::EcdsaSecp256r1Sha256))
+    }
+}
+
+impl TlsVerifier<Aes128GcmSha256> for ServerVerifier<'_> {
+    fn set_hostname_verification(&mut self, hostname: &str) -> Result<(), TlsError> {
+        match self {
+            ServerVerifier::None => Ok(()),
+            ServerVerifier::Ca(verifier) => verifier.set_hostname_verification(hostname),
+            ServerVerifier::Pinned(verifier) => verifier.set_hostname_verification(hostname),
+        }
+    }
+
+    fn verify_certificate(
+        &mut self,
+        transcript: &<Aes128GcmSha256 as TlsCipherSuite>::Hash,
+        cert: CertificateRef,
+    ) -> Result<(), TlsError> {
+        match self {
+            ServerVerifier::None => Ok(()),
+            ServerVerifier::Ca(verifier) => verifier.verify_certificate(transcript, cert),
+            ServerVerifier::Pinned(verifier) => verifier.verify_certificate(transcript, cert),
+        }
+    }
+
+    fn verify_signature(&mut self, verify: HandshakeVerifyRef) -> Result<(), TlsError> {
+        match self {
+            ServerVerifier::None => Ok(()),
+            ServerVerifier::Ca(verifier) => verifier.verify_signature(verify),
+            ServerVerifier::Pinned(verifier) => verifier.verify_signature(verify),
+        }
+    }
+}
+
+impl SpkiPinVerifier {
+    /// Create a verifier accepting only the key whose SPKI hashes to `pin`
+    pub fn new(pin: [u8; 32]) -> Self {
+        Self {
+            pin,
+            key: None,
+            transcript_hash: [0; 32],
+        }
+    }
+}
+
+impl TlsVerifier<Aes128GcmSha256> for SpkiPinVerifier {
+    fn set_hostname_verification(&mut self, _hostname: &str) -> Result<(), TlsError> {
+        // The pin identifies the server; the name on the certificate is irrelevant
+        Ok(())
+    }
+
+    fn verify_certificate(
+        &mut self,
+        transcript: &<Aes128GcmSha256 as TlsCipherSuite>::Hash,
+        cert: CertificateRef,
+    ) -> Result<(), TlsError> {
+        let leaf = match cert.entries.first() {
+            Some(CertificateEntryRef::X509(der)) => *der,
+            _ => return Err(TlsError::InvalidCertificate),
+        };
+
+        let spki = subject_public_key_info(leaf).ok_or(TlsError::InvalidCertificate)?;
+        let hash: [u8; 32] = Sha256::digest(spki).into();
+        if hash != self.pin {
+            return Err(TlsError::InvalidCertificate);
+        }
+
+        self.key = Some(ServerKey::from_spki(spki).ok_or(TlsError::InvalidCertificate)?);
+        self.transcript_hash = transcript.clone().finalize().into();
+        Ok(())
+    }
+
+    fn verify_signature(&mut self, verify: HandshakeVerifyRef) -> Result<(), TlsError> {
+        let key = self.key.as_ref().ok_or(TlsError::InvalidCertificate)?;
+
+        // RFC 8446 section 4.4.3: 64 spaces, context string, zero byte, transcript hash
+        let mut message = [0u8; 64 + SERVER_VERIFY_CONTEXT.len() + 1 + 32];
+        message[..64].fill(0x20);
+        message[64..64 + SERVER_VERIFY_CONTEXT.len()].copy_from_slice(SERVER_VERIFY_CONTEXT);
+        message[64 + SERVER_VERIFY_CONTEXT.len() + 1..].copy_from_slice(&self.transcript_hash);
+
+        match (key, verify.signature_scheme) {
+            (ServerKey::P256(key), SignatureScheme::EcdsaSecp256r1Sha256) => {
+                use p256::ecdsa::signature::Verifier;
+                let signature = p256::ecdsa::Signature::from_der(verify.signature)
+                    .map_err(|_| TlsError::InvalidSignature)?;
+                key.verify(&message, &signature)
+                    .map_err(|_| TlsError::InvalidSignature)
+            }
+            (ServerKey::Ed25519(key), SignatureScheme::Ed25519) => {
+                use ed25519_dalek::Verifier;
+                let signature = ed25519_dalek::Signature::from_slice(verify.signature)
+                    .map_err(|_| TlsError::InvalidSignature)?;
+                key.verify(&message, &signature)
+                    .map_err(|_| TlsError::InvalidSignature)
+            }
+            _ => Err(TlsError::InvalidSignatureScheme),
+        }
+    }
+}
+
+impl ServerKey {
+    /// Decode a supported public key from a SubjectPublicKeyInfo structure
+    fn from_spki(spki: &[u8]) -> Option<Self> {
+        // SubjectPublicKeyInfo ::= SEQUENCE { algorithm AlgorithmIdentifier, subjectPublicKey BIT STRING }
+        let (_, body, _) = der_element(spki, 0x30)?;
+        let (_, algorithm, rest) = der_element(body, 0x30)?;
+        let (_, oid, _) = der_element(algorithm, 0x06)?;
+        let (_, bits, _) = der_element(rest, 0x03)?;
+
+        // First byte of a BIT STRING is the number of unused bits
+        let key = bits.split_first().filter(|(unused, _)| **unused == 0)?.1;
+
+        if oid == OID_EC_PUBLIC_KEY {
+            p256::ecdsa::VerifyingKey::from_sec1_bytes(key)
+                .ok()
+                .map(ServerKey::P256)
+        } else if oid == OID_ED25519 {
+            let key: &[u8; 32] = key.try_into().ok()?;
+            ed25519_dalek::VerifyingKey::from_bytes(key)
+                .ok()
+                .map(ServerKey::Ed25519)
+        } else {
+            None
+        }
+    }
+}
+
+/// Locate the complete SubjectPublicKeyInfo element in an X.509 certificate
+pub fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+    let (_, certificate, _) = der_element(cert, 0x30)?;
+    let (_, mut tbs, _) = der_element(certificate, 0x30)?;
+
+    // Optional explicit version tag [0]
+    if tbs.first() == Some(&0xA0) {
+        tbs = der_element(tbs, 0xA0)?.2;
+    }
+
+    // serialNumber, signature, issuer, validity, subject
+    tbs = der_element(tbs, 0x02)?.2;
+    for _ in 0..4 {
+        tbs = der_element(tbs, 0x30)?.2;
+    }
+
+    // subjectPublicKeyInfo, including its tag and length
+    let (whole, _, _) = der_element(tbs, 0x30)?;
+    Some(whole)
+}
+
+/// Split one DER element with the expected tag off the front of `data`
+///
+/// Returns the complete element, its contents and the remaining input.
+fn der_element(data: &[u8], tag: u8) -> Option<(&[u8], &[u8], &[u8])> {
+    if *data.first()? != tag {
+        return None;
+    }
+
+    let first = *data.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7F;
+        if count == 0 || count > 3 {
+            return None;
+        }
+        let len = data
+            .get(2..2 + count)?
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, 2 + count)
+    };
+
+    let end = header.checked_add(len)?;
+    if end > data.len() {
+        return None;
+    }
+    Some((&data[..end], &data[header..end], &data[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// DER element with a definite length in short or long form
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match contents.len() {
+            len if len < 0x80 => out.push(len as u8),
+            len if len < 0x100 => out.extend([0x81, len as u8]),
+            len => out.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        out.extend_from_slice(contents);
+        out
+    }
+
+    fn spki(algorithm: &[u8], key: &[u8]) -> Vec<u8> {
+        let algorithm = der(0x30, &der(0x06, algorithm));
+        let key = der(0x03, &[&[0][..], key].concat());
+        der(0x30, &[algorithm, key].concat())
+    }
+
+    /// Minimal X.509 certificate around `spki`, with a padded subject so
+    /// that the outer lengths use the long form
+    fn certificate(spki: &[u8]) -> Vec<u8> {
+        let signature_algorithm = der(
+            0x30,
+            &der(0x06, &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02]),
+        );
+        let name = der(0x30, &der(0x31, &der(0x30, &der(0x0C, &[b'x'; 150]))));
+        let tbs = [
+            der(0xA0, &der(0x02, &[2])),
+            der(0x02, &[0x01, 0x02, 0x03]),
+            signature_algorithm.clone(),
+            name.clone(),
+            der(
+                0x30,
+                &[der(0x17, b"250101000000Z"), der(0x17, b"350101000000Z")].concat(),
+            ),
+            name,
+            spki.to_vec(),
+        ]
+        .concat();
+        der(
+            0x30,
+            &[
+                der(0x30, &tbs),
+                signature_algorithm,
+                der(0x03, &[0, 1, 2, 3]),
+            ]
+            .concat(),
+        )
+    }
+
+    fn chain(cert: &[u8]) -> CertificateRef<'_> {
+        let mut chain = CertificateRef::with_context(&[]);
+        chain.add(CertificateEntryRef::X509(cert)).unwrap();
+        chain
+    }
+
+    fn verify_message(transcript: &Sha256) -> Vec<u8> {
+        [
+            &[0x20; 64][..],
+            SERVER_VERIFY_CONTEXT,
+            &[0],
+            &transcript.clone().finalize(),
+        ]
+        .concat()
+    }
+
+    fn pin(spki: &[u8]) -> [u8; 32] {
+        Sha256::digest(spki).into()
+    }
+
+    #[test]
+    fn spki_is_found_in_certificate() {
+        let key = spki(OID_ED25519, &[7; 32]);
+        assert_eq!(subject_public_key_info(&certificate(&key)), Some(&key[..]));
+
+        // Version tag is optional
+        let cert = certificate(&key);
+        let (_, outer, _) = der_element(&cert, 0x30).unwrap();
+        let (_, tbs, rest) = der_element(outer, 0x30).unwrap();
+        let v1 = der(0x30, &[der(0x30, &tbs[5..]), rest.to_vec()].concat());
+        assert_eq!(subject_public_key_info(&v1), Some(&key[..]));
+    }
+
+    #[test]
+    fn matching_ed25519_pin_is_accepted() {
+        let signer = ed25519_dalek::SigningKey::from_bytes(&[0x42; 32]);
+        let key = spki(OID_ED25519, signer.verifying_key().as_bytes());
+        let cert = certificate(&key);
+        let transcript = Sha256::new_with_prefix(b"ClientHello ServerHello");
+
+        let mut verifier = SpkiPinVerifier::new(pin(&key));
+        assert_eq!(
+            verifier.verify_certificate(&transcript, chain(&cert)),
+            Ok(())
+        );
+
+        use ed25519_dalek::Signer;
+        let signature = signer.sign(&verify_message(&transcript)).to_bytes();
+        let verify = HandshakeVerifyRef {
+            signature_scheme: SignatureScheme::Ed25519,
+            signature: &signature,
+        };
+        assert_eq!(verifier.verify_signature(verify), Ok(()));
+    }
+
+    #[test]
+    fn matching_p256_pin_is_accepted() {
+        let signer = p256::ecdsa::SigningKey::from_slice(&[0x17; 32]).unwrap();
+        let point = signer.verifying_key().to_encoded_point(false);
+        let key = spki(OID_EC_PUBLIC_KEY, point.as_bytes());
+        let cert = certificate(&key);
+        let transcript = Sha256::new_with_prefix(b"ClientHello ServerHello");
+
+        let mut verifier = ServerVerifier::Pinned(SpkiPinVerifier::new(pin(&key)));
+        assert_eq!(
+            verifier.verify_certificate(&transcript, chain(&cert)),
+            Ok(())
+        );
+
+        use p256::ecdsa::signature::Signer;
+        let signature: p256::ecdsa::Signature = signer.sign(&verify_message(&transcript));
+        let signature = signature.to_der();
+        let verify = HandshakeVerifyRef {
+            signature_scheme: SignatureScheme::EcdsaSecp256r1Sha256,
+            signature: signature.as_bytes(),
+        };
+        assert_eq!(verifier.verify_signature(verify), Ok(()));
+    }
+
+    #[test]
+    fn wrong_pin_is_rejected() {
+        let signer = ed25519_dalek::SigningKey::from_bytes(&[0x42; 32]);
+        let key = spki(OID_ED25519, signer.verifying_key().as_bytes());
+        let cert = certificate(&key);
+        let transcript = Sha256::new();
+
+        let mut wrong = pin(&key);
+        wrong[31] ^= 1;
+        let mut verifier = SpkiPinVerifier::new(wrong);
+        assert_eq!(
+            verifier.verify_certificate(&transcript, chain(&cert)),
+            Err(TlsError::InvalidCertificate)
+        );
+
+        // Without an accepted certificate no signature is good enough
+        use ed25519_dalek::Signer;
+        let signature = signer.sign(&verify_message(&transcript)).to_bytes();
+        let verify = HandshakeVerifyRef {
+            signature_scheme: SignatureScheme::Ed25519,
+            signature: &signature,
+        };
+        assert_eq!(
+            verifier.verify_signature(verify),
+            Err(TlsError::InvalidCertificate)
+        );
+    }
+
+    #[test]
+    fn pinned_key_must_sign_the_transcript() {
+        let signer = ed25519_dalek::SigningKey::from_bytes(&[0x42; 32]);
+        let key = spki(OID_ED25519, signer.verifying_key().as_bytes());
+        let cert = certificate(&key);
+        let transcript = Sha256::new_with_prefix(b"ClientHello ServerHello");
+
+        let mut verifier = SpkiPinVerifier::new(pin(&key));
+        verifier
+            .verify_certificate(&transcript, chain(&cert))
+            .unwrap();
+
+        // Signature over a different transcript, e.g. from a replayed handshake
+        use ed25519_dalek::Signer;
+        let other = Sha256::new_with_prefix(b"another handshake");
+        let signature = signer.sign(&verify_message(&other)).to_bytes();
+        let verify = HandshakeVerifyRef {
+            signature_scheme: SignatureScheme::Ed25519,
+            signature: &signature,
+        };
+        assert_eq!(
+            verifier.verify_signature(verify),
+            Err(TlsError::InvalidSignature)
+        );
+
+        // Signature by another key
+        let intruder = ed25519_dalek::SigningKey::from_bytes(&[0x43; 32]);
+        let signature = intruder.sign(&verify_message(&transcript)).to_bytes();
+        let verify = HandshakeVerifyRef {
+            signature_scheme: SignatureScheme::Ed25519,
+            signature: &signature,
+        };
+        assert_eq!(
+            verifier.verify_signature(verify),
+            Err(TlsError::InvalidSignature)
+        );
+
+        // Scheme that does not match the pinned key
+        let verify = HandshakeVerifyRef {
+            signature_scheme: SignatureScheme::EcdsaSecp256r1Sha256,
+            signature: &signature,
+        };
+        assert_eq!(
+            verifier.verify_signature(verify),
+            Err(TlsError::InvalidSignatureScheme)
+        );
+    }
+
+    #[test]
+    fn malformed_lengths_are_errors() {
+        let cases: &[&[u8]] = &[
+            // Empty input and missing length
+            &[],
+            &[0x30],
+            // Contents shorter than the length
+            &[0x30, 0x05, 0x01, 0x02],
+            // Indefinite length
+            &[0x30, 0x80, 0x00, 0x00],
+            // Length bytes cut off
+            &[0x30, 0x82, 0x01],
+            // More length bytes than supported
+            &[0x30, 0x84, 0x00, 0x00, 0x00, 0x01, 0x00],
+            &[0x30, 0x88, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+            // Length far past the end of the input
+            &[0x30, 0x83, 0xFF, 0xFF, 0xFF, 0x00],
+        ];
+        for case in cases {
+            assert_eq!(der_element(case, 0x30), None, "{case:02X?}");
+            assert_eq!(subject_public_key_info(case), None, "{case:02X?}");
+            assert!(ServerKey::from_spki(case).is_none(), "{case:02X?}");
+        }
+
+        assert_eq!(
+            der_element(&[0x30, 0x00, 0x05], 0x30),
+            Some((&[0x30, 0x00][..], &[][..], &[0x05][..]))
+        );
+        assert_eq!(der_element(&[0x02, 0x00], 0x30), None);
+    }
+
+    #[test]
+    fn truncated_certificate_is_rejected() {
+        let key = spki(OID_ED25519, &[7; 32]);
+        let cert = certificate(&key);
+        let transcript = Sha256::new();
+
+        for len in 0..cert.len() {
+            let truncated = &cert[..len];
+            assert_eq!(subject_public_key_info(truncated), None, "length {len}");
+            let mut verifier = SpkiPinVerifier::new(pin(&key));
+            assert_eq!(
+                verifier.verify_certificate(&transcript, chain(truncated)),
+                Err(TlsError::InvalidCertificate)
+            );
+        }
+
+        // An outer length claiming more than is there
+        let mut oversized = cert.clone();
+        oversized[3] = oversized[3].wrapping_add(1);
+        assert_eq!(subject_public_key_info(&oversized), None);
+    }
+
+    #[test]
+    fn unsupported_keys_are_rejected() {
+        // RSA (1.2.840.113549.1.1.1) is not supported for pinning
+        let rsa = spki(
+            &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x01],
+            &[0x30, 0x00],
+        );
+        let mut verifier = SpkiPinVerifier::new(pin(&rsa));
+        assert_eq!(
+            verifier.verify_certificate(&Sha256::new(), chain(&certificate(&rsa))),
+            Err(TlsError::InvalidCertificate)
+        );
+
+        // Wrong key length and unused bits in the BIT STRING
+        assert!(ServerKey::from_spki(&spki(OID_ED25519, &[7; 31])).is_none());
+        let mut padded = spki(OID_ED25519, &[7; 32]);
+        let unused = padded.len() - 33;
+        padded[unused] = 1;
+        assert!(ServerKey::from_spki(&padded).is_none());
+
+        // No certificate at all
+        let mut verifier = SpkiPinVerifier::new([0; 32]);
+        assert_eq!(
+            verifier.verify_certificate(&Sha256::new(), CertificateRef::with_context(&[])),
+            Err(TlsError::InvalidCertificate)
+        );
+    }
+}
@@ -0,0 +1,580 @@
+//! URL parsing and joining for OTA endpoints
+//!
+//! Supports absolute `http`, `https` and `coap` URLs with an optional port,
+//! query and IPv6 literal host. User info is rejected and fragments are dropped, since
+//! neither is ever sent to the server.
+
+use crate::config::MAX_URL_LENGTH;
+use crate::error::{ConfigError, Result};
+use core::fmt::{self, Write as _};
+use heapless::String;
+
+/// Maximum length of a host name or IP literal
+pub const MAX_HOST_LENGTH: usize = 64;
+
+/// Maximum length of a `host[:port]` authority (IPv6 brackets included)
+pub const MAX_AUTHORITY_LENGTH: usize = MAX_HOST_LENGTH + 8;
+
+/// Supported URL schemes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Http,
+    Https,
+    /// CoAP over UDP (RFC 7252), used by the `coap` transport
+    Coap,
+}
+
+/// An absolute URL split into its components
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    /// URL scheme
+    pub scheme: Scheme,
+
+    /// Host name or IP address (IPv6 literals without brackets)
+    pub host: String<MAX_HOST_LENGTH>,
+
+    /// Port, defaulted from the scheme when not given
+    pub port: u16,
+
+    /// Absolute path, always starting with '/'
+    pub path: String<MAX_URL_LENGTH>,
+
+    /// Query string without the leading '?'
+    pub query: Option<String<MAX_URL_LENGTH>>,
+}
+
+/// How far ".." segments in a relative reference may climb
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Floor {
+    /// Not above the base directory; climbing further is an error
+    Base,
+    /// Not above the root; further ".." segments are ignored (RFC 3986)
+    Root,
+}
+
+impl Scheme {
+    /// Get the scheme name as written in URLs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+            Scheme::Coap => "coap",
+        }
+    }
+
+    /// Port used when the URL does not name one
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Scheme::Http => 80,
+            Scheme::Https => 443,
+            Scheme::Coap => 5683,
+        }
+    }
+
+    fn parse(scheme: &str) -> Result<Self> {
+        if scheme.eq_ignore_ascii_case("https") {
+            Ok(Scheme::Https)
+        } else if scheme.eq_ignore_ascii_case("http") {
+            Ok(Scheme::Http)
+        } else if scheme.eq_ignore_ascii_case("coap") {
+            Ok(Scheme::Coap)
+        } else {
+            Err(ConfigError::UnsupportedScheme.into())
+        }
+    }
+}
+
+impl Url {
+    /// Parse an absolute URL such as "https://solari.local:8443/ota?fleet=a"
+    pub fn parse(url: &str) -> Result<Self> {
+        let (scheme, rest) = url
+            .trim()
+            .split_once("://")
+            .ok_or(ConfigError::InvalidUrl)?;
+        let scheme = Scheme::parse(scheme)?;
+
+        // The fragment never leaves the client
+        let rest = rest.split('#').next().unwrap_or("");
+
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, rest) = rest.split_at(authority_end);
+        let (host, port) = parse_authority(authority, scheme)?;
+
+        let (path, query) = match rest.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (rest, None),
+        };
+
+        let mut url = Self {
+            scheme,
+            host,
+            port,
+            path: String::new(),
+            query: None,
+        };
+        url.set_path(if path.is_empty() { "/" } else { path })?;
+        if let Some(query) = query {
+            url.set_query(query)?;
+        }
+        Ok(url)
+    }
+
+    /// Resolve `reference` against this URL
+    ///
+    /// Absolute URLs and absolute paths replace the corresponding parts of
+    /// this URL. Relative paths are resolved below this URL's path, which is
+    /// treated as a directory ("https://host/ota" + "fw.bin" gives
+    /// "https://host/ota/fw.bin"), and may not climb out of it with "..".
+    /// As in RFC 3986, the query of this URL is not carried over.
+    pub fn join(&self, reference: &str) -> Result<Self> {
+        self.resolve_with(reference, Floor::Base)
+    }
+
+    /// Resolve `reference` against this URL following RFC 3986
+    ///
+    /// Unlike [`Url::join`], relative paths replace the last segment of this
+    /// URL's path, as browsers do for links and `Location` headers.
+    pub fn resolve(&self, reference: &str) -> Result<Self> {
+        self.resolve_with(reference, Floor::Root)
+    }
+
+    /// Check whether `other` has the same scheme, host and port
+    pub fn same_origin(&self, other: &Url) -> bool {
+        self.scheme == other.scheme && self.host.eq_ignore_ascii_case(&other.host) && self.port == other.port
+    }
+
+    fn resolve_with(&self, reference: &str, floor: Floor) -> Result<Self> {
+        let reference = reference.trim();
+        if has_scheme(reference) {
+            return Self::parse(reference);
+        }
+        if reference.starts_with("//") {
+            let mut absolute: String<MAX_URL_LENGTH> = String::new();
+            write!(absolute, "{}:{}", self.scheme.as_str(), reference)
+                .map_err(|_| ConfigError::UrlTooLong)?;
+            return Self::parse(&absolute);
+        }
+
+        let reference = reference.split('#').next().unwrap_or("");
+        let (path, query) = match reference.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (reference, None),
+        };
+
+        let mut joined = Self {
+            scheme: self.scheme,
+            host: self.host.clone(),
+            port: self.port,
+            path: String::new(),
+            query: None,
+        };
+        if path.is_empty() && floor == Floor::Root {
+            // Same document: RFC 3986 keeps the base path, and its query unless replaced
+            joined.path = self.path.clone();
+            joined.query = self.query.clone();
+        } else if let Some(absolute) = path.strip_prefix('/') {
+            let resolved = resolve_relative("/", absolute, floor)?;
+            joined.set_path(&resolved)?;
+        } else {
+            let base = match floor {
+                Floor::Base => self.path.as_str(),
+                // Directory of the base path, e.g. "/a/" for "/a/b"
+                Floor::Root => &self.path[..self.path.rfind('/').map_or(0, |i| i + 1)],
+            };
+            let resolved = resolve_relative(base, path, floor)?;
+            joined.set_path(&resolved)?;
+        }
+        if let Some(query) = query {
+            joined.set_query(query)?;
+        }
+        Ok(joined)
+    }
+
+    /// Replace the path
+    pub fn set_path(&mut self, path: &str) -> Result<()> {
+        if !path.starts_with('/') || !path.bytes().all(|b| is_url_byte(b) && b != b'?') {
+            return Err(ConfigError::InvalidPath.into());
+        }
+        self.path = String::try_from(path).map_err(|_| ConfigError::UrlTooLong)?;
+        Ok(())
+    }
+
+    /// Replace the query string (without the leading '?')
+    pub fn set_query(&mut self, query: &str) -> Result<()> {
+        if !query.bytes().all(is_url_byte) {
+            return Err(ConfigError::InvalidQuery.into());
+        }
+        self.query = Some(String::try_from(query).map_err(|_| ConfigError::UrlTooLong)?);
+        Ok(())
+    }
+
+    /// Append a `name=value` pair to the query, percent-encoding both
+    pub fn append_query_param(&mut self, name: &str, value: &str) -> Result<()> {
+        let query = self.query.get_or_insert_with(String::new);
+        if !query.is_empty() {
+            query.push('&').map_err(|_| ConfigError::UrlTooLong)?;
+        }
+        percent_encode(query, name)?;
+        query.push('=').map_err(|_| ConfigError::UrlTooLong)?;
+        percent_encode(query, value)
+    }
+
+    /// Check whether the host is an IPv6 literal
+    pub fn is_ipv6(&self) -> bool {
+        self.host.contains(':')
+    }
+
+    /// Value of the Host header: the host, plus the port if not the default
+    pub fn authority(&self) -> Result<String<MAX_AUTHORITY_LENGTH>> {
+        let mut authority = String::new();
+        self.write_authority(&mut authority)
+            .map_err(|_| ConfigError::UrlTooLong)?;
+        Ok(authority)
+    }
+
+    /// Path and query as sent in the request line
+    pub fn request_target(&self) -> Result<String<MAX_URL_LENGTH>> {
+        let mut target = self.path.clone();
+        if let Some(query) = &self.query {
+            target.push('?').map_err(|_| ConfigError::UrlTooLong)?;
+            target.push_str(query).map_err(|_| ConfigError::UrlTooLong)?;
+        }
+        Ok(target)
+    }
+
+    /// Serialize the complete URL
+    pub fn render(&self) -> Result<String<MAX_URL_LENGTH>> {
+        let mut url = String::new();
+        write!(url, "{}", self).map_err(|_| ConfigError::UrlTooLong)?;
+        Ok(url)
+    }
+
+    fn write_authority<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        if self.is_ipv6() {
+            write!(out, "[{}]", self.host)?;
+        } else {
+            out.write_str(&self.host)?;
+        }
+        if self.port != self.scheme.default_port() {
+            write!(out, ":{}", self.port)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://", self.scheme.as_str())?;
+        self.write_authority(f)?;
+        f.write_str(&self.path)?;
+        if let Some(query) = &self.query {
+            write!(f, "?{}", query)?;
+        }
+        Ok(())
+    }
+}
+
+/// Append `value` to `out`, percent-encoding everything but unreserved characters
+pub fn percent_encode<const N: usize>(out: &mut String<N>, value: &str) -> Result<()> {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+    for byte in value.bytes() {
+        let pushed = if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            out.push(byte as char)
+        } else {
+            out.push('%')
+                .and_then(|_| out.push(HEX[(byte >> 4) as usize] as char))
+                .and_then(|_| out.push(HEX[(byte & 0x0F) as usize] as char))
+        };
+        pushed.map_err(|_| ConfigError::UrlTooLong)?;
+    }
+    Ok(())
+}
+
+/// Split an authority into host and port
+fn parse_authority(authority: &str, scheme: Scheme) -> Result<(String<MAX_HOST_LENGTH>, u16)> {
+    if authority.contains('@') {
+        return Err(ConfigError::UserinfoNotSupported.into());
+    }
+
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        // IPv6 literal, e.g. "[fe80::1]:8443"
+        let (host, after) = rest.split_once(']').ok_or(ConfigError::InvalidHost)?;
+        let port = match after {
+            "" => None,
+            after => Some(after.strip_prefix(':').ok_or(ConfigError::InvalidPort)?),
+        };
+        if host.is_empty() {
+            return Err(ConfigError::MissingHost.into());
+        }
+        let valid = host.contains(':')
+            && host.bytes().all(|b| b.is_ascii_hexdigit() || b == b':' || b == b'.');
+        if !valid {
+            return Err(ConfigError::InvalidHost.into());
+        }
+        (host, port)
+    } else {
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        };
+        if host.is_empty() {
+            return Err(ConfigError::MissingHost.into());
+        }
+        let valid = host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.');
+        if !valid || host.starts_with(['.', '-']) {
+            return Err(ConfigError::InvalidHost.into());
+        }
+        (host, port)
+    };
+
+    let port = match port {
+        None => scheme.default_port(),
+        Some(port) => port
+            .parse::<u16>()
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or(ConfigError::InvalidPort)?,
+    };
+
+    let host = String::try_from(host).map_err(|_| ConfigError::UrlTooLong)?;
+    Ok((host, port))
+}
+
+/// Resolve a relative path below the directory `base`, removing dot segments
+fn resolve_relative(base: &str, relative: &str, floor: Floor) -> Result<String<MAX_URL_LENGTH>> {
+    let mut path: String<MAX_URL_LENGTH> =
+        String::try_from(base).map_err(|_| ConfigError::UrlTooLong)?;
+    if !path.ends_with('/') {
+        path.push('/').map_err(|_| ConfigError::UrlTooLong)?;
+    }
+    let floor_len = match floor {
+        Floor::Base => path.len(),
+        Floor::Root => 1,
+    };
+
+    let mut trailing_slash = true;
+    for segment in relative.split('/') {
+        trailing_slash = true;
+        if segment.is_empty() || is_dot(segment) {
+            continue;
+        }
+        if is_dot_dot(segment) {
+            if path.len() == floor_len {
+                if floor == Floor::Base {
+                    return Err(ConfigError::PathTraversal.into());
+                }
+                continue;
+            }
+            // Drop the last segment, keeping its leading '/'
+            let parent = path[..path.len() - 1].rfind('/').map_or(floor_len, |i| i + 1);
+            path.truncate(parent.max(floor_len));
+            continue;
+        }
+        path.push_str(segment).map_err(|_| ConfigError::UrlTooLong)?;
+        path.push('/').map_err(|_| ConfigError::UrlTooLong)?;
+        trailing_slash = false;
+    }
+    if !trailing_slash {
+        path.pop();
+    }
+    Ok(path)
+}
+
+/// Check whether a reference starts with a scheme (e.g. "https:")
+fn has_scheme(reference: &str) -> bool {
+    let scheme = match reference.split_once(':') {
+        Some((scheme, _)) => scheme,
+        None => return false,
+    };
+    scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'-' | b'.'))
+}
+
+/// "." segment, including its percent-encoded form
+fn is_dot(segment: &str) -> bool {
+    segment == "." || segment.eq_ignore_ascii_case("%2e")
+}
+
+/// ".." segment, including percent-encoded forms servers may decode
+fn is_dot_dot(segment: &str) -> bool {
+    ["..", ".%2e", "%2e.", "%2e%2e"]
+        .iter()
+        .any(|form| segment.eq_ignore_ascii_case(form))
+}
+
+/// Bytes allowed unescaped in a path or query
+fn is_url_byte(byte: u8) -> bool {
+    byte.is_ascii_graphic()
+        && !matches!(
+            byte,
+            b'#' | b'"' | b'<' | b'>' | b'\\' | b'^' | b'`' | b'{' | b'|' | b'}'
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    fn config_error(result: Result<Url>) -> ConfigError {
+        match result {
+            Err(Error::Config(error)) => error,
+            other => panic!("expected a config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_splits_components() {
+        let parsed = url("HTTPS://Solari.local:8443/ota/fw.bin?fleet=a#top");
+        assert_eq!(parsed.scheme, Scheme::Https);
+        assert_eq!(parsed.host.as_str(), "Solari.local");
+        assert_eq!(parsed.port, 8443);
+        assert_eq!(parsed.path.as_str(), "/ota/fw.bin");
+        assert_eq!(parsed.query.as_deref(), Some("fleet=a"));
+        assert_eq!(parsed.request_target().unwrap().as_str(), "/ota/fw.bin?fleet=a");
+        assert_eq!(parsed.authority().unwrap().as_str(), "Solari.local:8443");
+    }
+
+    #[test]
+    fn parse_defaults_port_and_path() {
+        let parsed = url("https://example.com");
+        assert_eq!(parsed.port, 443);
+        assert_eq!(parsed.path.as_str(), "/");
+        assert_eq!(parsed.query, None);
+        assert_eq!(parsed.authority().unwrap().as_str(), "example.com");
+
+        assert_eq!(url("http://example.com?x=1").path.as_str(), "/");
+        assert_eq!(url("coap://[fd00::1]/fw").port, 5683);
+    }
+
+    #[test]
+    fn parse_handles_ipv6_literals() {
+        let parsed = url("https://[fe80::1]:8443/ota");
+        assert_eq!(parsed.host.as_str(), "fe80::1");
+        assert!(parsed.is_ipv6());
+        assert_eq!(parsed.authority().unwrap().as_str(), "[fe80::1]:8443");
+        assert_eq!(parsed.render().unwrap().as_str(), "https://[fe80::1]:8443/ota");
+    }
+
+    #[test]
+    fn parse_rejects_malformed_urls() {
+        assert_eq!(config_error(Url::parse("example.com/ota")), ConfigError::InvalidUrl);
+        assert_eq!(config_error(Url::parse("ftp://example.com/")), ConfigError::UnsupportedScheme);
+        assert_eq!(config_error(Url::parse("https://user@example.com/")), ConfigError::UserinfoNotSupported);
+        assert_eq!(config_error(Url::parse("https:///ota")), ConfigError::MissingHost);
+        assert_eq!(config_error(Url::parse("https://exa_mple.com/")), ConfigError::InvalidHost);
+        assert_eq!(config_error(Url::parse("https://[zz::1]/")), ConfigError::InvalidHost);
+        assert_eq!(config_error(Url::parse("https://example.com:0/")), ConfigError::InvalidPort);
+        assert_eq!(config_error(Url::parse("https://example.com:99999/")), ConfigError::InvalidPort);
+        assert_eq!(config_error(Url::parse("https://example.com/a b")), ConfigError::InvalidPath);
+    }
+
+    #[test]
+    fn join_resolves_below_the_base_path() {
+        let base = url("https://host/ota?fleet=a");
+        assert_eq!(base.join("fw.bin").unwrap().render().unwrap().as_str(), "https://host/ota/fw.bin");
+        assert_eq!(base.join("./v2/fw.bin?x=1").unwrap().render().unwrap().as_str(), "https://host/ota/v2/fw.bin?x=1");
+        assert_eq!(base.join("v2/../fw.bin").unwrap().path.as_str(), "/ota/fw.bin");
+        assert_eq!(base.join("/other/fw.bin").unwrap().path.as_str(), "/other/fw.bin");
+        assert_eq!(base.join("//cdn.example.com/fw.bin").unwrap().render().unwrap().as_str(), "https://cdn.example.com/fw.bin");
+        assert_eq!(base.join("http://mirror/fw.bin").unwrap().scheme, Scheme::Http);
+    }
+
+    #[test]
+    fn join_rejects_climbing_out_of_the_base() {
+        let base = url("https://host/ota/");
+        for reference in ["../../x", "../x", "v2/../../x", "%2e%2e/x", ".%2E/x", "/../x"] {
+            assert_eq!(config_error(base.join(reference)), ConfigError::PathTraversal, "{}", reference);
+        }
+    }
+
+    #[test]
+    fn resolve_follows_rfc3986_examples() {
+        // RFC 3986 section 5.4, minus fragments (never kept) and "http:g"
+        let base = url("http://a/b/c/d;p?q");
+        let cases = [
+            ("g", "http://a/b/c/g"),
+            ("./g", "http://a/b/c/g"),
+            ("g/", "http://a/b/c/g/"),
+            ("/g", "http://a/g"),
+            ("//g", "http://g/"),
+            ("?y", "http://a/b/c/d;p?y"),
+            ("g?y", "http://a/b/c/g?y"),
+            ("#s", "http://a/b/c/d;p?q"),
+            ("g#s", "http://a/b/c/g"),
+            ("g?y#s", "http://a/b/c/g?y"),
+            (";x", "http://a/b/c/;x"),
+            ("g;x?y#s", "http://a/b/c/g;x?y"),
+            ("", "http://a/b/c/d;p?q"),
+            (".", "http://a/b/c/"),
+            ("./", "http://a/b/c/"),
+            ("..", "http://a/b/"),
+            ("../", "http://a/b/"),
+            ("../g", "http://a/b/g"),
+            ("../..", "http://a/"),
+            ("../../", "http://a/"),
+            ("../../g", "http://a/g"),
+            // Abnormal examples
+            ("../../../g", "http://a/g"),
+            ("../../../../g", "http://a/g"),
+            ("/./g", "http://a/g"),
+            ("/../g", "http://a/g"),
+            ("g.", "http://a/b/c/g."),
+            (".g", "http://a/b/c/.g"),
+            ("g..", "http://a/b/c/g.."),
+            ("..g", "http://a/b/c/..g"),
+            ("./../g", "http://a/b/g"),
+            ("./g/.", "http://a/b/c/g/"),
+            ("g/./h", "http://a/b/c/g/h"),
+            ("g/../h", "http://a/b/c/h"),
+            ("g;x=1/./y", "http://a/b/c/g;x=1/y"),
+            ("g;x=1/../y", "http://a/b/c/y"),
+            ("g?y/./x", "http://a/b/c/g?y/./x"),
+            ("g?y/../x", "http://a/b/c/g?y/../x"),
+        ];
+        for (reference, expected) in cases {
+            let resolved = base.resolve(reference).unwrap();
+            assert_eq!(resolved.render().unwrap().as_str(), expected, "{}", reference);
+        }
+    }
+
+    #[test]
+    fn percent_encode_escapes_reserved_bytes() {
+        let mut out: String<64> = String::new();
+        percent_encode(&mut out, "AZaz09-._~").unwrap();
+        assert_eq!(out.as_str(), "AZaz09-._~");
+
+        out.clear();
+        percent_encode(&mut out, "a b/c?d=e&f%ü").unwrap();
+        assert_eq!(out.as_str(), "a%20b%2Fc%3Fd%3De%26f%25%C3%BC");
+
+        let mut short: String<4> = String::new();
+        assert_eq!(percent_encode(&mut short, "a b"), Err(ConfigError::UrlTooLong.into()));
+    }
+
+    #[test]
+    fn append_query_param_encodes_name_and_value() {
+        let mut target = url("https://host/ota/manifest.json");
+        target.append_query_param("device", "dev 1").unwrap();
+        target.append_query_param("v", "1.2&3").unwrap();
+        assert_eq!(target.query.as_deref(), Some("device=dev%201&v=1.2%263"));
+    }
+
+    #[test]
+    fn same_origin_compares_scheme_host_and_port() {
+        let origin = url("https://ota.example.com/fw/");
+        assert!(origin.same_origin(&url("https://OTA.example.com:443/other")));
+        assert!(!origin.same_origin(&url("http://ota.example.com:443/fw/")));
+        assert!(!origin.same_origin(&url("https://ota.example.com:8443/fw/")));
+        assert!(!origin.same_origin(&url("https://cdn.example.com/fw/")));
+    }
+}
@@ -29,6 +29,11 @@ impl SignatureVerifier {
     
     /// Verify a manifest signature
     pub fn verify_manifest(&self, manifest_data: &[u8], signature: &Signature) -> Result<()> {
+        self.verify_message(manifest_data, signature)
+    }
+    
+    /// Verify a signature made with the update key over arbitrary data
+    pub fn verify_message(&self, data: &[u8], signature: &Signature) -> Result<()> {
         // Check algorithm matches
         if signature.algorithm != self.public_key.algorithm {
             return Err(VerificationError::InvalidSignature.into());
@@ -36,7 +41,7 @@ impl SignatureVerifier {
         
         match &self.public_key.key_data {
             KeyData::Ed25519(key) => {
-                self.verify_ed25519(manifest_data, &signature.data, key)
+                self.verify_ed25519(data, &signature.data, key)
             }
         }
     }
@@ -45,9 +50,14 @@ impl SignatureVerifier {
     pub fn verify_firmware(&self, firmware_data: &[u8], expected_hash: &[u8; 32]) -> Result<()> {
         let mut hasher = Sha256::new();
         hasher.update(firmware_data);
-        let computed_hash = hasher.finalize();
+        let computed_hash: [u8; 32] = hasher.finalize().into();
         
-        if computed_hash.as_slice() != expected_hash {
+        self.verify_firmware_digest(&computed_hash, expected_hash)
+    }
+    
+    /// Verify firmware integrity from a SHA256 digest computed while streaming
+    pub fn verify_firmware_digest(&self, computed_hash: &[u8; 32], expected_hash: &[u8; 32]) -> Result<()> {
+        if computed_hash != expected_hash {
             return Err(VerificationError::HashMismatch.into());
         }
         
@@ -0,0 +1,415 @@
+//! Streaming firmware writer
+//!
+//! Hashes and writes a firmware image to [`UpdateStorage`] chunk by chunk as it
+//! arrives, so RAM use stays bounded regardless of the image size.
+//!
+//! Progress is checkpointed to a [`StateStore`] so an interrupted download can
+//! resume with an HTTP Range request. The running SHA256 state is not saved;
+//! on resume the already written prefix is re-hashed from flash instead.
+
+use crate::error::{NetworkError, Result, StorageError, VerificationError};
+use crate::http::{BodySink, ResponseHead};
+use crate::state::{self, StateKey, StateStore};
+use crate::storage::{UpdateProgress, UpdateStorage};
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Size of the staging buffer flushed to storage in one write
+pub const WRITE_BUFFER_SIZE: usize = 4096;
+
+/// Number of written bytes between persisted checkpoints
+pub const CHECKPOINT_INTERVAL: u32 = 64 * 1024;
+
+/// Persisted record of an interrupted download
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadCheckpoint {
+    /// Digest of the manifest the download belongs to
+    pub manifest_hash: [u8; 32],
+
+    /// Expected SHA256 of the file being downloaded
+    pub file_hash: [u8; 32],
+
+    /// Bytes already written to storage
+    pub bytes_written: u32,
+}
+
+/// Body sink that streams an image into update storage
+pub struct FirmwareWriter<'a, S, P> {
+    storage: &'a mut S,
+    state: &'a mut P,
+    progress: Option<&'a mut UpdateProgress>,
+    checkpoint: DownloadCheckpoint,
+    hasher: Sha256,
+    buffer: Vec<u8, WRITE_BUFFER_SIZE>,
+    expected_size: u32,
+    written: u32,
+    erased: u32,
+}
+
+impl DownloadCheckpoint {
+    /// Create a checkpoint for a download that has not started yet
+    pub fn new(manifest_hash: [u8; 32], file_hash: [u8; 32]) -> Self {
+        Self {
+            manifest_hash,
+            file_hash,
+            bytes_written: 0,
+        }
+    }
+
+    /// Check whether this checkpoint belongs to the same download as `other`
+    pub fn matches(&self, other: &DownloadCheckpoint) -> bool {
+        self.manifest_hash == other.manifest_hash && self.file_hash == other.file_hash
+    }
+}
+
+impl<'a, S, P> FirmwareWriter<'a, S, P>
+where
+    S: UpdateStorage,
+    P: StateStore,
+{
+    /// Create a writer for an image of `expected_size` bytes
+    ///
+    /// If `checkpoint.bytes_written` is non-zero, the writer resumes at that
+    /// offset after re-hashing the data already in storage.
+    pub async fn new(
+        storage: &'a mut S,
+        state: &'a mut P,
+        checkpoint: DownloadCheckpoint,
+        expected_size: u32,
+        progress: Option<&'a mut UpdateProgress>,
+    ) -> Result<Self> {
+        if expected_size > storage.capacity() {
+            return Err(StorageError::InsufficientSpace.into());
+        }
+
+        let mut writer = Self {
+            storage,
+            state,
+            progress,
+            checkpoint,
+            hasher: Sha256::new(),
+            buffer: Vec::new(),
+            expected_size,
+            written: 0,
+            erased: 0,
+        };
+
+        if checkpoint.bytes_written > 0 && checkpoint.bytes_written <= expected_size {
+            writer.rehash(checkpoint.bytes_written).await?;
+        }
+
+        Ok(writer)
+    }
+
+    /// Number of bytes written to storage so far
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// Check whether the whole image has been received
+    pub fn is_complete(&self) -> bool {
+        self.written + self.buffer.len() as u32 == self.expected_size
+    }
+
+    /// Persist the current position so a later attempt can resume from it
+    pub async fn save_checkpoint(&mut self) -> Result<()> {
+        self.checkpoint.bytes_written = self.written;
+        state::store_record(self.state, StateKey::DownloadCheckpoint, &self.checkpoint).await
+    }
+
+    /// Flush any buffered data and return the SHA256 digest of the image
+    pub async fn finish(mut self) -> Result<[u8; 32]> {
+        self.flush().await?;
+        Ok(self.hasher.finalize().into())
+    }
+
+    /// Re-hash the first `length` bytes already in storage
+    async fn rehash(&mut self, length: u32) -> Result<()> {
+        let mut chunk = [0u8; 256];
+        let mut offset = 0;
+        while offset < length {
+            let n = (length - offset).min(chunk.len() as u32);
+            self.storage.read(offset, &mut chunk[..n as usize]).await?;
+            self.hasher.update(&chunk[..n as usize]);
+            offset += n;
+        }
+
+        // Everything up to the end of the last written block is already erased
+        let erase_size = self.storage.erase_size();
+        self.written = length;
+        self.erased = length.div_ceil(erase_size) * erase_size;
+        self.report_progress();
+
+        Ok(())
+    }
+
+    /// Discard resumed state and start again from the beginning
+    fn restart(&mut self) {
+        self.hasher = Sha256::new();
+        self.buffer.clear();
+        self.written = 0;
+        self.erased = 0;
+        self.report_progress();
+    }
+
+    /// Write the staging buffer to storage, erasing ahead as needed
+    async fn flush(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let end = self.written + self.buffer.len() as u32;
+        self.ensure_erased(end).await?;
+        self.storage.write(self.written, &self.buffer).await?;
+
+        let previous = self.written;
+        self.written = end;
+        self.buffer.clear();
+        self.report_progress();
+
+        if end / CHECKPOINT_INTERVAL != previous / CHECKPOINT_INTERVAL {
+            self.save_checkpoint().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Erase whole blocks until at least `end` bytes are erased
+    async fn ensure_erased(&mut self, end: u32) -> Result<()> {
+        if end <= self.erased {
+            return Ok(());
+        }
+
+        let erase_size = self.storage.erase_size();
+        let target = end.div_ceil(erase_size) * erase_size;
+        self.storage.erase(self.erased, target - self.erased).await?;
+        self.erased = target;
+
+        Ok(())
+    }
+
+    fn report_progress(&mut self) {
+        if let Some(progress) = self.progress.as_deref_mut() {
+            progress.update_file(self.written);
+        }
+    }
+}
+
+impl<S, P> BodySink for FirmwareWriter<'_, S, P>
+where
+    S: UpdateStorage,
+    P: StateStore,
+{
+    async fn begin(&mut self, head: &ResponseHead) -> Result<()> {
+        if head.is_partial() {
+            // The server honoured our Range request; it must start where we left off
+            let range = head.content_range.ok_or(NetworkError::InvalidResponse)?;
+            if range.start != self.written {
+                return Err(NetworkError::InvalidResponse.into());
+            }
+        } else if self.written > 0 {
+            // The server ignored the Range request and is sending the whole file
+            self.restart();
+        }
+
+        Ok(())
+    }
+
+    async fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        // More than the manifest declared is the wrong file, not a lack of space
+        let received = self.written as usize + self.buffer.len() + data.len();
+        if received > self.expected_size as usize {
+            return Err(VerificationError::SizeMismatch.into());
+        }
+
+        self.hasher.update(data);
+
+        while !data.is_empty() {
+            let space = WRITE_BUFFER_SIZE - self.buffer.len();
+            let take = space.min(data.len());
+            // Cannot fail: `take` never exceeds the remaining capacity
+            let _ = self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.buffer.is_full() {
+                self.flush().await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::error::Error;
+    use crate::testing::{block_on, RamState, RamStorage};
+    use std::vec::Vec as StdVec;
+
+    const MANIFEST_HASH: [u8; 32] = [0x11; 32];
+    const FILE_HASH: [u8; 32] = [0x22; 32];
+
+    fn image(len: usize) -> StdVec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn head(response: &str) -> ResponseHead {
+        ResponseHead::parse(response.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn checkpoint(state: &mut RamState) -> Option<DownloadCheckpoint> {
+        block_on(state::load_record(state, StateKey::DownloadCheckpoint)).unwrap()
+    }
+
+    /// Feed `data` in uneven pieces, as a socket would hand it over
+    async fn feed<S: UpdateStorage, P: StateStore>(writer: &mut FirmwareWriter<'_, S, P>, data: &[u8]) -> Result<()> {
+        for piece in data.chunks(1000) {
+            writer.write(piece).await?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn exact_size_completes() {
+        let data = image(10_000);
+        let mut storage = RamStorage::new(16 * 1024);
+        let mut state = RamState::default();
+        let digest = block_on(async {
+            let fresh = DownloadCheckpoint::new(MANIFEST_HASH, FILE_HASH);
+            let mut writer = FirmwareWriter::new(&mut storage, &mut state, fresh, 10_000, None).await?;
+            writer.begin(&head("HTTP/1.1 200 OK\r\n\r\n")).await?;
+            feed(&mut writer, &data[..9_999]).await?;
+            assert!(!writer.is_complete());
+            writer.write(&data[9_999..]).await?;
+            assert!(writer.is_complete());
+            writer.finish().await
+        })
+        .unwrap();
+
+        assert_eq!(digest, <[u8; 32]>::from(Sha256::digest(&data)));
+        assert_eq!(&storage.0[..data.len()], &data[..]);
+    }
+
+    #[test]
+    fn oversized_body_is_a_size_mismatch() {
+        let data = image(10_001);
+        let mut storage = RamStorage::new(16 * 1024);
+        let mut state = RamState::default();
+        let result = block_on(async {
+            let fresh = DownloadCheckpoint::new(MANIFEST_HASH, FILE_HASH);
+            let mut writer = FirmwareWriter::new(&mut storage, &mut state, fresh, 10_000, None).await?;
+            feed(&mut writer, &data).await
+        });
+
+        assert_eq!(result, Err(Error::Verification(VerificationError::SizeMismatch)));
+    }
+
+    #[test]
+    fn image_larger_than_storage_is_insufficient_space() {
+        let mut storage = RamStorage::new(8 * 1024);
+        let mut state = RamState::default();
+        let fresh = DownloadCheckpoint::new(MANIFEST_HASH, FILE_HASH);
+        let result = block_on(FirmwareWriter::new(&mut storage, &mut state, fresh, 8 * 1024 + 1, None));
+
+        assert!(matches!(result, Err(Error::Storage(StorageError::InsufficientSpace))));
+    }
+
+    #[test]
+    fn checkpoints_every_interval() {
+        let interval = CHECKPOINT_INTERVAL as usize;
+        let data = image(2 * interval + 100);
+        let mut storage = RamStorage::new(3 * interval);
+        let mut state = RamState::default();
+        let mut saved = StdVec::new();
+        block_on(async {
+            let fresh = DownloadCheckpoint::new(MANIFEST_HASH, FILE_HASH);
+            let mut writer = FirmwareWriter::new(&mut storage, &mut state, fresh, data.len() as u32, None).await?;
+            let mut fed = 0;
+            for end in [interval - 1, interval, 2 * interval - 1, 2 * interval, data.len()] {
+                feed(&mut writer, &data[fed..end]).await?;
+                fed = end;
+                let record = state::load_record(writer.state, StateKey::DownloadCheckpoint).await?;
+                saved.push(record);
+            }
+            writer.finish().await
+        })
+        .unwrap();
+
+        let at = |bytes_written: usize| {
+            Some(DownloadCheckpoint {
+                bytes_written: bytes_written as u32,
+                ..DownloadCheckpoint::new(MANIFEST_HASH, FILE_HASH)
+            })
+        };
+        assert_eq!(saved, [None, at(interval), at(interval), at(2 * interval), at(2 * interval)]);
+        // Finishing does not save: a complete image is never resumed
+        assert_eq!(checkpoint(&mut state), at(2 * interval));
+    }
+
+    /// Storage holding the first `written` bytes of `data` from an earlier attempt
+    fn interrupted(data: &[u8], written: usize) -> (RamStorage, DownloadCheckpoint) {
+        let mut storage = RamStorage::new(16 * 1024);
+        storage.0[..written].copy_from_slice(&data[..written]);
+        let checkpoint = DownloadCheckpoint {
+            bytes_written: written as u32,
+            ..DownloadCheckpoint::new(MANIFEST_HASH, FILE_HASH)
+        };
+        (storage, checkpoint)
+    }
+
+    #[test]
+    fn resume_rehashes_what_is_in_flash() {
+        let data = image(10_000);
+        let (mut storage, resumed) = interrupted(&data, 8192);
+        let mut state = RamState::default();
+        let digest = block_on(async {
+            let mut writer = FirmwareWriter::new(&mut storage, &mut state, resumed, 10_000, None).await?;
+            assert_eq!(writer.written(), 8192);
+            writer.begin(&head("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 8192-9999/10000\r\n\r\n")).await?;
+            feed(&mut writer, &data[8192..]).await?;
+            writer.finish().await
+        })
+        .unwrap();
+
+        // Only the hash of the whole image matches, so the prefix was read back from flash
+        assert_eq!(digest, <[u8; 32]>::from(Sha256::digest(&data)));
+        assert_eq!(&storage.0[..data.len()], &data[..]);
+    }
+
+    #[test]
+    fn partial_response_must_start_where_flash_ends() {
+        let data = image(10_000);
+        let (mut storage, resumed) = interrupted(&data, 8192);
+        let mut state = RamState::default();
+        let result = block_on(async {
+            let mut writer = FirmwareWriter::new(&mut storage, &mut state, resumed, 10_000, None).await?;
+            writer.begin(&head("HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 4096-9999/10000\r\n\r\n")).await
+        });
+
+        assert_eq!(result, Err(Error::Network(NetworkError::InvalidResponse)));
+    }
+
+    #[test]
+    fn full_response_restarts_from_zero() {
+        let data = image(10_000);
+        // What flash holds is stale: a full answer must overwrite it, not extend it
+        let (mut storage, resumed) = interrupted(&[0xA5; 8192], 8192);
+        let mut state = RamState::default();
+        let digest = block_on(async {
+            let mut writer = FirmwareWriter::new(&mut storage, &mut state, resumed, 10_000, None).await?;
+            writer.begin(&head("HTTP/1.1 200 OK\r\nContent-Length: 10000\r\n\r\n")).await?;
+            assert_eq!(writer.written(), 0);
+            feed(&mut writer, &data).await?;
+            assert!(writer.is_complete());
+            writer.finish().await
+        })
+        .unwrap();
+
+        assert_eq!(digest, <[u8; 32]>::from(Sha256::digest(&data)));
+        assert_eq!(&storage.0[..data.len()], &data[..]);
+    }
+}
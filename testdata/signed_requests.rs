@@ -0,0 +1,32 @@
+// Requests signed by `RequestSigner` (src/auth.rs), shared with the reference
+// verifier (tools/request-verifier). The signer's tests check that it builds
+// exactly these headers, and the verifier's tests that it accepts them.
+
+/// Seed of the signing device's key
+const DEVICE_SEED: [u8; 32] = [7; 32];
+
+/// Device ID, escaped in the headers
+const DEVICE: &str = "sensor 42/α";
+
+/// Targets of the signed GET and POST
+const MANIFEST: &str = "/ota/manifest.json?device_id=sensor%2042%2F%CE%B1";
+const REPORT: &str = "/ota/report";
+
+/// Unix time both requests were signed at
+const TIMESTAMP: u64 = 1_760_000_000;
+
+const GET_NONCE: [u8; 16] = [
+    0x0e, 0xa4, 0xdb, 0x80, 0x17, 0x86, 0x51, 0x0c, 0x94, 0xc6, 0xfc, 0xd6, 0x32, 0x19, 0x07, 0x1d,
+];
+const POST_NONCE: [u8; 16] = [
+    0xa7, 0x4d, 0xf6, 0x54, 0xfb, 0xe8, 0x4f, 0xdc, 0x2d, 0x29, 0x91, 0x2e, 0x69, 0x49, 0x20, 0x20,
+];
+
+const DEVICE_GET: &str = "Genesis-Ed25519 device=\"sensor%2042%2F%CE%B1\", ts=\"1760000000\", \
+    nonce=\"0ea4db801786510c94c6fcd63219071d\", \
+    sig=\"1ac88042c8a6b042fc163f9dae80b0a99a23a1d9eb4517e162ac4ec3f7d252f8\
+    497a37947758b35ad6a9574c7f88d47f7055f6c314873c77e52f8571b90ee409\"";
+const DEVICE_POST: &str = "Genesis-Ed25519 device=\"sensor%2042%2F%CE%B1\", ts=\"1760000000\", \
+    nonce=\"a74df654fbe84fdc2d29912e69492020\", \
+    sig=\"0f65da0c947b852d9be5d3b01ee61b18e2185e7e6914e703f707cd03571a4fd3\
+    7630b3058c0388ac6df9a903debec8c63e4f6d48f9425b6638a20e7e8b626406\"";
@@ -0,0 +1,241 @@
+//! Host-side sender for the genesis serial transport
+//!
+//! Serves a release directory to a device running `SerialTransport`:
+//!
+//! ```text
+//! stty -F /dev/ttyUSB0 115200 raw -echo
+//! ota-sender /dev/ttyUSB0 ./release
+//! ```
+//!
+//! `./release` is laid out like the HTTP server: `manifest.json` plus the
+//! files it lists. The frame format must match `src/transport/serial.rs`.
+//!
+//! The repository's cargo config targets the ESP32-C3, so build this tool with
+//! an explicit host target, e.g. `cargo run --target x86_64-unknown-linux-gnu`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use std::{env, fs, process, thread};
+
+/// Largest payload carried by a single frame
+const MAX_FRAME_PAYLOAD: usize = 512;
+
+/// Bytes marking the start of a frame
+const SYNC: [u8; 2] = [0xA5, 0x5A];
+
+/// How long to wait for the device to acknowledge a frame
+const ACK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Times a frame is resent before giving up on the device
+const MAX_RESENDS: u32 = 10;
+
+// Frame kinds
+const KIND_GET: u8 = 0x01;
+const KIND_HEAD: u8 = 0x02;
+const KIND_DATA: u8 = 0x03;
+const KIND_ACK: u8 = 0x04;
+const KIND_NAK: u8 = 0x05;
+
+struct Frame {
+    kind: u8,
+    seq: u16,
+    payload: Vec<u8>,
+}
+
+/// What the device said about the frame we just sent
+enum Reply {
+    Ack,
+    Resend,
+    /// The device started over with a new request
+    Get(Frame),
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: ota-sender <serial-device> <release-directory>");
+        process::exit(2);
+    }
+    if let Err(e) = run(&args[1], Path::new(&args[2])) {
+        eprintln!("ota-sender: {e}");
+        process::exit(1);
+    }
+}
+
+fn run(device: &str, root: &Path) -> io::Result<()> {
+    let mut port = OpenOptions::new().read(true).write(true).open(device)?;
+    let frames = spawn_reader(port.try_clone()?);
+    eprintln!("serving {} on {device}", root.display());
+
+    let mut next = None;
+    loop {
+        let frame = match next.take() {
+            Some(frame) => frame,
+            None => frames
+                .recv()
+                .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "serial port closed"))?,
+        };
+        if frame.kind == KIND_GET {
+            next = serve(&mut port, &frames, root, &frame.payload)?;
+        }
+    }
+}
+
+/// Answer one `Get`, returning a newer `Get` that interrupted it
+fn serve(port: &mut File, frames: &Receiver<Frame>, root: &Path, request: &[u8]) -> io::Result<Option<Frame>> {
+    let Some((offset, target)) = request.split_first_chunk::<4>() else {
+        return Ok(None);
+    };
+    let offset = u32::from_le_bytes(*offset) as usize;
+    let target = String::from_utf8_lossy(target);
+
+    let Some(body) = resolve(root, &target).and_then(|path| fs::read(path).ok()) else {
+        eprintln!("{target}: not found");
+        send(port, KIND_HEAD, 0, &head(404, 0))?;
+        return Ok(None);
+    };
+    if offset > body.len() {
+        eprintln!("{target}: offset {offset} past the end");
+        send(port, KIND_HEAD, 0, &head(416, body.len()))?;
+        return Ok(None);
+    }
+
+    let status = if offset > 0 { 206 } else { 200 };
+    eprintln!("{target}: sending {} bytes from offset {offset}", body.len() - offset);
+    send(port, KIND_HEAD, 0, &head(status, body.len()))?;
+
+    for (seq, chunk) in body[offset..].chunks(MAX_FRAME_PAYLOAD).enumerate() {
+        // Wraps like the device's counter
+        let seq = seq as u16;
+        let mut resends = 0;
+        loop {
+            send(port, KIND_DATA, seq, chunk)?;
+            match wait_reply(frames, seq)? {
+                Reply::Ack => break,
+                Reply::Resend if resends < MAX_RESENDS => resends += 1,
+                Reply::Resend => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "device stopped acknowledging"));
+                }
+                Reply::Get(frame) => return Ok(Some(frame)),
+            }
+        }
+    }
+    eprintln!("{target}: done");
+    Ok(None)
+}
+
+/// Wait for the device to acknowledge data frame `seq`
+fn wait_reply(frames: &Receiver<Frame>, seq: u16) -> io::Result<Reply> {
+    loop {
+        match frames.recv_timeout(ACK_TIMEOUT) {
+            Ok(frame) if frame.kind == KIND_ACK && frame.seq == seq => return Ok(Reply::Ack),
+            Ok(frame) if frame.kind == KIND_NAK && frame.seq == seq => return Ok(Reply::Resend),
+            Ok(frame) if frame.kind == KIND_GET => return Ok(Reply::Get(frame)),
+            // Late acknowledgements of earlier frames
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => return Ok(Reply::Resend),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "serial port closed"));
+            }
+        }
+    }
+}
+
+/// Map a request target onto a file below `root`, refusing to leave it
+fn resolve(root: &Path, target: &str) -> Option<PathBuf> {
+    let path = target.split('?').next()?.trim_start_matches('/');
+    let path = Path::new(path);
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then(|| root.join(path))
+}
+
+/// Payload of a `Head` frame
+fn head(status: u16, total: usize) -> [u8; 6] {
+    let mut payload = [0u8; 6];
+    payload[..2].copy_from_slice(&status.to_le_bytes());
+    payload[2..].copy_from_slice(&(total as u32).to_le_bytes());
+    payload
+}
+
+fn send(port: &mut File, kind: u8, seq: u16, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 9);
+    frame.extend_from_slice(&SYNC);
+    frame.push(kind);
+    frame.extend_from_slice(&seq.to_le_bytes());
+    frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    frame.extend_from_slice(payload);
+    let crc = crc16(0xFFFF, &frame[2..]);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    port.write_all(&frame)?;
+    port.flush()
+}
+
+/// Read frames on a background thread so acknowledgements can time out
+///
+/// Corrupt frames are dropped; the resulting silence makes the sender resend.
+fn spawn_reader(port: File) -> Receiver<Frame> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut port = BufReader::new(port);
+        while let Ok(frame) = read_frame(&mut port) {
+            if let Some(frame) = frame {
+                if tx.send(frame).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
+
+/// Read the next frame, or `None` if it was corrupt
+fn read_frame(port: &mut impl Read) -> io::Result<Option<Frame>> {
+    let mut previous = 0;
+    loop {
+        let mut byte = [0u8];
+        port.read_exact(&mut byte)?;
+        if previous == SYNC[0] && byte[0] == SYNC[1] {
+            break;
+        }
+        previous = byte[0];
+    }
+
+    let mut header = [0u8; 5];
+    port.read_exact(&mut header)?;
+    let len = u16::from_le_bytes([header[3], header[4]]) as usize;
+    if len > MAX_FRAME_PAYLOAD {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; len];
+    port.read_exact(&mut payload)?;
+    let mut crc = [0u8; 2];
+    port.read_exact(&mut crc)?;
+
+    if crc16(crc16(0xFFFF, &header), &payload) != u16::from_le_bytes(crc) {
+        return Ok(None);
+    }
+    Ok(Some(Frame {
+        kind: header[0],
+        seq: u16::from_le_bytes([header[1], header[2]]),
+        payload,
+    }))
+}
+
+/// Update a CRC-16/CCITT-FALSE checksum with `data`
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,389 @@
+//! Reference verifier for genesis device-signed requests
+//!
+//! Devices configured with a device key send
+//!
+//! ```text
+//! Authorization: Genesis-Ed25519 device="sensor-0042", ts="1760000000", nonce="<32 hex>", sig="<128 hex>"
+//! ```
+//!
+//! signed over the method, request target, timestamp and nonce (see
+//! `src/auth.rs` for the exact message). An update server checks it like so:
+//!
+//! ```no_run
+//! use request_verifier::Verifier;
+//!
+//! # let (public_key, header, now) = ([0u8; 32], "", 0);
+//! let mut verifier = Verifier::new(300);
+//! verifier.register("sensor-0042", &public_key)?;
+//! let device = verifier.verify("GET", "/ota/manifest.json?device_id=sensor-0042", header, now)?;
+//! # Ok::<(), request_verifier::VerifyError>(())
+//! ```
+//!
+//! [`authorization_header`] produces headers the way a device does, for
+//! testing servers without hardware.
+//!
+//! The repository's cargo config targets the ESP32-C3, so build this crate
+//! with an explicit host target, e.g. `cargo test --target x86_64-unknown-linux-gnu`.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier as _, VerifyingKey};
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+
+/// Authentication scheme named in the `Authorization` header
+pub const AUTH_SCHEME: &str = "Genesis-Ed25519";
+
+/// First line of every signed message, versioning the format
+pub const SIGNATURE_CONTEXT: &str = "genesis-request-v1";
+
+/// Size of a request nonce in bytes
+pub const NONCE_SIZE: usize = 16;
+
+/// Why a request was not authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The header is not a well-formed `Genesis-Ed25519` authorization
+    Malformed,
+    /// No public key is registered for the device
+    UnknownDevice,
+    /// A registered public key is not a valid Ed25519 point
+    InvalidPublicKey,
+    /// The signature does not match the request
+    BadSignature,
+    /// The timestamp is outside the accepted window
+    Stale,
+    /// The nonce was already used within the window
+    Replayed,
+}
+
+/// Parsed `Authorization` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authorization {
+    /// Device ID as sent (percent-encoded)
+    pub device: String,
+    /// Unix time the request was signed at, in seconds
+    pub timestamp: u64,
+    pub nonce: [u8; NONCE_SIZE],
+    pub signature: [u8; 64],
+}
+
+/// Checks signed requests against registered device keys
+///
+/// Nonces of accepted requests are remembered for the timestamp window, so a
+/// captured request cannot be replayed while its timestamp is still accepted.
+pub struct Verifier {
+    max_skew: u64,
+    keys: HashMap<String, VerifyingKey>,
+    seen: HashMap<(String, [u8; NONCE_SIZE]), u64>,
+}
+
+impl Authorization {
+    /// Parse an `Authorization` header value
+    pub fn parse(value: &str) -> Result<Self, VerifyError> {
+        let params = value
+            .trim()
+            .strip_prefix(AUTH_SCHEME)
+            .and_then(|rest| rest.strip_prefix(' '))
+            .ok_or(VerifyError::Malformed)?;
+
+        let (mut device, mut timestamp, mut nonce, mut signature) = (None, None, None, None);
+        for param in params.split(',') {
+            let (name, value) = param.trim().split_once('=').ok_or(VerifyError::Malformed)?;
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .ok_or(VerifyError::Malformed)?;
+            let slot = match name {
+                "device" => &mut device,
+                "ts" => &mut timestamp,
+                "nonce" => &mut nonce,
+                "sig" => &mut signature,
+                _ => continue,
+            };
+            if slot.replace(value).is_some() {
+                return Err(VerifyError::Malformed);
+            }
+        }
+
+        let device = device.ok_or(VerifyError::Malformed)?;
+        if device.is_empty() || percent_decode(device).is_none() {
+            return Err(VerifyError::Malformed);
+        }
+        Ok(Self {
+            device: device.to_owned(),
+            timestamp: timestamp
+                .ok_or(VerifyError::Malformed)?
+                .parse()
+                .map_err(|_| VerifyError::Malformed)?,
+            nonce: decode_hex(nonce.ok_or(VerifyError::Malformed)?)?,
+            signature: decode_hex(signature.ok_or(VerifyError::Malformed)?)?,
+        })
+    }
+
+    /// Device ID with percent-encoding removed
+    pub fn device_id(&self) -> String {
+        // Checked in `parse`
+        percent_decode(&self.device).unwrap_or_default()
+    }
+
+    /// The message the device signed for a request
+    pub fn signed_message(&self, method: &str, target: &str) -> String {
+        signed_message(&self.device, method, target, self.timestamp, &self.nonce)
+    }
+}
+
+impl Verifier {
+    /// Accept timestamps up to `max_skew` seconds from the server's clock
+    pub fn new(max_skew: u64) -> Self {
+        Self {
+            max_skew,
+            keys: HashMap::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Register the public key of a device
+    pub fn register(&mut self, device_id: &str, public_key: &[u8; 32]) -> Result<(), VerifyError> {
+        let key = VerifyingKey::from_bytes(public_key).map_err(|_| VerifyError::InvalidPublicKey)?;
+        self.keys.insert(device_id.to_owned(), key);
+        Ok(())
+    }
+
+    /// Authenticate a request, returning the device ID it came from
+    ///
+    /// `target` is the path and query from the request line, `now` the
+    /// server's Unix time in seconds.
+    pub fn verify(&mut self, method: &str, target: &str, header: &str, now: u64) -> Result<String, VerifyError> {
+        let auth = Authorization::parse(header)?;
+        if auth.timestamp.abs_diff(now) > self.max_skew {
+            return Err(VerifyError::Stale);
+        }
+
+        let device_id = auth.device_id();
+        let key = self.keys.get(&device_id).ok_or(VerifyError::UnknownDevice)?;
+        let message = auth.signed_message(method, target);
+        key.verify(message.as_bytes(), &Signature::from_bytes(&auth.signature))
+            .map_err(|_| VerifyError::BadSignature)?;
+
+        // Only remember nonces of genuine requests, so forgeries cannot fill the cache
+        let max_skew = self.max_skew;
+        self.seen
+            .retain(|_, timestamp| timestamp.abs_diff(now) <= max_skew);
+        if self
+            .seen
+            .insert((device_id.clone(), auth.nonce), auth.timestamp)
+            .is_some()
+        {
+            return Err(VerifyError::Replayed);
+        }
+        Ok(device_id)
+    }
+}
+
+/// Build an `Authorization` header value the way a device does
+pub fn authorization_header(
+    key: &SigningKey,
+    device_id: &str,
+    method: &str,
+    target: &str,
+    timestamp: u64,
+    nonce: &[u8; NONCE_SIZE],
+) -> String {
+    let device = percent_encode(device_id);
+    let message = signed_message(&device, method, target, timestamp, nonce);
+    let signature = key.sign(message.as_bytes()).to_bytes();
+    format!(
+        "{AUTH_SCHEME} device=\"{device}\", ts=\"{timestamp}\", nonce=\"{}\", sig=\"{}\"",
+        encode_hex(nonce),
+        encode_hex(&signature)
+    )
+}
+
+fn signed_message(device: &str, method: &str, target: &str, timestamp: u64, nonce: &[u8]) -> String {
+    format!(
+        "{SIGNATURE_CONTEXT}\n{device}\n{method}\n{target}\n{timestamp}\n{}",
+        encode_hex(nonce)
+    )
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+fn decode_hex<const N: usize>(hex: &str) -> Result<[u8; N], VerifyError> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return Err(VerifyError::Malformed);
+    }
+    let mut bytes = [0u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| VerifyError::Malformed)?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| VerifyError::Malformed)?;
+    }
+    Ok(bytes)
+}
+
+/// Percent-encode everything but unreserved characters, as the device does
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        if byte == b'%' {
+            let pair = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&pair).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            VerifyError::Malformed => "malformed authorization header",
+            VerifyError::UnknownDevice => "unknown device",
+            VerifyError::InvalidPublicKey => "invalid public key",
+            VerifyError::BadSignature => "signature does not match",
+            VerifyError::Stale => "timestamp outside the accepted window",
+            VerifyError::Replayed => "nonce already used",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    include!("../../../testdata/signed_requests.rs");
+
+    fn device_key() -> SigningKey {
+        SigningKey::from_bytes(&DEVICE_SEED)
+    }
+
+    fn verifier() -> Verifier {
+        let mut verifier = Verifier::new(300);
+        verifier
+            .register(DEVICE, &device_key().verifying_key().to_bytes())
+            .unwrap();
+        verifier
+    }
+
+    #[test]
+    fn device_signed_requests_are_accepted() {
+        let mut verifier = verifier();
+        assert_eq!(verifier.verify("GET", MANIFEST, DEVICE_GET, TIMESTAMP).as_deref(), Ok(DEVICE));
+        assert_eq!(
+            verifier.verify("POST", REPORT, DEVICE_POST, TIMESTAMP + 10).as_deref(),
+            Ok(DEVICE)
+        );
+    }
+
+    #[test]
+    fn headers_are_built_like_the_device() {
+        let auth = Authorization::parse(DEVICE_GET).unwrap();
+        assert_eq!(auth.device_id(), DEVICE);
+        assert_eq!(auth.nonce, GET_NONCE);
+        let header = authorization_header(&device_key(), DEVICE, "GET", MANIFEST, TIMESTAMP, &GET_NONCE);
+        assert_eq!(header, DEVICE_GET);
+        let header = authorization_header(&device_key(), DEVICE, "POST", REPORT, TIMESTAMP, &POST_NONCE);
+        assert_eq!(header, DEVICE_POST);
+    }
+
+    #[test]
+    fn tampered_requests_are_rejected() {
+        let mut verifier = verifier();
+        verifier
+            .register("sensor-0043", &SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes())
+            .unwrap();
+
+        // Signed for another method or target
+        assert_eq!(verifier.verify("POST", MANIFEST, DEVICE_GET, TIMESTAMP), Err(VerifyError::BadSignature));
+        assert_eq!(
+            verifier.verify("GET", "/ota/manifest.json?device_id=sensor-0043", DEVICE_GET, TIMESTAMP),
+            Err(VerifyError::BadSignature)
+        );
+        // Header fields changed after signing
+        let later = DEVICE_GET.replace("ts=\"1760000000\"", "ts=\"1760000100\"");
+        assert_eq!(verifier.verify("GET", MANIFEST, &later, TIMESTAMP), Err(VerifyError::BadSignature));
+        let other_device = DEVICE_GET.replace("sensor%2042%2F%CE%B1", "sensor-0043");
+        assert_eq!(verifier.verify("GET", MANIFEST, &other_device, TIMESTAMP), Err(VerifyError::BadSignature));
+        let other_nonce = DEVICE_GET.replace("nonce=\"0e", "nonce=\"1e");
+        assert_eq!(verifier.verify("GET", MANIFEST, &other_nonce, TIMESTAMP), Err(VerifyError::BadSignature));
+        let bad_signature = DEVICE_GET.replace("sig=\"1a", "sig=\"1b");
+        assert_eq!(verifier.verify("GET", MANIFEST, &bad_signature, TIMESTAMP), Err(VerifyError::BadSignature));
+        // Signed by a key that is not registered
+        let unknown = authorization_header(&device_key(), "sensor-0044", "GET", MANIFEST, TIMESTAMP, &[1; NONCE_SIZE]);
+        assert_eq!(verifier.verify("GET", MANIFEST, &unknown, TIMESTAMP), Err(VerifyError::UnknownDevice));
+
+        // None of the failures used up the nonce
+        assert_eq!(verifier.verify("GET", MANIFEST, DEVICE_GET, TIMESTAMP).as_deref(), Ok(DEVICE));
+    }
+
+    #[test]
+    fn requests_outside_the_window_are_rejected() {
+        let mut verifier = verifier();
+        assert_eq!(verifier.verify("GET", MANIFEST, DEVICE_GET, TIMESTAMP + 301), Err(VerifyError::Stale));
+        assert_eq!(verifier.verify("GET", MANIFEST, DEVICE_GET, TIMESTAMP - 301), Err(VerifyError::Stale));
+        assert_eq!(verifier.verify("GET", MANIFEST, DEVICE_GET, TIMESTAMP + 300).as_deref(), Ok(DEVICE));
+
+        let mut verifier = Verifier::new(300);
+        verifier
+            .register(DEVICE, &device_key().verifying_key().to_bytes())
+            .unwrap();
+        assert_eq!(verifier.verify("GET", MANIFEST, DEVICE_GET, TIMESTAMP - 300).as_deref(), Ok(DEVICE));
+    }
+
+    #[test]
+    fn replayed_requests_are_rejected() {
+        let mut verifier = verifier();
+        assert!(verifier.verify("GET", MANIFEST, DEVICE_GET, TIMESTAMP).is_ok());
+        assert_eq!(verifier.verify("GET", MANIFEST, DEVICE_GET, TIMESTAMP + 60), Err(VerifyError::Replayed));
+        // Once the nonce is forgotten, the timestamp is too old anyway
+        assert_eq!(verifier.verify("GET", MANIFEST, DEVICE_GET, TIMESTAMP + 400), Err(VerifyError::Stale));
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let mut verifier = verifier();
+        let headers = [
+            "",
+            "Bearer abc",
+            "Genesis-Ed25519",
+            "Genesis-Ed25519 device=\"sensor\", ts=\"1\", nonce=\"00\", sig=\"00\"",
+            &DEVICE_GET.replace("ts=\"1760000000\"", "ts=\"soon\""),
+            &DEVICE_GET.replace("device=\"sensor%2042%2F%CE%B1\"", "device=\"sensor%4\""),
+            &DEVICE_GET.replace("device=\"sensor%2042%2F%CE%B1\"", "device=\"\""),
+            &DEVICE_GET.replace(", sig=", ", ts=\"1760000000\", sig="),
+            &DEVICE_GET.replace("nonce=\"0e", "nonce=\"0g"),
+        ];
+        for header in headers {
+            assert_eq!(verifier.verify("GET", MANIFEST, header, TIMESTAMP), Err(VerifyError::Malformed), "{header}");
+        }
+    }
+
+    #[test]
+    fn invalid_public_keys_are_refused() {
+        // y = 2 is not on the curve
+        let mut point = [0u8; 32];
+        point[0] = 2;
+        assert_eq!(Verifier::new(300).register(DEVICE, &point), Err(VerifyError::InvalidPublicKey));
+    }
+}